@@ -1,4 +1,13 @@
-use std::{collections::HashSet, env};
+// The runtime of libraries doesn't use the parts reading the input of programs
+#![cfg_attr(snek_lib, allow(dead_code))]
+
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::DefaultHasher, HashSet},
+    fs,
+    hash::{Hash, Hasher},
+    io::{self, Write},
+};
 
 type SnekVal = u64;
 
@@ -10,25 +19,214 @@ pub enum ErrCode {
     IndexOutOfBounds = 3,
     InvalidVecSize = 4,
     OutOfMemory = 5,
+    DivideByZero = 6,
+    WrongStruct = 7,
+    NoMatch = 8,
+    Uncaught = 9,
+    AssertionFailed = 10,
+    Io = 11,
+}
+
+impl ErrCode {
+    /// The string a `try` catches when the error is raised inside it.
+    fn kind(self) -> Option<&'static [u64; 6]> {
+        match self {
+            ErrCode::InvalidArgument => Some(&INVALID_ARGUMENT),
+            ErrCode::Overflow => Some(&OVERFLOW),
+            ErrCode::IndexOutOfBounds => Some(&INDEX_OUT_OF_BOUNDS),
+            ErrCode::InvalidVecSize => Some(&INVALID_VEC_SIZE),
+            ErrCode::DivideByZero => Some(&DIVIDE_BY_ZERO),
+            ErrCode::WrongStruct => Some(&WRONG_STRUCT),
+            ErrCode::NoMatch => Some(&NO_MATCH),
+            ErrCode::AssertionFailed => Some(&ASSERTION_FAILED),
+            ErrCode::Io => Some(&IO_ERROR),
+            ErrCode::OutOfMemory | ErrCode::Uncaught => None,
+        }
+    }
 }
 
 const TRUE: u64 = 7;
 const FALSE: u64 = 3;
+const NIL: u64 = 1;
+
+/// Vectors are tagged with `0b001`. Other heap objects are tagged with `0b101` and have a header
+/// word after the size whose low byte identifies the kind of object:
+///
+/// ```text
+/// [gc word][size][header][data..]
+/// ```
+///
+/// where `size` counts the header and the data words.
+const VEC_TAG: u64 = 0b001;
+const BOX_TAG: u64 = 0b101;
+const TAG_MASK: u64 = 0b111;
+
+/// Header kind of strings. The rest of the header holds the length in bytes, and the data words
+/// hold the (UTF-8) bytes.
+const STRING_KIND: u64 = 1;
+
+/// Header kind of struct instances. The rest of the header holds the address of the struct's
+/// descriptor, laid out as `[field count][name][field names..]` (names are strings), and the data
+/// words hold the fields.
+const STRUCT_KIND: u64 = 2;
+
+/// Header kind of bignums, the integers too large to be tagged numbers. The rest of the header
+/// holds the sign (1 if negative), and the data words hold the magnitude, least significant word
+/// first. Arithmetic always returns a tagged number when the result fits in one.
+const BIGNUM_KIND: u64 = 3;
+
+/// Header kind of floats. The data word holds the bits of the (double precision) float.
+const FLOAT_KIND: u64 = 4;
+
+/// Header kind of hash maps. The data words hold the number of entries (a tagged number) and the
+/// table, a vector of `[key][value]` slots whose length is a power of two. Maps use open
+/// addressing with linear probing, and unused slots have the key [`EMPTY`].
+///
+/// Strings and numbers are hashed by value and other objects by address, so the garbage collector
+/// rehashes the maps it moves.
+const MAP_KIND: u64 = 5;
+
+/// Key of the unused slots of a map's table. It isn't a valid snek value (its tag is `0b111` but
+/// it isn't `true`) and the garbage collector doesn't follow it.
+const EMPTY: u64 = 0b1111;
+/// Number of slots of a new map's table.
+const MAP_MIN_CAPACITY: usize = 8;
+
+/// Smallest and largest integers that can be represented as a tagged number.
+const MIN_NUM: i64 = -(1 << 62);
+const MAX_NUM: i64 = (1 << 62) - 1;
 
 static mut HEAP_START: *const u64 = std::ptr::null();
-static mut HEAP_END: *const u64 = std::ptr::null();
+#[export_name = "\x01snek_heap_end"]
+pub static mut HEAP_END: *const u64 = std::ptr::null();
+/// The heap pointer when the program last called the runtime, kept up to date by the primitives
+/// so an error they raise can hand it back to the program. The C functions exported by a library
+/// start from it, and store theirs back when they return.
+#[export_name = "\x01snek_heap_ptr"]
+pub static mut HEAP_PTR: *const u64 = std::ptr::null();
+
+/// The input of the program and its command line arguments, allocated on the heap before the
+/// program starts. They are roots for the garbage collector.
+#[export_name = "\x01snek_input"]
+pub static mut SNEK_INPUT: SnekVal = FALSE;
+static mut SNEK_ARGV: SnekVal = NIL;
 
+/// Handler record of the innermost `try`, or null if there is none. Records are on the stack and
+/// hold, from the lowest address to the highest, the previous handler, the address of the handler
+/// code, and the values of `%rsp`, `%rbp`, `%rbx` and `%r14` to restore before running it.
+///
+/// Programs compiled to C have records without handler code (0 in its place), followed by a
+/// function resuming the `try` that never returns, see [`CResume`].
+#[export_name = "\x01snek_handler"]
+pub static mut SNEK_HANDLER: *const u64 = std::ptr::null();
+
+/// A string object outside of the heap, for the errors caught by `try`. The string must fit in the
+/// three data words.
+const fn static_str(s: &str) -> [u64; 6] {
+    let bytes = s.as_bytes();
+    let mut obj = [0, 4, STRING_KIND | (bytes.len() as u64) << 8, 0, 0, 0];
+    let mut i = 0;
+    while i < bytes.len() {
+        obj[3 + i / 8] |= (bytes[i] as u64) << (8 * (i % 8));
+        i += 1;
+    }
+    obj
+}
+
+static INVALID_ARGUMENT: [u64; 6] = static_str("invalid argument");
+static OVERFLOW: [u64; 6] = static_str("overflow");
+static INDEX_OUT_OF_BOUNDS: [u64; 6] = static_str("index out of bounds");
+static INVALID_VEC_SIZE: [u64; 6] = static_str("invalid vector size");
+static DIVIDE_BY_ZERO: [u64; 6] = static_str("division by zero");
+static WRONG_STRUCT: [u64; 6] = static_str("wrong struct");
+static NO_MATCH: [u64; 6] = static_str("no match");
+static ASSERTION_FAILED: [u64; 6] = static_str("assertion failed");
+static IO_ERROR: [u64; 6] = static_str("io error");
+
+// Libraries compiled with `--lib` are linked with the runtime built with `--cfg snek_lib`, which
+// leaves out the entry point of programs
+#[cfg(not(snek_lib))]
 #[link(name = "our_code")]
 extern "C" {
     // The \x01 here is an undocumented feature of LLVM that ensures
     // it does not add an underscore in front of the name.
     // Courtesy of Max New (https://maxsnew.com/teaching/eecs-483-fa22/hw_adder_assignment.html)
     #[link_name = "\x01our_code_starts_here"]
-    fn our_code_starts_here(input: u64, heap_start: *const u64, heap_end: *const u64) -> u64;
+    fn our_code_starts_here(heap_start: *const u64, heap_end: *const u64) -> u64;
+}
+
+/// Transfers control to the handler of the innermost `try` with `val` as the caught value, if there
+/// is one.
+unsafe fn unwind(val: SnekVal) {
+    let handler = SNEK_HANDLER;
+    if handler.is_null() {
+        return;
+    }
+    SNEK_HANDLER = *handler as *const u64;
+    if *handler.add(1) == 0 {
+        let resume: CResume = std::mem::transmute(*handler.add(2));
+        resume(handler, val, HEAP_PTR);
+    }
+    std::arch::asm!(
+        "mov rax, rsi",
+        "mov r15, rdx",
+        "mov rsp, [rdi + 16]",
+        "mov rbp, [rdi + 24]",
+        "mov rbx, [rdi + 32]",
+        "mov r14, [rdi + 40]",
+        "jmp qword ptr [rdi + 8]",
+        in("rdi") handler,
+        in("rsi") val,
+        in("rdx") HEAP_PTR,
+        options(noreturn)
+    );
+}
+
+/// Resumes the `try` of a program compiled to C, given its handler record, the raised value and the
+/// heap pointer.
+type CResume = unsafe extern "C" fn(*const u64, SnekVal, *const u64) -> !;
+
+#[export_name = "\x01snek_raise"]
+pub unsafe extern "C" fn snek_raise(val: SnekVal, heap_ptr: *const u64) -> ! {
+    HEAP_PTR = heap_ptr;
+    unwind(val);
+    eprintln!("uncaught exception: {}", snek_str(val, &mut HashSet::new()));
+    std::process::exit(ErrCode::Uncaught as i32);
 }
 
 #[export_name = "\x01snek_error"]
-pub extern "C" fn snek_error(errcode: i64) {
+pub unsafe extern "C" fn snek_error(errcode: i64, heap_ptr: *const u64) -> ! {
+    HEAP_PTR = heap_ptr;
+    raise_error(match errcode {
+        1 => ErrCode::InvalidArgument,
+        2 => ErrCode::Overflow,
+        3 => ErrCode::IndexOutOfBounds,
+        4 => ErrCode::InvalidVecSize,
+        6 => ErrCode::DivideByZero,
+        _ => {
+            eprintln!("an error ocurred {}", errcode);
+            std::process::exit(errcode as i32);
+        }
+    })
+}
+
+/// Raises an I/O error on `what`, which `try` catches like the other runtime errors. Without a
+/// `try`, the program exits with the description of the error.
+fn io_error(what: &str, err: io::Error) -> ! {
+    if let Some(kind) = ErrCode::Io.kind() {
+        unsafe { unwind(kind.as_ptr() as u64 | BOX_TAG) };
+    }
+    eprintln!("io error: {}: {}", what, err);
+    std::process::exit(ErrCode::Io as i32);
+}
+
+/// Raises a runtime error, which the innermost `try` catches as a string describing the error.
+/// Without a `try`, the program exits with the error's code.
+fn raise_error(code: ErrCode) -> ! {
+    if let Some(kind) = code.kind() {
+        unsafe { unwind(kind.as_ptr() as u64 | BOX_TAG) };
+    }
+    let errcode = code as i64;
     if errcode == ErrCode::InvalidArgument as i64 {
         eprintln!("invalid argument");
     } else if errcode == ErrCode::Overflow as i64 {
@@ -37,15 +235,65 @@ pub extern "C" fn snek_error(errcode: i64) {
         eprintln!("index out of bounds");
     } else if errcode == ErrCode::InvalidVecSize as i64 {
         eprintln!("vector size must be non-negative");
+    } else if errcode == ErrCode::DivideByZero as i64 {
+        eprintln!("division by zero");
     } else {
         eprintln!("an error ocurred {}", errcode);
     }
     std::process::exit(errcode as i32);
 }
 
+#[export_name = "\x01snek_struct_error"]
+pub unsafe extern "C" fn snek_struct_error(
+    desc: *const u64,
+    val: SnekVal,
+    heap_ptr: *const u64,
+) -> ! {
+    HEAP_PTR = heap_ptr;
+    unwind(WRONG_STRUCT.as_ptr() as u64 | BOX_TAG);
+    eprintln!(
+        "expected struct {}, got {}",
+        String::from_utf8_lossy(expect_str(desc.add(1).read())),
+        snek_str(val, &mut HashSet::new())
+    );
+    std::process::exit(ErrCode::WrongStruct as i32);
+}
+
+#[export_name = "\x01snek_no_match"]
+pub unsafe extern "C" fn snek_no_match(val: SnekVal, heap_ptr: *const u64) -> ! {
+    HEAP_PTR = heap_ptr;
+    unwind(NO_MATCH.as_ptr() as u64 | BOX_TAG);
+    eprintln!("no match for {}", snek_str(val, &mut HashSet::new()));
+    std::process::exit(ErrCode::NoMatch as i32);
+}
+
+/// Reports a failed `assert`, `cond` is its condition as it was written.
+#[export_name = "\x01snek_assert_failed"]
+pub unsafe extern "C" fn snek_assert_failed(
+    cond: SnekVal,
+    msg: SnekVal,
+    heap_ptr: *const u64,
+) -> ! {
+    HEAP_PTR = heap_ptr;
+    unwind(ASSERTION_FAILED.as_ptr() as u64 | BOX_TAG);
+    let msg = match str_bytes(msg) {
+        Some(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        None => snek_str(msg, &mut HashSet::new()),
+    };
+    eprintln!(
+        "assertion failed: {}: {}",
+        String::from_utf8_lossy(expect_str(cond)),
+        msg
+    );
+    std::process::exit(ErrCode::AssertionFailed as i32);
+}
+
 #[export_name = "\x01snek_print"]
 pub unsafe extern "C" fn snek_print(val: SnekVal) -> SnekVal {
-    println!("{}", snek_str(val, &mut HashSet::new()));
+    match str_bytes(val) {
+        Some(bytes) => println!("{}", String::from_utf8_lossy(bytes)),
+        None => println!("{}", snek_str(val, &mut HashSet::new())),
+    }
     val
 }
 
@@ -70,7 +318,7 @@ pub unsafe extern "C" fn snek_print(val: SnekVal) -> SnekVal {
 /// The new heap pointer where the program should allocate the vector (i.e., the new value of `%r15`)
 ///
 #[export_name = "\x01snek_try_gc"]
-pub unsafe fn snek_try_gc(
+pub unsafe extern "C" fn snek_try_gc(
     count: isize,
     heap_ptr: *const u64,
     stack_base: *const u64,
@@ -112,7 +360,26 @@ unsafe fn find_stack_roots(
 
 fn mark(roots: Vec<*mut u64>) {
     for item in roots {
-        unsafe {heap_mark((*item-1) as *mut u64)};
+        unsafe {heap_mark(*item)};
+    }
+}
+
+/// Address of the heap object `val` points to.
+fn untag(val: SnekVal) -> *mut u64 {
+    (val & !TAG_MASK) as *mut u64
+}
+
+/// Indices (relative to the start of the object) of the words of `val` that hold snek values, as
+/// opposed to raw data the garbage collector must not interpret.
+unsafe fn traced_words(val: SnekVal) -> std::ops::Range<usize> {
+    let obj = untag(val);
+    let size = obj.add(1).read() as usize;
+    if val & TAG_MASK == VEC_TAG {
+        return 2..2 + size;
+    }
+    match obj.add(2).read() & 0xff {
+        STRING_KIND | BIGNUM_KIND | FLOAT_KIND => 3..3,
+        _ => 3..2 + size,
     }
 }
 
@@ -126,10 +393,8 @@ unsafe fn is_heap_obj(obj: u64) -> bool {
     false 
 }
 
-unsafe fn heap_mark(obj_addr: *mut u64) {
-    ///////////////////
-    // obj_addr is the heap address of a heap object
-    ///////////////////
+unsafe fn heap_mark(val: SnekVal) {
+    let obj_addr = untag(val);
 
     // check if object has already been visited
     if *obj_addr == 1 {
@@ -141,15 +406,11 @@ unsafe fn heap_mark(obj_addr: *mut u64) {
 
     // iterate through remaining items stored in heap object to determine 
     // if there exists pointer to other heap object, recursively mark these items
-    let obj_len = obj_addr.add(1).read() as usize;
-    let mut ind = 0;
-
-    while ind < obj_len {
-        let heap_val = *obj_addr.add(2+ind);
+    for ind in traced_words(val) {
+        let heap_val = *obj_addr.add(ind);
         if is_heap_obj(heap_val) {
-            heap_mark((heap_val - 1) as *mut u64);
+            heap_mark(heap_val);
         }
-        ind+=1;
     }
 }
 
@@ -160,8 +421,9 @@ unsafe fn fwd_headers(heap_ptr: *const u64){
     while from < heap_ptr as *mut u64 {
         if (*from) == 1 {
             *from = (to as u64) + 1;
-            to = to.add((2+*to.add(1)) as usize);
-            from = from.add((2+*from.add(1)) as usize);
+            let obj_len = (2+*from.add(1)) as usize;
+            to = to.add(obj_len);
+            from = from.add(obj_len);
 
         } else if (*from) == 0 {
             from = from.add((2+*from.add(1)) as usize);
@@ -172,10 +434,9 @@ unsafe fn fwd_headers(heap_ptr: *const u64){
     } 
 }
 
-unsafe fn fwd_internal(roots: Vec<*mut u64>, heap_ptr: *const u64){
+unsafe fn fwd_internal(roots: Vec<*mut u64>, heap_ptr: *const u64, maps: &mut Vec<SnekVal>){
     for stack_ref in roots {
-        let heap_obj = (*stack_ref - 1) as *mut u64;
-        fwd_heap(heap_obj);
+        fwd_heap(*stack_ref, maps);
         update_stack(stack_ref);
     }
 
@@ -190,87 +451,66 @@ unsafe fn fwd_internal(roots: Vec<*mut u64>, heap_ptr: *const u64){
     }
 }
 
+/// The forwarding address of the object `val` points to, with the same tag as `val`
+unsafe fn forwarded(val: SnekVal) -> SnekVal {
+    (*untag(val) & !TAG_MASK) | (val & TAG_MASK)
+}
+
 /// Update references on the stack
 unsafe fn update_stack(stack_ref: *mut u64){
-    let heap_addr = (*stack_ref - 1) as *mut u64;
-    let heap_val  = *heap_addr;
-    *stack_ref = heap_val;
+    *stack_ref = forwarded(*stack_ref);
 }
 
-/// Update internal heap references
-unsafe fn fwd_heap(obj: *mut u64){
+/// Update internal heap references, collecting the forwarded maps in `maps`
+unsafe fn fwd_heap(val: SnekVal, maps: &mut Vec<SnekVal>){
+    let obj = untag(val);
     let obj_len = (obj).add(1).read() as usize;
 
     if (obj_len as i64) < 0 {
         return
-    } else 
-    {   
-        // mark as forwarded
-        let obj_len_addr = obj.add(1);
-        *obj_len_addr = ((obj_len as i64) * -1) as u64;
     }
+    if is_map(val) {
+        maps.push(forwarded(val));
+    }
+    let fields = traced_words(val);
+    // mark as forwarded
+    let obj_len_addr = obj.add(1);
+    *obj_len_addr = ((obj_len as i64) * -1) as u64;
 
-    let mut ind = 0;
-    while ind < obj_len {
-        let heap_val = *obj.add(2+ind);
+    for ind in fields {
+        let heap_val = *obj.add(ind);
         if is_heap_obj(heap_val) {
-            let mut fwd_addr = *((heap_val-1)as *mut u64);
-            let mut obj_ref = obj.add(2+ind);
-            *obj_ref = fwd_addr;
-            fwd_heap((heap_val-1) as *mut u64)
+            *obj.add(ind) = forwarded(heap_val);
+            fwd_heap(heap_val, maps)
         }
-        ind+=1;
     }
 }
 
-/// Iterate through heap compacting references and resetting mark word
+/// Iterate through heap moving live objects to their forwarding address and resetting mark word.
+/// Returns the number of words freed.
 unsafe fn compact(heap_ptr: *const u64) -> u64 {
-    let mut addr = HEAP_START as *mut u64;
-
-    let mut remain_garb = 0;
-    let mut total_garb = 0;
-
-    while addr < heap_ptr as *mut u64 {
-        // find garbage memory and length of garbage 
-        if (*addr) == 0 {
-            remain_garb = (addr.add(1).read() + 2) as usize;
-            total_garb += remain_garb;
-            
-            // advance address to end of garbage memory (next heap object)        
-            let mut temp_addr = addr.add(remain_garb);
-            if temp_addr >= heap_ptr as *mut u64 {
-                break
-            }
+    let mut from = HEAP_START as *mut u64;
+    let mut end = HEAP_START as *mut u64;
 
-            // shift every word after this down by the length of garbage memory
-            while temp_addr < heap_ptr as *mut u64 {
-                let mut garb_mem = temp_addr.sub(remain_garb);
-                *garb_mem = *temp_addr;
-                temp_addr = temp_addr.add(1);
-            }
-        } else {
-            addr = addr.add((addr.add(1).read() + 2) as usize) as *mut u64
+    while from < heap_ptr as *mut u64 {
+        let obj_len = (from.add(1).read() + 2) as usize;
+        if (*from) != 0 {
+            // objects only move down, so this never overwrites an object we haven't moved yet
+            let to = untag(*from);
+            std::ptr::copy(from, to, obj_len);
+            *to = 0;
+            end = to.add(obj_len);
         }
-
+        from = from.add(obj_len);
     }
 
-    // hop through heap unmarking all metabit used for garbage collection
-    addr = HEAP_START as *mut u64;
-    while addr < heap_ptr.sub(total_garb + 1) as *mut u64 {
-        let obj_len = (addr.add(1).read() + 2) as usize;
-
-        *addr = 0;
-        addr = addr.add(obj_len);
-    }
-
-
-    return total_garb as u64
+    heap_ptr.offset_from(end) as u64
 }
 
 /// This function should trigger garbage collection and return the updated heap pointer (i.e., the new
 /// value of `%r15`). See [`snek_try_gc`] for a description of the meaning of the arguments.
 #[export_name = "\x01snek_gc"]
-pub unsafe fn snek_gc(
+pub unsafe extern "C" fn snek_gc(
     heap_ptr: *const u64,
     stack_base: *const u64,
     curr_rbp: *const u64,
@@ -280,7 +520,12 @@ pub unsafe fn snek_gc(
     // snek_print_stack(stack_base,curr_rbp,curr_rsp);
 
     // first find all roots on the stack (i.e. search for anything with heap data tag)
-    let roots = find_stack_roots(stack_base,curr_rbp,curr_rsp);
+    let mut roots = find_stack_roots(stack_base,curr_rbp,curr_rsp);
+    for root in [std::ptr::addr_of_mut!(SNEK_INPUT), std::ptr::addr_of_mut!(SNEK_ARGV)] {
+        if is_heap_obj(*root) {
+            roots.push(root);
+        }
+    }
 
     // mark active heap objects
     mark(roots.clone());
@@ -289,17 +534,23 @@ pub unsafe fn snek_gc(
     fwd_headers(heap_ptr);
 
     // forward internal references and stack references
-    fwd_internal(roots.clone(), heap_ptr);
+    let mut maps = Vec::new();
+    fwd_internal(roots.clone(), heap_ptr, &mut maps);
 
     // compact heap
     let removed_words = compact(heap_ptr);
+
+    // keys hashed by address have moved
+    for map in maps {
+        map_rehash(map);
+    }
     heap_ptr.sub(removed_words as usize)
 }
 
 
 /// Helper function to print heap
 #[export_name = "\x01snek_print_heap"]
-unsafe fn print_heap(heap_ptr: *const u64) {
+unsafe extern "C" fn print_heap(heap_ptr: *const u64) {
     let mut ptr = HEAP_START;
     println!("HEAP PTR {:?}", heap_ptr);
     println!("************************");
@@ -317,7 +568,7 @@ unsafe fn print_heap(heap_ptr: *const u64) {
 /// A helper function that can called with the `(snek-printstack)` snek function. It prints the stack
 /// See [`snek_try_gc`] for a description of the meaning of the arguments.
 #[export_name = "\x01snek_print_stack"]
-pub unsafe fn snek_print_stack(stack_base: *const u64, curr_rbp: *const u64, curr_rsp: *const u64) {
+pub unsafe extern "C" fn snek_print_stack(stack_base: *const u64, curr_rbp: *const u64, curr_rsp: *const u64) {
     let mut ptr = stack_base;
     println!("-----------------------------------------");
     while ptr >= curr_rsp {
@@ -335,13 +586,48 @@ unsafe fn snek_str(val: SnekVal, seen: &mut HashSet<SnekVal>) -> String {
         format!("false")
     } else if val & 1 == 0 {
         format!("{}", (val as i64) >> 1)
-    } else if val == 1 {
+    } else if val == NIL {
         format!("nil")
-    } else if val & 1 == 1 {
+    } else if is_bignum(val) {
+        read_num(val).unwrap().to_string()
+    } else if let Some(x) = read_float(val) {
+        float_str(x)
+    } else if let Some(bytes) = str_bytes(val) {
+        format!("{:?}", String::from_utf8_lossy(bytes))
+    } else if is_map(val) {
+        if !seen.insert(val) {
+            return "#{...}".to_string();
+        }
+        let entries: Vec<String> = map_entries(val)
+            .into_iter()
+            .map(|(key, value)| format!("{}: {}", snek_str(key, seen), snek_str(value, seen)))
+            .collect();
+        seen.remove(&val);
+        format!("#{{{}}}", entries.join(", "))
+    } else if let Some(desc) = struct_desc(val) {
+        let name = String::from_utf8_lossy(expect_str(desc.add(1).read()));
+        if desc.read() == 0 {
+            return name.to_string();
+        }
+        if !seen.insert(val) {
+            return "{...}".to_string();
+        }
+        let fields = untag(val).add(3);
+        let mut res = format!("{}{{", name);
+        for i in 0..desc.read() as usize {
+            if i > 0 {
+                res = res + ", ";
+            }
+            let name = String::from_utf8_lossy(expect_str(desc.add(2 + i).read()));
+            res = res + &format!("{}: {}", name, snek_str(fields.add(i).read(), seen));
+        }
+        seen.remove(&val);
+        res + "}"
+    } else if val & TAG_MASK == VEC_TAG {
         if !seen.insert(val) {
             return "[...]".to_string();
         }
-        let addr = (val - 1) as *const u64;
+        let addr = untag(val);
         let size = addr.add(1).read() as usize;
         let mut res = "[".to_string();
         for i in 0..size {
@@ -358,32 +644,1150 @@ unsafe fn snek_str(val: SnekVal, seen: &mut HashSet<SnekVal>) -> String {
     }
 }
 
-fn parse_input(input: &str) -> u64 {
-    match input {
-        "true" => TRUE,
-        "false" => FALSE,
-        _ => (input.parse::<i64>().unwrap() << 1) as u64,
+/// The bytes of `val` if it is a string.
+unsafe fn str_bytes<'a>(val: SnekVal) -> Option<&'a [u8]> {
+    let obj = untag(val);
+    if val & TAG_MASK != BOX_TAG || obj.add(2).read() & 0xff != STRING_KIND {
+        return None;
+    }
+    let len = (obj.add(2).read() >> 8) as usize;
+    Some(std::slice::from_raw_parts(obj.add(3) as *const u8, len))
+}
+
+/// The descriptor of `val` if it is a struct instance.
+unsafe fn struct_desc(val: SnekVal) -> Option<*const u64> {
+    let obj = untag(val);
+    if val & TAG_MASK != BOX_TAG || obj.add(2).read() & 0xff != STRUCT_KIND {
+        return None;
+    }
+    Some((obj.add(2).read() >> 8) as *const u64)
+}
+
+unsafe fn expect_str<'a>(val: SnekVal) -> &'a [u8] {
+    str_bytes(val).unwrap_or_else(|| raise_error(ErrCode::InvalidArgument))
+}
+
+/// Whether `val` is a bignum.
+unsafe fn is_bignum(val: SnekVal) -> bool {
+    val & TAG_MASK == BOX_TAG && untag(val).add(2).read() & 0xff == BIGNUM_KIND
+}
+
+/// The value of `val` if it is a number, tagged or not.
+unsafe fn read_num(val: SnekVal) -> Option<Big> {
+    if val & 1 == 0 {
+        return Some(Big::from_i64((val as i64) >> 1));
+    }
+    if !is_bignum(val) {
+        return None;
+    }
+    let obj = untag(val);
+    let words = obj.add(1).read() as usize - 1;
+    let mut mag = Vec::with_capacity(2 * words);
+    for i in 0..words {
+        let word = obj.add(3 + i).read();
+        mag.extend([word as u32, (word >> 32) as u32]);
+    }
+    Some(Big::new(obj.add(2).read() >> 8 == 1, mag))
+}
+
+/// The value of `val` if it is a float.
+unsafe fn read_float(val: SnekVal) -> Option<f64> {
+    if val & TAG_MASK != BOX_TAG || untag(val).add(2).read() & 0xff != FLOAT_KIND {
+        return None;
+    }
+    Some(f64::from_bits(untag(val).add(3).read()))
+}
+
+/// The value of `val` converted to a float if it is a number of any kind.
+unsafe fn to_float(val: SnekVal) -> Option<f64> {
+    read_float(val).or_else(|| read_num(val).map(|n| n.to_f64()))
+}
+
+/// The shortest representation that reads back as the same float, always with a decimal point or
+/// an exponent so it doesn't read back as an integer.
+fn float_str(x: f64) -> String {
+    format!("{x:?}")
+}
+
+unsafe fn expect_num(val: SnekVal) -> i64 {
+    if val & 1 != 0 {
+        raise_error(ErrCode::InvalidArgument);
+    }
+    (val as i64) >> 1
+}
+
+/// Value returned by runtime primitives: the result and the new heap pointer (i.e., the new value
+/// of `%r15`), in `%rax` and `%rdx` respectively.
+#[repr(C)]
+pub struct PrimResult {
+    val: SnekVal,
+    heap_ptr: *const u64,
+}
+
+/// State of the program when it calls a runtime primitive. The compiled code pushes the arguments
+/// on the stack and passes a pointer to them, so the garbage collector sees (and updates) them if
+/// the primitive needs to allocate. Primitives must thus re-read their arguments after allocating.
+///
+/// All primitives take the same arguments: a pointer to the arguments followed by the current heap
+/// pointer, stack base, `%rbp` and `%rsp`. See [`snek_try_gc`] for the meaning of the last four.
+struct Prim {
+    args: *const SnekVal,
+    heap_ptr: *const u64,
+    stack_base: *const u64,
+    curr_rbp: *const u64,
+    curr_rsp: *const u64,
+}
+
+impl Prim {
+    unsafe fn arg(&self, i: usize) -> SnekVal {
+        *self.args.add(i)
+    }
+
+    /// Allocates an object with `words` words after the gc and size words.
+    unsafe fn alloc(&mut self, words: usize) -> *mut u64 {
+        let count = words + 2;
+        if self.heap_ptr.add(count) > HEAP_END {
+            self.heap_ptr = snek_try_gc(
+                count as isize,
+                self.heap_ptr,
+                self.stack_base,
+                self.curr_rbp,
+                self.curr_rsp,
+            );
+        }
+        let obj = self.heap_ptr as *mut u64;
+        *obj = 0;
+        *obj.add(1) = words as u64;
+        self.heap_ptr = self.heap_ptr.add(count);
+        HEAP_PTR = self.heap_ptr;
+        obj
+    }
+
+    /// Returns `n` as a tagged number if it fits in one, and as a new bignum otherwise.
+    unsafe fn alloc_num(&mut self, n: &Big) -> SnekVal {
+        if let Some(n) = n.to_i64().filter(|n| (MIN_NUM..=MAX_NUM).contains(n)) {
+            return (n << 1) as u64;
+        }
+        let words: Vec<u64> = n
+            .mag
+            .chunks(2)
+            .map(|c| c[0] as u64 | (*c.get(1).unwrap_or(&0) as u64) << 32)
+            .collect();
+        let obj = self.alloc(1 + words.len());
+        *obj.add(2) = BIGNUM_KIND | (n.neg as u64) << 8;
+        for (i, word) in words.iter().enumerate() {
+            *obj.add(3 + i) = *word;
+        }
+        obj as u64 | BOX_TAG
+    }
+
+    unsafe fn alloc_float(&mut self, x: f64) -> SnekVal {
+        let obj = self.alloc(2);
+        *obj.add(2) = FLOAT_KIND;
+        *obj.add(3) = x.to_bits();
+        obj as u64 | BOX_TAG
+    }
+
+    /// Allocates a string of `len` bytes, filled by `init` once the string is allocated.
+    unsafe fn alloc_str(&mut self, len: usize, init: impl FnOnce(&Prim, &mut [u8])) -> SnekVal {
+        let obj = self.alloc(1 + len.div_ceil(8));
+        *obj.add(2) = STRING_KIND | (len as u64) << 8;
+        init(self, std::slice::from_raw_parts_mut(obj.add(3) as *mut u8, len));
+        obj as u64 | BOX_TAG
+    }
+
+    fn ret(self, val: SnekVal) -> PrimResult {
+        PrimResult {
+            val,
+            heap_ptr: self.heap_ptr,
+        }
+    }
+}
+
+macro_rules! prim {
+    ($(#[$attr:meta])* $name:ident($cx:ident) $body:block) => {
+        $(#[$attr])*
+        pub unsafe extern "C" fn $name(
+            args: *const SnekVal,
+            heap_ptr: *const u64,
+            stack_base: *const u64,
+            curr_rbp: *const u64,
+            curr_rsp: *const u64,
+        ) -> PrimResult {
+            HEAP_PTR = heap_ptr;
+            #[allow(unused_mut)]
+            let mut $cx = Prim {
+                args,
+                heap_ptr,
+                stack_base,
+                curr_rbp,
+                curr_rsp,
+            };
+            let val = $body;
+            $cx.ret(val)
+        }
+    };
+}
+
+fn snek_bool(b: bool) -> SnekVal {
+    if b {
+        TRUE
+    } else {
+        FALSE
+    }
+}
+
+prim! {
+    #[export_name = "\x01snek_string_length"]
+    snek_string_length(cx) {
+        (expect_str(cx.arg(0)).len() as u64) << 1
+    }
+}
+
+prim! {
+    #[export_name = "\x01snek_string_append"]
+    snek_string_append(cx) {
+        let len = expect_str(cx.arg(0)).len() + expect_str(cx.arg(1)).len();
+        cx.alloc_str(len, |cx, buf| {
+            let (s1, s2) = (expect_str(cx.arg(0)), expect_str(cx.arg(1)));
+            buf[..s1.len()].copy_from_slice(s1);
+            buf[s1.len()..].copy_from_slice(s2);
+        })
+    }
+}
+
+prim! {
+    #[export_name = "\x01snek_substring"]
+    snek_substring(cx) {
+        let len = expect_str(cx.arg(0)).len() as i64;
+        let (start, end) = (expect_num(cx.arg(1)), expect_num(cx.arg(2)));
+        if start < 0 || start > end || end > len {
+            raise_error(ErrCode::IndexOutOfBounds);
+        }
+        let (start, end) = (start as usize, end as usize);
+        cx.alloc_str(end - start, |cx, buf| {
+            buf.copy_from_slice(&expect_str(cx.arg(0))[start..end])
+        })
+    }
+}
+
+prim! {
+    /// The byte at the given index, as a string of length one.
+    #[export_name = "\x01snek_string_ref"]
+    snek_string_ref(cx) {
+        let s = expect_str(cx.arg(0));
+        let i = expect_num(cx.arg(1));
+        if i < 0 || i >= s.len() as i64 {
+            raise_error(ErrCode::IndexOutOfBounds);
+        }
+        let i = i as usize;
+        cx.alloc_str(1, |cx, buf| buf[0] = expect_str(cx.arg(0))[i])
+    }
+}
+
+prim! {
+    #[export_name = "\x01snek_string_eq"]
+    snek_string_eq(cx) {
+        snek_bool(expect_str(cx.arg(0)) == expect_str(cx.arg(1)))
+    }
+}
+
+prim! {
+    /// Lexicographic comparison of the bytes of two strings.
+    #[export_name = "\x01snek_string_less"]
+    snek_string_less(cx) {
+        snek_bool(expect_str(cx.arg(0)) < expect_str(cx.arg(1)))
+    }
+}
+
+prim! {
+    #[export_name = "\x01snek_number_to_string"]
+    snek_number_to_string(cx) {
+        let s = match (read_num(cx.arg(0)), read_float(cx.arg(0))) {
+            (Some(n), _) => n.to_string(),
+            (_, Some(x)) => float_str(x),
+            _ => raise_error(ErrCode::InvalidArgument),
+        };
+        cx.alloc_str(s.len(), |_, buf| buf.copy_from_slice(s.as_bytes()))
+    }
+}
+
+prim! {
+    /// Parses a number, returning `false` if the string isn't a valid snek number.
+    #[export_name = "\x01snek_string_to_number"]
+    snek_string_to_number(cx) {
+        let s = String::from_utf8_lossy(expect_str(cx.arg(0)));
+        let s = s.trim();
+        // Like in source code, floats start with a digit so `inf` and `nan` aren't numbers
+        let starts_with_digit = s
+            .trim_start_matches(['-', '+'])
+            .starts_with(|c: char| c.is_ascii_digit());
+        match (Big::parse(s), s.parse::<f64>()) {
+            (Some(n), _) => cx.alloc_num(&n),
+            (None, Ok(x)) if starts_with_digit => cx.alloc_float(x),
+            _ => FALSE,
+        }
+    }
+}
+
+prim! {
+    /// Structural equality: strings are equal if they have the same bytes and vectors if they
+    /// have the same length and their elements are structurally equal. Anything else is compared
+    /// by identity.
+    #[export_name = "\x01snek_equal"]
+    snek_equal(cx) {
+        snek_bool(equal(cx.arg(0), cx.arg(1), &mut HashSet::new()))
+    }
+}
+
+/// `seen` holds the pairs of vectors being compared further up, which are assumed to be equal
+/// when found again so cyclic vectors can be compared.
+unsafe fn equal(v1: SnekVal, v2: SnekVal, seen: &mut HashSet<(SnekVal, SnekVal)>) -> bool {
+    if v1 == v2 {
+        return true;
+    }
+    if let (Some(s1), Some(s2)) = (str_bytes(v1), str_bytes(v2)) {
+        return s1 == s2;
+    }
+    if is_bignum(v1) || is_bignum(v2) {
+        return read_num(v1) == read_num(v2);
+    }
+    // Integers are never equal to floats, like different kinds of objects
+    if let (Some(x1), Some(x2)) = (read_float(v1), read_float(v2)) {
+        return x1 == x2;
+    }
+    let (a1, a2) = (untag(v1), untag(v2));
+    // Index of the first element/field
+    let start = match (struct_desc(v1), struct_desc(v2)) {
+        (Some(d1), Some(d2)) if d1 == d2 => 3,
+        (None, None) => {
+            let is_vec = |v: SnekVal| v != NIL && v & TAG_MASK == VEC_TAG;
+            if !is_vec(v1) || !is_vec(v2) {
+                return false;
+            }
+            2
+        }
+        _ => return false,
+    };
+    if !seen.insert((v1, v2)) {
+        return true;
+    }
+    // The size counts the header of structs
+    let size = a1.add(1).read() as usize + 2 - start;
+    size == a2.add(1).read() as usize + 2 - start
+        && (0..size).all(|i| equal(a1.add(start + i).read(), a2.add(start + i).read(), seen))
+}
+
+/// Operations computed by [`snek_arith`], numbered as in the compiler.
+const ARITH_OPS: [&str; 11] = ["+", "-", "*", "/", "remainder", "modulo", "=", ">", ">=", "<", "<="];
+
+prim! {
+    /// Arithmetic and comparisons on numbers that aren't both tagged numbers, or whose result
+    /// overflowed a tagged number. The first argument is the operation (a tagged number indexing
+    /// [`ARITH_OPS`]). `=` also accepts two values that aren't numbers, and compares them by
+    /// identity.
+    ///
+    /// If either operand is a float the other is converted to a float, and so is the result of
+    /// arithmetic. Float division by zero follows IEEE 754 instead of being an error.
+    #[export_name = "\x01snek_arith"]
+    snek_arith(cx) {
+        let op = ARITH_OPS[expect_num(cx.arg(0)) as usize];
+        let (v1, v2) = (cx.arg(1), cx.arg(2));
+        if read_float(v1).is_some() || read_float(v2).is_some() {
+            let (Some(x1), Some(x2)) = (to_float(v1), to_float(v2)) else {
+                raise_error(ErrCode::InvalidArgument)
+            };
+            let val = float_arith(&mut cx, op, x1, x2);
+            return cx.ret(val);
+        }
+        let (n1, n2) = match (read_num(v1), read_num(v2)) {
+            (Some(n1), Some(n2)) => (n1, n2),
+            (None, None) if op == "=" => return cx.ret(snek_bool(v1 == v2)),
+            _ => raise_error(ErrCode::InvalidArgument),
+        };
+        if matches!(op, "/" | "remainder" | "modulo") && n2.is_zero() {
+            raise_error(ErrCode::DivideByZero);
+        }
+        let ord = n1.cmp(&n2);
+        match op {
+            "+" => cx.alloc_num(&n1.add(&n2)),
+            "-" => cx.alloc_num(&n1.sub(&n2)),
+            "*" => cx.alloc_num(&n1.mul(&n2)),
+            "/" => cx.alloc_num(&n1.div_rem(&n2).0),
+            "remainder" => cx.alloc_num(&n1.div_rem(&n2).1),
+            "modulo" => {
+                // The remainder has the sign of the dividend, the modulo the sign of the divisor
+                let rem = n1.div_rem(&n2).1;
+                if !rem.is_zero() && rem.neg != n2.neg {
+                    cx.alloc_num(&rem.add(&n2))
+                } else {
+                    cx.alloc_num(&rem)
+                }
+            }
+            "=" => snek_bool(ord == Ordering::Equal),
+            ">" => snek_bool(ord == Ordering::Greater),
+            ">=" => snek_bool(ord != Ordering::Less),
+            "<" => snek_bool(ord == Ordering::Less),
+            _ => snek_bool(ord != Ordering::Greater),
+        }
+    }
+}
+
+unsafe fn float_arith(cx: &mut Prim, op: &str, x1: f64, x2: f64) -> SnekVal {
+    match op {
+        "+" => cx.alloc_float(x1 + x2),
+        "-" => cx.alloc_float(x1 - x2),
+        "*" => cx.alloc_float(x1 * x2),
+        "/" => cx.alloc_float(x1 / x2),
+        // `%` truncates like `remainder`
+        "remainder" => cx.alloc_float(x1 % x2),
+        "modulo" => {
+            let rem = x1 % x2;
+            if rem != 0.0 && (rem < 0.0) != (x2 < 0.0) {
+                cx.alloc_float(rem + x2)
+            } else {
+                cx.alloc_float(rem)
+            }
+        }
+        "=" => snek_bool(x1 == x2),
+        ">" => snek_bool(x1 > x2),
+        ">=" => snek_bool(x1 >= x2),
+        "<" => snek_bool(x1 < x2),
+        _ => snek_bool(x1 <= x2),
+    }
+}
+
+prim! {
+    /// Converts a number to a float.
+    #[export_name = "\x01snek_to_float"]
+    snek_to_float(cx) {
+        let x = to_float(cx.arg(0)).unwrap_or_else(|| raise_error(ErrCode::InvalidArgument));
+        cx.alloc_float(x)
+    }
+}
+
+prim! {
+    #[export_name = "\x01snek_floor"]
+    snek_floor(cx) {
+        round_with(&mut cx, f64::floor)
+    }
+}
+
+prim! {
+    #[export_name = "\x01snek_ceiling"]
+    snek_ceiling(cx) {
+        round_with(&mut cx, f64::ceil)
+    }
+}
+
+prim! {
+    /// Rounds to the nearest integer, and halfway cases to the even one.
+    #[export_name = "\x01snek_round"]
+    snek_round(cx) {
+        round_with(&mut cx, f64::round_ties_even)
+    }
+}
+
+prim! {
+    #[export_name = "\x01snek_truncate"]
+    snek_truncate(cx) {
+        round_with(&mut cx, f64::trunc)
+    }
+}
+
+/// Converts the first argument to an integer with `round` if it is a float. Integers are returned
+/// as they are, and infinities and NaN are invalid arguments.
+unsafe fn round_with(cx: &mut Prim, round: fn(f64) -> f64) -> SnekVal {
+    let val = cx.arg(0);
+    if read_num(val).is_some() {
+        return val;
+    }
+    match read_float(val).map(round) {
+        Some(x) if x.is_finite() => cx.alloc_num(&Big::from_f64(x)),
+        _ => raise_error(ErrCode::InvalidArgument),
+    }
+}
+
+/// Whether `val` is a hash map.
+unsafe fn is_map(val: SnekVal) -> bool {
+    val & TAG_MASK == BOX_TAG && untag(val).add(2).read() & 0xff == MAP_KIND
+}
+
+unsafe fn expect_map(val: SnekVal) -> SnekVal {
+    if !is_map(val) {
+        raise_error(ErrCode::InvalidArgument);
+    }
+    val
+}
+
+/// The slots of the table of `map`.
+unsafe fn map_slots<'a>(map: SnekVal) -> &'a mut [[SnekVal; 2]] {
+    let table = untag(untag(map).add(4).read());
+    let len = table.add(1).read() as usize / 2;
+    std::slice::from_raw_parts_mut(table.add(2) as *mut [SnekVal; 2], len)
+}
+
+/// The keys and values of `map`, in table order.
+unsafe fn map_entries(map: SnekVal) -> Vec<(SnekVal, SnekVal)> {
+    map_slots(map)
+        .iter()
+        .filter(|[key, _]| *key != EMPTY)
+        .map(|[key, value]| (*key, *value))
+        .collect()
+}
+
+unsafe fn map_count(map: SnekVal) -> usize {
+    (untag(map).add(3).read() >> 1) as usize
+}
+
+unsafe fn set_map_count(map: SnekVal, count: usize) {
+    *untag(map).add(3) = (count as u64) << 1;
+}
+
+/// Whether the hash of `key` depends on its address.
+unsafe fn hashed_by_address(key: SnekVal) -> bool {
+    is_heap_obj(key) && str_bytes(key).is_none() && !is_bignum(key) && read_float(key).is_none()
+}
+
+unsafe fn key_hash(key: SnekVal) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    if let Some(bytes) = str_bytes(key) {
+        bytes.hash(&mut hasher);
+    } else if is_bignum(key) {
+        let n = read_num(key).unwrap();
+        (n.neg, n.mag).hash(&mut hasher);
+    } else if let Some(x) = read_float(key) {
+        // Adding zero turns -0.0 into 0.0, which is the same key
+        (x + 0.0).to_bits().hash(&mut hasher);
+    } else {
+        key.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Keys are equal if they are the same number or have the same bytes. Other objects are only
+/// equal to themselves.
+unsafe fn key_eq(k1: SnekVal, k2: SnekVal) -> bool {
+    if k1 == k2 {
+        return true;
+    }
+    if let (Some(s1), Some(s2)) = (str_bytes(k1), str_bytes(k2)) {
+        return s1 == s2;
+    }
+    if is_bignum(k1) && is_bignum(k2) {
+        return read_num(k1) == read_num(k2);
+    }
+    matches!((read_float(k1), read_float(k2)), (Some(x1), Some(x2)) if x1 == x2)
+}
+
+/// The index of the slot of `key` in `slots`: the slot holding it if it is there, the unused slot
+/// it would go in otherwise. `slots` must have an unused slot.
+unsafe fn find_slot(slots: &[[SnekVal; 2]], key: SnekVal) -> usize {
+    let mask = slots.len() - 1;
+    let mut i = key_hash(key) as usize & mask;
+    while slots[i][0] != EMPTY && !key_eq(slots[i][0], key) {
+        i = (i + 1) & mask;
+    }
+    i
+}
+
+/// Clears `slots` and inserts `entries` in them.
+unsafe fn fill_slots(slots: &mut [[SnekVal; 2]], entries: Vec<(SnekVal, SnekVal)>) {
+    slots.fill([EMPTY, NIL]);
+    for (key, value) in entries {
+        let i = find_slot(slots, key);
+        slots[i] = [key, value];
+    }
+}
+
+/// Moves the entries of `map` whose hash depends on their address to their new slot.
+unsafe fn map_rehash(map: SnekVal) {
+    let entries = map_entries(map);
+    if entries.iter().any(|(key, _)| hashed_by_address(*key)) {
+        fill_slots(map_slots(map), entries);
+    }
+}
+
+/// Lays out a table with `capacity` unused slots at `obj`, which has room for `2 * capacity`
+/// words after the gc and size words.
+unsafe fn init_table(obj: *mut u64, capacity: usize) -> SnekVal {
+    *obj = 0;
+    *obj.add(1) = 2 * capacity as u64;
+    std::slice::from_raw_parts_mut(obj.add(2) as *mut [SnekVal; 2], capacity).fill([EMPTY, NIL]);
+    obj as u64 | VEC_TAG
+}
+
+prim! {
+    #[export_name = "\x01snek_make_map"]
+    snek_make_map(cx) {
+        // The map and its table are allocated together so a collection can't happen in between
+        let map = cx.alloc(3 + 2 + 2 * MAP_MIN_CAPACITY);
+        *map.add(1) = 3;
+        *map.add(2) = MAP_KIND;
+        *map.add(3) = 0;
+        *map.add(4) = init_table(map.add(5), MAP_MIN_CAPACITY);
+        map as u64 | BOX_TAG
+    }
+}
+
+prim! {
+    /// The value of a key, or the third argument if the map doesn't have the key.
+    #[export_name = "\x01snek_map_get"]
+    snek_map_get(cx) {
+        let (map, key) = (expect_map(cx.arg(0)), cx.arg(1));
+        let slots = map_slots(map);
+        match slots[find_slot(slots, key)] {
+            [EMPTY, _] => cx.arg(2),
+            [_, value] => value,
+        }
+    }
+}
+
+prim! {
+    /// Sets the value of a key, growing the table when it gets three quarters full. Returns the
+    /// map.
+    #[export_name = "\x01snek_map_set"]
+    snek_map_set(cx) {
+        let map = expect_map(cx.arg(0));
+        let slots = map_slots(map);
+        let i = find_slot(slots, cx.arg(1));
+        if slots[i][0] != EMPTY {
+            slots[i][1] = cx.arg(2);
+            return cx.ret(map);
+        }
+        let count = map_count(map) + 1;
+        if 4 * count > 3 * slots.len() {
+            let capacity = 2 * slots.len();
+            let table = cx.alloc(2 * capacity);
+            // The allocation may have moved the map
+            let map = cx.arg(0);
+            let entries = map_entries(map);
+            *untag(map).add(4) = init_table(table, capacity);
+            fill_slots(map_slots(map), entries);
+        }
+        let (map, key) = (cx.arg(0), cx.arg(1));
+        let slots = map_slots(map);
+        slots[find_slot(slots, key)] = [key, cx.arg(2)];
+        set_map_count(map, count);
+        map
+    }
+}
+
+prim! {
+    /// Removes a key (if the map has it) and returns the map.
+    #[export_name = "\x01snek_map_delete"]
+    snek_map_delete(cx) {
+        let map = expect_map(cx.arg(0));
+        let slots = map_slots(map);
+        let mask = slots.len() - 1;
+        let mut i = find_slot(slots, cx.arg(1));
+        if slots[i][0] == EMPTY {
+            return cx.ret(map);
+        }
+        // Shifts back the following entries that would no longer be found, instead of leaving a
+        // tombstone
+        let mut j = i;
+        loop {
+            j = (j + 1) & mask;
+            if slots[j][0] == EMPTY {
+                break;
+            }
+            let home = key_hash(slots[j][0]) as usize & mask;
+            // The entry stays if its home slot is cyclically in (i, j]
+            let stays = if i <= j { i < home && home <= j } else { i < home || home <= j };
+            if !stays {
+                slots[i] = slots[j];
+                i = j;
+            }
+        }
+        slots[i] = [EMPTY, NIL];
+        set_map_count(map, map_count(map) - 1);
+        map
+    }
+}
+
+prim! {
+    #[export_name = "\x01snek_map_has"]
+    snek_map_has(cx) {
+        let map = expect_map(cx.arg(0));
+        let slots = map_slots(map);
+        snek_bool(slots[find_slot(slots, cx.arg(1))][0] != EMPTY)
+    }
+}
+
+prim! {
+    #[export_name = "\x01snek_map_count"]
+    snek_map_count(cx) {
+        untag(expect_map(cx.arg(0))).add(3).read()
+    }
+}
+
+prim! {
+    /// A new vector with the keys of a map, in no particular order.
+    #[export_name = "\x01snek_map_keys"]
+    snek_map_keys(cx) {
+        let count = map_count(expect_map(cx.arg(0)));
+        let keys = cx.alloc(count);
+        for (i, (key, _)) in map_entries(cx.arg(0)).into_iter().enumerate() {
+            *keys.add(2 + i) = key;
+        }
+        keys as u64 | VEC_TAG
+    }
+}
+
+/// Reads a line from stdin without its line terminator, or `None` at the end of the input.
+fn read_line() -> Option<String> {
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) => None,
+        Ok(_) => {
+            let len = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(len);
+            Some(line)
+        }
+        Err(err) => io_error("stdin", err),
+    }
+}
+
+prim! {
+    /// The next line of stdin, or nil at the end of the input.
+    #[export_name = "\x01snek_read_line"]
+    snek_read_line(cx) {
+        match read_line() {
+            Some(line) => cx.alloc_str(line.len(), |_, bytes| bytes.copy_from_slice(line.as_bytes())),
+            None => NIL,
+        }
+    }
+}
+
+prim! {
+    /// The integer on the next line of stdin, or nil at the end of the input.
+    #[export_name = "\x01snek_read_int"]
+    snek_read_int(cx) {
+        match read_line() {
+            Some(line) => match Big::parse(line.trim()) {
+                Some(n) => cx.alloc_num(&n),
+                None => raise_error(ErrCode::InvalidArgument),
+            },
+            None => NIL,
+        }
+    }
+}
+
+prim! {
+    /// Prints a value like `print`, without the newline.
+    #[export_name = "\x01snek_display"]
+    snek_display(cx) {
+        let val = cx.arg(0);
+        match str_bytes(val) {
+            Some(bytes) => print!("{}", String::from_utf8_lossy(bytes)),
+            None => print!("{}", snek_str(val, &mut HashSet::new())),
+        }
+        // The program may stop before the next newline
+        io::stdout().flush().unwrap_or_else(|err| io_error("stdout", err));
+        val
+    }
+}
+
+prim! {
+    /// Prints a value like `print`, on stderr.
+    #[export_name = "\x01snek_eprint"]
+    snek_eprint(cx) {
+        let val = cx.arg(0);
+        match str_bytes(val) {
+            Some(bytes) => eprintln!("{}", String::from_utf8_lossy(bytes)),
+            None => eprintln!("{}", snek_str(val, &mut HashSet::new())),
+        }
+        val
+    }
+}
+
+prim! {
+    /// The contents of the file at a path, as a string.
+    #[export_name = "\x01snek_read_file"]
+    snek_read_file(cx) {
+        let path = String::from_utf8_lossy(expect_str(cx.arg(0))).into_owned();
+        let contents = fs::read(&path).unwrap_or_else(|err| io_error(&path, err));
+        cx.alloc_str(contents.len(), |_, bytes| bytes.copy_from_slice(&contents))
+    }
+}
+
+prim! {
+    /// Replaces the contents of the file at a path with a string, creating the file if needed.
+    #[export_name = "\x01snek_write_file"]
+    snek_write_file(cx) {
+        let path = String::from_utf8_lossy(expect_str(cx.arg(0))).into_owned();
+        fs::write(&path, expect_str(cx.arg(1))).unwrap_or_else(|err| io_error(&path, err));
+        NIL
     }
 }
 
+/// An integer of any size: a sign and a magnitude in base 2^32, least significant digit first and
+/// without leading zero digits. Zero has no digits and is positive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Big {
+    neg: bool,
+    mag: Vec<u32>,
+}
+
+impl Big {
+    fn new(neg: bool, mut mag: Vec<u32>) -> Big {
+        while mag.last() == Some(&0) {
+            mag.pop();
+        }
+        Big {
+            neg: neg && !mag.is_empty(),
+            mag,
+        }
+    }
+
+    fn from_i64(n: i64) -> Big {
+        let abs = n.unsigned_abs();
+        Big::new(n < 0, vec![abs as u32, (abs >> 32) as u32])
+    }
+
+    fn to_i64(&self) -> Option<i64> {
+        if self.mag.len() > 2 {
+            return None;
+        }
+        let abs = self.mag.iter().rev().fold(0u64, |acc, &d| acc << 32 | d as u64);
+        if self.neg {
+            0i64.checked_sub_unsigned(abs)
+        } else {
+            (abs <= i64::MAX as u64).then_some(abs as i64)
+        }
+    }
+
+    /// The integer `x`, which must be integral and finite.
+    fn from_f64(x: f64) -> Big {
+        if x.abs() < 9.2e18 {
+            return Big::from_i64(x as i64);
+        }
+        // Larger floats are their 53-bit mantissa shifted left
+        let bits = x.to_bits();
+        let shift = ((bits >> 52) & 0x7ff) as usize - 1075;
+        let mantissa = (bits & ((1 << 52) - 1)) | 1 << 52;
+        let mut mag = vec![0; shift / 32];
+        mag.extend([mantissa as u32, (mantissa >> 32) as u32]);
+        let n = Big::new(x < 0.0, mag);
+        n.mul(&Big::from_i64(1 << (shift % 32)))
+    }
+
+    /// The nearest float, or an infinity if the integer is too large.
+    fn to_f64(&self) -> f64 {
+        let abs = self.mag.iter().rev().fold(0.0, |acc, &d| acc * 4294967296.0 + d as f64);
+        if self.neg {
+            -abs
+        } else {
+            abs
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        self.mag.is_empty()
+    }
+
+    /// Parses an optional sign followed by decimal digits.
+    fn parse(s: &str) -> Option<Big> {
+        let (neg, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let mut mag = vec![];
+        for c in digits.bytes() {
+            mag = mag_mul_add(&mag, 10, (c - b'0') as u32);
+        }
+        Some(Big::new(neg, mag))
+    }
+
+    fn add(&self, other: &Big) -> Big {
+        if self.neg == other.neg {
+            return Big::new(self.neg, mag_add(&self.mag, &other.mag));
+        }
+        match mag_cmp(&self.mag, &other.mag) {
+            Ordering::Less => Big::new(other.neg, mag_sub(&other.mag, &self.mag)),
+            _ => Big::new(self.neg, mag_sub(&self.mag, &other.mag)),
+        }
+    }
+
+    fn sub(&self, other: &Big) -> Big {
+        self.add(&Big::new(!other.neg, other.mag.clone()))
+    }
+
+    fn mul(&self, other: &Big) -> Big {
+        let mut mag = vec![0u32; self.mag.len() + other.mag.len()];
+        for (i, &a) in self.mag.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &b) in other.mag.iter().enumerate() {
+                let t = mag[i + j] as u64 + a as u64 * b as u64 + carry;
+                mag[i + j] = t as u32;
+                carry = t >> 32;
+            }
+            mag[i + other.mag.len()] = carry as u32;
+        }
+        Big::new(self.neg != other.neg, mag)
+    }
+
+    /// The quotient rounded towards zero and the remainder, which has the sign of `self`.
+    fn div_rem(&self, other: &Big) -> (Big, Big) {
+        // Long division in base 2
+        let mut quot = vec![0u32; self.mag.len()];
+        let mut rem = vec![];
+        for i in (0..32 * self.mag.len()).rev() {
+            rem = mag_mul_add(&rem, 2, self.mag[i / 32] >> (i % 32) & 1);
+            if mag_cmp(&rem, &other.mag) != Ordering::Less {
+                rem = mag_sub(&rem, &other.mag);
+                quot[i / 32] |= 1 << (i % 32);
+            }
+        }
+        (
+            Big::new(self.neg != other.neg, quot),
+            Big::new(self.neg, rem),
+        )
+    }
+}
+
+impl PartialOrd for Big {
+    fn partial_cmp(&self, other: &Big) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Big {
+    fn cmp(&self, other: &Big) -> Ordering {
+        match (self.neg, other.neg) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => mag_cmp(&self.mag, &other.mag),
+            (true, true) => mag_cmp(&other.mag, &self.mag),
+        }
+    }
+}
+
+impl std::fmt::Display for Big {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Digits in base 10^9, least significant first
+        let mut chunks = vec![];
+        let mut mag = self.mag.clone();
+        while !mag.is_empty() {
+            let mut rem = 0u64;
+            for d in mag.iter_mut().rev() {
+                let t = rem << 32 | *d as u64;
+                *d = (t / 1_000_000_000) as u32;
+                rem = t % 1_000_000_000;
+            }
+            chunks.push(rem);
+            while mag.last() == Some(&0) {
+                mag.pop();
+            }
+        }
+        if self.neg {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.pop().unwrap_or(0))?;
+        for chunk in chunks.iter().rev() {
+            write!(f, "{chunk:09}")?;
+        }
+        Ok(())
+    }
+}
+
+fn mag_cmp(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn mag_add(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut sum = vec![];
+    let mut carry = 0u64;
+    for i in 0..a.len().max(b.len()) {
+        let t = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        sum.push(t as u32);
+        carry = t >> 32;
+    }
+    sum.push(carry as u32);
+    sum
+}
+
+/// `a - b`, where `a >= b`.
+fn mag_sub(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut diff = vec![];
+    let mut borrow = 0i64;
+    for (i, &d) in a.iter().enumerate() {
+        let mut t = d as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = (t < 0) as i64;
+        if t < 0 {
+            t += 1 << 32;
+        }
+        diff.push(t as u32);
+    }
+    while diff.last() == Some(&0) {
+        diff.pop();
+    }
+    diff
+}
+
+/// `a * m + c`, without leading zeros.
+fn mag_mul_add(a: &[u32], m: u32, c: u32) -> Vec<u32> {
+    let mut res = vec![];
+    let mut carry = c as u64;
+    for &d in a {
+        let t = d as u64 * m as u64 + carry;
+        res.push(t as u32);
+        carry = t >> 32;
+    }
+    if carry != 0 {
+        res.push(carry as u32);
+    }
+    res
+}
+
+prim! {
+    #[export_name = "\x01snek_command_line"]
+    snek_command_line(_cx) {
+        SNEK_ARGV
+    }
+}
+
+/// The input of the program: an integer, a boolean, nil or a vector of inputs, e.g.
+/// `[1, [2, 3], nil]`.
+enum Input {
+    Num(Big),
+    Bool(bool),
+    Nil,
+    Vec(Vec<Input>),
+}
+
+impl Input {
+    fn parse(s: &str) -> Option<Input> {
+        let mut rest = s;
+        let input = Input::parse_prefix(&mut rest)?;
+        if rest.trim().is_empty() {
+            Some(input)
+        } else {
+            None
+        }
+    }
+
+    /// Parses the input at the start of `s`, leaving the rest in `s`.
+    fn parse_prefix(s: &mut &str) -> Option<Input> {
+        *s = s.trim_start();
+        if let Some(rest) = s.strip_prefix('[') {
+            *s = rest;
+            let mut elems = vec![];
+            loop {
+                *s = s.trim_start();
+                if let Some(rest) = s.strip_prefix(']') {
+                    *s = rest;
+                    return Some(Input::Vec(elems));
+                }
+                if !elems.is_empty() {
+                    *s = s.strip_prefix(',')?;
+                }
+                elems.push(Input::parse_prefix(s)?);
+            }
+        }
+        let end = s
+            .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+            .unwrap_or(s.len());
+        let (atom, rest) = s.split_at(end);
+        *s = rest;
+        match atom {
+            "true" => Some(Input::Bool(true)),
+            "false" => Some(Input::Bool(false)),
+            "nil" => Some(Input::Nil),
+            _ => Big::parse(atom).map(Input::Num),
+        }
+    }
+
+    /// Number of heap words the input takes.
+    fn words(&self) -> usize {
+        match self {
+            Input::Num(n) if n.to_i64().is_some_and(|n| (MIN_NUM..=MAX_NUM).contains(&n)) => 0,
+            Input::Num(n) => 3 + n.mag.len().div_ceil(2),
+            Input::Bool(_) | Input::Nil => 0,
+            Input::Vec(elems) => 2 + elems.len() + elems.iter().map(Input::words).sum::<usize>(),
+        }
+    }
+
+    unsafe fn alloc(&self, cx: &mut Prim) -> SnekVal {
+        match self {
+            Input::Num(n) => cx.alloc_num(n),
+            Input::Bool(b) => snek_bool(*b),
+            Input::Nil => NIL,
+            Input::Vec(elems) => {
+                let vals: Vec<SnekVal> = elems.iter().map(|elem| elem.alloc(cx)).collect();
+                let obj = cx.alloc(vals.len());
+                std::ptr::copy_nonoverlapping(vals.as_ptr(), obj.add(2), vals.len());
+                obj as u64 | VEC_TAG
+            }
+        }
+    }
+}
+
+/// Number of heap words the command line arguments take, as a vector of strings.
+fn args_words(args: &[String]) -> usize {
+    2 + args.len() + args.iter().map(|arg| 3 + arg.len().div_ceil(8)).sum::<usize>()
+}
+
+/// Allocates the input and the command line arguments at the start of the heap, and returns the
+/// heap pointer after them.
+unsafe fn alloc_inputs(input: &Input, args: &[String]) -> *const u64 {
+    let mut cx = Prim {
+        args: std::ptr::null(),
+        heap_ptr: HEAP_START,
+        stack_base: std::ptr::null(),
+        curr_rbp: std::ptr::null(),
+        curr_rsp: std::ptr::null(),
+    };
+    SNEK_INPUT = input.alloc(&mut cx);
+    let strs: Vec<SnekVal> = args
+        .iter()
+        .map(|arg| cx.alloc_str(arg.len(), |_, bytes| bytes.copy_from_slice(arg.as_bytes())))
+        .collect();
+    let argv = cx.alloc(strs.len());
+    std::ptr::copy_nonoverlapping(strs.as_ptr(), argv.add(2), strs.len());
+    SNEK_ARGV = argv as u64 | VEC_TAG;
+    cx.heap_ptr
+}
+
 fn parse_heap_size(input: &str) -> usize {
     input.parse::<usize>().unwrap()
 }
 
+/// Allocates a heap with room for `heap_size` words on top of the input and the command line
+/// arguments, which are allocated first. Returns the heap pointer after them.
+unsafe fn init_heap(heap_size: usize, input: &Input, args: &[String]) -> *const u64 {
+    let heap_size = heap_size + input.words() + args_words(args);
+    let heap: &mut [u64] = Vec::with_capacity(heap_size).leak();
+    HEAP_START = heap.as_mut_ptr();
+    HEAP_END = HEAP_START.add(heap_size);
+    alloc_inputs(input, args)
+}
+
+/// Sets up the heap for the functions exported by a library compiled with `--lib`. Their input is
+/// `false`, and they have no command line arguments.
+#[export_name = "\x01snek_init"]
+pub unsafe extern "C" fn snek_init(heap_size: usize) {
+    HEAP_PTR = init_heap(heap_size, &Input::Bool(false), &[]);
+}
+
+#[cfg(not(snek_lib))]
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = std::env::args().collect();
     let input = if args.len() >= 2 { &args[1] } else { "false" };
     let heap_size = if args.len() >= 3 { &args[2] } else { "10000" };
-    let input = parse_input(&input);
+    let input = Input::parse(input).unwrap_or_else(|| {
+        eprintln!("invalid input: {}", input);
+        std::process::exit(ErrCode::InvalidArgument as i32)
+    });
     let heap_size = parse_heap_size(&heap_size);
 
-    // Initialize heap
-    let mut heap: Vec<u64> = Vec::with_capacity(heap_size);
-    unsafe {
-        HEAP_START = heap.as_mut_ptr();
-        HEAP_END = HEAP_START.add(heap_size);
-    }
-
-    let i: u64 = unsafe { our_code_starts_here(input, HEAP_START, HEAP_END) };
+    let heap_ptr = unsafe { init_heap(heap_size, &input, &args[1..]) };
+    let i: u64 = unsafe { our_code_starts_here(heap_ptr, HEAP_END) };
     unsafe { snek_print(i) };
 }
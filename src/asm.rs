@@ -22,6 +22,15 @@ pub enum Reg {
     R15,
 }
 
+/// SSE registers, holding floats.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Xmm {
+    Xmm0,
+    Xmm1,
+    Xmm2,
+    Xmm3,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct MemRef {
     pub reg: Reg,
@@ -117,6 +126,18 @@ pub enum CMov {
     GE(Reg, Arg64),
     L(Reg, Arg64),
     LE(Reg, Arg64),
+    /// Above, the unsigned (and float) greater than
+    A(Reg, Arg64),
+    AE(Reg, Arg64),
+}
+
+/// Arithmetic on scalar double precision floats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SseOp {
+    Addsd,
+    Subsd,
+    Mulsd,
+    Divsd,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -156,6 +177,7 @@ pub enum Instr {
     Jge(String),
 
     Js(String),  // jump if msb is 1
+    Jns(String), // jump if msb is 0
     Jz(String),  // jump if result was 0
     Jnz(String), // jump if result was not 0
 
@@ -163,12 +185,36 @@ pub enum Instr {
     Jno(String), // jump if last arith operation didn't overflow
 
     Lea(Reg, MemRef),
+    /// `lea reg, [rel label + offset]`
+    LeaRel(Reg, String, i32),
     Rep(StrOp),
     Cqo,
 
+    /// `movsd xmm, [mem]`
+    MovsdLoad(Xmm, MemRef),
+    /// `movsd [mem], xmm`
+    MovsdStore(MemRef, Xmm),
+    /// `cvtsi2sd xmm, reg` converts an integer to a float
+    Cvtsi2sd(Xmm, Reg),
+    Sse(SseOp, Xmm, Xmm),
+    /// Compares two floats setting the flags like an unsigned comparison. Unordered operands (NaN)
+    /// set the zero, parity and carry flags.
+    Ucomisd(Xmm, Xmm),
+
     Comment(String),
 }
 
+/// Contents of the data section.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Data {
+    Label(String),
+    Align(u32),
+    Quad(i64),
+    /// The address of a label plus an offset
+    Addr(String, i32),
+    Bytes(Vec<u8>),
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum StrOp {
     Stosq,
@@ -195,6 +241,15 @@ pub fn reg_to_string(r: Reg) -> String {
     }
 }
 
+pub fn xmm_to_string(x: Xmm) -> String {
+    match x {
+        Xmm::Xmm0 => String::from("xmm0"),
+        Xmm::Xmm1 => String::from("xmm1"),
+        Xmm::Xmm2 => String::from("xmm2"),
+        Xmm::Xmm3 => String::from("xmm3"),
+    }
+}
+
 impl PartialEq<Loc> for Arg64 {
     fn eq(&self, other: &Loc) -> bool {
         match (self, other) {
@@ -339,7 +394,7 @@ pub fn instr_to_string(i: &Instr) -> String {
         Instr::Label(s) => format!("{}:", s),
 
         Instr::Call(s) => format!("  call {s}"),
-        Instr::Ret => format!("  ret"),
+        Instr::Ret => "  ret".to_string(),
         Instr::Jmp(s) => format!("  jmp {s}"),
         Instr::Je(s) => format!("  je {s}"),
         Instr::Jne(s) => format!("  jne {s}"),
@@ -348,6 +403,7 @@ pub fn instr_to_string(i: &Instr) -> String {
         Instr::Jg(s) => format!("  jg {s}"),
         Instr::Jge(s) => format!("  jge {s}"),
         Instr::Js(s) => format!("  js {s}"),
+        Instr::Jns(s) => format!("  jns {s}"),
         Instr::Jz(s) => format!("  jz {s}"),
         Instr::Jnz(s) => format!("  jnz {s}"),
         Instr::Jo(s) => format!("  jo {s}"),
@@ -377,18 +433,54 @@ pub fn instr_to_string(i: &Instr) -> String {
             CMov::LE(reg, arg) => {
                 format!("  cmovle {}, {}", reg_to_string(*reg), arg64_to_string(arg))
             }
+            CMov::A(reg, arg) => {
+                format!("  cmova {}, {}", reg_to_string(*reg), arg64_to_string(arg))
+            }
+            CMov::AE(reg, arg) => {
+                format!("  cmovae {}, {}", reg_to_string(*reg), arg64_to_string(arg))
+            }
         },
         Instr::Lea(reg, mem) => {
             format!("  lea {}, {}", reg_to_string(*reg), mem_ref_to_string(*mem))
         }
+        Instr::LeaRel(reg, lbl, offset) => {
+            format!("  lea {}, [rel {lbl} + {offset}]", reg_to_string(*reg))
+        }
         Instr::Rep(op) => format!("  rep {}", str_op_to_string(*op)),
-        Instr::Cqo => format!("  cqo"),
+        Instr::Cqo => "  cqo".to_string(),
+        Instr::MovsdLoad(x, mem) => {
+            format!("  movsd {}, {}", xmm_to_string(*x), mem_ref_to_string(*mem))
+        }
+        Instr::MovsdStore(mem, x) => {
+            format!("  movsd {}, {}", mem_ref_to_string(*mem), xmm_to_string(*x))
+        }
+        Instr::Cvtsi2sd(x, reg) => {
+            format!("  cvtsi2sd {}, {}", xmm_to_string(*x), reg_to_string(*reg))
+        }
+        Instr::Sse(op, x1, x2) => format!(
+            "  {} {}, {}",
+            sse_op_to_string(*op),
+            xmm_to_string(*x1),
+            xmm_to_string(*x2)
+        ),
+        Instr::Ucomisd(x1, x2) => {
+            format!("  ucomisd {}, {}", xmm_to_string(*x1), xmm_to_string(*x2))
+        }
+    }
+}
+
+fn sse_op_to_string(op: SseOp) -> &'static str {
+    match op {
+        SseOp::Addsd => "addsd",
+        SseOp::Subsd => "subsd",
+        SseOp::Mulsd => "mulsd",
+        SseOp::Divsd => "divsd",
     }
 }
 
 fn str_op_to_string(op: StrOp) -> String {
     match op {
-        StrOp::Stosq => format!("stosq"),
+        StrOp::Stosq => "stosq".to_string(),
     }
 }
 
@@ -400,3 +492,22 @@ pub fn instrs_to_string(is: &[Instr]) -> String {
     }
     buf
 }
+
+pub fn data_to_string(ds: &[Data]) -> String {
+    let mut buf = String::new();
+    for d in ds {
+        match d {
+            Data::Label(lbl) => buf.push_str(&format!("{lbl}:")),
+            Data::Align(n) => buf.push_str(&format!("  align {n}")),
+            Data::Quad(n) => buf.push_str(&format!("  dq {n}")),
+            Data::Addr(lbl, offset) => buf.push_str(&format!("  dq {lbl} + {offset}")),
+            Data::Bytes(bytes) if bytes.is_empty() => continue,
+            Data::Bytes(bytes) => {
+                let bytes: Vec<_> = bytes.iter().map(|b| b.to_string()).collect();
+                buf.push_str(&format!("  db {}", bytes.join(", ")));
+            }
+        }
+        buf.push('\n');
+    }
+    buf
+}
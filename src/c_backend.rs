@@ -0,0 +1,946 @@
+use std::collections::HashMap;
+
+use crate::{
+    compiler::{
+        arith_code, check_dup_bindings, decimal_to_words, depth, fun_arity_map,
+        raise_duplicate_function, raise_integer_overflow, raise_outside_loop,
+        raise_unbound_identifier, raise_undefined_fun, raise_unknown_label,
+        raise_wrong_number_of_args, BIGNUM_KIND, BOX_TAG, FLOAT_KIND, GC_WORD_VAL, STRING_KIND,
+    },
+    lift,
+    options::Options,
+    prelude,
+    syntax::{Expr, FunDecl, Op1, Op2, Prim, Prog, StructDecl, Symbol},
+};
+
+/// Number of words of the stack of snek values. Functions compiled to C keep their arguments and
+/// locals there instead of on the C stack, so the garbage collector can scan them.
+const STACK_WORDS: u32 = 1 << 23;
+
+/// Declarations shared by the modules of a program: the representation of values (see
+/// `runtime/start.rs`) and the runtime functions, except the primitives.
+const HEADER: &str = "#include <setjmp.h>
+#include <stdint.h>
+
+#define SNEK_NIL 1
+#define SNEK_TRUE 7
+#define SNEK_FALSE 3
+#define SNEK_BOOL(c) ((c) ? SNEK_TRUE : SNEK_FALSE)
+#define SNEK_IS_NUM(v) (((v) & 1) == 0)
+#define SNEK_IS_BOXED(v) (((v) & 7) == 5)
+#define SNEK_VEC(v) ((uint64_t *)((v) - 1))
+#define SNEK_BOX(v) ((uint64_t *)((v) - 5))
+#define SNEK_STRUCT_HEADER(desc) (((uint64_t)(desc) << 8) | 2)
+#define SNEK_STACK_BASE (snek_stack + SNEK_STACK_WORDS - 1)
+
+enum {
+  SNEK_INVALID_ARGUMENT = 1,
+  SNEK_OVERFLOW = 2,
+  SNEK_INDEX_OUT_OF_BOUNDS = 3,
+  SNEK_INVALID_VEC_SIZE = 4,
+  SNEK_DIVIDE_BY_ZERO = 6,
+};
+
+typedef struct {
+  uint64_t val;
+  uint64_t *heap_ptr;
+} SnekPrimResult;
+typedef SnekPrimResult (*SnekPrim)(uint64_t *args, uint64_t *heap_ptr, uint64_t *stack_base,
+                                   uint64_t *rbp, uint64_t *rsp);
+
+extern uint64_t *snek_heap_ptr, *snek_heap_end, *snek_handler, snek_input;
+
+_Noreturn void snek_error(int64_t code, uint64_t *heap_ptr);
+_Noreturn void snek_raise(uint64_t val, uint64_t *heap_ptr);
+_Noreturn void snek_struct_error(const void *const *desc, uint64_t val, uint64_t *heap_ptr);
+_Noreturn void snek_no_match(uint64_t val, uint64_t *heap_ptr);
+_Noreturn void snek_assert_failed(uint64_t cond, uint64_t msg, uint64_t *heap_ptr);
+uint64_t snek_print(uint64_t val);
+uint64_t *snek_try_gc(int64_t count, uint64_t *heap_ptr, uint64_t *stack_base, uint64_t *rbp,
+                      uint64_t *rsp);
+uint64_t *snek_gc(uint64_t *heap_ptr, uint64_t *stack_base, uint64_t *rbp, uint64_t *rsp);
+void snek_print_stack(uint64_t *stack_base, uint64_t *rbp, uint64_t *rsp);
+void snek_print_heap(uint64_t *heap_ptr);
+";
+
+/// Helpers of the generated code, after the declarations of the runtime functions.
+const HELPERS: &str = "
+/* Makes room for `words` words on the heap, calling the garbage collector if needed. */
+static inline void snek_c_alloc(int64_t words) {
+  if (snek_heap_end - snek_heap_ptr < words)
+    snek_heap_ptr = snek_try_gc(words, snek_heap_ptr, SNEK_STACK_BASE, snek_sp, snek_sp);
+}
+
+/* Calls `prim` on the `n` values at the top of the stack, and pops them. */
+static inline uint64_t snek_c_prim(SnekPrim prim, int64_t n) {
+  SnekPrimResult res = prim(snek_sp, snek_heap_ptr, SNEK_STACK_BASE, snek_sp, snek_sp);
+  snek_heap_ptr = res.heap_ptr;
+  snek_sp += n;
+  return res.val;
+}
+
+/* Computes the arithmetic operation `op` (numbered as in `snek_arith`) on operands that aren't both
+   tagged numbers, or whose result overflows. */
+static inline uint64_t snek_c_arith(int64_t op, uint64_t x, uint64_t y) {
+  if (!SNEK_BIGNUMS && SNEK_IS_NUM(x) && SNEK_IS_NUM(y))
+    snek_error(SNEK_OVERFLOW, snek_heap_ptr);
+  snek_sp -= 3;
+  snek_sp[0] = (uint64_t)op << 1;
+  snek_sp[1] = x;
+  snek_sp[2] = y;
+  return snek_c_prim(snek_arith, 3);
+}
+
+/* Converts a number or a boolean to a C integer, booleans being 0 or 1. */
+static inline int64_t snek_c_int(uint64_t v) {
+  if (SNEK_IS_NUM(v))
+    return (int64_t)v >> 1;
+  if ((v | 4) != SNEK_TRUE)
+    snek_error(SNEK_INVALID_ARGUMENT, snek_heap_ptr);
+  return v >> 2;
+}
+
+/* Converts the result of a C function to a number. */
+static inline uint64_t snek_c_num(int64_t n) {
+  int64_t t;
+  if (__builtin_add_overflow(n, n, &t))
+    snek_error(SNEK_OVERFLOW, snek_heap_ptr);
+  return t;
+}
+
+/* The value raised to the innermost `try`, for `snek_c_resume`. */
+static uint64_t snek_c_raised;
+
+/* Resumes the `try` whose handler record is `record` with the raised value `val`. The records of
+   a `try` hold the previous handler, 0, this function and the `jmp_buf` of the `try`. */
+static inline _Noreturn void snek_c_resume(uint64_t *record, uint64_t val, uint64_t *heap_ptr) {
+  snek_c_raised = val;
+  snek_heap_ptr = heap_ptr;
+  longjmp(*(jmp_buf *)record[3], 1);
+}
+";
+
+struct Session {
+    tag: u32,
+    /// The code of the functions, indented by `indent` levels
+    code: String,
+    indent: usize,
+    funs: HashMap<Symbol, usize>,
+    bignums: bool,
+    /// String literals, defined as `snek_str_{index}`
+    strings: Vec<String>,
+    /// Integer literals too large for a tagged number, defined as `snek_big_{index}`
+    big_literals: Vec<String>,
+    /// Float literals, defined as `snek_float_{index}`
+    float_literals: Vec<f64>,
+}
+
+#[derive(Debug, Clone)]
+struct Ctxt {
+    /// The slot of the stack holding each variable
+    env: im::HashMap<Symbol, String>,
+    si: u32,
+    /// The label and tag of each enclosing loop, and the number of enclosing `try`s at its start,
+    /// innermost last
+    loops: im::Vector<(Option<Symbol>, u32, usize)>,
+    /// The tag of each enclosing `try` in the current function, innermost last
+    tries: im::Vector<u32>,
+}
+
+impl Ctxt {
+    fn with_params(params: &[Symbol]) -> Ctxt {
+        let env = params
+            .iter()
+            .enumerate()
+            .map(|(i, param)| (*param, format!("snek_fp[{i}]")))
+            .collect();
+        Ctxt {
+            si: 0,
+            loops: im::Vector::new(),
+            tries: im::Vector::new(),
+            env,
+        }
+    }
+
+    fn lookup(&self, x: Symbol) -> String {
+        self.env
+            .get(&x)
+            .cloned()
+            .unwrap_or_else(|| raise_unbound_identifier(x))
+    }
+
+    fn enter_loop(&self, label: Option<Symbol>, tag: u32) -> Ctxt {
+        let mut loops = self.loops.clone();
+        loops.push_back((label, tag, self.tries.len()));
+        Ctxt {
+            loops,
+            ..self.clone()
+        }
+    }
+
+    /// The tag of the loop left by a `break` or restarted by a `continue` with the given label,
+    /// and the number of `try`s enclosing it.
+    fn target_loop(&self, label: Option<Symbol>, keyword: &str) -> (u32, usize) {
+        let target = match label {
+            Some(label) => self.loops.iter().rev().find(|(l, ..)| *l == Some(label)),
+            None => self.loops.last(),
+        };
+        match (target, label) {
+            (Some((_, tag, tries)), _) => (*tag, *tries),
+            (None, Some(label)) => raise_unknown_label(label),
+            (None, None) => raise_outside_loop(keyword),
+        }
+    }
+
+    fn next_local(&self) -> (Ctxt, String) {
+        (
+            Ctxt {
+                si: self.si + 1,
+                ..self.clone()
+            },
+            slot(self.si),
+        )
+    }
+
+    fn add_binding(&self, x: Symbol, slot: String) -> Ctxt {
+        Ctxt {
+            env: self.env.update(x, slot),
+            ..self.clone()
+        }
+    }
+}
+
+/// Compiles a program to C, with the semantics of the x86-64 backend without optimisations. Values
+/// are tagged `uint64_t`s, and functions take their arguments on a stack of values that the
+/// garbage collector scans (`snek_stack`, defined by the module with the main expression). The
+/// program calls the same runtime, which starts it with `our_code_starts_here`.
+pub fn compile(prg: &Prog, opts: &Options) -> String {
+    let funs = fun_arity_map(prg).unwrap_or_else(raise_duplicate_function);
+    let mut sess = Session::new(funs, opts.bignums);
+    // Libraries export their definitions to the modules importing them
+    let linkage = |exported: bool| if exported { "" } else { "static " };
+    let library = prg.main.is_none();
+
+    let mut decls = String::new();
+    for decl in &prg.structs {
+        decls += &sess.descriptor(decl, linkage(library));
+    }
+    for decl in &prg.imported_structs {
+        decls += &format!("extern const void *const {}[];\n", struct_name(decl.name));
+    }
+    for fun in &prg.funs {
+        let exported = library && !prelude::is_prelude(fun.name) && !lift::is_local(fun.name);
+        decls += &format!(
+            "{}uint64_t {}(void);\n",
+            linkage(exported),
+            fun_name(fun.name)
+        );
+    }
+    for &(fun, _) in &prg.imported_funs {
+        decls += &format!("uint64_t {}(void);\n", fun_name(fun));
+    }
+    for &(fun, arity) in &prg.externs {
+        let params = if arity == 0 {
+            "void".to_string()
+        } else {
+            vec!["int64_t"; arity].join(", ")
+        };
+        decls += &format!("int64_t {fun}({params});\n");
+    }
+
+    for fun in &prg.funs {
+        sess.compile_fun(fun);
+    }
+    if let Some(main) = &prg.main {
+        sess.compile_body(
+            "uint64_t our_code_starts_here(uint64_t *heap_ptr, uint64_t *heap_end)",
+            &[],
+            main,
+            &["snek_heap_ptr = heap_ptr;", "snek_heap_end = heap_end;"],
+        );
+    }
+
+    let stack = if library {
+        "extern uint64_t snek_stack[], *snek_sp;"
+    } else {
+        "uint64_t snek_stack[SNEK_STACK_WORDS], *snek_sp = snek_stack + SNEK_STACK_WORDS;"
+    };
+    let prims: String = Prim::ALL
+        .iter()
+        .map(|prim| prim.symbol())
+        .chain(["snek_arith"])
+        .map(|symbol| {
+            format!("SnekPrimResult {symbol}(uint64_t *, uint64_t *, uint64_t *, uint64_t *, uint64_t *);\n")
+        })
+        .collect();
+    format!(
+        "/* Generated by forest-flame */
+#define SNEK_BIGNUMS {}
+#define SNEK_STACK_WORDS {STACK_WORDS}
+{HEADER}{prims}
+{stack}
+{HELPERS}
+{}{decls}
+{}",
+        opts.bignums as u8,
+        sess.data(),
+        sess.code
+    )
+}
+
+impl Session {
+    fn new(funs: HashMap<Symbol, usize>, bignums: bool) -> Session {
+        Session {
+            tag: 0,
+            code: String::new(),
+            indent: 0,
+            funs,
+            bignums,
+            strings: vec![],
+            big_literals: vec![],
+            float_literals: vec![],
+        }
+    }
+
+    /// The definition of the descriptor of a struct, laid out like the descriptors of the x86-64
+    /// backend.
+    fn descriptor(&mut self, decl: &StructDecl, linkage: &str) -> String {
+        let mut words = vec![format!("(const void *){}", decl.fields.len())];
+        for name in [decl.name.unqualified().to_string()]
+            .into_iter()
+            .chain(decl.fields.iter().map(Symbol::to_string))
+        {
+            let label = self.intern(&name);
+            words.push(format!("(const char *)&{label} + {BOX_TAG}"));
+        }
+        format!(
+            "{linkage}const void *const {}[] = {{{}}};\n",
+            struct_name(decl.name),
+            words.join(", ")
+        )
+    }
+
+    /// Returns the name of the string literal `s`.
+    fn intern(&mut self, s: &str) -> String {
+        let idx = match self.strings.iter().position(|other| other == s) {
+            Some(idx) => idx,
+            None => {
+                self.strings.push(s.to_string());
+                self.strings.len() - 1
+            }
+        };
+        format!("snek_str_{idx}")
+    }
+
+    /// String, bignum and float literals laid out like heap objects (with a zero GC word), as in
+    /// the x86-64 backend.
+    fn data(&self) -> String {
+        let mut data = String::new();
+        for (i, s) in self.strings.iter().enumerate() {
+            let len = s.len() as u64;
+            data += &format!(
+                "static struct {{\n  uint64_t words[3];\n  char bytes[{}];\n}} snek_str_{i} = {{{{{GC_WORD_VAL}, {}, {}}}, {}}};\n",
+                len + 1,
+                1 + len.div_ceil(8),
+                c_word(STRING_KIND as u64 | len << 8),
+                c_string(s.as_bytes())
+            );
+        }
+        for (i, n) in self.big_literals.iter().enumerate() {
+            let (neg, digits) = match n.strip_prefix('-') {
+                Some(digits) => (1, digits),
+                None => (0, n.as_str()),
+            };
+            let magnitude = decimal_to_words(digits);
+            let mut words = vec![
+                GC_WORD_VAL as u64,
+                1 + magnitude.len() as u64,
+                BIGNUM_KIND as u64 | neg << 8,
+            ];
+            words.extend(magnitude);
+            data += &format!("static uint64_t snek_big_{i}[] = {};\n", c_words(&words));
+        }
+        for (i, x) in self.float_literals.iter().enumerate() {
+            let words = [GC_WORD_VAL as u64, 2, FLOAT_KIND as u64, x.to_bits()];
+            data += &format!("static uint64_t snek_float_{i}[] = {};\n", c_words(&words));
+        }
+        data
+    }
+
+    fn compile_fun(&mut self, fun: &FunDecl) {
+        check_dup_bindings(&fun.params);
+        let signature = format!("uint64_t {}(void)", fun_name(fun.name));
+        self.compile_body(&signature, &fun.params, &fun.body, &[]);
+    }
+
+    /// Emits a C function evaluating `body`, whose parameters are at the top of the stack when
+    /// it's called. Its frame is below them, and `prologue` runs before it's set up.
+    fn compile_body(&mut self, signature: &str, params: &[Symbol], body: &Expr, prologue: &[&str]) {
+        let locals = depth(body);
+        self.line(&format!("{signature} {{"));
+        self.indent += 1;
+        for line in prologue {
+            self.line(line);
+        }
+        self.line("uint64_t *snek_fp = snek_sp, snek_r, snek_a, snek_b;");
+        self.line("int64_t snek_t;");
+        self.line(&format!("snek_sp = snek_fp - {locals};"));
+        self.line(&format!("for (snek_t = 0; snek_t < {locals}; snek_t++)"));
+        self.line("  snek_sp[snek_t] = SNEK_NIL;");
+        self.compile_expr(&Ctxt::with_params(params), "snek_r", body);
+        self.line("snek_sp = snek_fp;");
+        self.line("return snek_r;");
+        self.indent -= 1;
+        self.line("}\n");
+    }
+
+    /// Emits the code storing the value of `e` in `dst`, an lvalue.
+    fn compile_expr(&mut self, cx: &Ctxt, dst: &str, e: &Expr) {
+        match e {
+            Expr::Number(n) => {
+                let n = n.checked_shl(1).unwrap();
+                self.line(&format!("{dst} = {};", c_word(n as u64)));
+            }
+            Expr::BigNum(n) => {
+                if !self.bignums {
+                    raise_integer_overflow()
+                }
+                let label = format!("snek_big_{}", self.big_literals.len());
+                self.big_literals.push(n.clone());
+                self.line(&format!("{dst} = (uint64_t)&{label} + {BOX_TAG};"));
+            }
+            Expr::Float(x) => {
+                let label = format!("snek_float_{}", self.float_literals.len());
+                self.float_literals.push(*x);
+                self.line(&format!("{dst} = (uint64_t)&{label} + {BOX_TAG};"));
+            }
+            Expr::Boolean(b) => self.line(&format!("{dst} = {};", c_bool(*b))),
+            Expr::Str(s) => {
+                let label = self.intern(s);
+                self.line(&format!("{dst} = (uint64_t)&{label} + {BOX_TAG};"));
+            }
+            Expr::Var(x) => self.move_to(dst, &cx.lookup(*x)),
+            Expr::Let(bindings, body) => {
+                check_dup_bindings(bindings.iter().map(|(id, _)| id));
+                let mut currcx = cx.clone();
+                for (var, rhs) in bindings {
+                    let (nextcx, slot) = currcx.next_local();
+                    self.compile_expr(&currcx, &slot, rhs);
+                    currcx = nextcx.add_binding(*var, slot);
+                }
+                self.compile_expr(&currcx, "snek_r", body);
+                self.clear(cx.si, bindings.len() as u32);
+                self.move_to(dst, "snek_r");
+            }
+            Expr::UnOp(op, e) => self.compile_un_op(cx, dst, *op, e),
+            Expr::BinOp(op, e1, e2) => self.compile_bin_op(cx, dst, *op, e1, e2),
+            Expr::If(e1, e2, e3) => {
+                self.compile_expr(cx, "snek_r", e1);
+                self.line(&format!("if (snek_r != {}) {{", c_bool(false)));
+                self.indent += 1;
+                self.compile_expr(cx, dst, e2);
+                self.indent -= 1;
+                self.line("} else {");
+                self.indent += 1;
+                self.compile_expr(cx, dst, e3);
+                self.indent -= 1;
+                self.line("}");
+            }
+            Expr::Loop(label, e) => {
+                let tag = self.next_tag();
+                self.line(&format!("loop_start_{tag}:;"));
+                self.compile_expr(&cx.enter_loop(*label, tag), "snek_r", e);
+                self.line(&format!("goto loop_start_{tag};"));
+                self.line(&format!("loop_end_{tag}:;"));
+                self.move_to(dst, "snek_r");
+            }
+            Expr::Break(label, e) => {
+                let (tag, tries) = cx.target_loop(*label, "break");
+                self.compile_expr(cx, "snek_r", e);
+                self.leave_tries(cx, tries);
+                self.line(&format!("goto loop_end_{tag};"));
+            }
+            Expr::Continue(label) => {
+                let (tag, tries) = cx.target_loop(*label, "continue");
+                self.leave_tries(cx, tries);
+                self.line(&format!("goto loop_start_{tag};"));
+            }
+            Expr::Raise(e) => {
+                self.compile_expr(cx, "snek_r", e);
+                self.line("snek_raise(snek_r, snek_heap_ptr);");
+            }
+            Expr::Try(body, x, handler) => self.compile_try(cx, dst, body, *x, handler),
+            Expr::Set(var, e) => {
+                let slot = cx.lookup(*var);
+                self.compile_expr(cx, &slot, e);
+                self.move_to(dst, &slot);
+            }
+            Expr::Block(es) => {
+                for e in &es[..es.len() - 1] {
+                    self.compile_expr(cx, "snek_r", e);
+                }
+                self.compile_expr(cx, dst, &es[es.len() - 1]);
+            }
+            Expr::Call(fun, args) => {
+                let Some(arity) = self.funs.get(fun) else {
+                    return raise_undefined_fun(*fun);
+                };
+                if args.len() != *arity {
+                    raise_wrong_number_of_args(*fun, *arity, args.len());
+                }
+                self.compile_args(cx, args);
+                self.push_args(cx, args.len() as u32);
+                self.line(&format!("snek_r = {}();", fun_name(*fun)));
+                self.line(&format!("snek_sp += {};", args.len()));
+                self.clear(cx.si, args.len() as u32);
+                self.move_to(dst, "snek_r");
+            }
+            Expr::CallExtern(fun, args) => {
+                self.compile_args(cx, args);
+                let args: Vec<_> = (cx.si..cx.si + args.len() as u32)
+                    .map(|i| format!("snek_c_int({})", slot(i)))
+                    .collect();
+                self.line(&format!("snek_r = snek_c_num({fun}({}));", args.join(", ")));
+                self.clear(cx.si, args.len() as u32);
+                self.move_to(dst, "snek_r");
+            }
+            Expr::Prim(prim, args) => {
+                self.compile_args(cx, args);
+                self.push_args(cx, args.len() as u32);
+                self.line(&format!(
+                    "snek_r = snek_c_prim({}, {});",
+                    prim.symbol(),
+                    args.len()
+                ));
+                self.clear(cx.si, args.len() as u32);
+                self.move_to(dst, "snek_r");
+            }
+            Expr::Nil => self.line(&format!("{dst} = SNEK_NIL;")),
+            Expr::Input => self.line(&format!("{dst} = snek_input;")),
+            Expr::MakeVec(size, elem) => {
+                let (nextcx, size_slot) = cx.next_local();
+                let (_, elem_slot) = nextcx.next_local();
+                self.compile_expr(cx, &size_slot, size);
+                self.compile_expr(&nextcx, &elem_slot, elem);
+                self.check_is_num(&size_slot);
+                self.line(&format!("snek_t = (int64_t){size_slot} >> 1;"));
+                self.fail_if("snek_t < 0", "SNEK_INVALID_VEC_SIZE");
+                self.line("snek_c_alloc(snek_t + 2);");
+                self.line(&format!("snek_heap_ptr[0] = {GC_WORD_VAL};"));
+                self.line("snek_heap_ptr[1] = snek_t;");
+                self.line("for (snek_a = 0; snek_a < (uint64_t)snek_t; snek_a++)");
+                self.line(&format!("  snek_heap_ptr[2 + snek_a] = {elem_slot};"));
+                self.line("snek_r = (uint64_t)snek_heap_ptr + 1;");
+                self.line("snek_heap_ptr += snek_t + 2;");
+                self.clear(cx.si, 2);
+                self.move_to(dst, "snek_r");
+            }
+            Expr::Vec(elems) => {
+                self.compile_args(cx, elems);
+                self.line(&format!("snek_c_alloc({});", elems.len() + 2));
+                self.line(&format!("snek_heap_ptr[0] = {GC_WORD_VAL};"));
+                self.line(&format!("snek_heap_ptr[1] = {};", elems.len()));
+                for i in 0..elems.len() as u32 {
+                    self.line(&format!("snek_heap_ptr[{}] = {};", i + 2, slot(cx.si + i)));
+                }
+                self.line("snek_r = (uint64_t)snek_heap_ptr + 1;");
+                self.line(&format!("snek_heap_ptr += {};", elems.len() + 2));
+                self.clear(cx.si, elems.len() as u32);
+                self.move_to(dst, "snek_r");
+            }
+            Expr::StructNew(name, fields) => {
+                self.compile_args(cx, fields);
+                self.line(&format!("snek_c_alloc({});", fields.len() + 3));
+                self.line(&format!("snek_heap_ptr[0] = {GC_WORD_VAL};"));
+                // The size includes the header
+                self.line(&format!("snek_heap_ptr[1] = {};", fields.len() + 1));
+                self.line(&format!(
+                    "snek_heap_ptr[2] = SNEK_STRUCT_HEADER({});",
+                    struct_name(*name)
+                ));
+                for i in 0..fields.len() as u32 {
+                    self.line(&format!("snek_heap_ptr[{}] = {};", i + 3, slot(cx.si + i)));
+                }
+                self.line(&format!("snek_r = (uint64_t)snek_heap_ptr + {BOX_TAG};"));
+                self.line(&format!("snek_heap_ptr += {};", fields.len() + 3));
+                self.clear(cx.si, fields.len() as u32);
+                self.move_to(dst, "snek_r");
+            }
+            Expr::StructGet(name, field, e) => {
+                self.compile_expr(cx, "snek_r", e);
+                self.check_is_struct(*name, "snek_r");
+                self.line(&format!("{dst} = SNEK_BOX(snek_r)[{}];", field + 3));
+            }
+            Expr::StructSet(name, field, e, val) => {
+                let (nextcx, slot) = cx.next_local();
+                self.compile_expr(cx, &slot, e);
+                self.compile_expr(&nextcx, "snek_b", val);
+                self.line(&format!("snek_r = {slot};"));
+                self.clear(cx.si, 1);
+                self.check_is_struct(*name, "snek_r");
+                self.line(&format!("SNEK_BOX(snek_r)[{}] = snek_b;", field + 3));
+                self.move_to(dst, "snek_r");
+            }
+            // Erased after type checking, but they don't affect the generated code anyway
+            Expr::Annot(_, e) | Expr::At(_, e) => self.compile_expr(cx, dst, e),
+            Expr::NoMatch(e) => {
+                self.compile_expr(cx, "snek_r", e);
+                self.line("snek_no_match(snek_r, snek_heap_ptr);");
+            }
+            Expr::AssertFailed(cond, msg) => {
+                self.compile_expr(cx, "snek_r", msg);
+                let label = self.intern(cond);
+                self.line(&format!(
+                    "snek_assert_failed((uint64_t)&{label} + {BOX_TAG}, snek_r, snek_heap_ptr);"
+                ));
+            }
+            Expr::StructIs(name, e) => {
+                self.compile_expr(cx, "snek_r", e);
+                self.line(&format!(
+                    "{dst} = SNEK_BOOL(SNEK_IS_BOXED(snek_r) && SNEK_BOX(snek_r)[2] == SNEK_STRUCT_HEADER({}));",
+                    struct_name(*name)
+                ));
+            }
+            Expr::VecSet(vec, idx, elem) => {
+                let (nextcx1, vec_slot) = cx.next_local();
+                let (nextcx2, idx_slot) = nextcx1.next_local();
+                self.compile_expr(cx, &vec_slot, vec);
+                self.compile_expr(&nextcx1, &idx_slot, idx);
+                self.compile_expr(&nextcx2, "snek_b", elem);
+                self.line(&format!("snek_r = {vec_slot};"));
+                self.line(&format!("snek_a = {idx_slot};"));
+                self.clear(cx.si, 2);
+                self.check_is_vec("snek_r");
+                self.check_is_num("snek_a");
+                self.check_index("snek_r", "snek_a");
+                self.line("SNEK_VEC(snek_r)[2 + snek_t] = snek_b;");
+                self.move_to(dst, "snek_r");
+            }
+            Expr::VecGet(vec, idx) => {
+                let (nextcx, vec_slot) = cx.next_local();
+                self.compile_expr(cx, &vec_slot, vec);
+                self.compile_expr(&nextcx, "snek_a", idx);
+                self.line(&format!("snek_r = {vec_slot};"));
+                self.clear(cx.si, 1);
+                self.check_is_vec("snek_r");
+                self.check_is_num("snek_a");
+                self.check_index("snek_r", "snek_a");
+                self.line(&format!("{dst} = SNEK_VEC(snek_r)[2 + snek_t];"));
+            }
+            Expr::VecLen(vec) => {
+                self.compile_expr(cx, "snek_r", vec);
+                self.check_is_vec("snek_r");
+                self.line(&format!("{dst} = SNEK_VEC(snek_r)[1] << 1;"));
+            }
+            Expr::Gc => {
+                self.line(
+                    "snek_heap_ptr = snek_gc(snek_heap_ptr, SNEK_STACK_BASE, snek_sp, snek_sp);",
+                );
+                self.line(&format!("{dst} = 0;"));
+            }
+            Expr::PrintStack => {
+                self.line("snek_print_stack(SNEK_STACK_BASE, snek_sp, snek_sp);");
+                self.line(&format!("{dst} = 0;"));
+            }
+            Expr::PrintHeap => {
+                self.line("snek_print_heap(snek_heap_ptr);");
+                self.line(&format!("{dst} = 0;"));
+            }
+        }
+    }
+
+    /// Evaluates `args` in the next locals.
+    fn compile_args(&mut self, cx: &Ctxt, args: &[Expr]) {
+        let mut currcx = cx.clone();
+        for arg in args {
+            let (nextcx, slot) = currcx.next_local();
+            self.compile_expr(&currcx, &slot, arg);
+            currcx = nextcx;
+        }
+    }
+
+    /// Pushes the `count` values of the next locals, the first one at the top of the stack.
+    fn push_args(&mut self, cx: &Ctxt, count: u32) {
+        self.line(&format!("snek_sp -= {count};"));
+        for i in 0..count {
+            self.line(&format!("snek_sp[{i}] = {};", slot(cx.si + i)));
+        }
+    }
+
+    fn compile_un_op(&mut self, cx: &Ctxt, dst: &str, op: Op1, e: &Expr) {
+        self.compile_expr(cx, "snek_r", e);
+        match op {
+            Op1::Add1 | Op1::Sub1 => {
+                let (op2, builtin) = match op {
+                    Op1::Add1 => (Op2::Plus, "add"),
+                    _ => (Op2::Minus, "sub"),
+                };
+                self.line(&format!(
+                    "if (SNEK_IS_NUM(snek_r) && !__builtin_{builtin}_overflow((int64_t)snek_r, 2, &snek_t))"
+                ));
+                self.line("  snek_r = snek_t;");
+                self.line("else");
+                self.line(&format!("  snek_r = snek_c_arith({}, snek_r, 2);", arith_code(op2)));
+            }
+            // Bignums are boxed
+            Op1::IsNum if self.bignums => self.line(&format!(
+                "snek_r = SNEK_BOOL(SNEK_IS_NUM(snek_r) || (SNEK_IS_BOXED(snek_r) && (SNEK_BOX(snek_r)[2] & 0xff) == {BIGNUM_KIND}));"
+            )),
+            Op1::IsNum => self.line("snek_r = SNEK_BOOL(SNEK_IS_NUM(snek_r));"),
+            Op1::IsBool => self.line("snek_r = SNEK_BOOL((snek_r & 3) == 3);"),
+            Op1::IsVec => self.line("snek_r = SNEK_BOOL((snek_r & 7) == 1);"),
+            Op1::IsString => self.line(&format!(
+                "snek_r = SNEK_BOOL(SNEK_IS_BOXED(snek_r) && (SNEK_BOX(snek_r)[2] & 0xff) == {STRING_KIND});"
+            )),
+            Op1::IsFloat => self.line(&format!(
+                "snek_r = SNEK_BOOL(SNEK_IS_BOXED(snek_r) && SNEK_BOX(snek_r)[2] == {FLOAT_KIND});"
+            )),
+            Op1::Print => self.line("snek_r = snek_print(snek_r);"),
+        }
+        self.move_to(dst, "snek_r");
+    }
+
+    /// Computes `op` on tagged numbers inline, and calls the runtime for other operands or when
+    /// the result overflows.
+    fn compile_bin_op(&mut self, cx: &Ctxt, dst: &str, op: Op2, e1: &Expr, e2: &Expr) {
+        let (nextcx, slot) = cx.next_local();
+        self.compile_expr(cx, &slot, e1);
+        self.compile_expr(&nextcx, "snek_b", e2);
+        self.line(&format!("snek_a = {slot};"));
+        self.clear(cx.si, 1);
+
+        let nums = "SNEK_IS_NUM(snek_a) && SNEK_IS_NUM(snek_b)";
+        let (x, y) = ("(int64_t)snek_a", "(int64_t)snek_b");
+        if matches!(op, Op2::Divide | Op2::Remainder | Op2::Modulo) {
+            self.fail_if(&format!("{nums} && snek_b == 0"), "SNEK_DIVIDE_BY_ZERO");
+        }
+        // Operations that may overflow compute their result in `snek_t`, the others in `snek_r`
+        let checked = match op {
+            Op2::Plus => Some(format!("__builtin_add_overflow({x}, {y}, &snek_t)")),
+            Op2::Minus => Some(format!("__builtin_sub_overflow({x}, {y}, &snek_t)")),
+            Op2::Times => Some(format!("__builtin_mul_overflow({x} >> 1, {y}, &snek_t)")),
+            // Both operands are shifted by the tag so the quotient must be shifted back, but the
+            // remainder is already tagged.
+            Op2::Divide => Some(format!("__builtin_mul_overflow({x} / {y}, 2, &snek_t)")),
+            _ => None,
+        };
+        let unchecked = match op {
+            Op2::Remainder => vec![format!("snek_r = {x} % {y};")],
+            // A non-zero remainder with a sign different from the divisor's is adjusted by adding
+            // the divisor
+            Op2::Modulo => vec![
+                format!("snek_t = {x} % {y};"),
+                format!("snek_r = snek_t != 0 && (snek_t ^ {y}) < 0 ? snek_t + {y} : snek_t;"),
+            ],
+            Op2::Greater => vec![format!("snek_r = SNEK_BOOL({x} > {y});")],
+            Op2::GreaterEqual => vec![format!("snek_r = SNEK_BOOL({x} >= {y});")],
+            Op2::Less => vec![format!("snek_r = SNEK_BOOL({x} < {y});")],
+            Op2::LessEqual => vec![format!("snek_r = SNEK_BOOL({x} <= {y});")],
+            _ => vec![],
+        };
+        let code = arith_code(op);
+        if let Some(overflows) = checked {
+            self.line(&format!("if ({nums} && !{overflows})"));
+            self.line("  snek_r = snek_t;");
+            self.line("else");
+            self.line(&format!("  snek_r = snek_c_arith({code}, snek_a, snek_b);"));
+        } else if !unchecked.is_empty() {
+            self.line(&format!("if ({nums}) {{"));
+            self.indent += 1;
+            for line in &unchecked {
+                self.line(line);
+            }
+            self.indent -= 1;
+            self.line("} else {");
+            self.line(&format!("  snek_r = snek_c_arith({code}, snek_a, snek_b);"));
+            self.line("}");
+        } else {
+            // Bignums and floats are equal if they have the same value, other values must be of
+            // the same kind
+            self.line("if (SNEK_IS_BOXED(snek_a) || SNEK_IS_BOXED(snek_b)) {");
+            self.line(&format!("  snek_r = snek_c_arith({code}, snek_a, snek_b);"));
+            self.line("} else {");
+            self.indent += 1;
+            self.fail_if(
+                "((snek_a ^ snek_b) & 3) != 0 && ((snek_a | snek_b) & 1) != 0",
+                "SNEK_INVALID_ARGUMENT",
+            );
+            self.line("snek_r = SNEK_BOOL(snek_a == snek_b);");
+            self.indent -= 1;
+            self.line("}");
+        }
+        self.move_to(dst, "snek_r");
+    }
+
+    /// Evaluates `body` with a handler record on the C stack, and `handler` with `x` bound to the
+    /// raised value if the runtime resumes the `try` (see `snek_c_resume`).
+    fn compile_try(&mut self, cx: &Ctxt, dst: &str, body: &Expr, x: Symbol, handler: &Expr) {
+        let tag = self.next_tag();
+        self.line("{");
+        self.indent += 1;
+        self.line(&format!("jmp_buf snek_buf_{tag};"));
+        self.line(&format!(
+            "uint64_t snek_record_{tag}[4] = {{(uint64_t)snek_handler, 0, (uint64_t)&snek_c_resume, (uint64_t)&snek_buf_{tag}}};"
+        ));
+        self.line(&format!("uint64_t *snek_try_sp_{tag} = snek_sp;"));
+        self.line(&format!("if (!setjmp(snek_buf_{tag})) {{"));
+        self.indent += 1;
+        self.line(&format!("snek_handler = snek_record_{tag};"));
+        let mut bodycx = cx.clone();
+        bodycx.tries.push_back(tag);
+        self.compile_expr(&bodycx, "snek_r", body);
+        self.leave_tries(&bodycx, cx.tries.len());
+        self.indent -= 1;
+        self.line("} else {");
+        self.indent += 1;
+        // The runtime restored the previous handler
+        self.line(&format!("snek_sp = snek_try_sp_{tag};"));
+        let (nextcx, slot) = cx.next_local();
+        self.line(&format!("{slot} = snek_c_raised;"));
+        self.compile_expr(&nextcx.add_binding(x, slot), "snek_r", handler);
+        self.clear(cx.si, 1);
+        self.indent -= 1;
+        self.line("}");
+        self.indent -= 1;
+        self.line("}");
+        self.move_to(dst, "snek_r");
+    }
+
+    /// Reinstates the handler that was current outside the `try`s of `cx` after the first
+    /// `tries`, when leaving them without raising.
+    fn leave_tries(&mut self, cx: &Ctxt, tries: usize) {
+        if let Some(tag) = cx.tries.get(tries) {
+            self.line(&format!("snek_handler = (uint64_t *)snek_record_{tag}[0];"));
+        }
+    }
+
+    /// Sets the `count` locals from `start` to nil, so the garbage collector doesn't keep what
+    /// they held alive.
+    fn clear(&mut self, start: u32, count: u32) {
+        for i in start..start + count {
+            self.line(&format!("{} = SNEK_NIL;", slot(i)));
+        }
+    }
+
+    fn move_to(&mut self, dst: &str, src: &str) {
+        if dst != src {
+            self.line(&format!("{dst} = {src};"));
+        }
+    }
+
+    fn fail_if(&mut self, cond: &str, code: &str) {
+        self.line(&format!("if ({cond})"));
+        self.line(&format!("  snek_error({code}, snek_heap_ptr);"));
+    }
+
+    fn check_is_num(&mut self, val: &str) {
+        self.fail_if(&format!("!SNEK_IS_NUM({val})"), "SNEK_INVALID_ARGUMENT");
+    }
+
+    /// Checks `val` is a vector other than nil.
+    fn check_is_vec(&mut self, val: &str) {
+        self.fail_if(
+            &format!("({val} & 7) != 1 || {val} == SNEK_NIL"),
+            "SNEK_INVALID_ARGUMENT",
+        );
+    }
+
+    /// Checks the number `idx` is an index of the vector `vec`, and leaves it untagged in
+    /// `snek_t`.
+    fn check_index(&mut self, vec: &str, idx: &str) {
+        self.line(&format!("snek_t = (int64_t){idx} >> 1;"));
+        self.fail_if(
+            &format!("snek_t < 0 || (uint64_t)snek_t >= SNEK_VEC({vec})[1]"),
+            "SNEK_INDEX_OUT_OF_BOUNDS",
+        );
+    }
+
+    /// Checks `val` is an instance of the struct `name`.
+    fn check_is_struct(&mut self, name: Symbol, val: &str) {
+        let desc = struct_name(name);
+        self.line(&format!(
+            "if (!SNEK_IS_BOXED({val}) || SNEK_BOX({val})[2] != SNEK_STRUCT_HEADER({desc}))"
+        ));
+        self.line(&format!(
+            "  snek_struct_error({desc}, {val}, snek_heap_ptr);"
+        ));
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.code += "  ";
+        }
+        self.code += line;
+        self.code += "\n";
+    }
+
+    fn next_tag(&mut self) -> u32 {
+        self.tag = self.tag.checked_add(1).unwrap();
+        self.tag - 1
+    }
+}
+
+/// The `i`-th local of the current function.
+fn slot(i: u32) -> String {
+    format!("snek_fp[-{}]", i + 1)
+}
+
+fn c_bool(b: bool) -> &'static str {
+    if b {
+        "SNEK_TRUE"
+    } else {
+        "SNEK_FALSE"
+    }
+}
+
+/// A word as a C literal, in hexadecimal when it's large.
+fn c_word(word: u64) -> String {
+    if word < 1 << 31 {
+        word.to_string()
+    } else {
+        format!("{word:#x}u")
+    }
+}
+
+fn c_words(words: &[u64]) -> String {
+    let words: Vec<_> = words.iter().map(|word| c_word(*word)).collect();
+    format!("{{{}}}", words.join(", "))
+}
+
+/// A C string literal with the bytes `s`, escaping everything but printable ASCII.
+fn c_string(s: &[u8]) -> String {
+    let mut lit = String::from("\"");
+    for &b in s {
+        match b {
+            b'"' | b'\\' | b'?' => lit += &format!("\\{}", b as char),
+            b' '..=b'~' => lit.push(b as char),
+            _ => lit += &format!("\\{b:03o}"),
+        }
+    }
+    lit.push('"');
+    lit
+}
+
+fn struct_name(name: Symbol) -> String {
+    format!("snek_struct_{}", mangle(name))
+}
+
+fn fun_name(fun: Symbol) -> String {
+    format!("snek_fun_{}", mangle(fun))
+}
+
+/// Makes a C identifier out of a name, which may be qualified by a module (`lists/append`). Unlike
+/// the x86-64 backend's, different names give different identifiers.
+fn mangle(name: Symbol) -> String {
+    let mut mangled = String::new();
+    for b in name.to_string().bytes() {
+        match b {
+            b'_' => mangled += "__",
+            b'-' => mangled += "_d",
+            b'/' => mangled += "_s",
+            _ if b.is_ascii_alphanumeric() => mangled.push(b as char),
+            _ => mangled += &format!("_x{b:02x}"),
+        }
+    }
+    mangled
+}
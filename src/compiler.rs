@@ -2,54 +2,124 @@ use std::collections::{HashMap, HashSet};
 
 use crate::{
     asm::{
-        instrs_to_string, Arg32, Arg64, BinArgs, CMov, Instr, Loc, MemRef, MovArgs, Offset,
+        data_to_string, instrs_to_string, Arg32, Arg64, BinArgs, CMov, Data, Instr, Loc, MemRef,
+        MovArgs, Offset,
         Reg::{self, *},
-        Reg32,
+        Reg32, SseOp,
         StrOp::Stosq,
+        Xmm::{self, *},
     },
-    mref,
-    syntax::{Expr, FunDecl, Op1, Op2, Prog, Symbol},
+    export, lift, mref,
+    options::Options,
+    peephole, prelude,
+    syntax::{Expr, FunDecl, Op1, Op2, Prim, Prog, StructDecl, Symbol, Type},
+    tags::{self, Facts, Ty},
 };
 
 struct Session {
     tag: u32,
     instrs: Vec<Instr>,
     funs: HashMap<Symbol, usize>,
+    facts: Facts,
+    checks: CheckStats,
+    /// String literals, stored in the data section as `snek_str_{index}`
+    strings: Vec<String>,
+    /// Struct descriptors
+    descriptors: Vec<Data>,
+    /// Whether arithmetic promotes to bignums instead of failing on overflow
+    bignums: bool,
+    /// Integer literals too large for a tagged number, stored in the data section as
+    /// `snek_big_{index}`
+    big_literals: Vec<String>,
+    /// Float literals, stored in the data section as `snek_float_{index}`
+    float_literals: Vec<f64>,
+}
+
+/// Labels of the code computing an arithmetic operation when its operands aren't both tagged
+/// numbers, or when its result overflows and bignums are enabled.
+struct SlowPath {
+    /// Where operands that aren't tagged numbers go: `float` if it exists, `runtime` otherwise
+    not_num: String,
+    /// Computes the operation with SSE instructions if both operands are floats or tagged numbers
+    float: Option<String>,
+    /// Calls the runtime to compute the operation (or report that it can't be computed)
+    runtime: String,
+}
+
+/// Number of runtime checks emitted and eliminated thanks to the static type of their operands.
+#[derive(Default)]
+struct CheckStats {
+    emitted: u32,
+    elided: u32,
 }
 
 const INVALID_ARG: &str = "invalid_argument";
 const OVERFLOW: &str = "overflow";
 const INDEX_OUT_OF_BOUNDS: &str = "index_out_of_bounds";
 const INVALID_SIZE: &str = "invalid_vec_size";
+const DIVIDE_BY_ZERO: &str = "divide_by_zero";
 
 const STACK_BASE: Reg = Rbx;
-const INPUT_REG: Reg = R13;
+/// Registers of the arguments of C functions, in order
+const C_ARGS: [Reg; 6] = [Rdi, Rsi, Rdx, Rcx, R8, R9];
 const HEAP_END: Reg = R14;
 const HEAP_PTR: Reg = R15;
 
-const NIL: i32 = 0b001;
+/// The runtime's copy of the input, which the garbage collector updates when it moves the input
+const INPUT: &str = "snek_input";
+/// The runtime's heap pointer and end of the heap, which C functions exported by a library
+/// compiled with `--lib` use between calls
+const RUNTIME_HEAP_PTR: &str = "snek_heap_ptr";
+const RUNTIME_HEAP_END: &str = "snek_heap_end";
+/// The runtime's pointer to the handler record of the innermost `try`, or null if there is none
+const HANDLER: &str = "snek_handler";
+/// Number of words of a handler record: the previous handler, the address of the handler code,
+/// and the values of `%rsp`, `%rbp`, `%rbx` and `%r14` to restore before running it (from the lowest
+/// address to the highest, see `snek_raise` in the runtime). The runtime restores the heap pointer
+/// itself.
+const TRY_RECORD: u32 = 6;
+
+pub const NIL: i32 = 0b001;
 const MEM_SET_VAL: i32 = NIL;
-const GC_WORD_VAL: i32 = 0;
+pub const GC_WORD_VAL: i32 = 0;
+
+/// Tag of heap objects other than vectors. They have an extra header word after the size whose low
+/// byte is the kind of object, see `runtime/start.rs`.
+pub const BOX_TAG: i32 = 0b101;
+/// Offset of the header word from a pointer tagged with `BOX_TAG`
+const BOX_HEADER: i32 = 16 - BOX_TAG;
+pub const STRING_KIND: i32 = 1;
+/// Structs have a pointer to a descriptor in their header (shifted by 8), with the number of
+/// fields and the names of the struct and its fields.
+pub const STRUCT_KIND: i32 = 2;
+/// Bignums have their sign in their header (shifted by 8), and their magnitude in the following
+/// words, least significant first.
+pub const BIGNUM_KIND: i32 = 3;
+/// Floats have their bits in the word after the header.
+pub const FLOAT_KIND: i32 = 4;
 
 #[derive(Debug, Clone)]
-struct Ctxt<'a> {
+struct Ctxt {
     env: im::HashMap<Symbol, MemRef>,
     si: u32,
-    curr_lbl: Option<&'a str>,
-    in_fun: bool,
+    /// The label and tag of each enclosing loop, and the number of enclosing `try`s at its start,
+    /// innermost last
+    loops: im::Vector<(Option<Symbol>, u32, usize)>,
+    /// The handler record of each enclosing `try` in the current function, innermost last
+    tries: im::Vector<MemRef>,
 }
 
-impl<'a> Ctxt<'a> {
-    fn new() -> Ctxt<'a> {
+impl Ctxt {
+    fn new() -> Ctxt {
         Ctxt {
             si: 0,
-            curr_lbl: None,
+            loops: im::Vector::new(),
+            tries: im::Vector::new(),
             env: im::HashMap::default(),
-            in_fun: false,
         }
     }
 
-    fn with_params(params: &[Symbol]) -> Ctxt<'a> {
+    fn with_params(params: &[Symbol]) -> Ctxt {
         let env = params
             .iter()
             .enumerate()
@@ -57,9 +127,9 @@ impl<'a> Ctxt<'a> {
             .collect();
         Ctxt {
             si: 0,
-            curr_lbl: None,
+            loops: im::Vector::new(),
+            tries: im::Vector::new(),
             env,
-            in_fun: true,
         }
     }
 
@@ -70,14 +140,30 @@ impl<'a> Ctxt<'a> {
             .unwrap_or_else(|| raise_unbound_identifier(x))
     }
 
-    fn set_curr_lbl(&self, lbl: &'a str) -> Ctxt<'a> {
+    fn enter_loop(&self, label: Option<Symbol>, tag: u32) -> Ctxt {
+        let mut loops = self.loops.clone();
+        loops.push_back((label, tag, self.tries.len()));
         Ctxt {
-            curr_lbl: Some(lbl),
+            loops,
             ..self.clone()
         }
     }
 
-    fn next_local(&self) -> (Ctxt<'a>, MemRef) {
+    /// The tag of the loop left by a `break` or restarted by a `continue` with the given label,
+    /// and the number of `try`s enclosing it.
+    fn target_loop(&self, label: Option<Symbol>, keyword: &str) -> (u32, usize) {
+        let target = match label {
+            Some(label) => self.loops.iter().rev().find(|(l, ..)| *l == Some(label)),
+            None => self.loops.last(),
+        };
+        match (target, label) {
+            (Some((_, tag, tries)), _) => (*tag, *tries),
+            (None, Some(label)) => raise_unknown_label(label),
+            (None, None) => raise_outside_loop(keyword),
+        }
+    }
+
+    fn next_local(&self) -> (Ctxt, MemRef) {
         let si: i32 = (self.si + 1).try_into().unwrap();
         (
             Ctxt {
@@ -88,58 +174,126 @@ impl<'a> Ctxt<'a> {
         )
     }
 
-    fn add_binding(&self, x: Symbol, mem: MemRef) -> Ctxt<'a> {
+    fn add_binding(&self, x: Symbol, mem: MemRef) -> Ctxt {
         Ctxt {
             env: self.env.update(x, mem),
-            ..*self
+            ..self.clone()
         }
     }
 }
 
-pub fn compile(prg: &Prog) -> String {
+pub fn compile(prg: &Prog, opts: &Options) -> String {
     match fun_arity_map(prg) {
         Ok(funs) => {
-            let mut sess = Session::new(funs);
-            let locals = depth(&prg.main);
+            let facts = if opts.opt_level >= 1 {
+                Facts::analyze(prg, opts.bignums)
+            } else {
+                Facts::default()
+            };
+            let mut sess = Session::new(funs, facts, opts.bignums);
+            sess.compile_structs(&prg.structs, &prg.imported_structs);
             sess.compile_funs(&prg.funs);
-            sess.emit_instr(Instr::Label("our_code_starts_here".to_string()));
-            let callee_saved = [Rbp, STACK_BASE, INPUT_REG, HEAP_END, HEAP_PTR];
-            sess.fun_entry(locals, &callee_saved);
-            sess.emit_instrs([
-                Instr::Mov(MovArgs::ToReg(STACK_BASE, Arg64::Reg(Rbp))),
-                Instr::Mov(MovArgs::ToReg(INPUT_REG, Arg64::Reg(Rdi))),
-                Instr::Mov(MovArgs::ToReg(HEAP_PTR, Arg64::Reg(Rsi))),
-                Instr::Mov(MovArgs::ToReg(HEAP_END, Arg64::Reg(Rdx))),
-            ]);
-            sess.compile_expr(&Ctxt::new(), Loc::Reg(Rax), &prg.main);
-            sess.fun_exit(locals, &callee_saved);
+            for &export in &prg.exports {
+                sess.compile_export(prg.funs.iter().find(|fun| fun.name == export).unwrap());
+            }
+            if let Some(main) = &prg.main {
+                let locals = depth(main);
+                sess.emit_instr(Instr::Label("our_code_starts_here".to_string()));
+                let callee_saved = [Rbp, STACK_BASE, HEAP_END, HEAP_PTR];
+                sess.fun_entry(locals, &callee_saved);
+                sess.emit_instrs([
+                    Instr::Mov(MovArgs::ToReg(STACK_BASE, Arg64::Reg(Rbp))),
+                    Instr::Mov(MovArgs::ToReg(HEAP_PTR, Arg64::Reg(Rdi))),
+                    Instr::Mov(MovArgs::ToReg(HEAP_END, Arg64::Reg(Rsi))),
+                ]);
+                sess.compile_expr(&Ctxt::new(), Loc::Reg(Rax), main);
+                sess.fun_exit(locals, &callee_saved);
+            }
+
+            let data = sess.data();
+            let instrs = if opts.peephole() {
+                peephole::optimize(sess.instrs)
+            } else {
+                sess.instrs
+            };
+
+            if opts.stats {
+                let CheckStats { emitted, elided } = sess.checks;
+                eprintln!("eliminated {elided} of {} runtime checks", emitted + elided);
+            }
 
+            let externs: String = Prim::ALL
+                .iter()
+                .map(|prim| format!("extern {}\n", prim.symbol()))
+                .collect();
+            // Libraries export their definitions to the modules importing them
+            let mut linkage = String::new();
+            for &(fun, _) in &prg.imported_funs {
+                linkage += &format!("extern {}\n", fun_label(fun));
+            }
+            for decl in &prg.imported_structs {
+                linkage += &format!("extern {}\n", struct_label(decl.name));
+            }
+            for &(fun, _) in &prg.externs {
+                linkage += &format!("extern {}\n", c_symbol(&fun.to_string()));
+            }
+            if !prg.exports.is_empty() {
+                linkage += &format!("extern {RUNTIME_HEAP_PTR}\nextern {RUNTIME_HEAP_END}\n");
+            }
+            for &fun in &prg.exports {
+                linkage += &format!("global {}\n", c_symbol(&export::c_name(fun)));
+            }
+            if prg.main.is_some() {
+                linkage += "global our_code_starts_here\n";
+            } else {
+                for fun in (prg.funs.iter())
+                    .filter(|fun| !prelude::is_prelude(fun.name) && !lift::is_local(fun.name))
+                {
+                    linkage += &format!("global {}\n", fun_label(fun.name));
+                }
+                for decl in &prg.structs {
+                    linkage += &format!("global {}\n", struct_label(decl.name));
+                }
+            }
             format!(
                 "
 section .text
-extern snek_error
+{externs}extern snek_error
+extern snek_struct_error
+extern snek_no_match
+extern snek_assert_failed
 extern snek_print
 extern snek_print_heap
 extern snek_alloc_vec
 extern snek_print_stack
 extern snek_try_gc
 extern snek_gc
-global our_code_starts_here
-{}
+extern snek_arith
+extern snek_raise
+extern {HANDLER}
+extern {INPUT}
+{linkage}{}
 {INVALID_ARG}:
   mov edi, 1
-  call snek_error
+  jmp runtime_error
 {OVERFLOW}:
   mov edi, 2
-  call snek_error
+  jmp runtime_error
 {INDEX_OUT_OF_BOUNDS}:
   mov edi, 3
-  call snek_error
+  jmp runtime_error
 {INVALID_SIZE}:
   mov edi, 4
+  jmp runtime_error
+{DIVIDE_BY_ZERO}:
+  mov edi, 6
+runtime_error:
+  mov rsi, r15
   call snek_error
-",
-                instrs_to_string(&sess.instrs)
+section .data
+{}",
+                instrs_to_string(&instrs),
+                data_to_string(&data)
             )
         }
         Err(dup) => raise_duplicate_function(dup),
@@ -147,14 +301,103 @@ global our_code_starts_here
 }
 
 impl Session {
-    fn new(funs: HashMap<Symbol, usize>) -> Session {
+    fn new(funs: HashMap<Symbol, usize>, facts: Facts, bignums: bool) -> Session {
         Session {
             tag: 0,
             instrs: vec![],
             funs,
+            facts,
+            checks: CheckStats::default(),
+            strings: vec![],
+            descriptors: vec![],
+            bignums,
+            big_literals: vec![],
+            float_literals: vec![],
         }
     }
 
+    /// Emits the descriptors of the structs defined by this module, and the code reporting a value
+    /// that isn't the expected struct for those and the imported ones.
+    fn compile_structs(&mut self, structs: &[StructDecl], imported: &[StructDecl]) {
+        for decl in structs {
+            let mut descriptor = vec![
+                Data::Align(8),
+                Data::Label(struct_label(decl.name)),
+                Data::Quad(decl.fields.len() as i64),
+                Data::Addr(self.intern(decl.name.unqualified()), BOX_TAG),
+            ];
+            for field in &decl.fields {
+                descriptor.push(Data::Addr(self.intern(&field.to_string()), BOX_TAG));
+            }
+            self.descriptors.extend(descriptor);
+        }
+        for decl in structs.iter().chain(imported) {
+            let label = struct_label(decl.name);
+            self.emit_instrs([
+                Instr::Label(format!("{label}_error")),
+                Instr::LeaRel(Rdi, label, 0),
+                Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Reg(Rax))),
+                Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(HEAP_PTR))),
+                Instr::Call("snek_struct_error".to_string()),
+            ]);
+        }
+    }
+
+    /// Returns the label of the string literal `s` in the data section.
+    fn intern(&mut self, s: &str) -> String {
+        let idx = match self.strings.iter().position(|other| other == s) {
+            Some(idx) => idx,
+            None => {
+                self.strings.push(s.to_string());
+                self.strings.len() - 1
+            }
+        };
+        format!("snek_str_{idx}")
+    }
+
+    /// String, bignum and float literals laid out like heap objects (with a zero GC word) so the runtime
+    /// can treat them like any other. They live outside the heap so the GC never moves them.
+    fn data(&self) -> Vec<Data> {
+        let mut data = self.descriptors.clone();
+        for (i, s) in self.strings.iter().enumerate() {
+            let len = s.len() as i64;
+            data.extend([
+                Data::Align(8),
+                Data::Label(format!("snek_str_{i}")),
+                Data::Quad(GC_WORD_VAL as i64),
+                Data::Quad(1 + (len + 7) / 8),
+                Data::Quad(STRING_KIND as i64 | len << 8),
+                Data::Bytes(s.as_bytes().to_vec()),
+            ]);
+        }
+        for (i, n) in self.big_literals.iter().enumerate() {
+            let (neg, digits) = match n.strip_prefix('-') {
+                Some(digits) => (1, digits),
+                None => (0, n.as_str()),
+            };
+            let words = decimal_to_words(digits);
+            data.extend([
+                Data::Align(8),
+                Data::Label(format!("snek_big_{i}")),
+                Data::Quad(GC_WORD_VAL as i64),
+                Data::Quad(1 + words.len() as i64),
+                Data::Quad(BIGNUM_KIND as i64 | neg << 8),
+            ]);
+            data.extend(words.into_iter().map(|word| Data::Quad(word as i64)));
+        }
+        for (i, x) in self.float_literals.iter().enumerate() {
+            data.extend([
+                Data::Align(8),
+                Data::Label(format!("snek_float_{i}")),
+                Data::Quad(GC_WORD_VAL as i64),
+                Data::Quad(2),
+                Data::Quad(FLOAT_KIND as i64),
+                Data::Quad(x.to_bits() as i64),
+            ]);
+        }
+        data
+    }
+
     fn fun_entry(&mut self, locals: u32, callee_saved: &[Reg]) {
         let size = frame_size(locals, callee_saved);
         for reg in callee_saved {
@@ -194,10 +437,73 @@ impl Session {
         self.fun_exit(locals, &[Rbp]);
     }
 
+    /// Emits the C function exporting `fun`. It converts its arguments to snek values, and calls
+    /// `fun` with the heap of the runtime like `our_code_starts_here` does, saving the heap pointer
+    /// for the next call before converting the result to C.
+    fn compile_export(&mut self, fun: &FunDecl) {
+        let callee_saved = [Rbp, STACK_BASE, HEAP_END, HEAP_PTR];
+        self.emit_instr(Instr::Label(c_symbol(&export::c_name(fun.name))));
+        self.fun_entry(0, &callee_saved);
+        self.emit_instrs([
+            Instr::Mov(MovArgs::ToReg(STACK_BASE, Arg64::Reg(Rbp))),
+            Instr::LeaRel(Rax, RUNTIME_HEAP_PTR.to_string(), 0),
+            Instr::Mov(MovArgs::ToReg(HEAP_PTR, Arg64::Mem(mref![Rax + 0]))),
+            Instr::LeaRel(Rax, RUNTIME_HEAP_END.to_string(), 0),
+            Instr::Mov(MovArgs::ToReg(HEAP_END, Arg64::Mem(mref![Rax + 0]))),
+        ]);
+        let args = &C_ARGS[..fun.params.len()];
+        for (&reg, ty) in args.iter().zip(&fun.param_tys) {
+            if *ty == Type::Bool {
+                self.emit_instrs([
+                    Instr::And(BinArgs::ToReg(reg, Arg32::Imm(1))),
+                    Instr::Shl(BinArgs::ToReg(reg, Arg32::Imm(2))),
+                    Instr::Or(BinArgs::ToReg(reg, false.repr32())),
+                ]);
+            } else {
+                self.emit_instrs([
+                    Instr::Add(BinArgs::ToReg(reg, Arg32::Reg(reg))),
+                    Instr::Jo(OVERFLOW.to_string()),
+                ]);
+            }
+        }
+        self.call(fun.name, args.iter().map(|&reg| Arg32::Reg(reg)));
+        self.emit_instrs([
+            Instr::LeaRel(Rdx, RUNTIME_HEAP_PTR.to_string(), 0),
+            Instr::Mov(MovArgs::ToMem(mref![Rdx + 0], Reg32::Reg(HEAP_PTR))),
+        ]);
+        let ty = self.facts.ty(&fun.body);
+        if fun.ret_ty == Some(Type::Bool) {
+            self.untag_c_bool(ty);
+        } else {
+            self.untag_c_arg(ty);
+        }
+        self.fun_exit(0, &callee_saved);
+    }
+
     fn compile_expr(&mut self, cx: &Ctxt, dst: Loc, e: &Expr) {
         match e {
             Expr::Number(n) => self.move_to(dst, n.repr64()),
+            Expr::BigNum(n) => {
+                if !self.bignums {
+                    raise_integer_overflow()
+                }
+                let label = format!("snek_big_{}", self.big_literals.len());
+                self.big_literals.push(n.clone());
+                self.emit_instr(Instr::LeaRel(Rax, label, BOX_TAG));
+                self.move_to(dst, Arg64::Reg(Rax));
+            }
+            Expr::Float(x) => {
+                let label = format!("snek_float_{}", self.float_literals.len());
+                self.float_literals.push(*x);
+                self.emit_instr(Instr::LeaRel(Rax, label, BOX_TAG));
+                self.move_to(dst, Arg64::Reg(Rax));
+            }
             Expr::Boolean(b) => self.move_to(dst, b.repr64()),
+            Expr::Str(s) => {
+                let label = self.intern(s);
+                self.emit_instr(Instr::LeaRel(Rax, label, BOX_TAG));
+                self.move_to(dst, Arg64::Reg(Rax));
+            }
             Expr::Var(x) => self.move_to(dst, Arg32::Mem(cx.lookup(*x))),
             Expr::Let(bindings, body) => {
                 check_dup_bindings(bindings.iter().map(|(id, _)| id));
@@ -220,7 +526,7 @@ impl Session {
 
                 self.compile_expr(cx, Loc::Reg(Rax), e1);
                 self.emit_instrs([
-                    Instr::Cmp(BinArgs::ToReg(Rax, false.repr32().into())),
+                    Instr::Cmp(BinArgs::ToReg(Rax, false.repr32())),
                     Instr::Je(else_lbl.clone()),
                 ]);
                 self.compile_expr(cx, dst, e2);
@@ -228,24 +534,36 @@ impl Session {
                 self.compile_expr(cx, dst, e3);
                 self.emit_instr(Instr::Label(end_lbl))
             }
-            Expr::Loop(e) => {
+            Expr::Loop(label, e) => {
                 let tag = self.next_tag();
                 let loop_start_lbl = format!("loop_start_{tag}");
                 let loop_end_lbl = format!("loop_end_{tag}");
 
                 self.emit_instr(Instr::Label(loop_start_lbl.clone()));
-                self.compile_expr(&cx.set_curr_lbl(&loop_end_lbl), dst, e);
+                self.compile_expr(&cx.enter_loop(*label, tag), dst, e);
                 self.emit_instrs([Instr::Jmp(loop_start_lbl), Instr::Label(loop_end_lbl)]);
                 self.move_to(dst, Arg64::Reg(Rax));
             }
-            Expr::Break(e) => {
-                if let Some(lbl) = cx.curr_lbl {
-                    self.compile_expr(cx, Loc::Reg(Rax), e);
-                    self.emit_instr(Instr::Jmp(lbl.to_string()));
-                } else {
-                    raise_break_outside_loop()
-                }
+            Expr::Break(label, e) => {
+                let (tag, tries) = cx.target_loop(*label, "break");
+                self.compile_expr(cx, Loc::Reg(Rax), e);
+                self.leave_tries(cx, tries);
+                self.emit_instr(Instr::Jmp(format!("loop_end_{tag}")));
             }
+            Expr::Continue(label) => {
+                let (tag, tries) = cx.target_loop(*label, "continue");
+                self.leave_tries(cx, tries);
+                self.emit_instr(Instr::Jmp(format!("loop_start_{tag}")));
+            }
+            Expr::Raise(e) => {
+                self.compile_expr(cx, Loc::Reg(Rax), e);
+                self.emit_instrs([
+                    Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Reg(Rax))),
+                    Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Reg(HEAP_PTR))),
+                    Instr::Call("snek_raise".to_string()),
+                ]);
+            }
+            Expr::Try(body, x, handler) => self.compile_try(cx, dst, body, *x, handler),
             Expr::Set(var, e) => {
                 let mem = cx.lookup(*var);
                 self.compile_expr(cx, Loc::Mem(mem), e);
@@ -275,15 +593,49 @@ impl Session {
                 self.memset(cx.si, args.len() as u32, Reg32::Imm(MEM_SET_VAL));
                 self.move_to(dst, Arg64::Reg(Rax));
             }
+            Expr::CallExtern(fun, args) => {
+                let mut currcx = cx.clone();
+                for arg in args {
+                    let (nextcx, mem) = currcx.next_local();
+                    self.compile_expr(&currcx, Loc::Mem(mem), arg);
+                    currcx = nextcx;
+                }
+                let mems = locals(cx.si, args.len() as u32);
+                for ((arg, mem), reg) in args.iter().zip(mems).zip(C_ARGS) {
+                    self.emit_instr(Instr::Mov(MovArgs::ToReg(Rax, Arg64::Mem(mem))));
+                    self.untag_c_arg(self.facts.ty(arg));
+                    self.emit_instr(Instr::Mov(MovArgs::ToReg(reg, Arg64::Reg(Rax))));
+                }
+                // The stack is aligned, and the C function preserves the registers holding the
+                // stack base and the heap
+                self.emit_instrs([
+                    Instr::Call(c_symbol(&fun.to_string())),
+                    Instr::Add(BinArgs::ToReg(Rax, Arg32::Reg(Rax))),
+                    Instr::Jo(OVERFLOW.to_string()),
+                ]);
+                self.memset(cx.si, args.len() as u32, Reg32::Imm(MEM_SET_VAL));
+                self.move_to(dst, Arg64::Reg(Rax));
+            }
+            Expr::Prim(prim, args) => {
+                let mut currcx = cx.clone();
+                for arg in args {
+                    let (nextcx, mem) = currcx.next_local();
+                    self.compile_expr(&currcx, Loc::Mem(mem), arg);
+                    currcx = nextcx;
+                }
+                self.call_prim(*prim, locals(cx.si, args.len() as u32).map(Arg32::Mem));
+                self.memset(cx.si, args.len() as u32, Reg32::Imm(MEM_SET_VAL));
+                self.move_to(dst, Arg64::Reg(Rax));
+            }
             Expr::Nil => {
                 self.move_to(dst, Arg32::Imm(NIL));
             }
             Expr::Input => {
-                if cx.in_fun {
-                    raise_input_in_fun()
-                } else {
-                    self.move_to(dst, Arg32::Reg(INPUT_REG))
-                }
+                self.emit_instrs([
+                    Instr::LeaRel(Rax, INPUT.to_string(), 0),
+                    Instr::Mov(MovArgs::ToReg(Rax, Arg64::Mem(mref![Rax + 0]))),
+                ]);
+                self.move_to(dst, Arg64::Reg(Rax));
             }
             Expr::MakeVec(size, elem) => {
                 let tag = self.next_tag();
@@ -295,7 +647,7 @@ impl Session {
                 self.compile_expr(cx, Loc::Mem(size_mem), size);
                 self.compile_expr(&nextcx, Loc::Mem(elem_mem), elem);
                 self.emit_instr(Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Mem(size_mem))));
-                self.check_is_num(Rdi);
+                self.check_is_num(Rdi, self.facts.ty(size));
                 self.emit_instrs([
                     Instr::Sar(BinArgs::ToReg(Rdi, Arg32::Imm(1))),
                     Instr::Cmp(BinArgs::ToReg(Rdi, Arg32::Imm(0))),
@@ -334,9 +686,6 @@ impl Session {
                 self.move_to(dst, Arg64::Reg(Rax));
             }
             Expr::Vec(elems) => {
-                let tag = self.next_tag();
-                let vec_alloc_finish_lbl = format!("vec_alloc_finish_{tag}");
-
                 let size: i32 = elems.len().try_into().unwrap();
                 let mut currcx = cx.clone();
                 for elem in elems {
@@ -345,25 +694,12 @@ impl Session {
                     currcx = nextcx;
                 }
 
-                self.emit_instrs([
-                    Instr::Lea(Rax, mref![HEAP_PTR + %(8 * (size + 2))]),
-                    Instr::Cmp(BinArgs::ToReg(Rax, Arg32::Reg(HEAP_END))),
-                    Instr::Jle(vec_alloc_finish_lbl.clone()),
-                    // Call try_gc to ensure we can allocate `size + 2` quad words
-                    // (1 extra for the size of the vector + 1 extra for the GC metadata)
-                    Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Imm(size as i64 + 2))),
-                    Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Reg(HEAP_PTR))),
-                    Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(STACK_BASE))),
-                    Instr::Mov(MovArgs::ToReg(Rcx, Arg64::Reg(Rbp))),
-                    Instr::Mov(MovArgs::ToReg(R8, Arg64::Reg(Rsp))),
-                    Instr::Call("snek_try_gc".to_string()),
-                    Instr::Mov(MovArgs::ToReg(HEAP_PTR, Arg64::Reg(Rax))),
-                    Instr::Label(vec_alloc_finish_lbl),
-                    // Write GC word in HEAP_PTR
-                    Instr::Mov(MovArgs::ToMem(mref!(HEAP_PTR + 0), Reg32::Imm(GC_WORD_VAL))),
-                    // Write size in HEAP_PTR + 8
-                    Instr::Mov(MovArgs::ToMem(mref!(HEAP_PTR + 8), Reg32::Imm(size))),
-                ]);
+                self.alloc(size + 2);
+                // Write size in HEAP_PTR + 8
+                self.emit_instr(Instr::Mov(MovArgs::ToMem(
+                    mref!(HEAP_PTR + 8),
+                    Reg32::Imm(size),
+                )));
 
                 for i in 0..elems.len() as u32 {
                     self.move_to(
@@ -381,6 +717,82 @@ impl Session {
                 self.memset(cx.si, elems.len() as u32, Reg32::Imm(MEM_SET_VAL));
                 self.move_to(dst, Arg64::Reg(Rax));
             }
+            Expr::StructNew(name, fields) => {
+                let size: i32 = fields.len().try_into().unwrap();
+                let mut currcx = cx.clone();
+                for field in fields {
+                    let (nextcx, mem) = currcx.next_local();
+                    self.compile_expr(&currcx, Loc::Mem(mem), field);
+                    currcx = nextcx;
+                }
+
+                self.alloc(size + 3);
+                self.struct_header(Rcx, *name);
+                self.emit_instrs([
+                    // The size includes the header
+                    Instr::Mov(MovArgs::ToMem(mref!(HEAP_PTR + 8), Reg32::Imm(size + 1))),
+                    Instr::Mov(MovArgs::ToMem(mref!(HEAP_PTR + 16), Reg32::Reg(Rcx))),
+                ]);
+                for (i, mem) in locals(cx.si, size as u32).enumerate() {
+                    self.move_to(Loc::Mem(mref!(HEAP_PTR + %(8 * (i + 3)))), Arg64::Mem(mem))
+                }
+                self.emit_instrs([
+                    Instr::Lea(Rax, mref!(HEAP_PTR + %(BOX_TAG))),
+                    Instr::Lea(HEAP_PTR, mref!(HEAP_PTR + %(8 * (size + 3)))),
+                ]);
+                self.memset(cx.si, size as u32, Reg32::Imm(MEM_SET_VAL));
+                self.move_to(dst, Arg64::Reg(Rax));
+            }
+            Expr::StructGet(name, field, e) => {
+                self.compile_expr(cx, Loc::Reg(Rax), e);
+                self.check_is_struct(*name);
+                self.emit_instr(Instr::Mov(MovArgs::ToReg(
+                    Rax,
+                    Arg64::Mem(mref!(Rax + %(field_offset(*field)))),
+                )));
+                self.move_to(dst, Arg64::Reg(Rax));
+            }
+            Expr::StructSet(name, field, e, val) => {
+                let (nextcx, mem) = cx.next_local();
+                self.compile_expr(cx, Loc::Mem(mem), e);
+                self.compile_expr(&nextcx, Loc::Reg(Rsi), val);
+                self.emit_instr(Instr::Mov(MovArgs::ToReg(Rax, Arg64::Mem(mem))));
+                self.memset(cx.si, 1, Reg32::Imm(MEM_SET_VAL));
+                self.check_is_struct(*name);
+                self.emit_instr(Instr::Mov(MovArgs::ToMem(
+                    mref!(Rax + %(field_offset(*field))),
+                    Reg32::Reg(Rsi),
+                )));
+                self.move_to(dst, Arg64::Reg(Rax));
+            }
+            // Erased after type checking, but they don't affect the generated code anyway
+            Expr::Annot(_, e) | Expr::At(_, e) => self.compile_expr(cx, dst, e),
+            Expr::NoMatch(e) => {
+                self.compile_expr(cx, Loc::Reg(Rax), e);
+                self.emit_instrs([
+                    Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Reg(Rax))),
+                    Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Reg(HEAP_PTR))),
+                    Instr::Call("snek_no_match".to_string()),
+                ]);
+            }
+            Expr::AssertFailed(cond, msg) => {
+                self.compile_expr(cx, Loc::Reg(Rax), msg);
+                let label = self.intern(cond);
+                self.emit_instrs([
+                    Instr::LeaRel(Rdi, label, BOX_TAG),
+                    Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Reg(Rax))),
+                    Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(HEAP_PTR))),
+                    Instr::Call("snek_assert_failed".to_string()),
+                ]);
+            }
+            Expr::StructIs(name, e) => {
+                self.compile_expr(cx, Loc::Reg(Rax), e);
+                self.compile_is_boxed(|sess| {
+                    sess.struct_header(Rsi, *name);
+                    sess.emit_instr(Instr::Cmp(BinArgs::ToReg(Rcx, Arg32::Reg(Rsi))));
+                });
+                self.move_to(dst, Arg64::Reg(Rax));
+            }
             Expr::VecSet(vec, idx, elem) => {
                 let (nextcx1, vec_mem) = cx.next_local();
                 let (nextcx2, idx_mem) = nextcx1.next_local();
@@ -394,9 +806,8 @@ impl Session {
                     Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Mem(idx_mem))),
                 ]);
                 self.memset(cx.si, 2, Reg32::Imm(MEM_SET_VAL));
-                self.check_is_vec(Rax);
-                self.check_is_not_nil(Rax);
-                self.check_is_num(Rdi);
+                self.check_is_vec(Rax, self.facts.ty(vec));
+                self.check_is_num(Rdi, self.facts.ty(idx));
                 self.emit_instrs([
                     Instr::Mov(MovArgs::ToReg(Rcx, Arg64::Reg(Rax))),
                     Instr::Sub(BinArgs::ToReg(Rcx, Arg32::Imm(1))),
@@ -418,9 +829,8 @@ impl Session {
 
                 self.emit_instrs([Instr::Mov(MovArgs::ToReg(Rax, Arg64::Mem(vec_mem)))]);
                 self.memset(cx.si, 1, Reg32::Imm(MEM_SET_VAL));
-                self.check_is_vec(Rax);
-                self.check_is_not_nil(Rax);
-                self.check_is_num(Rdi);
+                self.check_is_vec(Rax, self.facts.ty(vec));
+                self.check_is_num(Rdi, self.facts.ty(idx));
                 self.emit_instrs([
                     Instr::Sub(BinArgs::ToReg(Rax, Arg32::Imm(1))),
                     Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Mem(mref![Rax + 8]))),
@@ -435,8 +845,7 @@ impl Session {
             }
             Expr::VecLen(vec) => {
                 self.compile_expr(cx, Loc::Reg(Rax), vec);
-                self.check_is_vec(Rax);
-                self.check_is_not_nil(Rax);
+                self.check_is_vec(Rax, self.facts.ty(vec));
                 self.emit_instrs([
                     Instr::Sub(BinArgs::ToReg(Rax, Arg32::Imm(1))),
                     Instr::Mov(MovArgs::ToReg(Rax, Arg64::Mem(mref![Rax + 8]))),
@@ -463,7 +872,7 @@ impl Session {
                     Instr::Call("snek_print_stack".to_string()),
                 ]);
                 self.move_to(dst, 0.repr32());
-            },
+            }
             Expr::PrintHeap => {
                 self.emit_instrs([
                     Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Reg(HEAP_PTR))),
@@ -477,6 +886,39 @@ impl Session {
     }
 
     fn call(&mut self, fun: Symbol, args: impl IntoIterator<Item = Arg32>) {
+        let size = self.push_args(args);
+        self.emit_instrs([
+            Instr::Call(fun_label(fun)),
+            Instr::Add(BinArgs::ToReg(Rsp, Arg32::Imm(size))),
+        ]);
+    }
+
+    /// Calls a primitive implemented in the runtime. The arguments are pushed on the stack so the
+    /// garbage collector can find them, and the primitive gets a pointer to them (in `%rdi`)
+    /// followed by the same arguments as `snek_try_gc`. It returns the result in `%rax` and the new
+    /// heap pointer in `%rdx`.
+    fn call_prim(&mut self, prim: Prim, args: impl IntoIterator<Item = Arg32>) {
+        self.call_runtime(prim.symbol(), args)
+    }
+
+    /// Calls the function `symbol` of the runtime following the same convention as primitives.
+    fn call_runtime(&mut self, symbol: &str, args: impl IntoIterator<Item = Arg32>) {
+        let size = self.push_args(args);
+        self.emit_instrs([
+            Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Reg(Rsp))),
+            Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Reg(HEAP_PTR))),
+            Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(STACK_BASE))),
+            Instr::Mov(MovArgs::ToReg(Rcx, Arg64::Reg(Rbp))),
+            Instr::Mov(MovArgs::ToReg(R8, Arg64::Reg(Rsp))),
+            Instr::Call(symbol.to_string()),
+            Instr::Mov(MovArgs::ToReg(HEAP_PTR, Arg64::Reg(Rdx))),
+            Instr::Add(BinArgs::ToReg(Rsp, Arg32::Imm(size))),
+        ]);
+    }
+
+    /// Pushes `args` (the first one at the top of the stack) padding them to keep the stack
+    /// aligned. Returns the number of bytes pushed.
+    fn push_args(&mut self, args: impl IntoIterator<Item = Arg32>) -> i32 {
         let mut args: Vec<_> = args.into_iter().collect();
         if args.len() % 2 != 0 {
             args.push(Arg32::Imm(MEM_SET_VAL));
@@ -484,28 +926,54 @@ impl Session {
         for arg in args.iter().rev() {
             self.emit_instr(Instr::Push(*arg))
         }
-        self.emit_instrs([
-            Instr::Call(fun_label(fun)),
-            Instr::Add(BinArgs::ToReg(Rsp, Arg32::Imm(8 * args.len() as i32))),
-        ]);
+        8 * args.len() as i32
     }
 
     fn compile_un_op(&mut self, cx: &Ctxt, dst: Loc, op: Op1, e: &Expr) {
         self.compile_expr(cx, Loc::Reg(Rax), e);
+        let ty = self.facts.ty(e);
         match op {
-            Op1::Add1 => {
-                self.check_is_num(Reg::Rax);
-                self.emit_instrs([
-                    Instr::Add(BinArgs::ToReg(Rax, 1.repr32())),
-                    Instr::Jo(OVERFLOW.to_string()),
-                ])
+            Op1::Add1 | Op1::Sub1 => {
+                let overflows = tags::un_op_overflows(op, ty);
+                let op2 = match op {
+                    Op1::Add1 => Op2::Plus,
+                    _ => Op2::Minus,
+                };
+                // Floats are incremented by the runtime
+                let slow = self.slow_path(op2, &[ty], overflows, false);
+                let (not_num, overflow) = self.slow_labels(&slow);
+                if slow.is_some() {
+                    self.emit_instr(Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Reg(Rax))));
+                }
+                self.check_is_num_or(Rax, ty, &not_num);
+                self.emit_instr(match op {
+                    Op1::Add1 => Instr::Add(BinArgs::ToReg(Rax, 1.repr32())),
+                    _ => Instr::Sub(BinArgs::ToReg(Rax, 1.repr32())),
+                });
+                self.check_overflow(overflows, &overflow);
+                if let Some(slow) = slow {
+                    self.compile_slow_arith(slow, op2, 1.repr32());
+                }
             }
-            Op1::Sub1 => {
-                self.check_is_num(Reg::Rax);
+            // Bignums are boxed
+            Op1::IsNum if self.bignums && ty.may_be_big() => {
+                let tag = self.next_tag();
+                let end_lbl = format!("is_num_end_{tag}");
                 self.emit_instrs([
-                    Instr::Sub(BinArgs::ToReg(Rax, 1.repr32())),
-                    Instr::Jo(OVERFLOW.to_string()),
-                ])
+                    Instr::Test(BinArgs::ToReg(Rax, Arg32::Imm(0b001))),
+                    Instr::Mov(MovArgs::ToReg(Rdx, true.repr64())),
+                    Instr::Jz(end_lbl.clone()),
+                ]);
+                self.compile_is_boxed(|sess| {
+                    sess.emit_instrs([
+                        Instr::And(BinArgs::ToReg(Rcx, Arg32::Imm(0xff))),
+                        Instr::Cmp(BinArgs::ToReg(Rcx, Arg32::Imm(BIGNUM_KIND))),
+                    ])
+                });
+                self.emit_instrs([
+                    Instr::Label(end_lbl),
+                    Instr::Mov(MovArgs::ToReg(Rax, Arg64::Reg(Rdx))),
+                ]);
             }
             Op1::IsNum => {
                 self.emit_instrs([
@@ -533,8 +1001,19 @@ impl Session {
                     Instr::CMov(CMov::Z(Rax, Arg64::Reg(Rcx))),
                     Instr::Test(BinArgs::ToReg(Rdx, Arg32::Imm(0b10))),
                     Instr::CMov(CMov::NZ(Rax, Arg64::Reg(Rcx))),
+                    Instr::Test(BinArgs::ToReg(Rdx, Arg32::Imm(0b100))),
+                    Instr::CMov(CMov::NZ(Rax, Arg64::Reg(Rcx))),
                 ]);
             }
+            Op1::IsString => self.compile_is_boxed(|sess| {
+                sess.emit_instrs([
+                    Instr::And(BinArgs::ToReg(Rcx, Arg32::Imm(0xff))),
+                    Instr::Cmp(BinArgs::ToReg(Rcx, Arg32::Imm(STRING_KIND))),
+                ])
+            }),
+            Op1::IsFloat => self.compile_is_boxed(|sess| {
+                sess.emit_instr(Instr::Cmp(BinArgs::ToReg(Rcx, Arg32::Imm(FLOAT_KIND))))
+            }),
             Op1::Print => self.emit_instrs([
                 Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Reg(Rax))),
                 Instr::Call("snek_print".to_string()),
@@ -550,66 +1029,105 @@ impl Session {
         self.emit_instr(Instr::Mov(MovArgs::ToReg(Rax, Arg64::Mem(mem))));
         self.memset(cx.si, 1, Reg32::Imm(MEM_SET_VAL));
 
+        let (t1, t2) = (self.facts.ty(e1), self.facts.ty(e2));
+        let overflows = tags::bin_op_overflows(op, t1, t2);
+        let slow = self.slow_path(op, &[t1, t2], overflows, true);
+        let (not_num, overflow) = self.slow_labels(&slow);
+        if slow.is_some() {
+            self.emit_instr(Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Reg(Rax))));
+        }
         match op {
             Op2::Plus
             | Op2::Minus
             | Op2::Times
             | Op2::Divide
+            | Op2::Remainder
+            | Op2::Modulo
             | Op2::Greater
             | Op2::GreaterEqual
             | Op2::Less
             | Op2::LessEqual => {
-                self.check_is_num(Rax);
-                self.check_is_num(Rcx);
+                self.check_is_num_or(Rax, t1, &not_num);
+                self.check_is_num_or(Rcx, t2, &not_num);
             }
             Op2::Equal => {
-                let tag = self.next_tag();
-                let check_eq_finish_lbl = format!("check_eq_finish_{tag}");
-                // if (%rax ^ %rcx) & 0b11 == 0 {
-                //     jmp check_eq_finish
-                // } else if (%rax | %rcx) & 0b01 != 0 {
-                //     jmp invalid_arg
-                // }
-                self.emit_instrs([
-                    Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(Rax))),
-                    Instr::Xor(BinArgs::ToReg(Rdx, Arg32::Reg(Rcx))),
-                    Instr::Test(BinArgs::ToReg(Rdx, Arg32::Imm(0b11))),
-                    Instr::Jz(check_eq_finish_lbl.to_string()),
-                    Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(Rax))),
-                    Instr::Or(BinArgs::ToReg(Rdx, Arg32::Reg(Rcx))),
-                    Instr::Test(BinArgs::ToReg(Rdx, Arg32::Imm(0b01))),
-                    Instr::Jnz(INVALID_ARG.to_string()),
-                    Instr::Label(check_eq_finish_lbl.to_string()),
-                ]);
+                // Bignums and floats are equal if they have the same value
+                if slow.is_some() {
+                    for (reg, ty) in [(Rax, t1), (Rcx, t2)] {
+                        if self.may_be_boxed_num(ty) {
+                            self.emit_instrs([
+                                Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(reg))),
+                                Instr::And(BinArgs::ToReg(Rdx, Arg32::Imm(0b111))),
+                                Instr::Cmp(BinArgs::ToReg(Rdx, Arg32::Imm(BOX_TAG))),
+                                Instr::Je(not_num.clone()),
+                            ]);
+                        }
+                    }
+                }
+                if !self.elide_check(t1.comparable(&t2)) {
+                    let tag = self.next_tag();
+                    let check_eq_finish_lbl = format!("check_eq_finish_{tag}");
+                    // if (%rax ^ %rcx) & 0b11 == 0 {
+                    //     jmp check_eq_finish
+                    // } else if (%rax | %rcx) & 0b01 != 0 {
+                    //     jmp invalid_arg
+                    // }
+                    self.emit_instrs([
+                        Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(Rax))),
+                        Instr::Xor(BinArgs::ToReg(Rdx, Arg32::Reg(Rcx))),
+                        Instr::Test(BinArgs::ToReg(Rdx, Arg32::Imm(0b11))),
+                        Instr::Jz(check_eq_finish_lbl.to_string()),
+                        Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(Rax))),
+                        Instr::Or(BinArgs::ToReg(Rdx, Arg32::Reg(Rcx))),
+                        Instr::Test(BinArgs::ToReg(Rdx, Arg32::Imm(0b01))),
+                        Instr::Jnz(INVALID_ARG.to_string()),
+                        Instr::Label(check_eq_finish_lbl.to_string()),
+                    ]);
+                }
             }
         }
 
         match op {
             Op2::Plus => {
-                self.emit_instrs([
-                    Instr::Add(BinArgs::ToReg(Rax, Arg32::Reg(Rcx))),
-                    Instr::Jo(OVERFLOW.to_string()),
-                ]);
+                self.emit_instr(Instr::Add(BinArgs::ToReg(Rax, Arg32::Reg(Rcx))));
+                self.check_overflow(overflows, &overflow);
             }
             Op2::Minus => {
-                self.emit_instrs([
-                    Instr::Sub(BinArgs::ToReg(Rax, Arg32::Reg(Rcx))),
-                    Instr::Jo(OVERFLOW.to_string()),
-                ]);
+                self.emit_instr(Instr::Sub(BinArgs::ToReg(Rax, Arg32::Reg(Rcx))));
+                self.check_overflow(overflows, &overflow);
             }
             Op2::Times => {
                 self.emit_instrs([
                     Instr::Sar(BinArgs::ToReg(Rax, Arg32::Imm(1))),
                     Instr::IMul(BinArgs::ToReg(Rax, Arg32::Reg(Rcx))),
-                    Instr::Jo(OVERFLOW.to_string()),
                 ]);
+                self.check_overflow(overflows, &overflow);
             }
+            // Both operands are shifted by the tag so the quotient must be shifted back, but the
+            // remainder (in %rdx) is already tagged.
             Op2::Divide => {
+                self.compile_idiv(t2);
+                self.emit_instr(Instr::Sal(BinArgs::ToReg(Rax, Arg32::Imm(1))));
+                self.check_overflow(overflows, &overflow);
+            }
+            Op2::Remainder => {
+                self.compile_idiv(t2);
+                self.emit_instr(Instr::Mov(MovArgs::ToReg(Rax, Arg64::Reg(Rdx))));
+            }
+            Op2::Modulo => {
+                let tag = self.next_tag();
+                let end_lbl = format!("modulo_end_{tag}");
+                self.compile_idiv(t2);
+                // A non-zero remainder with a sign different from the divisor's is adjusted by
+                // adding the divisor
                 self.emit_instrs([
-                    Instr::Cqo,
-                    Instr::IDiv(Rcx),
-                    Instr::Sal(BinArgs::ToReg(Rax, Arg32::Imm(1))),
-                    Instr::Jo(OVERFLOW.to_string()),
+                    Instr::Mov(MovArgs::ToReg(Rax, Arg64::Reg(Rdx))),
+                    Instr::Test(BinArgs::ToReg(Rax, Arg32::Reg(Rax))),
+                    Instr::Jz(end_lbl.clone()),
+                    Instr::Xor(BinArgs::ToReg(Rdx, Arg32::Reg(Rcx))),
+                    Instr::Jns(end_lbl.clone()),
+                    Instr::Add(BinArgs::ToReg(Rax, Arg32::Reg(Rcx))),
+                    Instr::Label(end_lbl),
                 ]);
             }
             Op2::Equal => self.compile_cmp(CMov::E),
@@ -618,9 +1136,103 @@ impl Session {
             Op2::Less => self.compile_cmp(CMov::L),
             Op2::LessEqual => self.compile_cmp(CMov::LE),
         }
+        if let Some(slow) = slow {
+            self.compile_slow_arith(slow, op, Arg32::Reg(Rcx));
+        }
         self.move_to(dst, Arg32::Reg(Rax));
     }
 
+    /// Evaluates `body` with a handler record in the next locals, and `handler` with `x` bound to
+    /// the raised value if the runtime resumes the program at the handler.
+    fn compile_try(&mut self, cx: &Ctxt, dst: Loc, body: &Expr, x: Symbol, handler: &Expr) {
+        let tag = self.next_tag();
+        let catch_lbl = format!("try_catch_{tag}");
+        let end_lbl = format!("try_end_{tag}");
+        let record = mref![Rbp - %(8 * (cx.si + TRY_RECORD))];
+        let field = |i: u32| mref![Rbp - %(8 * (cx.si + TRY_RECORD - i))];
+        self.emit_instrs([
+            Instr::LeaRel(Rdx, HANDLER.to_string(), 0),
+            Instr::Mov(MovArgs::ToReg(Rax, Arg64::Mem(mref![Rdx + 0]))),
+            Instr::Mov(MovArgs::ToMem(field(0), Reg32::Reg(Rax))),
+            Instr::LeaRel(Rax, catch_lbl.clone(), 0),
+            Instr::Mov(MovArgs::ToMem(field(1), Reg32::Reg(Rax))),
+        ]);
+        for (i, reg) in [Rsp, Rbp, STACK_BASE, HEAP_END].into_iter().enumerate() {
+            self.emit_instr(Instr::Mov(MovArgs::ToMem(
+                field(i as u32 + 2),
+                Reg32::Reg(reg),
+            )));
+        }
+        self.emit_instrs([
+            Instr::Lea(Rax, record),
+            Instr::Mov(MovArgs::ToMem(mref![Rdx + 0], Reg32::Reg(Rax))),
+        ]);
+
+        let mut bodycx = cx.clone();
+        bodycx.si += TRY_RECORD;
+        bodycx.tries.push_back(record);
+        self.compile_expr(&bodycx, Loc::Reg(Rax), body);
+        self.leave_tries(&bodycx, cx.tries.len());
+        self.memset(cx.si, TRY_RECORD, Reg32::Imm(MEM_SET_VAL));
+        self.emit_instr(Instr::Jmp(end_lbl.clone()));
+
+        // The runtime restored the registers and the previous handler, and put the value in %rax
+        self.emit_instr(Instr::Label(catch_lbl));
+        self.memset(cx.si, TRY_RECORD, Reg32::Imm(MEM_SET_VAL));
+        let (nextcx, mem) = cx.next_local();
+        self.emit_instr(Instr::Mov(MovArgs::ToMem(mem, Reg32::Reg(Rax))));
+        self.compile_expr(&nextcx.add_binding(x, mem), Loc::Reg(Rax), handler);
+        self.memset(cx.si, 1, Reg32::Imm(MEM_SET_VAL));
+        self.emit_instr(Instr::Label(end_lbl));
+        self.move_to(dst, Arg64::Reg(Rax));
+    }
+
+    /// Reinstates the handler that was current outside the `try`s of `cx` after the first
+    /// `tries`, when leaving them without raising. Uses `%rcx` and `%rdx`.
+    fn leave_tries(&mut self, cx: &Ctxt, tries: usize) {
+        if let Some(record) = cx.tries.get(tries) {
+            self.emit_instrs([
+                Instr::Mov(MovArgs::ToReg(Rcx, Arg64::Mem(*record))),
+                Instr::LeaRel(Rdx, HANDLER.to_string(), 0),
+                Instr::Mov(MovArgs::ToMem(mref![Rdx + 0], Reg32::Reg(Rcx))),
+            ]);
+        }
+    }
+
+    /// Divides `%rax` by `%rcx` (of type `divisor`) leaving the quotient in `%rax` and the remainder
+    /// in `%rdx`.
+    fn compile_idiv(&mut self, divisor: Ty) {
+        if !self.elide_check(!divisor.may_be_zero()) {
+            self.emit_instrs([
+                Instr::Test(BinArgs::ToReg(Rcx, Arg32::Reg(Rcx))),
+                Instr::Jz(DIVIDE_BY_ZERO.to_string()),
+            ]);
+        }
+        self.emit_instrs([Instr::Cqo, Instr::IDiv(Rcx)]);
+    }
+
+    /// Replaces the value in `%rax` with whether it is a boxed object whose header (loaded in
+    /// `%rcx`) is accepted by `cmp_header`, which must set the zero flag when it is.
+    fn compile_is_boxed(&mut self, cmp_header: impl FnOnce(&mut Session)) {
+        let tag = self.next_tag();
+        let end_lbl = format!("is_boxed_end_{tag}");
+        self.emit_instrs([
+            Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(Rax))),
+            Instr::And(BinArgs::ToReg(Rdx, Arg32::Imm(0b111))),
+            Instr::Cmp(BinArgs::ToReg(Rdx, Arg32::Imm(BOX_TAG))),
+            Instr::Mov(MovArgs::ToReg(Rdx, false.repr64())),
+            Instr::Jne(end_lbl.clone()),
+            Instr::Mov(MovArgs::ToReg(Rcx, Arg64::Mem(mref![Rax + %(BOX_HEADER)]))),
+        ]);
+        cmp_header(self);
+        self.emit_instrs([
+            Instr::Jne(end_lbl.clone()),
+            Instr::Mov(MovArgs::ToReg(Rdx, true.repr64())),
+            Instr::Label(end_lbl),
+            Instr::Mov(MovArgs::ToReg(Rax, Arg64::Reg(Rdx))),
+        ]);
+    }
+
     fn compile_cmp(&mut self, cmp: impl FnOnce(Reg, Arg64) -> CMov) {
         self.emit_instrs([
             Instr::Cmp(BinArgs::ToReg(Rax, Arg32::Reg(Rcx))),
@@ -663,29 +1275,286 @@ impl Session {
         }
     }
 
-    fn check_is_num(&mut self, reg: Reg) {
+    fn check_is_num(&mut self, reg: Reg, ty: Ty) {
+        self.check_is_num_or(reg, ty, INVALID_ARG)
+    }
+
+    /// Checks `reg` holds a tagged number, jumping to `fail` otherwise.
+    fn check_is_num_or(&mut self, reg: Reg, ty: Ty, fail: &str) {
+        if self.elide_check(ty.is_num()) {
+            return;
+        }
         self.emit_instrs([
             Instr::Test(BinArgs::ToReg(reg, Arg32::Imm(0b001))),
-            Instr::Jnz(INVALID_ARG.to_string()),
+            Instr::Jnz(fail.to_string()),
         ]);
     }
 
-    fn check_is_vec(&mut self, reg: Reg) {
+    /// Converts the number or boolean (of type `ty`) in `%rax` to a C integer, booleans being 0 or
+    /// 1. Uses `%r10` and `%r11`, which aren't C arguments.
+    fn untag_c_arg(&mut self, ty: Ty) {
+        if self.elide_check(ty.is_num()) {
+            self.emit_instr(Instr::Sar(BinArgs::ToReg(Rax, Arg32::Imm(1))));
+            return;
+        }
+        let done = format!("c_int_{}", self.next_tag());
         self.emit_instrs([
-            Instr::Test(BinArgs::ToReg(reg, Arg32::Imm(0b001))),
-            Instr::Jz(INVALID_ARG.to_string()), // jump if is num
-            Instr::Test(BinArgs::ToReg(reg, Arg32::Imm(0b010))),
-            Instr::Jnz(INVALID_ARG.to_string()), // jump if is bool
+            Instr::Mov(MovArgs::ToReg(R11, Arg64::Reg(Rax))),
+            Instr::Sar(BinArgs::ToReg(Rax, Arg32::Imm(1))),
+            Instr::Test(BinArgs::ToReg(R11, Arg32::Imm(0b001))),
+            Instr::Jz(done.clone()),
+            // Setting the bit that tells true from false gives true for booleans only
+            Instr::Mov(MovArgs::ToReg(R10, Arg64::Reg(R11))),
+            Instr::Or(BinArgs::ToReg(R10, Arg32::Imm(0b100))),
+            Instr::Cmp(BinArgs::ToReg(R10, true.repr32())),
+            Instr::Jne(INVALID_ARG.to_string()),
+            Instr::Shr(BinArgs::ToReg(R11, Arg32::Imm(2))),
+            Instr::Mov(MovArgs::ToReg(Rax, Arg64::Reg(R11))),
+            Instr::Label(done),
+        ]);
+    }
+
+    /// Converts the boolean (of type `ty`) in `%rax` to a C `bool`. Uses `%r10`.
+    fn untag_c_bool(&mut self, ty: Ty) {
+        if !self.elide_check(ty.is_bool()) {
+            self.emit_instrs([
+                Instr::Mov(MovArgs::ToReg(R10, Arg64::Reg(Rax))),
+                Instr::Or(BinArgs::ToReg(R10, Arg32::Imm(0b100))),
+                Instr::Cmp(BinArgs::ToReg(R10, true.repr32())),
+                Instr::Jne(INVALID_ARG.to_string()),
+            ]);
+        }
+        self.emit_instr(Instr::Shr(BinArgs::ToReg(Rax, Arg32::Imm(2))));
+    }
+
+    /// Checks `reg` holds a vector other than nil.
+    fn check_is_vec(&mut self, reg: Reg, ty: Ty) {
+        if !self.elide_check(ty.is_vec_or_nil()) {
+            self.emit_instrs([
+                Instr::Test(BinArgs::ToReg(reg, Arg32::Imm(0b001))),
+                Instr::Jz(INVALID_ARG.to_string()), // jump if is num
+                Instr::Test(BinArgs::ToReg(reg, Arg32::Imm(0b010))),
+                Instr::Jnz(INVALID_ARG.to_string()), // jump if is bool
+                Instr::Test(BinArgs::ToReg(reg, Arg32::Imm(0b100))),
+                Instr::Jnz(INVALID_ARG.to_string()), // jump if is another heap object
+            ]);
+        }
+        if !self.elide_check(ty.is_vec()) {
+            self.emit_instrs([
+                Instr::Cmp(BinArgs::ToReg(reg, Arg32::Imm(NIL))),
+                Instr::Jz(INVALID_ARG.to_string()), // jump if exactly equal to 1
+            ]);
+        }
+    }
+
+    /// Checks `%rax` holds an instance of the struct `name`. Uses `%rcx` and `%rdx`.
+    fn check_is_struct(&mut self, name: Symbol) {
+        let error_lbl = format!("{}_error", struct_label(name));
+        self.emit_instrs([
+            Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(Rax))),
+            Instr::And(BinArgs::ToReg(Rdx, Arg32::Imm(0b111))),
+            Instr::Cmp(BinArgs::ToReg(Rdx, Arg32::Imm(BOX_TAG))),
+            Instr::Jne(error_lbl.clone()),
+        ]);
+        self.struct_header(Rcx, name);
+        self.emit_instrs([
+            Instr::Cmp(BinArgs::ToReg(Rcx, Arg32::Mem(mref![Rax + %(BOX_HEADER)]))),
+            Instr::Jne(error_lbl),
+        ]);
+    }
+
+    /// Loads the header of instances of the struct `name` in `reg`.
+    fn struct_header(&mut self, reg: Reg, name: Symbol) {
+        self.emit_instrs([
+            Instr::LeaRel(reg, struct_label(name), 0),
+            Instr::Shl(BinArgs::ToReg(reg, Arg32::Imm(8))),
+            Instr::Or(BinArgs::ToReg(reg, Arg32::Imm(STRUCT_KIND))),
         ]);
     }
 
-    fn check_is_not_nil(&mut self, reg: Reg) {
+    /// Ensures there's space for `words` words in the heap, calling the GC if needed, and writes
+    /// the GC word of the new object at `HEAP_PTR`.
+    fn alloc(&mut self, words: i32) {
+        let tag = self.next_tag();
+        let alloc_finish_lbl = format!("alloc_finish_{tag}");
         self.emit_instrs([
-            Instr::Cmp(BinArgs::ToReg(reg, Arg32::Imm(NIL))),
-            Instr::Jz(INVALID_ARG.to_string()), // jump if exactly equal to 1
+            Instr::Lea(Rax, mref![HEAP_PTR + %(8 * words)]),
+            Instr::Cmp(BinArgs::ToReg(Rax, Arg32::Reg(HEAP_END))),
+            Instr::Jle(alloc_finish_lbl.clone()),
+            Instr::Mov(MovArgs::ToReg(Rdi, Arg64::Imm(words as i64))),
+            Instr::Mov(MovArgs::ToReg(Rsi, Arg64::Reg(HEAP_PTR))),
+            Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(STACK_BASE))),
+            Instr::Mov(MovArgs::ToReg(Rcx, Arg64::Reg(Rbp))),
+            Instr::Mov(MovArgs::ToReg(R8, Arg64::Reg(Rsp))),
+            Instr::Call("snek_try_gc".to_string()),
+            Instr::Mov(MovArgs::ToReg(HEAP_PTR, Arg64::Reg(Rax))),
+            Instr::Label(alloc_finish_lbl),
+            Instr::Mov(MovArgs::ToMem(mref!(HEAP_PTR + 0), Reg32::Imm(GC_WORD_VAL))),
         ]);
     }
 
+    /// Jumps to `fail` if the last arithmetic operation overflowed.
+    fn check_overflow(&mut self, overflows: bool, fail: &str) {
+        if !self.elide_check(!overflows) {
+            self.emit_instr(Instr::Jo(fail.to_string()));
+        }
+    }
+
+    /// Whether a value of type `ty` may be a number other than a tagged number.
+    fn may_be_boxed_num(&self, ty: Ty) -> bool {
+        ty.may_be_float() || (self.bignums && ty.may_be_big())
+    }
+
+    /// The slow path of the arithmetic operation `op` on operands of types `tys`, if it can be
+    /// reached. With `float_code`, operands that may be floats are handled inline when `op` has an
+    /// SSE counterpart.
+    fn slow_path(
+        &mut self,
+        op: Op2,
+        tys: &[Ty],
+        overflows: bool,
+        float_code: bool,
+    ) -> Option<SlowPath> {
+        let boxed = tys.iter().any(|ty| self.may_be_boxed_num(*ty));
+        if !(boxed || self.bignums && overflows) {
+            return None;
+        }
+        let tag = self.next_tag();
+        let runtime = format!("arith_runtime_{tag}");
+        let has_sse = matches!(
+            op,
+            Op2::Plus
+                | Op2::Minus
+                | Op2::Times
+                | Op2::Divide
+                | Op2::Greater
+                | Op2::GreaterEqual
+                | Op2::Less
+                | Op2::LessEqual
+        );
+        let float = (float_code && has_sse && tys.iter().any(Ty::may_be_float))
+            .then(|| format!("arith_float_{tag}"));
+        Some(SlowPath {
+            not_num: float.clone().unwrap_or_else(|| runtime.clone()),
+            float,
+            runtime,
+        })
+    }
+
+    /// Where the fast path of an arithmetic operation goes when its operands aren't tagged numbers,
+    /// and when it overflows.
+    fn slow_labels(&self, slow: &Option<SlowPath>) -> (String, String) {
+        match slow {
+            Some(slow) if self.bignums => (slow.not_num.clone(), slow.runtime.clone()),
+            Some(slow) => (slow.not_num.clone(), OVERFLOW.to_string()),
+            None => (INVALID_ARG.to_string(), OVERFLOW.to_string()),
+        }
+    }
+
+    /// Emits the slow path `slow` of the arithmetic operation `op` on `%rsi` (the first operand as
+    /// it was before the fast path) and `rhs`, leaving the result in `%rax` like the fast path.
+    fn compile_slow_arith(&mut self, slow: SlowPath, op: Op2, rhs: Arg32) {
+        let tag = self.next_tag();
+        let end_lbl = format!("arith_end_{tag}");
+        self.emit_instr(Instr::Jmp(end_lbl.clone()));
+        if let Some(float) = slow.float {
+            self.emit_instr(Instr::Label(float));
+            self.compile_float_arith(op, &slow.runtime);
+            self.emit_instr(Instr::Jmp(end_lbl.clone()));
+        }
+        self.emit_instr(Instr::Label(slow.runtime));
+        self.call_runtime(
+            "snek_arith",
+            [Arg32::Imm(arith_code(op) << 1), Arg32::Reg(Rsi), rhs],
+        );
+        self.emit_instr(Instr::Label(end_lbl));
+    }
+
+    /// Computes `op` on `%rsi` and `%rcx` as floats, or jumps to `fail` if they aren't both floats
+    /// or tagged numbers.
+    fn compile_float_arith(&mut self, op: Op2, fail: &str) {
+        // `ucomisd` sets the flags like an unsigned comparison, and "above" is false for NaN
+        let true_val = Arg64::Reg(Rcx);
+        let cmp = match op {
+            Op2::Greater => Some((Xmm0, Xmm1, CMov::A(Rax, true_val))),
+            Op2::GreaterEqual => Some((Xmm0, Xmm1, CMov::AE(Rax, true_val))),
+            Op2::Less => Some((Xmm1, Xmm0, CMov::A(Rax, true_val))),
+            Op2::LessEqual => Some((Xmm1, Xmm0, CMov::AE(Rax, true_val))),
+            _ => None,
+        };
+        if let Some((x1, x2, cmov)) = cmp {
+            self.load_float(Xmm0, Rsi, fail);
+            self.load_float(Xmm1, Rcx, fail);
+            self.emit_instrs([
+                Instr::Ucomisd(x1, x2),
+                Instr::Mov(MovArgs::ToReg(Rax, false.repr64())),
+                Instr::Mov(MovArgs::ToReg(Rcx, true.repr64())),
+                Instr::CMov(cmov),
+            ]);
+            return;
+        }
+        let sse_op = match op {
+            Op2::Plus => SseOp::Addsd,
+            Op2::Minus => SseOp::Subsd,
+            Op2::Times => SseOp::Mulsd,
+            _ => SseOp::Divsd,
+        };
+        // The result is allocated first because the GC doesn't preserve the float registers. The
+        // operands are pushed so the GC finds (and moves) them.
+        self.emit_instrs([Instr::Push(Arg32::Reg(Rsi)), Instr::Push(Arg32::Reg(Rcx))]);
+        self.alloc(4);
+        self.emit_instrs([Instr::Pop(Loc::Reg(Rcx)), Instr::Pop(Loc::Reg(Rsi))]);
+        self.load_float(Xmm0, Rsi, fail);
+        self.load_float(Xmm1, Rcx, fail);
+        self.emit_instrs([
+            Instr::Sse(sse_op, Xmm0, Xmm1),
+            Instr::Mov(MovArgs::ToMem(mref!(HEAP_PTR + 8), Reg32::Imm(2))),
+            Instr::Mov(MovArgs::ToMem(mref!(HEAP_PTR + 16), Reg32::Imm(FLOAT_KIND))),
+            Instr::MovsdStore(mref!(HEAP_PTR + 24), Xmm0),
+            Instr::Lea(Rax, mref!(HEAP_PTR + %(BOX_TAG))),
+            Instr::Lea(HEAP_PTR, mref!(HEAP_PTR + 32)),
+        ]);
+    }
+
+    /// Loads the float or tagged number in `reg` as a float in `xmm`, or jumps to `fail` if it is
+    /// neither. Uses `%rdx`.
+    fn load_float(&mut self, xmm: Xmm, reg: Reg, fail: &str) {
+        let tag = self.next_tag();
+        let int_lbl = format!("load_float_int_{tag}");
+        let end_lbl = format!("load_float_end_{tag}");
+        self.emit_instrs([
+            Instr::Test(BinArgs::ToReg(reg, Arg32::Imm(0b001))),
+            Instr::Jz(int_lbl.clone()),
+            Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(reg))),
+            Instr::And(BinArgs::ToReg(Rdx, Arg32::Imm(0b111))),
+            Instr::Cmp(BinArgs::ToReg(Rdx, Arg32::Imm(BOX_TAG))),
+            Instr::Jne(fail.to_string()),
+            Instr::Cmp(BinArgs::ToMem(
+                mref!(reg + %(BOX_HEADER)),
+                Reg32::Imm(FLOAT_KIND),
+            )),
+            Instr::Jne(fail.to_string()),
+            Instr::MovsdLoad(xmm, mref!(reg + %(BOX_HEADER + 8))),
+            Instr::Jmp(end_lbl.clone()),
+            Instr::Label(int_lbl),
+            Instr::Mov(MovArgs::ToReg(Rdx, Arg64::Reg(reg))),
+            Instr::Sar(BinArgs::ToReg(Rdx, Arg32::Imm(1))),
+            Instr::Cvtsi2sd(xmm, Rdx),
+            Instr::Label(end_lbl),
+        ]);
+    }
+
+    /// Records whether a runtime check is statically known to succeed, in which case it isn't
+    /// emitted.
+    fn elide_check(&mut self, known: bool) -> bool {
+        if known {
+            self.checks.elided += 1;
+        } else {
+            self.checks.emitted += 1;
+        }
+        known
+    }
+
     fn emit_instrs(&mut self, instrs: impl IntoIterator<Item = Instr>) {
         self.instrs.extend(instrs);
     }
@@ -707,14 +1576,14 @@ fn locals(start: u32, count: u32) -> impl Iterator<Item = MemRef> {
 fn frame_size(locals: u32, calle_saved: &[Reg]) -> u32 {
     // #locals + #callee saved + return address
     let n = locals + calle_saved.len() as u32 + 1;
-    if n % 2 == 0 {
+    if n.is_multiple_of(2) {
         locals
     } else {
         locals + 1
     }
 }
 
-fn depth(e: &Expr) -> u32 {
+pub fn depth(e: &Expr) -> u32 {
     match e {
         Expr::BinOp(_, e1, e2) => depth(e1).max(depth(e2) + 1),
         Expr::Let(bindings, e) => bindings
@@ -726,9 +1595,23 @@ fn depth(e: &Expr) -> u32 {
             .max(depth(e) + bindings.len() as u32),
         Expr::If(e1, e2, e3) => depth(e1).max(depth(e2)).max(depth(e3)),
         Expr::Block(es) => es.iter().map(depth).max().unwrap_or(0),
-        Expr::UnOp(_, e) | Expr::Loop(e) | Expr::Break(e) | Expr::Set(_, e) => depth(e),
+        Expr::UnOp(_, e)
+        | Expr::Loop(_, e)
+        | Expr::Break(_, e)
+        | Expr::Set(_, e)
+        | Expr::VecLen(e)
+        | Expr::NoMatch(e)
+        | Expr::AssertFailed(_, e)
+        | Expr::Raise(e)
+        | Expr::Annot(_, e)
+        | Expr::At(_, e) => depth(e),
+        Expr::Try(body, _, handler) => (depth(body) + TRY_RECORD).max(depth(handler) + 1),
         Expr::MakeVec(size, elem) => depth(size).max(depth(elem) + 1).max(2),
-        Expr::Call(_, es) | Expr::Vec(es) => es
+        Expr::Call(_, es)
+        | Expr::CallExtern(_, es)
+        | Expr::Prim(_, es)
+        | Expr::Vec(es)
+        | Expr::StructNew(_, es) => es
             .iter()
             .enumerate()
             .map(|(i, e)| depth(e) + (i as u32))
@@ -737,15 +1620,20 @@ fn depth(e: &Expr) -> u32 {
             .max(es.len() as u32),
         Expr::VecSet(vec, idx, val) => depth(vec).max(depth(idx) + 1).max(depth(val) + 2).max(2),
         Expr::VecGet(vec, idx) => depth(vec).max(depth(idx) + 1),
+        Expr::StructSet(_, _, e, val) => depth(e).max(depth(val) + 1).max(1),
+        Expr::StructGet(_, _, e) | Expr::StructIs(_, e) => depth(e),
         Expr::PrintStack
         | Expr::PrintHeap
         | Expr::Gc
-        | Expr::VecLen(_)
+        | Expr::Continue(_)
         | Expr::Input
         | Expr::Nil
         | Expr::Var(_)
         | Expr::Number(_)
-        | Expr::Boolean(_) => 0,
+        | Expr::BigNum(_)
+        | Expr::Float(_)
+        | Expr::Boolean(_)
+        | Expr::Str(_) => 0,
     }
 }
 
@@ -781,17 +1669,18 @@ impl Repr32 for bool {
     }
 }
 
-fn fun_arity_map(prg: &Prog) -> Result<HashMap<Symbol, usize>, Symbol> {
+pub fn fun_arity_map(prg: &Prog) -> Result<HashMap<Symbol, usize>, Symbol> {
     let mut map = HashMap::new();
     for fun in &prg.funs {
         if map.insert(fun.name, fun.params.len()).is_some() {
             return Err(fun.name);
         }
     }
+    map.extend(prg.imported_funs.iter().copied());
     Ok(map)
 }
 
-fn check_dup_bindings<'a>(bindings: impl IntoIterator<Item = &'a Symbol>) {
+pub fn check_dup_bindings<'a>(bindings: impl IntoIterator<Item = &'a Symbol>) {
     let mut seen = HashSet::new();
     for name in bindings {
         if !seen.insert(*name) {
@@ -800,34 +1689,95 @@ fn check_dup_bindings<'a>(bindings: impl IntoIterator<Item = &'a Symbol>) {
     }
 }
 
+/// The index of `op` in the operations computed by `snek_arith` in the runtime.
+pub fn arith_code(op: Op2) -> i32 {
+    match op {
+        Op2::Plus => 0,
+        Op2::Minus => 1,
+        Op2::Times => 2,
+        Op2::Divide => 3,
+        Op2::Remainder => 4,
+        Op2::Modulo => 5,
+        Op2::Equal => 6,
+        Op2::Greater => 7,
+        Op2::GreaterEqual => 8,
+        Op2::Less => 9,
+        Op2::LessEqual => 10,
+    }
+}
+
+/// The digits of the decimal number `digits` in base 2^64, least significant first.
+pub fn decimal_to_words(digits: &str) -> Vec<u64> {
+    let mut words: Vec<u64> = vec![];
+    for digit in digits.bytes() {
+        let mut carry = (digit - b'0') as u128;
+        for word in &mut words {
+            let n = *word as u128 * 10 + carry;
+            *word = n as u64;
+            carry = n >> 64;
+        }
+        if carry != 0 {
+            words.push(carry as u64);
+        }
+    }
+    words
+}
+
+pub fn raise_integer_overflow<T>() -> T {
+    panic!("Invalid syntax: integer literal overflow")
+}
+
 fn raise_duplicate_binding(id: Symbol) {
     panic!("duplicate binding {id}");
 }
 
-fn raise_duplicate_function<T>(name: Symbol) -> T {
+pub fn raise_duplicate_function<T>(name: Symbol) -> T {
     panic!("duplicate function name {name}")
 }
 
-fn raise_unbound_identifier<T>(id: Symbol) -> T {
+pub fn raise_unbound_identifier<T>(id: Symbol) -> T {
     panic!("unbound variable identifier {id}")
 }
 
-fn raise_break_outside_loop() {
-    panic!("break outside loop")
+pub fn raise_outside_loop<T>(keyword: &str) -> T {
+    panic!("{keyword} outside loop")
 }
 
-fn raise_input_in_fun<T>() -> T {
-    panic!("cannot use input inside function definition")
+pub fn raise_unknown_label<T>(label: Symbol) -> T {
+    panic!("unknown loop label {label}")
 }
 
-fn raise_undefined_fun(fun: Symbol) {
+pub fn raise_undefined_fun(fun: Symbol) {
     panic!("function {fun} not defined")
 }
 
-fn raise_wrong_number_of_args(fun: Symbol, expected: usize, got: usize) {
+pub fn raise_wrong_number_of_args(fun: Symbol, expected: usize, got: usize) {
     panic!("function {fun} takes {expected} arguments but {got} were supplied")
 }
 
+fn struct_label(name: Symbol) -> String {
+    format!("snek_struct_{}", mangle(name))
+}
+
+/// Offset of a field from a pointer to a struct.
+fn field_offset(field: usize) -> usize {
+    8 * (field + 3) - BOX_TAG as usize
+}
+
+/// The symbol of a C function, which has a leading underscore on macOS.
+fn c_symbol(name: &str) -> String {
+    if cfg!(target_os = "macos") {
+        format!("_{name}")
+    } else {
+        name.to_string()
+    }
+}
+
 fn fun_label(fun: Symbol) -> String {
-    format!("snek_fun_{}", fun.replace("-", "_"))
+    format!("snek_fun_{}", mangle(fun))
+}
+
+/// Makes a valid symbol out of a name, which may be qualified by a module (`lists/append`).
+fn mangle(name: Symbol) -> String {
+    name.replace("-", "_").replace('/', ".")
 }
@@ -0,0 +1,78 @@
+use crate::syntax::{Prog, Symbol, Type};
+
+/// The name of the C function exporting `fun`: its unqualified name with dashes replaced by
+/// underscores.
+pub fn c_name(fun: Symbol) -> String {
+    fun.unqualified().replace('-', "_")
+}
+
+/// The C type of parameters and results annotated with `ty` (`None` for a result without
+/// annotation), or `None` if C can't represent its values. Numbers are passed as 64-bit integers,
+/// which must fit in a tagged number, and booleans as `bool`.
+pub fn c_type(ty: Option<&Type>) -> Option<&'static str> {
+    match ty {
+        None | Some(Type::Any | Type::Num) => Some("int64_t"),
+        Some(Type::Bool) => Some("bool"),
+        Some(_) => None,
+    }
+}
+
+/// The C header of the library `name`, declaring the functions `prg` exports and the runtime
+/// function setting up the heap.
+pub fn header(prg: &Prog, name: &str) -> String {
+    let guard: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let mut decls = String::new();
+    for &export in &prg.exports {
+        let fun = prg.funs.iter().find(|fun| fun.name == export).unwrap();
+        let params: Vec<_> = (fun.params.iter().zip(&fun.param_tys))
+            .map(|(param, ty)| format!("{} {}", c_type(Some(ty)).unwrap(), c_name(*param)))
+            .collect();
+        let params = if params.is_empty() {
+            "void".to_string()
+        } else {
+            params.join(", ")
+        };
+        decls += &format!(
+            "{} {}({params});\n",
+            c_type(fun.ret_ty.as_ref()).unwrap(),
+            c_name(export)
+        );
+    }
+    format!(
+        "/* Generated by forest-flame from {name}.snek */
+#ifndef SNEK_{guard}_H
+#define SNEK_{guard}_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern \"C\" {{
+#endif
+
+/* Sets up the heap of the snek runtime, with room for `heap_size` 8-byte words. It must be called
+   once, before any of the functions below. */
+void snek_init(size_t heap_size);
+
+/* Numbers must fit in 63 bits. A runtime error that the functions don't catch prints a message
+   and exits, as it does in a program. The functions may not be called from different threads, or
+   from the C functions they call. */
+{decls}
+#ifdef __cplusplus
+}}
+#endif
+
+#endif
+"
+    )
+}
@@ -0,0 +1,279 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::syntax::{Expr, FunDecl, Prog, Symbol};
+
+/// Maximum size (in expression nodes) of a function body that will be inlined at a call site.
+const INLINE_BUDGET: usize = 24;
+
+/// Replaces calls to small non-recursive functions with their bodies.
+///
+/// A call `(f a1 .. an)` becomes `(let ((p1' a1) .. (pn' an)) body')` where every variable bound
+/// in `body` (including the parameters) is renamed to a fresh name that can't be written in source
+/// code, so the arguments can't capture or be captured by the callee's bindings. Function
+/// declarations are kept as they are, they're still needed for non-inlined call sites and to report
+/// errors in their bodies.
+pub fn inline(prg: Prog) -> Prog {
+    let Some(mut inliner) = Inliner::new(&prg.funs) else {
+        // Duplicate function names, let the compiler report the error
+        return prg;
+    };
+    let funs = prg
+        .funs
+        .iter()
+        .map(|fun| FunDecl {
+            body: inliner.inline_expr(&fun.body),
+            ..fun.clone()
+        })
+        .collect();
+    let main = prg.main.as_ref().map(|main| inliner.inline_expr(main));
+    Prog { funs, main, ..prg }
+}
+
+struct Inliner<'a> {
+    funs: HashMap<Symbol, &'a FunDecl>,
+    recursive: HashSet<Symbol>,
+    /// Bodies of non-recursive functions after inlining the calls inside them
+    inlined: HashMap<Symbol, Expr>,
+    next_id: u32,
+}
+
+impl<'a> Inliner<'a> {
+    fn new(funs: &'a [FunDecl]) -> Option<Inliner<'a>> {
+        let mut map = HashMap::new();
+        for fun in funs {
+            if map.insert(fun.name, fun).is_some() {
+                return None;
+            }
+        }
+        let recursive = recursive_funs(&map);
+        Some(Inliner {
+            funs: map,
+            recursive,
+            inlined: HashMap::new(),
+            next_id: 0,
+        })
+    }
+
+    fn inline_expr(&mut self, e: &Expr) -> Expr {
+        match e {
+            Expr::Call(fun, args) => {
+                let args: Vec<_> = args.iter().map(|arg| self.inline_expr(arg)).collect();
+                match self.candidate(*fun, args.len()) {
+                    Some((params, body)) => self.substitute(&params, args, &body),
+                    None => Expr::Call(*fun, args),
+                }
+            }
+            _ => e.map_children(|e| self.inline_expr(e)),
+        }
+    }
+
+    /// Returns the parameters and (inlined) body of `fun` if a call to it with `nargs` arguments
+    /// should be inlined. Calls with the wrong number of arguments are left for the compiler to
+    /// report.
+    fn candidate(&mut self, fun: Symbol, nargs: usize) -> Option<(Vec<Symbol>, Expr)> {
+        let decl = *self.funs.get(&fun)?;
+        if self.recursive.contains(&fun) || decl.params.len() != nargs {
+            return None;
+        }
+        if !self.inlined.contains_key(&fun) {
+            let body = self.inline_expr(&decl.body);
+            self.inlined.insert(fun, body);
+        }
+        let body = &self.inlined[&fun];
+        (size(body) <= INLINE_BUDGET).then(|| (decl.params.clone(), body.clone()))
+    }
+
+    fn substitute(&mut self, params: &[Symbol], args: Vec<Expr>, body: &Expr) -> Expr {
+        let mut env = im::HashMap::new();
+        let bindings: Vec<_> = params
+            .iter()
+            .zip(args)
+            .map(|(param, arg)| {
+                let fresh = self.fresh(*param);
+                env.insert(*param, fresh);
+                (fresh, arg)
+            })
+            .collect();
+        let body = self.rename(&env, body);
+        if bindings.is_empty() {
+            body
+        } else {
+            Expr::Let(bindings, Box::new(body))
+        }
+    }
+
+    fn rename(&mut self, env: &im::HashMap<Symbol, Symbol>, e: &Expr) -> Expr {
+        let lookup = |x: &Symbol| *env.get(x).unwrap_or(x);
+        match e {
+            Expr::Var(x) => Expr::Var(lookup(x)),
+            Expr::Set(x, e) => Expr::Set(lookup(x), Box::new(self.rename(env, e))),
+            Expr::Let(bindings, body) => {
+                let mut env = env.clone();
+                let bindings = bindings
+                    .iter()
+                    .map(|(x, rhs)| {
+                        let rhs = self.rename(&env, rhs);
+                        let fresh = self.fresh(*x);
+                        env.insert(*x, fresh);
+                        (fresh, rhs)
+                    })
+                    .collect();
+                Expr::Let(bindings, Box::new(self.rename(&env, body)))
+            }
+            Expr::Try(body, x, handler) => {
+                let body = self.rename(env, body);
+                let fresh = self.fresh(*x);
+                let handler = self.rename(&env.update(*x, fresh), handler);
+                Expr::Try(Box::new(body), fresh, Box::new(handler))
+            }
+            _ => e.map_children(|e| self.rename(env, e)),
+        }
+    }
+
+    fn fresh(&mut self, x: Symbol) -> Symbol {
+        self.next_id += 1;
+        // `#` is not valid in identifiers so the new name can't clash with user variables
+        Symbol::new(format!("{x}#{}", self.next_id))
+    }
+}
+
+/// Functions that can (directly or indirectly) call themselves.
+fn recursive_funs(funs: &HashMap<Symbol, &FunDecl>) -> HashSet<Symbol> {
+    let calls: HashMap<Symbol, HashSet<Symbol>> = funs
+        .iter()
+        .map(|(name, fun)| {
+            let mut callees = HashSet::new();
+            called_funs(&fun.body, &mut callees);
+            (*name, callees)
+        })
+        .collect();
+    funs.keys()
+        .copied()
+        .filter(|fun| {
+            let mut seen = HashSet::new();
+            let mut todo: Vec<_> = calls[fun].iter().copied().collect();
+            while let Some(next) = todo.pop() {
+                if next == *fun {
+                    return true;
+                }
+                if seen.insert(next) {
+                    todo.extend(calls.get(&next).into_iter().flatten().copied());
+                }
+            }
+            false
+        })
+        .collect()
+}
+
+fn called_funs(e: &Expr, out: &mut HashSet<Symbol>) {
+    if let Expr::Call(fun, _) = e {
+        out.insert(*fun);
+    }
+    e.for_each_child(|e| called_funs(e, out));
+}
+
+fn size(e: &Expr) -> usize {
+    let mut n = 1;
+    e.for_each_child(|e| n += size(e));
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::{Op2, Type};
+
+    fn var(x: &str) -> Expr {
+        Expr::Var(Symbol::new(x))
+    }
+
+    fn call(f: &str, args: Vec<Expr>) -> Expr {
+        Expr::Call(Symbol::new(f), args)
+    }
+
+    fn plus(e1: Expr, e2: Expr) -> Expr {
+        Expr::BinOp(Op2::Plus, Box::new(e1), Box::new(e2))
+    }
+
+    fn fun(name: &str, params: &[&str], body: Expr) -> FunDecl {
+        FunDecl {
+            name: Symbol::new(name),
+            params: params.iter().map(Symbol::new).collect(),
+            param_tys: vec![Type::Any; params.len()],
+            ret_ty: None,
+            body,
+        }
+    }
+
+    fn inline_main(funs: Vec<FunDecl>, main: Expr) -> Expr {
+        let prg = Prog {
+            funs,
+            main: Some(main),
+            ..Prog::default()
+        };
+        inline(prg).main.unwrap()
+    }
+
+    #[test]
+    fn replaces_call_with_renamed_body() {
+        // (fun (f x) (let ((y 1)) (+ x y))), called as (f x)
+        let body = Expr::Let(
+            vec![(Symbol::new("y"), Expr::Number(1))],
+            Box::new(plus(var("x"), var("y"))),
+        );
+        let main = inline_main(vec![fun("f", &["x"], body)], call("f", vec![var("x")]));
+        let Expr::Let(params, body) = main else {
+            panic!("call not inlined: {main:?}");
+        };
+        let [(x, Expr::Var(arg))] = &params[..] else {
+            panic!("unexpected parameter bindings: {params:?}");
+        };
+        assert_eq!(*arg, Symbol::new("x"));
+        assert_ne!(*x, Symbol::new("x"));
+        let Expr::Let(locals, body) = *body else {
+            panic!("unexpected body: {body:?}");
+        };
+        let [(y, Expr::Number(1))] = &locals[..] else {
+            panic!("unexpected local bindings: {locals:?}");
+        };
+        assert_ne!(*y, Symbol::new("y"));
+        let Expr::BinOp(Op2::Plus, e1, e2) = *body else {
+            panic!("unexpected body: {body:?}");
+        };
+        assert!(matches!(*e1, Expr::Var(v) if v == *x));
+        assert!(matches!(*e2, Expr::Var(v) if v == *y));
+    }
+
+    #[test]
+    fn keeps_calls_to_large_functions() {
+        let mut body = var("x");
+        while size(&body) <= INLINE_BUDGET {
+            body = plus(body, Expr::Number(1));
+        }
+        let main = inline_main(
+            vec![fun("f", &["x"], body)],
+            call("f", vec![Expr::Number(0)]),
+        );
+        assert!(matches!(main, Expr::Call(..)), "{main:?}");
+    }
+
+    #[test]
+    fn keeps_calls_to_recursive_functions() {
+        let funs = vec![
+            fun("loop", &["x"], call("loop", vec![var("x")])),
+            fun("even", &["x"], call("odd", vec![var("x")])),
+            fun("odd", &["x"], call("even", vec![var("x")])),
+        ];
+        let main = Expr::Block(vec![
+            call("loop", vec![Expr::Number(0)]),
+            call("even", vec![Expr::Number(0)]),
+            call("odd", vec![Expr::Number(0)]),
+        ]);
+        let Expr::Block(es) = inline_main(funs, main) else {
+            panic!("block not preserved");
+        };
+        for e in es {
+            assert!(matches!(e, Expr::Call(..)), "{e:?}");
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    prelude,
+    syntax::{Expr, FunDecl, Symbol, Type},
+};
+
+/// A function defined inside an expression, with the name it was written with. The parser names
+/// it after the function defining it, `outer.helper`, and resolves the calls in its scope to it.
+pub struct LocalFun {
+    pub name: Symbol,
+    pub decl: FunDecl,
+}
+
+/// Turns the local functions defined in `body` (the body of a top-level function with parameters
+/// `params`, or the main expression) into top-level functions.
+///
+/// The variables of the enclosing functions a local function uses become extra parameters, and
+/// calls to it pass them along. Those variables must not be assigned by the local function, and
+/// must be bound only once in the top-level function, so they mean the same at every call site.
+pub fn lift(params: &[Symbol], body: Expr, locals: Vec<LocalFun>) -> (Expr, Vec<FunDecl>) {
+    if locals.is_empty() {
+        return (body, vec![]);
+    }
+    let names: HashSet<Symbol> = locals.iter().map(|f| f.decl.name).collect();
+
+    // The variables used by each local function, directly or through the local functions it calls
+    let mut captured: HashMap<Symbol, HashSet<Symbol>> = HashMap::new();
+    let mut calls: HashMap<Symbol, HashSet<Symbol>> = HashMap::new();
+    // The variables bound by each local function, with repetitions
+    let mut binders: HashMap<Symbol, Vec<Symbol>> = HashMap::new();
+    for f in &locals {
+        let mut vars = HashSet::new();
+        free_vars(
+            &f.decl.body,
+            &f.decl.params.iter().copied().collect(),
+            &mut vars,
+        );
+        let mut called = HashSet::new();
+        called_locals(&f.decl.body, &names, &mut called);
+        let mut bound = f.decl.params.clone();
+        let_binders(&f.decl.body, &mut bound);
+        captured.insert(f.decl.name, vars);
+        calls.insert(f.decl.name, called);
+        binders.insert(f.decl.name, bound);
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for f in &locals {
+            let mut vars = captured[&f.decl.name].clone();
+            for g in &calls[&f.decl.name] {
+                vars.extend(
+                    captured[g]
+                        .iter()
+                        .filter(|x| !binders[&f.decl.name].contains(x)),
+                );
+            }
+            if vars.len() > captured[&f.decl.name].len() {
+                captured.insert(f.decl.name, vars);
+                changed = true;
+            }
+        }
+    }
+
+    // Variables bound more than once could mean something else where the function is called
+    let mut bound = params.to_vec();
+    let_binders(&body, &mut bound);
+    let mut bindings: HashMap<Symbol, usize> = HashMap::new();
+    for x in bound.iter().chain(binders.values().flatten()) {
+        *bindings.entry(*x).or_default() += 1;
+    }
+    let mut extra_params = HashMap::new();
+    for f in &locals {
+        let mut vars: Vec<_> = captured[&f.decl.name].iter().copied().collect();
+        vars.sort_by_key(|x| x.to_string());
+        for x in &vars {
+            if bindings.get(x).copied().unwrap_or(0) > 1 {
+                syntax_error(format!(
+                    "local function {} uses variable {x}, which is bound more than once in the enclosing function",
+                    f.name
+                ))
+            }
+            if f.decl.body.assigns(*x) {
+                syntax_error(format!(
+                    "local function {} can't assign variable {x} of the enclosing function",
+                    f.name
+                ))
+            }
+        }
+        extra_params.insert(f.decl.name, vars);
+    }
+
+    let body = pass_captured(&body, &extra_params);
+    let funs = locals
+        .into_iter()
+        .map(|f| {
+            let extra = &extra_params[&f.decl.name];
+            let mut params = f.decl.params;
+            let mut param_tys = f.decl.param_tys;
+            params.extend(extra);
+            param_tys.extend(extra.iter().map(|_| Type::Any));
+            FunDecl {
+                params,
+                param_tys,
+                body: pass_captured(&f.decl.body, &extra_params),
+                ..f.decl
+            }
+        })
+        .collect();
+    (body, funs)
+}
+
+/// Whether `fun` was lifted from a local definition. Those functions are private to the module.
+pub fn is_local(fun: Symbol) -> bool {
+    !prelude::is_prelude(fun) && fun.unqualified().contains('.')
+}
+
+/// Adds the variables used by local functions to the arguments of the calls to them.
+fn pass_captured(e: &Expr, extra_params: &HashMap<Symbol, Vec<Symbol>>) -> Expr {
+    match e {
+        Expr::Call(fun, args) if extra_params.contains_key(fun) => {
+            let mut args: Vec<_> = args
+                .iter()
+                .map(|arg| pass_captured(arg, extra_params))
+                .collect();
+            args.extend(extra_params[fun].iter().map(|x| Expr::Var(*x)));
+            Expr::Call(*fun, args)
+        }
+        _ => e.map_children(|e| pass_captured(e, extra_params)),
+    }
+}
+
+/// The variables used in `e` that aren't in `bound`.
+fn free_vars(e: &Expr, bound: &HashSet<Symbol>, out: &mut HashSet<Symbol>) {
+    match e {
+        Expr::Var(x) | Expr::Set(x, _) if !bound.contains(x) => {
+            out.insert(*x);
+            e.for_each_child(|e| free_vars(e, bound, out));
+        }
+        // Each binding is in scope in the next ones
+        Expr::Let(bindings, body) => {
+            let mut inner = bound.clone();
+            for (x, rhs) in bindings {
+                free_vars(rhs, &inner, out);
+                inner.insert(*x);
+            }
+            free_vars(body, &inner, out);
+        }
+        Expr::Try(body, x, handler) => {
+            free_vars(body, bound, out);
+            let mut inner = bound.clone();
+            inner.insert(*x);
+            free_vars(handler, &inner, out);
+        }
+        _ => e.for_each_child(|e| free_vars(e, bound, out)),
+    }
+}
+
+fn let_binders(e: &Expr, out: &mut Vec<Symbol>) {
+    match e {
+        Expr::Let(bindings, _) => out.extend(bindings.iter().map(|(x, _)| *x)),
+        Expr::Try(_, x, _) => out.push(*x),
+        _ => {}
+    }
+    e.for_each_child(|e| let_binders(e, out));
+}
+
+fn called_locals(e: &Expr, names: &HashSet<Symbol>, out: &mut HashSet<Symbol>) {
+    if let Expr::Call(fun, _) = e {
+        if names.contains(fun) {
+            out.insert(*fun);
+        }
+    }
+    e.for_each_child(|e| called_locals(e, names, out));
+}
+
+fn syntax_error<T>(note: impl ToString) -> T {
+    panic!("Invalid syntax: {}", note.to_string())
+}
@@ -0,0 +1,565 @@
+use std::collections::HashMap;
+
+use crate::{
+    parser,
+    sexp::{Atom::*, Pos, Sexp},
+};
+
+/// Maximum number of nested expansions, to report macros that expand forever.
+const MAX_DEPTH: usize = 256;
+
+/// Expands the uses of the macros defined in a module, and removes their definitions.
+///
+/// `(define-syntax name (syntax-rules (literal*) (pattern template)+))` defines a macro: a use
+/// `(name ..)` is replaced by the template of the first rule whose pattern matches it. In patterns,
+/// `_` matches anything, literals match themselves, other symbols are pattern variables and `p ...`
+/// matches any number of `p`. In templates, pattern variables are replaced by what they matched and
+/// `t ...` repeats `t` for each match of the pattern variables inside it.
+///
+/// Expansion is hygienic. The symbols introduced by a template are marked with the expansion they
+/// come from: variables they bind are renamed (marked names can't be written in source code, so
+/// they can't capture or be captured by variables of the macro use), and the other ones refer to
+/// what they mean where the macro is defined. Similarly, a `break` or `continue` introduced by a
+/// template targets a loop introduced by the same template if there is one, and one written by the
+/// user never targets an unlabelled loop introduced by a template.
+pub fn expand(e: &Sexp) -> Sexp {
+    let Sexp::List(forms, pos) = e else {
+        return e.clone();
+    };
+    let (defs, forms): (Vec<_>, Vec<_>) = forms.iter().partition(|e| is_form(e, "define-syntax"));
+    let mut expander = Expander {
+        macros: HashMap::new(),
+        next_mark: 0,
+        next_label: 0,
+        bound: vec![],
+        loops: vec![],
+        depth: 0,
+    };
+    for def in defs {
+        let (name, m) = parse_macro(def);
+        if expander.macros.insert(name.clone(), m).is_some() {
+            syntax_error(format!("duplicate definition of macro {name}"))
+        }
+    }
+    let forms = forms.into_iter().map(|e| expander.top_level(e)).collect();
+    Sexp::List(forms, *pos)
+}
+
+struct Macro {
+    literals: Vec<String>,
+    rules: Vec<(Sexp, Sexp)>,
+}
+
+/// What a pattern variable matched, nested in one `Vec` per ellipsis following it.
+#[derive(Debug, Clone)]
+enum Binding {
+    One(Sexp),
+    Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+struct Loop {
+    /// The expansion introducing the loop, `None` if it was written by the user
+    mark: Option<u32>,
+    label: String,
+    /// Whether the loop has a label, written by the user or needed by a `break` or `continue`
+    /// targeting it from a nested loop
+    labelled: bool,
+}
+
+struct Expander {
+    macros: HashMap<String, Macro>,
+    next_mark: u32,
+    next_label: u32,
+    /// Variables bound by templates in scope, by marked name
+    bound: Vec<String>,
+    loops: Vec<Loop>,
+    /// Number of expansions the current form comes from
+    depth: usize,
+}
+
+impl Expander {
+    fn top_level(&mut self, e: &Sexp) -> Sexp {
+        match e {
+            // Only the body of a function is an expression
+            Sexp::List(es, pos) if is_form(e, "fun") => {
+                let mut es = es.clone();
+                if let Some(body) = es.last_mut() {
+                    *body = self.expr(body);
+                }
+                Sexp::List(es, *pos)
+            }
+            // The body of a test is made of expressions
+            Sexp::List(es, pos)
+                if is_form(e, "test") && matches!(es.get(1), Some(Sexp::Atom(Str(_)))) =>
+            {
+                let mut es = es.clone();
+                for body in &mut es[2..] {
+                    *body = self.expr(body);
+                }
+                Sexp::List(es, *pos)
+            }
+            _ if is_form(e, "struct")
+                || is_form(e, "enum")
+                || is_form(e, "import")
+                || is_form(e, "extern")
+                || is_form(e, "export") =>
+            {
+                e.clone()
+            }
+            _ => self.expr(e),
+        }
+    }
+
+    fn expr(&mut self, e: &Sexp) -> Sexp {
+        let Sexp::List(es, pos) = e else {
+            return match e {
+                Sexp::Atom(S(s)) => Sexp::Atom(S(self.resolve(s))),
+                _ => e.clone(),
+            };
+        };
+        let head = match es.first() {
+            Some(Sexp::Atom(S(head))) if !self.bound.contains(head) => base(head),
+            _ => "",
+        };
+        if self.macros.contains_key(head) {
+            if self.depth == MAX_DEPTH {
+                syntax_error(format!(
+                    "expansion of macro `{head}` at {pos} doesn't terminate"
+                ))
+            }
+            self.next_mark += 1;
+            let expansion = expand_use(head, &self.macros[head], e, self.next_mark, *pos);
+            self.depth += 1;
+            let e = self.expr(&expansion);
+            self.depth -= 1;
+            return e;
+        }
+        let es = match (head, &es[..]) {
+            // (let ((x e) ..) body) and (let* ((x e) ..) body), bindings may be `(x : T e)`
+            ("let" | "let*", [keyword, Sexp::List(bindings, bpos), body @ ..]) => {
+                let scope = self.bound.len();
+                let bindings = bindings.iter().map(|binding| match binding {
+                    Sexp::List(b, pos) if b.len() >= 2 => {
+                        let (x, rest) = b.split_first().unwrap();
+                        let (rhs, annot) = rest.split_last().unwrap();
+                        let rhs = self.expr(rhs);
+                        let x = self.bind(x);
+                        let mut b = vec![x];
+                        b.extend(annot.iter().map(strip));
+                        b.push(rhs);
+                        Sexp::List(b, *pos)
+                    }
+                    _ => binding.clone(),
+                });
+                let bindings = Sexp::List(bindings.collect(), *bpos);
+                let mut es = vec![strip(keyword), bindings];
+                es.extend(body.iter().map(|e| self.expr(e)));
+                self.bound.truncate(scope);
+                es
+            }
+            // Local function definitions (fun (f x ..) body), parameters may be `(x : T)`
+            ("fun", [keyword, Sexp::List(header, hpos), rest @ ..]) if !header.is_empty() => {
+                let scope = self.bound.len();
+                let mut header2 = vec![strip(&header[0])];
+                for param in &header[1..] {
+                    header2.push(match param {
+                        Sexp::List(p, pos) if !p.is_empty() => {
+                            let mut p2 = vec![self.bind(&p[0])];
+                            p2.extend(p[1..].iter().map(strip));
+                            Sexp::List(p2, *pos)
+                        }
+                        _ => self.bind(param),
+                    });
+                }
+                let mut es = vec![strip(keyword), Sexp::List(header2, *hpos)];
+                // `break` and `continue` can't leave the function
+                let loops = std::mem::take(&mut self.loops);
+                if let Some((body, annot)) = rest.split_last() {
+                    es.extend(annot.iter().map(strip));
+                    es.push(self.expr(body));
+                }
+                self.loops = loops;
+                self.bound.truncate(scope);
+                es
+            }
+            // (try e (catch x handler))
+            ("try", [keyword, body, Sexp::List(clause, cpos)]) if clause.len() == 3 => {
+                let body = self.expr(body);
+                let scope = self.bound.len();
+                let x = self.bind(&clause[1]);
+                let handler = self.expr(&clause[2]);
+                self.bound.truncate(scope);
+                let clause = Sexp::List(vec![strip(&clause[0]), x, handler], *cpos);
+                vec![strip(keyword), body, clause]
+            }
+            // (cond (test body..) ..)
+            ("cond", [keyword, clauses @ ..]) => {
+                let mut es = vec![strip(keyword)];
+                for clause in clauses {
+                    es.push(match clause {
+                        Sexp::List(c, pos) => {
+                            Sexp::List(c.iter().map(|e| self.expr(e)).collect(), *pos)
+                        }
+                        _ => clause.clone(),
+                    });
+                }
+                es
+            }
+            // (match e (pattern body..) ..), clauses may have a guard `(pattern when guard body..)`
+            ("match", [keyword, scrutinee, clauses @ ..]) => {
+                let mut es = vec![strip(keyword), self.expr(scrutinee)];
+                for clause in clauses {
+                    es.push(match clause {
+                        Sexp::List(c, pos) if !c.is_empty() => {
+                            let scope = self.bound.len();
+                            let mut c2 = vec![self.pattern(&c[0])];
+                            for e in &c[1..] {
+                                c2.push(match e {
+                                    Sexp::Atom(S(s)) if base(s) == "when" => strip(e),
+                                    _ => self.expr(e),
+                                });
+                            }
+                            self.bound.truncate(scope);
+                            Sexp::List(c2, *pos)
+                        }
+                        _ => clause.clone(),
+                    });
+                }
+                es
+            }
+            // (loop ['label] body), (while ['label] cond body..),
+            // (for ['label] (x start end [step]) body..) and (for-each ['label] (x vec) body..)
+            ("loop" | "while" | "for" | "for-each", [keyword, rest @ ..]) => {
+                let (label, rest) = split_label(rest);
+                let scope = self.bound.len();
+                // The bounds of `for` and the vector of `for-each` are evaluated before the loop
+                let header = match (head, rest.first()) {
+                    ("for" | "for-each", Some(Sexp::List(h, pos))) if !h.is_empty() => {
+                        let mut header: Vec<_> = h[1..].iter().map(|e| self.expr(e)).collect();
+                        header.insert(0, self.bind(&h[0]));
+                        Some(Sexp::List(header, *pos))
+                    }
+                    _ => None,
+                };
+                self.loops.push(Loop {
+                    mark: mark_of(keyword),
+                    label: match label {
+                        Some(Sexp::Atom(S(label))) => label.clone(),
+                        _ => format!("'loop {}", self.next_label),
+                    },
+                    labelled: label.is_some(),
+                });
+                self.next_label += 1;
+                let mut es = vec![strip(keyword)];
+                let body = match header {
+                    Some(header) => {
+                        let mut body = vec![header];
+                        body.extend(rest[1..].iter().map(|e| self.expr(e)));
+                        body
+                    }
+                    None => rest.iter().map(|e| self.expr(e)).collect(),
+                };
+                let lp = self.loops.pop().unwrap();
+                self.bound.truncate(scope);
+                if lp.labelled {
+                    es.push(Sexp::Atom(S(lp.label)));
+                }
+                es.extend(body);
+                es
+            }
+            // (break ['label] [e]) and (continue ['label])
+            ("break" | "continue", [keyword, rest @ ..]) => {
+                let (label, rest) = split_label(rest);
+                let mut es = vec![strip(keyword)];
+                match label {
+                    Some(label) => es.push(label.clone()),
+                    None => {
+                        let mark = mark_of(keyword);
+                        let target = (self.loops.iter().rposition(|lp| lp.mark == mark))
+                            .or_else(|| self.loops.iter().rposition(|lp| lp.mark.is_none()));
+                        match target {
+                            Some(i) if i + 1 < self.loops.len() => {
+                                self.loops[i].labelled = true;
+                                es.push(Sexp::Atom(S(self.loops[i].label.clone())));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                es.extend(rest.iter().map(|e| self.expr(e)));
+                es
+            }
+            _ => es.iter().map(|e| self.expr(e)).collect(),
+        };
+        Sexp::List(es, *pos)
+    }
+
+    fn pattern(&mut self, e: &Sexp) -> Sexp {
+        match e {
+            Sexp::Atom(S(s)) if matches!(base(s), "_" | "true" | "false" | "nil") => strip(e),
+            Sexp::Atom(S(_)) => self.bind(e),
+            Sexp::Atom(_) => e.clone(),
+            Sexp::List(es, pos) => {
+                let mut ps = vec![];
+                if let Some((head, rest)) = es.split_first() {
+                    ps.push(strip(head));
+                    ps.extend(rest.iter().map(|e| self.pattern(e)));
+                }
+                Sexp::List(ps, *pos)
+            }
+        }
+    }
+
+    /// A variable bound by a `let` or a pattern. Those introduced by a template keep their mark.
+    fn bind(&mut self, x: &Sexp) -> Sexp {
+        if let Sexp::Atom(S(s)) = x {
+            if mark_of(x).is_some() {
+                self.bound.push(s.clone());
+            }
+        }
+        x.clone()
+    }
+
+    /// A symbol introduced by a template that isn't a variable bound by the template means the
+    /// same as it would in the user's code.
+    fn resolve(&self, s: &str) -> String {
+        if self.bound.iter().any(|x| x == s) {
+            s.to_string()
+        } else {
+            base(s).to_string()
+        }
+    }
+}
+
+/// Replaces the use `e` of a macro by the template of its first matching rule.
+fn expand_use(name: &str, m: &Macro, e: &Sexp, mark: u32, pos: Pos) -> Sexp {
+    let Sexp::List(es, _) = e else { unreachable!() };
+    for (pattern, template) in &m.rules {
+        let Sexp::List(ps, _) = pattern else {
+            unreachable!()
+        };
+        let mut bindings = Bindings::new();
+        if match_list(&ps[1..], &es[1..], &m.literals, &mut bindings) {
+            let expansion = Expansion { name, mark, pos };
+            return expansion.instantiate(template, &bindings);
+        }
+    }
+    syntax_error(format!("no rule of macro `{name}` matches {e} at {pos}"))
+}
+
+struct Expansion<'a> {
+    name: &'a str,
+    mark: u32,
+    /// Position of the macro use, given to the lists of the template
+    pos: Pos,
+}
+
+impl Expansion<'_> {
+    fn instantiate(&self, template: &Sexp, bindings: &Bindings) -> Sexp {
+        match template {
+            Sexp::Atom(S(s)) => match bindings.get(s) {
+                Some(Binding::One(e)) => e.clone(),
+                Some(Binding::Many(_)) => syntax_error(format!(
+                    "pattern variable {s} must be followed by ... in the template of macro `{}`",
+                    self.name
+                )),
+                None => Sexp::Atom(S(format!("{s} {}", self.mark))),
+            },
+            Sexp::Atom(_) => template.clone(),
+            Sexp::List(ts, _) => {
+                let mut es = vec![];
+                let mut i = 0;
+                while i < ts.len() {
+                    if ts.get(i + 1).is_some_and(is_ellipsis) {
+                        es.extend(self.repeat(&ts[i], bindings));
+                        i += 2;
+                    } else {
+                        es.push(self.instantiate(&ts[i], bindings));
+                        i += 1;
+                    }
+                }
+                Sexp::List(es, self.pos)
+            }
+        }
+    }
+
+    /// Instantiates `template` once for each match of the pattern variables inside it.
+    fn repeat(&self, template: &Sexp, bindings: &Bindings) -> Vec<Sexp> {
+        let mut vars = vec![];
+        symbols(template, &mut vars);
+        let seqs: Vec<_> = vars
+            .iter()
+            .filter_map(|x| match bindings.get(x) {
+                Some(Binding::Many(seq)) => Some((x, seq)),
+                _ => None,
+            })
+            .collect();
+        let Some((_, first)) = seqs.first() else {
+            return syntax_error(format!(
+                "no pattern variable before ... in the template of macro `{}`",
+                self.name
+            ));
+        };
+        if seqs.iter().any(|(_, seq)| seq.len() != first.len()) {
+            syntax_error(format!(
+                "pattern variables repeated by the same ... matched different numbers of forms in macro `{}` at {}",
+                self.name, self.pos
+            ))
+        }
+        (0..first.len())
+            .map(|i| {
+                let mut bindings = bindings.clone();
+                for (x, seq) in &seqs {
+                    bindings.insert(x.to_string(), seq[i].clone());
+                }
+                self.instantiate(template, &bindings)
+            })
+            .collect()
+    }
+}
+
+fn parse_macro(e: &Sexp) -> (String, Macro) {
+    let Sexp::List(es, _) = e else { unreachable!() };
+    let [_, Sexp::Atom(S(name)), Sexp::List(rules, _)] = &es[..] else {
+        return syntax_error("malformed define-syntax");
+    };
+    if parser::is_keyword(name) {
+        syntax_error(format!("cannot use keyword `{name}` as macro name"))
+    }
+    let [Sexp::Atom(S(keyword)), Sexp::List(literals, _), rules @ ..] = &rules[..] else {
+        return syntax_error(format!("macro {name} must be defined with syntax-rules"));
+    };
+    if keyword != "syntax-rules" {
+        syntax_error(format!("macro {name} must be defined with syntax-rules"))
+    }
+    let literals = literals
+        .iter()
+        .map(|e| match e {
+            Sexp::Atom(S(s)) => s.clone(),
+            _ => syntax_error(format!("invalid literal {e} in macro {name}")),
+        })
+        .collect();
+    let rules = rules
+        .iter()
+        .map(|rule| match rule {
+            Sexp::List(r, _) => match &r[..] {
+                [pattern @ Sexp::List(p, _), template] if !p.is_empty() => {
+                    (pattern.clone(), template.clone())
+                }
+                _ => syntax_error(format!("malformed rule in macro {name}")),
+            },
+            _ => syntax_error(format!("malformed rule in macro {name}")),
+        })
+        .collect();
+    (name.clone(), Macro { literals, rules })
+}
+
+fn match_pattern(pattern: &Sexp, e: &Sexp, literals: &[String], bindings: &mut Bindings) -> bool {
+    match (pattern, e) {
+        (Sexp::Atom(S(p)), _) if p == "_" => true,
+        (Sexp::Atom(S(p)), _) if literals.contains(p) => {
+            matches!(e, Sexp::Atom(S(s)) if base(s) == p)
+        }
+        (Sexp::Atom(S(p)), _) => {
+            bindings.insert(p.clone(), Binding::One(e.clone()));
+            true
+        }
+        (Sexp::Atom(p), Sexp::Atom(a)) => p == a,
+        (Sexp::List(ps, _), Sexp::List(es, _)) => match_list(ps, es, literals, bindings),
+        _ => false,
+    }
+}
+
+fn match_list(ps: &[Sexp], es: &[Sexp], literals: &[String], bindings: &mut Bindings) -> bool {
+    let Some(ellipsis) = ps.iter().position(is_ellipsis) else {
+        return ps.len() == es.len()
+            && ps
+                .iter()
+                .zip(es)
+                .all(|(p, e)| match_pattern(p, e, literals, bindings));
+    };
+    if ellipsis == 0 {
+        return syntax_error("... must follow a pattern");
+    }
+    let (before, repeated, after) = (&ps[..ellipsis - 1], &ps[ellipsis - 1], &ps[ellipsis + 1..]);
+    if es.len() < before.len() + after.len() {
+        return false;
+    }
+    let middle = &es[before.len()..es.len() - after.len()];
+    if !match_list(before, &es[..before.len()], literals, bindings)
+        || !match_list(after, &es[es.len() - after.len()..], literals, bindings)
+    {
+        return false;
+    }
+    let mut vars = vec![];
+    symbols(repeated, &mut vars);
+    let mut seqs: HashMap<String, Vec<Binding>> = HashMap::new();
+    for e in middle {
+        let mut inner = Bindings::new();
+        if !match_pattern(repeated, e, literals, &mut inner) {
+            return false;
+        }
+        for (x, binding) in inner {
+            seqs.entry(x).or_default().push(binding);
+        }
+    }
+    for x in vars {
+        if x != "_" && !literals.contains(&x) {
+            let seq = seqs.remove(&x).unwrap_or_default();
+            bindings.insert(x, Binding::Many(seq));
+        }
+    }
+    true
+}
+
+/// The symbols in a pattern or template.
+fn symbols(e: &Sexp, out: &mut Vec<String>) {
+    match e {
+        Sexp::Atom(S(s)) if !is_ellipsis(e) => out.push(s.clone()),
+        Sexp::Atom(_) => {}
+        Sexp::List(es, _) => es.iter().for_each(|e| symbols(e, out)),
+    }
+}
+
+/// Splits the `'label` off the operands of a loop, `break` or `continue`. Labels written in a
+/// template keep their mark, so they can only be targeted from the same expansion.
+fn split_label(es: &[Sexp]) -> (Option<&Sexp>, &[Sexp]) {
+    match es {
+        [label @ Sexp::Atom(S(s)), rest @ ..] if s.starts_with('\'') => (Some(label), rest),
+        _ => (None, es),
+    }
+}
+
+fn is_ellipsis(e: &Sexp) -> bool {
+    matches!(e, Sexp::Atom(S(s)) if s == "...")
+}
+
+fn is_form(e: &Sexp, keyword: &str) -> bool {
+    matches!(e, Sexp::List(es, _) if matches!(es.first(), Some(Sexp::Atom(S(k))) if k == keyword))
+}
+
+/// The name of a symbol without the mark of the expansion introducing it.
+fn base(s: &str) -> &str {
+    s.split(' ').next().unwrap()
+}
+
+fn mark_of(e: &Sexp) -> Option<u32> {
+    match e {
+        Sexp::Atom(S(s)) => s.split_once(' ').map(|(_, mark)| mark.parse().unwrap()),
+        _ => None,
+    }
+}
+
+fn strip(e: &Sexp) -> Sexp {
+    match e {
+        Sexp::Atom(S(s)) => Sexp::Atom(S(base(s).to_string())),
+        Sexp::Atom(_) => e.clone(),
+        Sexp::List(es, pos) => Sexp::List(es.iter().map(strip).collect(), *pos),
+    }
+}
+
+fn syntax_error<T>(note: impl ToString) -> T {
+    panic!("Invalid syntax: {}", note.to_string())
+}
@@ -1,26 +1,105 @@
 use std::{
-    env,
-    fs::File,
-    io::{self, Read, Write},
+    env, fs,
+    io::{self, Write},
+    path::Path,
+    process,
 };
 
 mod asm;
+mod c_backend;
 mod compiler;
+mod export;
+mod inline;
+mod lift;
+mod macros;
+mod modules;
+mod options;
 mod parser;
+mod pattern;
+mod peephole;
+mod prelude;
+mod runner;
+mod sexp;
 mod syntax;
+mod tags;
+mod typecheck;
+
+use options::{Backend, Options};
+use syntax::Prog;
 
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let in_name = &args[1];
-    let out_name = &args[2];
-    let mut in_contents = String::new();
-    let mut in_file = File::open(in_name)?;
-    in_file.read_to_string(&mut in_contents)?;
-    let expr = parser::parse(&in_contents);
-    let asm = compiler::compile(&expr);
-
-    let mut out_file = File::create(out_name)?;
+    let opts = Options::from_args(env::args().skip(1));
+    let mut modules = modules::load(Path::new(&opts.in_name), &opts);
+    let mut program = modules.pop().unwrap();
+    if opts.test {
+        program.prog.main = Some(runner::test_main(&program.prog.tests));
+    }
+
+    // Each imported module is compiled next to its source, and listed at the top of the program
+    // so the build can assemble and link it along with the program.
+    let comment = opts.backend.comment();
+    let mut asm = String::new();
+    for file in &opts.links {
+        asm += &format!("{comment} link {file}\n");
+    }
+    let mut module_asm = vec![];
+    for module in modules {
+        let path = module.path.with_extension(opts.backend.extension());
+        write_atomically(&path, &compile(module.prog, &opts))?;
+        asm += &format!(
+            "{comment} module {}\n",
+            module.path.with_extension("").display()
+        );
+        module_asm.push(path);
+    }
+    let tests: Vec<_> = program
+        .prog
+        .tests
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect();
+    let header = opts.lib.then(|| {
+        let name = program.path.file_stem().unwrap().to_string_lossy();
+        export::header(&program.prog, &name)
+    });
+    asm += &compile(program.prog, &opts);
+
+    if opts.run_tests {
+        if !runner::run(&asm, &module_asm, &opts.links, &tests)? {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let mut out_file = fs::File::create(&opts.out_name)?;
     out_file.write_all(asm.as_bytes())?;
+    if let Some(header) = header {
+        fs::write(Path::new(&opts.out_name).with_extension("h"), header)?;
+    }
 
     Ok(())
 }
+
+fn compile(mut prog: Prog, opts: &Options) -> String {
+    if opts.prelude {
+        prog = prelude::link(prog);
+    }
+    if opts.typecheck {
+        typecheck::check(&prog);
+    }
+    prog = prog.erase_annotations();
+    if opts.opt_level >= 1 {
+        prog = inline::inline(prog);
+    }
+    match opts.backend {
+        Backend::X86_64 => compiler::compile(&prog, opts),
+        Backend::C => c_backend::compile(&prog, opts),
+    }
+}
+
+/// Libraries may be compiled by several programs at once, so they are never left half written.
+fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp = path.with_extension(format!("s.{}", process::id()));
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
@@ -0,0 +1,120 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    options::Options,
+    parser,
+    syntax::{Interface, Prog},
+};
+
+/// A module of the program.
+pub struct Module {
+    /// The canonical path of the source file
+    pub path: PathBuf,
+    pub prog: Prog,
+}
+
+/// Loads the program at `path` and every module it imports, directly or not. Modules come after
+/// the modules they import, so the program itself is last. Imported paths are relative to the
+/// importing file. When compiling its tests, the program doesn't need a main expression, and a
+/// library compiled with `--lib` can't have one. Only the latter can export functions.
+pub fn load(path: &Path, opts: &Options) -> Vec<Module> {
+    let mut loader = Loader {
+        modules: vec![],
+        interfaces: HashMap::new(),
+        names: HashMap::new(),
+        tests: opts.test,
+        lib: opts.lib,
+    };
+    loader.load(path, &mut vec![]);
+    loader.modules
+}
+
+struct Loader {
+    modules: Vec<Module>,
+    /// Interfaces of the modules loaded so far
+    interfaces: HashMap<PathBuf, Interface>,
+    /// The path of the module with each name, as modules are compiled to symbols qualified by it
+    names: HashMap<String, PathBuf>,
+    tests: bool,
+    lib: bool,
+}
+
+impl Loader {
+    /// `importers` are the modules whose imports are being loaded, to detect cycles.
+    fn load(&mut self, path: &Path, importers: &mut Vec<PathBuf>) -> Interface {
+        let path = fs::canonicalize(path)
+            .unwrap_or_else(|err| panic!("Cannot load module {}: {err}", path.display()));
+        if let Some(start) = importers.iter().position(|p| *p == path) {
+            let cycle: Vec<_> = importers[start..]
+                .iter()
+                .chain([&path])
+                .map(|p| file_name(p))
+                .collect();
+            panic!("Invalid import: import cycle {}", cycle.join(" -> "))
+        }
+        if let Some(interface) = self.interfaces.get(&path) {
+            return interface.clone();
+        }
+
+        let source = fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("Cannot load module {}: {err}", path.display()));
+        let forms = parser::read(&source);
+        importers.push(path.clone());
+        let mut imports = HashMap::new();
+        for import in parser::imports(&forms) {
+            let interface = self.load(&path.parent().unwrap().join(&import), importers);
+            imports.insert(import, interface);
+        }
+        importers.pop();
+
+        let name = path
+            .file_stem()
+            .unwrap()
+            .to_string_lossy()
+            .replace('.', "_");
+        if let Some(other) = self.names.insert(name.clone(), path.clone()) {
+            panic!(
+                "Invalid import: modules {} and {} have the same name",
+                other.display(),
+                path.display()
+            )
+        }
+        let (prog, interface) = parser::parse(&forms, &name, &imports);
+        match (&prog.main, importers.is_empty()) {
+            (Some(_), false) => panic!(
+                "Invalid import: module {} has a main expression",
+                file_name(&path)
+            ),
+            (Some(_), true) if self.lib => {
+                panic!(
+                    "Invalid syntax: a library compiled with `--lib` can't have a main expression"
+                )
+            }
+            (None, true) if !self.tests && !self.lib => {
+                panic!("Invalid syntax: program must contain a main expression")
+            }
+            _ => {}
+        }
+        let exporting = self.lib && importers.is_empty();
+        if !prog.exports.is_empty() && !exporting {
+            panic!(
+                "Invalid syntax: {} exports functions but isn't compiled with `--lib`",
+                file_name(&path)
+            )
+        }
+        self.modules.push(Module {
+            path: path.clone(),
+            prog,
+        });
+        self.interfaces.insert(path, interface.clone());
+        interface
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap().to_string_lossy().into_owned()
+}
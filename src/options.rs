@@ -0,0 +1,145 @@
+/// Command line options accepted by the compiler.
+///
+/// Usage: `forest-flame [flags] <input.snek> <output.s>`, or `forest-flame test [flags] <input.snek>`
+/// to run the tests of a program.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub in_name: String,
+    /// Empty for the `test` command
+    pub out_name: String,
+    /// `-O0` disables all optimisations, `-O1` (the default) enables them.
+    pub opt_level: u8,
+    /// `--stats` reports how many runtime checks were eliminated by the optimiser.
+    pub stats: bool,
+    /// `--no-peephole` skips the peephole pass, useful when debugging code generation.
+    pub no_peephole: bool,
+    /// `--typecheck` checks the program against its type annotations before compiling it.
+    pub typecheck: bool,
+    /// `--no-prelude` doesn't link the functions of the prelude into the program.
+    pub prelude: bool,
+    /// `--no-bignums` makes arithmetic overflow a runtime error instead of promoting the result to
+    /// a bignum, and rejects integer literals too large for a tagged number.
+    pub bignums: bool,
+    /// `--test` compiles the tests of the program instead of its main expression. The input of the
+    /// compiled program is the index of the test to run.
+    pub test: bool,
+    /// The `test` command compiles the program with `--test` and runs each of its tests.
+    pub run_tests: bool,
+    /// `--link <file>` links an object file or a static library with the program, e.g. one
+    /// defining the C functions it declares with `extern`.
+    pub links: Vec<String>,
+    /// `--lib` compiles a library without a main expression for C programs: the functions it
+    /// exports become C functions, declared in a header written next to the output (with the
+    /// extension `.h`).
+    pub lib: bool,
+    /// `--backend <x86-64|c>` chooses what the program is compiled to: x86-64 assembly for NASM
+    /// (the default), or C for the system C compiler (see `c_backend`).
+    pub backend: Backend,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    X86_64,
+    C,
+}
+
+impl Backend {
+    /// The extension of the files the backend writes.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Backend::X86_64 => "s",
+            Backend::C => "gen.c",
+        }
+    }
+
+    /// How lines for the build (`module` and `link`) are commented out in the output.
+    pub fn comment(self) -> &'static str {
+        match self {
+            Backend::X86_64 => ";;",
+            Backend::C => "//",
+        }
+    }
+}
+
+impl Options {
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Options {
+        let mut opt_level = 1;
+        let mut stats = false;
+        let mut no_peephole = false;
+        let mut typecheck = false;
+        let mut prelude = true;
+        let mut bignums = true;
+        let mut test = false;
+        let mut links = vec![];
+        let mut lib = false;
+        let mut backend = Backend::X86_64;
+        let mut args = args.into_iter().peekable();
+        let run_tests = args.next_if(|arg| arg == "test").is_some();
+        let mut positional = vec![];
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-O0" => opt_level = 0,
+                "-O1" => opt_level = 1,
+                "--stats" => stats = true,
+                "--no-peephole" => no_peephole = true,
+                "--typecheck" => typecheck = true,
+                "--no-prelude" => prelude = false,
+                "--no-bignums" => bignums = false,
+                "--test" => test = true,
+                "--lib" => lib = true,
+                "--link" => match args.next() {
+                    Some(file) => links.push(file),
+                    None => usage_error("`--link` expects a file"),
+                },
+                "--backend" => match args.next().as_deref() {
+                    Some("x86-64") => backend = Backend::X86_64,
+                    Some("c") => backend = Backend::C,
+                    _ => usage_error("`--backend` expects `x86-64` or `c`"),
+                },
+                _ if arg.starts_with('-') => usage_error(format!("unknown flag `{arg}`")),
+                _ => positional.push(arg),
+            }
+        }
+        if backend != Backend::X86_64 && (lib || run_tests) {
+            let what = if lib { "`--lib`" } else { "the `test` command" };
+            usage_error(format!("{what} requires the x86-64 backend"))
+        }
+        let [in_name, out_name] = if run_tests {
+            let [in_name] = <[String; 1]>::try_from(positional)
+                .unwrap_or_else(|_| usage_error("expected an input file"));
+            [in_name, String::new()]
+        } else {
+            <[String; 2]>::try_from(positional)
+                .unwrap_or_else(|_| usage_error("expected an input and an output file"))
+        };
+        Options {
+            in_name,
+            out_name,
+            opt_level,
+            stats,
+            no_peephole,
+            typecheck,
+            prelude,
+            bignums,
+            test: test || run_tests,
+            run_tests,
+            links,
+            lib,
+            backend,
+        }
+    }
+
+    pub fn peephole(&self) -> bool {
+        self.opt_level >= 1 && !self.no_peephole
+    }
+}
+
+const FLAGS: &str =
+    "[-O0|-O1] [--stats] [--no-peephole] [--typecheck] [--no-prelude] [--no-bignums] [--test] [--link <file>].. [--lib] [--backend <x86-64|c>]";
+
+fn usage_error<T>(note: impl ToString) -> T {
+    panic!(
+        "{}\nusage: forest-flame {FLAGS} <input.snek> <output.s>\n       forest-flame test {FLAGS} <input.snek>",
+        note.to_string()
+    )
+}
@@ -1,47 +1,532 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+};
+
 use regex::Regex;
-use sexp::{Atom::*, Sexp};
 
-use crate::syntax::{Expr, FunDecl, Op1, Op2, Prog, Symbol};
+use crate::{
+    export,
+    lift::{self, LocalFun},
+    macros,
+    pattern::{self, Clause, Pattern, Signatures},
+    sexp::{self, Atom::*, Sexp},
+    syntax::{Expr, FunDecl, Interface, Op1, Op2, Prim, Prog, StructDecl, Symbol, Type},
+};
 
-pub fn parse(s: &str) -> Prog {
+/// Reads the forms of a module.
+pub fn read(s: &str) -> Sexp {
     let s = format!("({})", s);
-    let s = sexp::parse(&s).unwrap_or_else(|_| syntax_error("invalid s-expr"));
-    Parser::new().parse_prog(&s)
+    sexp::parse(&s).unwrap_or_else(|err| syntax_error(format!("invalid s-expr: {err}")))
+}
+
+/// The paths of the modules imported by a module, as written in its `import` forms.
+pub fn imports(e: &Sexp) -> Vec<String> {
+    let Sexp::List(es, _) = e else {
+        return vec![];
+    };
+    es.iter()
+        .filter(|e| is_decl(e, "import"))
+        .map(|e| match e {
+            Sexp::List(es, _) => match es.get(1) {
+                Some(Sexp::Atom(Str(path))) => path.clone(),
+                _ => syntax_error("import expects a path"),
+            },
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Parses the module `name`, given the interfaces of the modules it imports by path. A module
+/// whose last form is a declaration is a library: its definitions are qualified by its name.
+pub fn parse(e: &Sexp, name: &str, imports: &HashMap<String, Interface>) -> (Prog, Interface) {
+    Parser::new().parse_prog(&macros::expand(e), name, imports)
 }
 
 struct Parser {
     id_regex: Regex,
+    /// Counter to generate names for temporaries introduced when lowering derived forms
+    next_tmp: Cell<u32>,
+    /// The name qualifying the definitions of a library
+    module: Option<String>,
+    /// Functions defined or imported by the module, by the name they are called with
+    funs: HashMap<String, Symbol>,
+    /// C functions declared by `extern`, and their arity
+    externs: HashMap<String, usize>,
+    /// Functions generated by struct declarations, and the struct they belong to
+    struct_fns: HashMap<String, (Symbol, StructFn)>,
+    /// The variants of the type of each struct and enum variant, to check `match` exhaustiveness
+    sigs: Signatures,
+    /// The function whose body is being parsed, `None` in the main expression
+    enclosing: Cell<Option<Symbol>>,
+    /// Local functions in scope, by the name they are called with, innermost last
+    locals: RefCell<Vec<(Symbol, Symbol)>>,
+    /// Local functions defined in the current top-level function, lifted once it is parsed
+    lifted: RefCell<Vec<LocalFun>>,
+    /// Names given to local functions of the module so far
+    lifted_names: RefCell<HashSet<Symbol>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum StructFn {
+    /// The constructor, taking the given number of fields
+    New(usize),
+    Get(usize),
+    Set(usize),
+    Is,
 }
 
 impl Parser {
     fn new() -> Parser {
         Parser {
-            id_regex: Regex::new(r"^[a-zA-Z][a-zA-Z0-9_-]*$").unwrap(),
+            // Variables bound by macro templates are marked with the number of their expansion
+            id_regex: Regex::new(r"^[a-zA-Z][a-zA-Z0-9_-]*( [0-9]+)?$").unwrap(),
+            next_tmp: Cell::new(0),
+            module: None,
+            funs: HashMap::new(),
+            externs: HashMap::new(),
+            struct_fns: HashMap::new(),
+            sigs: HashMap::new(),
+            enclosing: Cell::new(None),
+            locals: RefCell::new(vec![]),
+            lifted: RefCell::new(vec![]),
+            lifted_names: RefCell::new(HashSet::new()),
         }
     }
 
-    fn parse_prog(&self, e: &Sexp) -> Prog {
-        let Sexp::List(es) = e else{
+    fn parse_prog(
+        &mut self,
+        e: &Sexp,
+        name: &str,
+        interfaces: &HashMap<String, Interface>,
+    ) -> (Prog, Interface) {
+        let Sexp::List(es, _) = e else {
             syntax_error("expected a list")
         };
-        if let [funcs @ .., main] = &es[..] {
-            let funcs = funcs.iter().map(|e| self.parse_func(e)).collect();
+        let (decls, main) = match &es[..] {
+            [] => return syntax_error("program must contain a main expression"),
+            [.., last] if is_any_decl(last) => (&es[..], None),
+            [decls @ .., main] => (decls, Some(main)),
+        };
+        if main.is_none() {
+            self.module = Some(name.to_string());
+        }
+        let (imports, decls): (Vec<_>, Vec<_>) = decls.iter().partition(|e| is_decl(e, "import"));
+        let mut imported_structs = vec![];
+        let mut imported_funs = vec![];
+        for e in imports {
+            let interface = self.parse_import(e, interfaces);
+            for decl in &interface.structs {
+                if !imported_structs
+                    .iter()
+                    .any(|d: &StructDecl| d.name == decl.name)
+                {
+                    imported_structs.push(decl.clone());
+                }
+            }
+            for fun in &interface.funs {
+                if !imported_funs.contains(fun) {
+                    imported_funs.push(*fun);
+                }
+            }
+        }
+        // Struct and enum declarations come first so their functions can be used anywhere
+        let (tests, decls): (Vec<_>, Vec<_>) = decls.into_iter().partition(|e| is_test_decl(e));
+        let (externs, decls): (Vec<_>, Vec<_>) =
+            decls.into_iter().partition(|e| is_decl(e, "extern"));
+        let externs: Vec<_> = externs.into_iter().map(|e| self.parse_extern(e)).collect();
+        let (exports, decls): (Vec<_>, Vec<_>) =
+            decls.into_iter().partition(|e| is_decl(e, "export"));
+        let (types, funcs): (Vec<_>, Vec<_>) = decls.into_iter().partition(|e| is_type_decl(e));
+        let mut structs = vec![];
+        let mut enums = vec![];
+        for e in types {
+            let variants = if is_decl(e, "enum") {
+                let (name, variants) = self.parse_enum(e);
+                enums.push((name, variants.iter().map(|v| v.name).collect()));
+                variants
+            } else {
+                vec![self.parse_struct(e)]
+            };
+            let sig: Vec<_> = variants.iter().map(|v| (v.name, v.fields.len())).collect();
+            for decl in &variants {
+                self.add_struct_fns(decl, None);
+                self.sigs.insert(decl.name, sig.clone());
+            }
+            structs.extend(variants);
+        }
+        // Functions may call each other regardless of the order of their definitions
+        for e in &funcs {
+            if let Some(name) = fun_name(e) {
+                let name = self.parse_identifier(name);
+                let fun = self.qualify(name);
+                if self.externs.contains_key(name.to_string().as_str()) {
+                    syntax_error(format!("function {name} conflicts with extern {name}"))
+                }
+                match self.funs.insert(name.to_string(), fun) {
+                    Some(prev) if prev != fun => syntax_error(format!(
+                        "function {name} conflicts with the import of {prev}"
+                    )),
+                    _ => {}
+                }
+            }
+        }
+        let mut lifted = vec![];
+        let funcs: Vec<_> = funcs
+            .iter()
+            .map(|e| {
+                let fun = self.parse_func(e, |name| self.qualify(name));
+                let (body, locals) = lift::lift(&fun.params, fun.body, self.lifted.take());
+                lifted.extend(locals);
+                FunDecl { body, ..fun }
+            })
+            .collect();
+        for fun in &funcs {
+            if let Some((decl, _)) = self.struct_fns.get(fun.name.unqualified()) {
+                syntax_error(format!(
+                    "function {} conflicts with the definition of struct {decl}",
+                    fun.name
+                ))
+            }
+        }
+        let mut exported = HashSet::new();
+        let exports = exports
+            .into_iter()
+            .flat_map(|e| self.parse_export(e, &funcs, &mut exported))
+            .collect();
+        let main = main.map(|main| {
             let main = self.parse_expr(main);
-            Prog { funs: funcs, main }
-        } else {
-            syntax_error("program must contain a main expression")
+            let (main, locals) = lift::lift(&[], main, self.lifted.take());
+            lifted.extend(locals);
+            main
+        });
+        let mut test_names = HashSet::new();
+        let tests = tests
+            .into_iter()
+            .map(|e| {
+                let (name, body) = self.parse_test(e);
+                if !test_names.insert(name.clone()) {
+                    syntax_error(format!("duplicate test {name:?}"))
+                }
+                let (body, locals) = lift::lift(&[], body, self.lifted.take());
+                lifted.extend(locals);
+                (name, body)
+            })
+            .collect();
+        let interface = Interface {
+            name: name.to_string(),
+            funs: funcs
+                .iter()
+                .map(|fun| (fun.name, fun.params.len()))
+                .collect(),
+            structs: structs.clone(),
+            enums,
+        };
+        let prog = Prog {
+            structs,
+            imported_structs,
+            funs: funcs.into_iter().chain(lifted).collect(),
+            imported_funs,
+            main,
+            tests,
+            externs,
+            exports,
+        };
+        (prog, interface)
+    }
+
+    /// `(extern name arity)` declares a C function taking `arity` 64-bit integers (at most 6) and
+    /// returning one.
+    fn parse_extern(&mut self, e: &Sexp) -> (Symbol, usize) {
+        let Sexp::List(es, _) = e else {
+            return syntax_error("expected a list");
+        };
+        let [_, name, Sexp::Atom(I(arity))] = &es[..] else {
+            return syntax_error("malformed extern");
+        };
+        let name = self.parse_identifier(name);
+        if name.to_string().contains('-') {
+            syntax_error(format!("extern {name} is not a C identifier"))
         }
+        if !(0..=6).contains(arity) {
+            syntax_error(format!("extern {name} takes at most 6 arguments"))
+        }
+        if self
+            .externs
+            .insert(name.to_string(), *arity as usize)
+            .is_some()
+        {
+            syntax_error(format!("duplicate extern {name}"))
+        }
+        (name, *arity as usize)
+    }
+
+    /// `(export f1 .. fn)` exports functions of the module to C, with the C types of their
+    /// annotations. `exported` are the C names of the functions exported so far.
+    fn parse_export(
+        &self,
+        e: &Sexp,
+        funs: &[FunDecl],
+        exported: &mut HashSet<String>,
+    ) -> Vec<Symbol> {
+        let Sexp::List(es, _) = e else {
+            return syntax_error("expected a list");
+        };
+        es[1..]
+            .iter()
+            .map(|name| {
+                let name = self.parse_identifier(name);
+                let fun = self.qualify(name);
+                let Some(fun) = funs.iter().find(|f| f.name == fun) else {
+                    return syntax_error(format!("export of undefined function {name}"));
+                };
+                if fun.params.len() > 6 {
+                    syntax_error(format!(
+                        "exported function {name} takes more than 6 arguments"
+                    ))
+                }
+                for ty in fun.param_tys.iter().chain(&fun.ret_ty) {
+                    if export::c_type(Some(ty)).is_none() {
+                        syntax_error(format!(
+                            "exported function {name} uses type {ty}, which C can't represent"
+                        ))
+                    }
+                }
+                if !exported.insert(export::c_name(fun.name)) {
+                    syntax_error(format!("duplicate export {}", export::c_name(fun.name)))
+                }
+                fun.name
+            })
+            .collect()
+    }
+
+    /// `(test "name" e1 .. en)` evaluates its body with the tests of the program, see `runner`.
+    fn parse_test(&self, e: &Sexp) -> (String, Expr) {
+        let Sexp::List(es, _) = e else {
+            return syntax_error("expected a list");
+        };
+        let [_, Sexp::Atom(Str(name)), body @ ..] = &es[..] else {
+            return syntax_error("malformed test");
+        };
+        let body = match body {
+            [] => syntax_error(format!("test {name:?} has no body")),
+            [e] => self.parse_expr(e),
+            es => Expr::Block(es.iter().map(|e| self.parse_expr(e)).collect()),
+        };
+        (name.clone(), body)
     }
 
+    /// `(import "path")` makes the definitions of a module available as `module/name`, and
+    /// `(import "path" as alias)` as `alias/name`. A list of names at the end also makes those
+    /// available unqualified: naming a struct imports its functions and naming an enum imports its
+    /// variants.
+    fn parse_import<'a>(
+        &mut self,
+        e: &Sexp,
+        interfaces: &'a HashMap<String, Interface>,
+    ) -> &'a Interface {
+        let Sexp::List(es, _) = e else {
+            return syntax_error("expected a list");
+        };
+        let (path, alias, names) = match &es[..] {
+            [_, Sexp::Atom(Str(path))] => (path, None, &[][..]),
+            [_, Sexp::Atom(Str(path)), Sexp::List(names, _)] => (path, None, &names[..]),
+            [_, Sexp::Atom(Str(path)), Sexp::Atom(S(keyword)), alias] if keyword == "as" => {
+                (path, Some(alias), &[][..])
+            }
+            [_, Sexp::Atom(Str(path)), Sexp::Atom(S(keyword)), alias, Sexp::List(names, _)]
+                if keyword == "as" =>
+            {
+                (path, Some(alias), &names[..])
+            }
+            _ => return syntax_error("malformed import"),
+        };
+        let interface = &interfaces[path];
+        let qualifier = match alias {
+            Some(alias) => self.parse_identifier(alias).to_string(),
+            None => interface.name.clone(),
+        };
+        for &(fun, _) in &interface.funs {
+            self.import_fun(format!("{qualifier}/{}", fun.unqualified()), fun);
+        }
+        for decl in &interface.structs {
+            self.add_struct_fns(decl, Some(&qualifier));
+            let sig = match interface
+                .enums
+                .iter()
+                .find(|(_, vs)| vs.contains(&decl.name))
+            {
+                Some((_, variants)) => variants
+                    .iter()
+                    .map(|v| {
+                        let decl = interface.structs.iter().find(|d| d.name == *v).unwrap();
+                        (decl.name, decl.fields.len())
+                    })
+                    .collect(),
+                None => vec![(decl.name, decl.fields.len())],
+            };
+            self.sigs.insert(decl.name, sig);
+        }
+        for name in names {
+            let name = self.parse_identifier(name);
+            let find_struct = |name: Symbol| {
+                interface
+                    .structs
+                    .iter()
+                    .find(|decl| decl.name.unqualified() == name.unqualified())
+            };
+            if let Some(&(fun, _)) = interface
+                .funs
+                .iter()
+                .find(|(f, _)| f.unqualified() == name.unqualified())
+            {
+                self.import_fun(name.to_string(), fun);
+            } else if let Some(decl) = find_struct(name) {
+                self.add_struct_fns(decl, None);
+            } else if let Some((_, variants)) = interface.enums.iter().find(|(e, _)| *e == name) {
+                for &variant in variants {
+                    self.add_struct_fns(find_struct(variant).unwrap(), None);
+                }
+            } else {
+                syntax_error(format!("module {} doesn't define {name}", interface.name))
+            }
+        }
+        interface
+    }
+
+    fn import_fun(&mut self, name: String, fun: Symbol) {
+        match self.funs.insert(name.clone(), fun) {
+            Some(prev) if prev != fun => {
+                syntax_error(format!("{name} refers to both {prev} and {fun}"))
+            }
+            _ => {}
+        }
+    }
+
+    /// The name of a definition of this module.
+    fn qualify(&self, name: Symbol) -> Symbol {
+        match &self.module {
+            Some(module) => Symbol::new(format!("{module}/{name}")),
+            None => name,
+        }
+    }
+
+    fn parse_struct(&self, e: &Sexp) -> StructDecl {
+        let Sexp::List(es, _) = e else {
+            return syntax_error("expected a list");
+        };
+        let [_, name, Sexp::List(fields, _)] = &es[..] else {
+            return syntax_error("malformed struct");
+        };
+        self.parse_struct_decl(name, fields)
+    }
+
+    /// `(enum Name (Variant field*)+)` declares a struct for each variant.
+    fn parse_enum(&self, e: &Sexp) -> (Symbol, Vec<StructDecl>) {
+        let Sexp::List(es, _) = e else {
+            return syntax_error("expected a list");
+        };
+        let [_, name, variants @ ..] = &es[..] else {
+            return syntax_error("malformed enum");
+        };
+        let name = self.parse_identifier(name);
+        if variants.is_empty() {
+            return syntax_error(format!("enum {name} must have at least one variant"));
+        }
+        let variants = variants
+            .iter()
+            .map(|variant| match variant {
+                Sexp::List(es, _) if !es.is_empty() => self.parse_struct_decl(&es[0], &es[1..]),
+                _ => syntax_error(format!("malformed variant of enum {name}")),
+            })
+            .collect();
+        (name, variants)
+    }
+
+    fn parse_struct_decl(&self, name: &Sexp, fields: &[Sexp]) -> StructDecl {
+        let name = self.parse_identifier(name);
+        let fields: Vec<_> = fields.iter().map(|e| self.parse_identifier(e)).collect();
+        for (i, field) in fields.iter().enumerate() {
+            if fields[..i].contains(field) {
+                syntax_error(format!("duplicate field {field} in struct {name}"))
+            }
+        }
+        StructDecl {
+            name: self.qualify(name),
+            fields,
+        }
+    }
+
+    /// Registers the constructor `Name`, the accessors `Name-field`, the setters
+    /// `set-Name-field!` and the predicate `Name?`, qualified as `qualifier/Name` etc. if given.
+    fn add_struct_fns(&mut self, decl: &StructDecl, qualifier: Option<&str>) {
+        let name = decl.name.unqualified();
+        let q = qualifier.map_or(String::new(), |q| format!("{q}/"));
+        let mut fns = vec![
+            (format!("{q}{name}"), StructFn::New(decl.fields.len())),
+            (format!("{q}{name}?"), StructFn::Is),
+        ];
+        for (i, field) in decl.fields.iter().enumerate() {
+            fns.push((format!("{q}{name}-{field}"), StructFn::Get(i)));
+            fns.push((format!("{q}set-{name}-{field}!"), StructFn::Set(i)));
+        }
+        for (fun, kind) in fns {
+            if self
+                .struct_fns
+                .insert(fun.clone(), (decl.name, kind))
+                .is_some()
+            {
+                syntax_error(format!("duplicate definition of {fun}"))
+            }
+        }
+    }
+
+    fn parse_struct_fn(&self, fun: &str, args: &[Sexp]) -> Expr {
+        let (name, kind) = self.struct_fns[fun];
+        let arity = match kind {
+            StructFn::New(n) => n,
+            StructFn::Get(_) | StructFn::Is => 1,
+            StructFn::Set(_) => 2,
+        };
+        if args.len() != arity {
+            return syntax_error(format!(
+                "`{fun}` takes {arity} arguments but {} were supplied",
+                args.len()
+            ));
+        }
+        let mut args: Vec<_> = args.iter().map(|e| self.parse_expr(e)).collect();
+        match kind {
+            StructFn::New(_) => Expr::StructNew(name, args),
+            StructFn::Get(i) => Expr::StructGet(name, i, Box::new(args.remove(0))),
+            StructFn::Set(i) => {
+                let val = args.pop().unwrap();
+                Expr::StructSet(name, i, Box::new(args.remove(0)), Box::new(val))
+            }
+            StructFn::Is => Expr::StructIs(name, Box::new(args.remove(0))),
+        }
+    }
+
+    /// Parses an expression, recording the position of compound expressions.
     fn parse_expr(&self, e: &Sexp) -> Expr {
+        let expr = self.parse_form(e);
+        match e {
+            Sexp::List(_, pos) => Expr::At(*pos, Box::new(expr)),
+            Sexp::Atom(_) => expr,
+        }
+    }
+
+    fn parse_form(&self, e: &Sexp) -> Expr {
         match e {
             &Sexp::Atom(I(n)) => {
-                if n >= -4611686018427387904 && n < 4611686018427387904 {
+                if (-4611686018427387904..4611686018427387904).contains(&n) {
                     Expr::Number(n)
                 } else {
-                    syntax_error("integer literal overflow")
+                    Expr::BigNum(n.to_string())
                 }
             }
+            Sexp::Atom(Big(n)) => Expr::BigNum(n.trim_start_matches('+').to_string()),
+            &Sexp::Atom(F(x)) => Expr::Float(x),
+            Sexp::Atom(Str(s)) => Expr::Str(s.clone()),
             Sexp::Atom(S(id)) => match id.as_str() {
                 "true" => Expr::Boolean(true),
                 "false" => Expr::Boolean(false),
@@ -55,7 +540,7 @@ impl Parser {
                     }
                 }
             },
-            Sexp::List(vec) => match &vec[..] {
+            Sexp::List(vec, _) => match &vec[..] {
                 // (snek-printstack)
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "snek-printstack" => {
                     if !es.is_empty() {
@@ -79,7 +564,7 @@ impl Parser {
                 }
                 // (make-vec size elem)
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "make-vec" => {
-                    let [size, elem] = &es[..] else {
+                    let [size, elem] = es else {
                         return syntax_error("malformed vec");
                     };
                     let size = self.parse_expr(size);
@@ -92,7 +577,7 @@ impl Parser {
                 }
                 // (vec-set! idx elem)
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "vec-set!" => {
-                    let [vec, size, elem] = &es[..] else {
+                    let [vec, size, elem] = es else {
                         return syntax_error("malformed vec-set!");
                     };
                     let vec = self.parse_expr(vec);
@@ -102,7 +587,7 @@ impl Parser {
                 }
                 // (vec-get idx elem)
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "vec-get" => {
-                    let [vec, idx] = &es[..] else {
+                    let [vec, idx] = es else {
                         return syntax_error("malformed vec-get");
                     };
                     let vec = self.parse_expr(vec);
@@ -111,29 +596,60 @@ impl Parser {
                 }
                 // (vec-len vec)
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "vec-len" => {
-                    let [vec] = &es[..] else {
+                    let [vec] = es else {
                         return syntax_error("malformed vec-len");
                     };
                     let vec = self.parse_expr(vec);
                     Expr::VecLen(Box::new(vec))
                 }
                 // Block
+                // Functions defined in a block can be called anywhere in it
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "block" => {
-                    let es: Vec<_> = es.iter().map(|e| self.parse_expr(e)).collect();
-                    if !es.is_empty() {
-                        Expr::Block(es)
-                    } else {
-                        syntax_error("blocks must contain at least one expression")
+                    let (defs, es): (Vec<_>, Vec<_>) = es.iter().partition(|e| is_decl(e, "fun"));
+                    self.parse_local_funs(&defs, || {
+                        let es: Vec<_> = es.iter().map(|e| self.parse_expr(e)).collect();
+                        if !es.is_empty() {
+                            Expr::Block(es)
+                        } else {
+                            syntax_error("blocks must contain at least one expression")
+                        }
+                    })
+                }
+
+                // (letrec ((fun (f x ..) body) ..) body), the functions can call each other
+                [Sexp::Atom(S(keyword)), es @ ..] if keyword == "letrec" => {
+                    let [Sexp::List(defs, _), body] = es else {
+                        return syntax_error("malformed letrec");
+                    };
+                    if defs.iter().any(|e| !is_decl(e, "fun")) {
+                        return syntax_error("letrec binds function definitions");
                     }
+                    let defs: Vec<_> = defs.iter().collect();
+                    self.parse_local_funs(&defs, || self.parse_expr(body))
+                }
+
+                // (let* <bindings> <expr>), a binding may shadow the previous ones
+                [Sexp::Atom(S(keyword)), es @ ..] if keyword == "let*" => {
+                    let [Sexp::List(bindings, _), body] = es else {
+                        return syntax_error("malformed let*");
+                    };
+                    if bindings.is_empty() {
+                        return syntax_error("empty bindings");
+                    }
+                    let bindings: Vec<_> = bindings.iter().map(|e| self.parse_binding(e)).collect();
+                    let body = self.parse_expr(body);
+                    bindings.into_iter().rev().fold(body, |body, binding| {
+                        Expr::Let(vec![binding], Box::new(body))
+                    })
                 }
 
                 // (let <bindings> <expr>)
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "let" => {
-                    let [e1, e2] = &es[..] else {
+                    let [e1, e2] = es else {
                         return syntax_error("malformed let");
                     };
                     match e1 {
-                        Sexp::List(bindings) => {
+                        Sexp::List(bindings, _) => {
                             if bindings.is_empty() {
                                 return syntax_error("empty bindings");
                             }
@@ -154,7 +670,7 @@ impl Parser {
 
                 // if <expr> <expr> <expr> => If
                 [Sexp::Atom(S(keyword)), es @ ..] if keyword == "if" => {
-                    let [e1, e2, e3] = &es[..] else {
+                    let [e1, e2, e3] = es else {
                         syntax_error("malformed if")
                     };
                     let e1 = self.parse_expr(e1);
@@ -164,10 +680,85 @@ impl Parser {
                     Expr::If(Box::new(e1), Box::new(e2), Box::new(e3))
                 }
 
+                // Loops may start with a label: (loop 'outer e), (while 'outer cond body..), ..
+                [Sexp::Atom(S(keyword)), es @ ..]
+                    if matches!(&keyword[..], "loop" | "while" | "for" | "for-each") =>
+                {
+                    let (label, es) = self.parse_label(es);
+                    self.parse_loop(keyword, label, es)
+                }
+
+                // (break), (break e), (break 'label) or (break 'label e)
+                [Sexp::Atom(S(keyword)), es @ ..] if keyword == "break" => {
+                    let (label, es) = self.parse_label(es);
+                    let e = match es {
+                        [] => Expr::Nil,
+                        [e] => self.parse_expr(e),
+                        _ => return syntax_error("malformed break"),
+                    };
+                    Expr::Break(label, Box::new(e))
+                }
+
+                // (assert cond msg) reports `cond` as it was written when it doesn't hold
+                [Sexp::Atom(S(keyword)), es @ ..] if keyword == "assert" => {
+                    let [cond, msg] = es else {
+                        return syntax_error("malformed assert");
+                    };
+                    Expr::If(
+                        Box::new(self.parse_expr(cond)),
+                        Box::new(Expr::Nil),
+                        Box::new(Expr::AssertFailed(
+                            cond.to_string(),
+                            Box::new(self.parse_expr(msg)),
+                        )),
+                    )
+                }
+
+                // (raise e)
+                [Sexp::Atom(S(keyword)), es @ ..] if keyword == "raise" => {
+                    let [e] = es else {
+                        return syntax_error("malformed raise");
+                    };
+                    Expr::Raise(Box::new(self.parse_expr(e)))
+                }
+
+                // (try e (catch x handler))
+                [Sexp::Atom(S(keyword)), es @ ..] if keyword == "try" => {
+                    let [body, Sexp::List(clause, _)] = es else {
+                        return syntax_error("malformed try");
+                    };
+                    let [Sexp::Atom(S(catch)), x, handler] = &clause[..] else {
+                        return syntax_error("malformed catch");
+                    };
+                    if catch != "catch" {
+                        return syntax_error("expected catch after the body of try");
+                    }
+                    Expr::Try(
+                        Box::new(self.parse_expr(body)),
+                        self.parse_identifier(x),
+                        Box::new(self.parse_expr(handler)),
+                    )
+                }
+
+                // (continue) or (continue 'label)
+                [Sexp::Atom(S(keyword)), es @ ..] if keyword == "continue" => {
+                    match self.parse_label(es) {
+                        (label, []) => Expr::Continue(label),
+                        _ => syntax_error("malformed continue"),
+                    }
+                }
+
                 [Sexp::Atom(S(keyword)), es @ ..]
                     if matches!(
                         &keyword[..],
-                        "loop" | "break" | "add1" | "sub1" | "isnum" | "isbool" | "isvec" | "print"
+                        "add1"
+                            | "sub1"
+                            | "isnum"
+                            | "isbool"
+                            | "isvec"
+                            | "isstring"
+                            | "isfloat"
+                            | "print"
                     ) =>
                 {
                     let [e] = es else {
@@ -176,14 +767,14 @@ impl Parser {
                     let e_expr = self.parse_expr(e);
 
                     match keyword.as_str() {
-                        "loop" => Expr::Loop(Box::new(e_expr)),
-                        "break" => Expr::Break(Box::new(e_expr)),
                         "print" => Expr::UnOp(Op1::Print, Box::new(e_expr)),
                         "add1" => Expr::UnOp(Op1::Add1, Box::new(e_expr)),
                         "sub1" => Expr::UnOp(Op1::Sub1, Box::new(e_expr)),
                         "isnum" => Expr::UnOp(Op1::IsNum, Box::new(e_expr)),
                         "isbool" => Expr::UnOp(Op1::IsBool, Box::new(e_expr)),
                         "isvec" => Expr::UnOp(Op1::IsVec, Box::new(e_expr)),
+                        "isstring" => Expr::UnOp(Op1::IsString, Box::new(e_expr)),
+                        "isfloat" => Expr::UnOp(Op1::IsFloat, Box::new(e_expr)),
                         _ => unreachable!(),
                     }
                 }
@@ -191,7 +782,17 @@ impl Parser {
                 [Sexp::Atom(S(op)), es @ ..]
                     if matches!(
                         op.as_str(),
-                        "+" | "-" | "*" | "/" | ">" | "<" | ">=" | "<=" | "="
+                        "+" | "-"
+                            | "*"
+                            | "/"
+                            | "%"
+                            | "remainder"
+                            | "modulo"
+                            | ">"
+                            | "<"
+                            | ">="
+                            | "<="
+                            | "="
                     ) =>
                 {
                     let [e1, e2] = es else {
@@ -202,6 +803,8 @@ impl Parser {
                         "-" => Op2::Minus,
                         "*" => Op2::Times,
                         "/" => Op2::Divide,
+                        "%" | "remainder" => Op2::Remainder,
+                        "modulo" => Op2::Modulo,
                         ">" => Op2::Greater,
                         "<" => Op2::Less,
                         ">=" => Op2::GreaterEqual,
@@ -216,6 +819,97 @@ impl Parser {
                     Expr::BinOp(expr_op, Box::new(e1_instrs), Box::new(e2_instrs))
                 }
 
+                // (and e*), (or e*)
+                [Sexp::Atom(S(keyword)), es @ ..] if keyword == "and" || keyword == "or" => {
+                    let es: Vec<_> = es.iter().map(|e| self.parse_expr(e)).collect();
+                    if keyword == "and" {
+                        lower_and(es)
+                    } else {
+                        self.lower_or(es)
+                    }
+                }
+
+                // (not e)
+                [Sexp::Atom(S(keyword)), es @ ..] if keyword == "not" => {
+                    let [e] = es else {
+                        return syntax_error("malformed not");
+                    };
+                    let e = self.parse_expr(e);
+                    Expr::If(
+                        Box::new(e),
+                        Box::new(Expr::Boolean(false)),
+                        Box::new(Expr::Boolean(true)),
+                    )
+                }
+
+                // (when <expr> <expr>+), (unless <expr> <expr>+)
+                [Sexp::Atom(S(keyword)), es @ ..] if keyword == "when" || keyword == "unless" => {
+                    let [cond, body @ ..] = es else {
+                        return syntax_error(format!("malformed {keyword}"));
+                    };
+                    let cond = self.parse_expr(cond);
+                    let body = self.parse_body(body, keyword);
+                    let (thn, els) = if keyword == "when" {
+                        (body, Expr::Nil)
+                    } else {
+                        (Expr::Nil, body)
+                    };
+                    Expr::If(Box::new(cond), Box::new(thn), Box::new(els))
+                }
+
+                // (cond (<expr> <expr>+)* (else <expr>+)?)
+                [Sexp::Atom(S(keyword)), clauses @ ..] if keyword == "cond" => {
+                    self.parse_cond(clauses)
+                }
+
+                // (match <expr> <clause>+)
+                [Sexp::Atom(S(keyword)), scrutinee, clauses @ ..] if keyword == "match" => {
+                    self.parse_match(scrutinee, clauses)
+                }
+
+                // Local functions shadow the other functions
+                [Sexp::Atom(S(fun)), args @ ..] if self.local_fun(fun).is_some() => {
+                    let exprs = args.iter().map(|e| self.parse_expr(e)).collect();
+                    Expr::Call(self.local_fun(fun).unwrap(), exprs)
+                }
+
+                // (Name e*), (Name-field e), (set-Name-field! e e), (Name? e)
+                [Sexp::Atom(S(fun)), es @ ..] if self.struct_fns.contains_key(fun) => {
+                    self.parse_struct_fn(fun, es)
+                }
+
+                // Functions of this module and imported ones
+                [Sexp::Atom(S(fun)), args @ ..] if self.funs.contains_key(fun) => {
+                    let exprs = args.iter().map(|e| self.parse_expr(e)).collect();
+                    Expr::Call(self.funs[fun], exprs)
+                }
+
+                // C functions
+                [Sexp::Atom(S(fun)), args @ ..] if self.externs.contains_key(fun) => {
+                    if args.len() != self.externs[fun] {
+                        return syntax_error(format!(
+                            "extern {fun} takes {} arguments but {} were supplied",
+                            self.externs[fun],
+                            args.len()
+                        ));
+                    }
+                    let exprs = args.iter().map(|e| self.parse_expr(e)).collect();
+                    Expr::CallExtern(Symbol::new(fun), exprs)
+                }
+
+                // (string-append s1 s2), (substring s start end), ...
+                [Sexp::Atom(S(name)), es @ ..] if Prim::from_name(name).is_some() => {
+                    let prim = Prim::from_name(name).unwrap();
+                    if es.len() != prim.arity() {
+                        return syntax_error(format!(
+                            "`{name}` takes {} arguments but {} were supplied",
+                            prim.arity(),
+                            es.len()
+                        ));
+                    }
+                    Expr::Prim(prim, es.iter().map(|e| self.parse_expr(e)).collect())
+                }
+
                 [func, args @ ..] => {
                     let func = self.parse_identifier(func);
                     let exprs: Vec<_> = args.iter().map(|e| self.parse_expr(e)).collect();
@@ -223,46 +917,423 @@ impl Parser {
                 }
                 _ => syntax_error("unexpected s-expr"),
             },
+        }
+    }
 
-            _ => syntax_error("unexpected s-expr"),
+    /// Lowers `cond` to nested `if`s. Without an `else` clause the result is `nil` when no test
+    /// succeeds.
+    fn parse_cond(&self, clauses: &[Sexp]) -> Expr {
+        let mut tests = vec![];
+        let mut otherwise = Expr::Nil;
+        for (i, clause) in clauses.iter().enumerate() {
+            let Sexp::List(clause, _) = clause else {
+                return syntax_error("malformed cond clause");
+            };
+            match &clause[..] {
+                [Sexp::Atom(S(keyword)), body @ ..] if keyword == "else" => {
+                    if i != clauses.len() - 1 {
+                        return syntax_error("else must be the last cond clause");
+                    }
+                    otherwise = self.parse_body(body, "cond clause");
+                }
+                [test, body @ ..] => {
+                    tests.push((self.parse_expr(test), self.parse_body(body, "cond clause")))
+                }
+                [] => return syntax_error("malformed cond clause"),
+            }
         }
+        tests.into_iter().rev().fold(otherwise, |els, (test, thn)| {
+            Expr::If(Box::new(test), Box::new(thn), Box::new(els))
+        })
     }
 
-    fn parse_binding(&self, e: &Sexp) -> (Symbol, Expr) {
-        let Sexp::List(es) = e else {
-            return syntax_error("expected a list")
+    /// Lowers `match` to tests on the value bound to a temporary, and warns when some value isn't
+    /// matched by any of the clauses without a guard.
+    fn parse_match(&self, scrutinee: &Sexp, clauses: &[Sexp]) -> Expr {
+        if clauses.is_empty() {
+            return syntax_error("match must contain at least one clause");
+        }
+        let clauses: Vec<_> = clauses.iter().map(|e| self.parse_clause(e)).collect();
+        let patterns: Vec<_> = clauses
+            .iter()
+            .filter(|clause| clause.guard.is_none())
+            .map(|clause| clause.pattern.clone())
+            .collect();
+        if let Some(missing) = pattern::uncovered(&patterns, &self.sigs) {
+            eprintln!(
+                "warning: match on `{scrutinee}` is not exhaustive: {missing} is not matched"
+            );
+        }
+        let tmp = self.fresh_tmp("match");
+        Expr::Let(
+            vec![(tmp, self.parse_expr(scrutinee))],
+            Box::new(pattern::lower_match(tmp, clauses)),
+        )
+    }
+
+    fn parse_clause(&self, e: &Sexp) -> Clause {
+        let Sexp::List(es, _) = e else {
+            return syntax_error("malformed match clause");
         };
-        if let [name, expr] = &es[..] {
-            (self.parse_identifier(name), self.parse_expr(expr))
-        } else {
-            syntax_error("malformed binding")
+        let [pattern, rest @ ..] = &es[..] else {
+            return syntax_error("malformed match clause");
+        };
+        let pattern = self.parse_pattern(pattern, &mut vec![]);
+        match rest {
+            [Sexp::Atom(S(keyword)), guard, body @ ..] if keyword == "when" => Clause {
+                pattern,
+                guard: Some(self.parse_expr(guard)),
+                body: self.parse_body(body, "match clause"),
+            },
+            body => Clause {
+                pattern,
+                guard: None,
+                body: self.parse_body(body, "match clause"),
+            },
+        }
+    }
+
+    /// Parses a pattern, adding the variables it binds to `vars`.
+    fn parse_pattern(&self, e: &Sexp, vars: &mut Vec<Symbol>) -> Pattern {
+        match e {
+            Sexp::Atom(S(id)) => match id.as_str() {
+                "_" => Pattern::Wildcard,
+                "true" => Pattern::Boolean(true),
+                "false" => Pattern::Boolean(false),
+                "nil" => Pattern::Nil,
+                _ => {
+                    let x = self.parse_identifier(e);
+                    if vars.contains(&x) {
+                        syntax_error(format!("variable {x} is bound twice in pattern"))
+                    }
+                    vars.push(x);
+                    Pattern::Var(x)
+                }
+            },
+            Sexp::Atom(I(_) | Big(_)) => match self.parse_expr(e) {
+                Expr::Number(n) => Pattern::Number(n),
+                _ => syntax_error("integer literal overflow"),
+            },
+            Sexp::Atom(Str(s)) => Pattern::Str(s.clone()),
+            Sexp::List(es, _) => match &es[..] {
+                [Sexp::Atom(S(keyword)), elems @ ..] if keyword == "vec" => {
+                    Pattern::Vec(elems.iter().map(|e| self.parse_pattern(e, vars)).collect())
+                }
+                [Sexp::Atom(S(name)), fields @ ..] => match self.struct_fns.get(name) {
+                    Some(&(name, StructFn::New(arity))) => {
+                        if fields.len() != arity {
+                            return syntax_error(format!(
+                                "pattern `{name}` takes {arity} fields but {} were supplied",
+                                fields.len()
+                            ));
+                        }
+                        let fields = fields.iter().map(|e| self.parse_pattern(e, vars));
+                        Pattern::Variant(name, fields.collect())
+                    }
+                    _ => syntax_error(format!("invalid pattern {e}")),
+                },
+                _ => syntax_error(format!("invalid pattern {e}")),
+            },
+            _ => syntax_error(format!("invalid pattern {e}")),
+        }
+    }
+
+    /// A sequence of one or more expressions evaluated in order.
+    fn parse_body(&self, es: &[Sexp], what: &str) -> Expr {
+        let mut es: Vec<_> = es.iter().map(|e| self.parse_expr(e)).collect();
+        match es.len() {
+            0 => syntax_error(format!("{what} must contain at least one expression")),
+            1 => es.pop().unwrap(),
+            _ => Expr::Block(es),
+        }
+    }
+
+    /// Splits the label off the operands of a loop, `break` or `continue`.
+    fn parse_label<'a>(&self, es: &'a [Sexp]) -> (Option<Symbol>, &'a [Sexp]) {
+        match es {
+            [Sexp::Atom(S(label)), rest @ ..] if label.starts_with('\'') => {
+                if !self.id_regex.is_match(&label[1..]) {
+                    syntax_error(format!("invalid loop label {label}"))
+                }
+                (Some(Symbol::new(label)), rest)
+            }
+            _ => (None, es),
+        }
+    }
+
+    /// `while`, `for` and `for-each` are lowered to `loop`. Their bodies run after the test and
+    /// the update of the loop variables, so `continue` just restarts the loop.
+    fn parse_loop(&self, keyword: &str, label: Option<Symbol>, es: &[Sexp]) -> Expr {
+        let exit_when = |test: Expr| {
+            Expr::If(
+                Box::new(test),
+                Box::new(Expr::Break(None, Box::new(Expr::Nil))),
+                Box::new(Expr::Nil),
+            )
+        };
+        let var = |x: Symbol| Box::new(Expr::Var(x));
+        match (keyword, es) {
+            ("loop", [e]) => Expr::Loop(label, Box::new(self.parse_expr(e))),
+            ("loop", _) => syntax_error("expected a single expression after keyword"),
+            // (while cond body..)
+            ("while", [cond, body @ ..]) => {
+                let cond = self.parse_expr(cond);
+                let test = Expr::If(
+                    Box::new(cond),
+                    Box::new(Expr::Boolean(false)),
+                    Box::new(Expr::Boolean(true)),
+                );
+                let body = self.parse_body(body, "while");
+                Expr::Loop(label, Box::new(Expr::Block(vec![exit_when(test), body])))
+            }
+            // (for (x start end) body..) or (for (x start end step) body..) counts from `start`
+            // up to `end` excluded, or down to it if `step` is negative
+            ("for", [Sexp::List(header, _), body @ ..]) => {
+                let (x, start, end, step) = match &header[..] {
+                    [x, start, end] => (x, start, end, None),
+                    [x, start, end, step] => (x, start, end, Some(step)),
+                    _ => return syntax_error("malformed for"),
+                };
+                let x = self.parse_identifier(x);
+                let next = self.fresh_tmp("next");
+                let end_var = self.fresh_tmp("end");
+                let step_var = self.fresh_tmp("step");
+                let past_end = |op| Expr::BinOp(op, var(next), var(end_var));
+                let test = match step {
+                    None => past_end(Op2::GreaterEqual),
+                    Some(&Sexp::Atom(I(n))) if n > 0 => past_end(Op2::GreaterEqual),
+                    Some(&Sexp::Atom(I(n))) if n < 0 => past_end(Op2::LessEqual),
+                    Some(&Sexp::Atom(I(_))) => {
+                        return syntax_error("the step of a for loop can't be 0")
+                    }
+                    Some(_) => Expr::If(
+                        Box::new(Expr::BinOp(
+                            Op2::Greater,
+                            var(step_var),
+                            Box::new(Expr::Number(0)),
+                        )),
+                        Box::new(past_end(Op2::GreaterEqual)),
+                        Box::new(past_end(Op2::LessEqual)),
+                    ),
+                };
+                let step = step.map_or(Expr::Number(1), |step| self.parse_expr(step));
+                let update = Expr::Set(
+                    next,
+                    Box::new(Expr::BinOp(Op2::Plus, var(next), var(step_var))),
+                );
+                let body = Expr::Let(
+                    vec![(x, Expr::Var(next))],
+                    Box::new(Expr::Block(vec![update, self.parse_body(body, "for")])),
+                );
+                Expr::Let(
+                    vec![
+                        (next, self.parse_expr(start)),
+                        (end_var, self.parse_expr(end)),
+                        (step_var, step),
+                    ],
+                    Box::new(Expr::Loop(
+                        label,
+                        Box::new(Expr::Block(vec![exit_when(test), body])),
+                    )),
+                )
+            }
+            // (for-each (x vec) body..)
+            ("for-each", [Sexp::List(header, _), body @ ..]) => {
+                let [x, vec] = &header[..] else {
+                    return syntax_error("malformed for-each");
+                };
+                let x = self.parse_identifier(x);
+                let vec_var = self.fresh_tmp("vec");
+                let idx = self.fresh_tmp("idx");
+                let test = Expr::BinOp(
+                    Op2::GreaterEqual,
+                    var(idx),
+                    Box::new(Expr::VecLen(var(vec_var))),
+                );
+                let update = Expr::Set(idx, Box::new(Expr::UnOp(Op1::Add1, var(idx))));
+                let body = Expr::Let(
+                    vec![(x, Expr::VecGet(var(vec_var), var(idx)))],
+                    Box::new(Expr::Block(vec![update, self.parse_body(body, "for-each")])),
+                );
+                Expr::Let(
+                    vec![(vec_var, self.parse_expr(vec)), (idx, Expr::Number(0))],
+                    Box::new(Expr::Loop(
+                        label,
+                        Box::new(Expr::Block(vec![exit_when(test), body])),
+                    )),
+                )
+            }
+            _ => syntax_error(format!("malformed {keyword}")),
+        }
+    }
+
+    /// `(or e1 e2 ..)` evaluates to the first operand that isn't `false`, without evaluating the
+    /// rest. Each operand is bound to a temporary so it's only evaluated once.
+    fn lower_or(&self, mut es: Vec<Expr>) -> Expr {
+        let Some(last) = es.pop() else {
+            return Expr::Boolean(false);
+        };
+        es.into_iter().rev().fold(last, |rest, e| {
+            let tmp = self.fresh_tmp("or");
+            Expr::Let(
+                vec![(tmp, e)],
+                Box::new(Expr::If(
+                    Box::new(Expr::Var(tmp)),
+                    Box::new(Expr::Var(tmp)),
+                    Box::new(rest),
+                )),
+            )
+        })
+    }
+
+    /// Parses the functions defined by `defs` and then `body`, where they are in scope. The functions
+    /// are named after the function defining them and lifted to the top level later.
+    fn parse_local_funs(&self, defs: &[&Sexp], body: impl FnOnce() -> Expr) -> Expr {
+        if defs.is_empty() {
+            return body();
+        }
+        let scope = self.locals.borrow().len();
+        let mut names = vec![];
+        for e in defs {
+            let Some(name) = fun_name(e) else {
+                return syntax_error("missing function name");
+            };
+            let name = self.parse_identifier(name);
+            if names.contains(&name) {
+                syntax_error(format!("duplicate function name {name}"))
+            }
+            names.push(name);
+            let lifted = self.lifted_name(name);
+            self.locals.borrow_mut().push((name, lifted));
         }
+        for (i, (e, name)) in defs.iter().zip(names).enumerate() {
+            let lifted = self.locals.borrow()[scope + i].1;
+            let decl = self.parse_func(e, |_| lifted);
+            self.lifted.borrow_mut().push(LocalFun { name, decl });
+        }
+        let body = body();
+        self.locals.borrow_mut().truncate(scope);
+        body
+    }
+
+    /// The local function called `name` in the current scope.
+    fn local_fun(&self, name: &str) -> Option<Symbol> {
+        let locals = self.locals.borrow();
+        locals
+            .iter()
+            .rev()
+            .find(|(f, _)| f.to_string() == name)
+            .map(|&(_, f)| f)
     }
 
-    fn parse_func(&self, e: &Sexp) -> FunDecl {
-        let Sexp::List(es) = e else {
+    /// `outer.name`, with a number if `outer` defines several functions called `name`.
+    fn lifted_name(&self, name: Symbol) -> Symbol {
+        let outer = self.enclosing.get().unwrap_or(Symbol::new("main"));
+        let mut names = self.lifted_names.borrow_mut();
+        let mut lifted = Symbol::new(format!("{outer}.{name}"));
+        let mut n = 1;
+        while !names.insert(lifted) {
+            n += 1;
+            lifted = Symbol::new(format!("{outer}.{name}.{n}"));
+        }
+        lifted
+    }
+
+    fn fresh_tmp(&self, prefix: &str) -> Symbol {
+        let id = self.next_tmp.get();
+        self.next_tmp.set(id + 1);
+        // `#` is not valid in identifiers so the name can't clash with user variables
+        Symbol::new(format!("{prefix}#{id}"))
+    }
+
+    /// `(x e)` or `(x : T e)`
+    fn parse_binding(&self, e: &Sexp) -> (Symbol, Expr) {
+        let Sexp::List(es, _) = e else {
             return syntax_error("expected a list");
         };
         match &es[..] {
-            [Sexp::Atom(S(keyword)), Sexp::List(es), body] if keyword == "fun" => {
-                let [name, params @ ..] = &es[..] else {
-                    return syntax_error("missing function name");
-                };
-                let params = params.iter().map(|e| self.parse_identifier(e)).collect();
-                let body = self.parse_expr(body);
-                let name = self.parse_identifier(name);
-                FunDecl {
-                    name: Symbol::new(name),
-                    params,
-                    body,
+            [name, expr] => (self.parse_identifier(name), self.parse_expr(expr)),
+            [name, Sexp::Atom(S(colon)), ty, expr] if colon == ":" => {
+                let ty = self.parse_type(ty);
+                let expr = self.parse_expr(expr);
+                (self.parse_identifier(name), Expr::Annot(ty, Box::new(expr)))
+            }
+            _ => syntax_error("malformed binding"),
+        }
+    }
+
+    /// `x` or `(x : T)`
+    fn parse_param(&self, e: &Sexp) -> (Symbol, Type) {
+        match e {
+            Sexp::List(es, _) => match &es[..] {
+                [name, Sexp::Atom(S(colon)), ty] if colon == ":" => {
+                    (self.parse_identifier(name), self.parse_type(ty))
                 }
+                _ => syntax_error("malformed parameter"),
+            },
+            _ => (self.parse_identifier(e), Type::Any),
+        }
+    }
+
+    /// `num`, `float`, `bool`, `nil`, `str`, `map`, `any` or `(vec T)`
+    fn parse_type(&self, e: &Sexp) -> Type {
+        match e {
+            Sexp::Atom(S(name)) => match name.as_str() {
+                "num" => Type::Num,
+                "float" => Type::Float,
+                "bool" => Type::Bool,
+                "nil" => Type::Nil,
+                "str" => Type::Str,
+                "map" => Type::Map,
+                "any" => Type::Any,
+                _ => syntax_error(format!("unknown type {name}")),
+            },
+            Sexp::List(es, _) => match &es[..] {
+                [Sexp::Atom(S(keyword)), elem] if keyword == "vec" => {
+                    Type::Vec(Box::new(self.parse_type(elem)))
+                }
+                _ => syntax_error(format!("unknown type {e}")),
+            },
+            _ => syntax_error(format!("unknown type {e}")),
+        }
+    }
+
+    /// `canonical` gives the canonical name of the function from the name it is defined with.
+    fn parse_func(&self, e: &Sexp, canonical: impl FnOnce(Symbol) -> Symbol) -> FunDecl {
+        let Sexp::List(es, _) = e else {
+            return syntax_error("expected a list");
+        };
+        let (es, body, ret_ty) = match &es[..] {
+            [Sexp::Atom(S(keyword)), Sexp::List(es, _), body] if keyword == "fun" => {
+                (es, body, None)
             }
-            _ => syntax_error("malformed function"),
+            // (fun (name params..) : T body)
+            [Sexp::Atom(S(keyword)), Sexp::List(es, _), Sexp::Atom(S(colon)), ty, body]
+                if keyword == "fun" && colon == ":" =>
+            {
+                (es, body, Some(self.parse_type(ty)))
+            }
+            _ => return syntax_error("malformed function"),
+        };
+        let [name, params @ ..] = &es[..] else {
+            return syntax_error("missing function name");
+        };
+        let (params, param_tys) = params.iter().map(|e| self.parse_param(e)).unzip();
+        let name = canonical(self.parse_identifier(name));
+        let outer = self.enclosing.replace(Some(name));
+        let body = self.parse_expr(body);
+        self.enclosing.set(outer);
+        FunDecl {
+            name,
+            params,
+            param_tys,
+            ret_ty,
+            body,
         }
     }
 
     fn parse_identifier(&self, e: &Sexp) -> Symbol {
-        let Sexp::Atom(S(s)) = e  else {
+        let Sexp::Atom(S(s)) = e else {
             return syntax_error("expected an identifier");
         };
 
@@ -276,7 +1347,52 @@ impl Parser {
     }
 }
 
-fn is_keyword(s: &str) -> bool {
+/// Whether `e` is a form starting with `keyword`.
+fn is_decl(e: &Sexp, keyword: &str) -> bool {
+    matches!(e, Sexp::List(es, _) if matches!(es.first(), Some(Sexp::Atom(S(k))) if k == keyword))
+}
+
+fn is_type_decl(e: &Sexp) -> bool {
+    is_decl(e, "struct") || is_decl(e, "enum")
+}
+
+/// `(test "name" ..)`, a call of a function named `test` isn't a declaration.
+fn is_test_decl(e: &Sexp) -> bool {
+    matches!(e, Sexp::List(es, _) if is_decl(e, "test") && matches!(es.get(1), Some(Sexp::Atom(Str(_)))))
+}
+
+fn is_any_decl(e: &Sexp) -> bool {
+    is_type_decl(e)
+        || is_test_decl(e)
+        || is_decl(e, "fun")
+        || is_decl(e, "import")
+        || is_decl(e, "extern")
+        || is_decl(e, "export")
+}
+
+/// The name of a function declaration `(fun (name ..) ..)`.
+fn fun_name(e: &Sexp) -> Option<&Sexp> {
+    match e {
+        Sexp::List(es, _) => match &es[..] {
+            [_, Sexp::List(header, _), ..] => header.first(),
+            _ => None,
+        },
+        Sexp::Atom(_) => None,
+    }
+}
+
+/// `(and e1 e2 ..)` evaluates to `false` as soon as an operand is `false`, otherwise to the last
+/// operand.
+pub fn lower_and(mut es: Vec<Expr>) -> Expr {
+    let Some(last) = es.pop() else {
+        return Expr::Boolean(true);
+    };
+    es.into_iter().rev().fold(last, |rest, e| {
+        Expr::If(Box::new(e), Box::new(rest), Box::new(Expr::Boolean(false)))
+    })
+}
+
+pub fn is_keyword(s: &str) -> bool {
     matches!(
         s,
         "loop"
@@ -288,8 +1404,12 @@ fn is_keyword(s: &str) -> bool {
             | "isnum"
             | "isbool"
             | "isvec"
+            | "isstring"
+            | "isfloat"
             | "print"
             | "let"
+            | "let*"
+            | "letrec"
             | "set!"
             | "input"
             | "nil"
@@ -301,7 +1421,31 @@ fn is_keyword(s: &str) -> bool {
             | "vec-len"
             | "snek-printstack"
             | "gc"
-    )
+            | "cond"
+            | "else"
+            | "when"
+            | "unless"
+            | "and"
+            | "or"
+            | "not"
+            | "remainder"
+            | "modulo"
+            | "struct"
+            | "enum"
+            | "match"
+            | "import"
+            | "extern"
+            | "export"
+            | "define-syntax"
+            | "while"
+            | "for"
+            | "for-each"
+            | "continue"
+            | "raise"
+            | "try"
+            | "assert"
+            | "catch"
+    ) || Prim::from_name(s).is_some()
 }
 
 fn syntax_error<T>(note: impl ToString) -> T {
@@ -0,0 +1,333 @@
+use std::{collections::HashMap, fmt, iter};
+
+use crate::{
+    parser::lower_and,
+    syntax::{Expr, Op1, Op2, Prim, Symbol},
+};
+
+/// A pattern in a `match` clause.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// `_`
+    Wildcard,
+    /// An identifier matches anything and binds it
+    Var(Symbol),
+    Number(i64),
+    Boolean(bool),
+    Nil,
+    Str(String),
+    /// `(vec p1 .. pn)` matches vectors of exactly `n` elements
+    Vec(Vec<Pattern>),
+    /// `(Name p1 .. pn)` matches instances of the struct or enum variant `Name`
+    Variant(Symbol, Vec<Pattern>),
+}
+
+/// `(pattern body)` or `(pattern when guard body)`
+#[derive(Debug, Clone)]
+pub struct Clause {
+    pub pattern: Pattern,
+    pub guard: Option<Expr>,
+    pub body: Expr,
+}
+
+/// For each struct or enum variant, the variants of the type it belongs to and their number of
+/// fields. A struct is a type with a single variant.
+pub type Signatures = HashMap<Symbol, Vec<(Symbol, usize)>>;
+
+/// Lowers the clauses of a `match` on the value of `scrutinee` to a chain of `if`s. Each clause
+/// tests the tags of the value and the parts it loads from it, and binds its variables to those
+/// loads. A value no clause accepts is a runtime error.
+pub fn lower_match(scrutinee: Symbol, clauses: Vec<Clause>) -> Expr {
+    let otherwise = Expr::NoMatch(Box::new(Expr::Var(scrutinee)));
+    clauses.into_iter().rev().fold(otherwise, |rest, clause| {
+        let mut tests = vec![];
+        let mut bindings = vec![];
+        clause
+            .pattern
+            .lower(Expr::Var(scrutinee), &mut tests, &mut bindings);
+        if let Some(guard) = clause.guard {
+            tests.push(with_bindings(bindings.clone(), guard));
+        }
+        let body = with_bindings(bindings, clause.body);
+        if tests.is_empty() {
+            body
+        } else {
+            Expr::If(Box::new(lower_and(tests)), Box::new(body), Box::new(rest))
+        }
+    })
+}
+
+fn with_bindings(bindings: Vec<(Symbol, Expr)>, body: Expr) -> Expr {
+    if bindings.is_empty() {
+        body
+    } else {
+        Expr::Let(bindings, Box::new(body))
+    }
+}
+
+impl Pattern {
+    /// Collects the tests `val` must pass to match the pattern, in an order where each test is
+    /// safe to evaluate once the previous ones succeeded, and the variables to bind.
+    fn lower(self, val: Expr, tests: &mut Vec<Expr>, bindings: &mut Vec<(Symbol, Expr)>) {
+        let is = |op: Op1, val: &Expr| Expr::UnOp(op, Box::new(val.clone()));
+        let eq = |a: Expr, b: Expr| Expr::BinOp(Op2::Equal, Box::new(a), Box::new(b));
+        match self {
+            Pattern::Wildcard => {}
+            Pattern::Var(x) => bindings.push((x, val)),
+            Pattern::Number(n) => {
+                tests.push(is(Op1::IsNum, &val));
+                tests.push(eq(val, Expr::Number(n)));
+            }
+            Pattern::Boolean(b) => {
+                tests.push(is(Op1::IsBool, &val));
+                tests.push(eq(val, Expr::Boolean(b)));
+            }
+            Pattern::Nil => {
+                tests.push(is(Op1::IsVec, &val));
+                tests.push(eq(val, Expr::Nil));
+            }
+            Pattern::Str(s) => {
+                tests.push(is(Op1::IsString, &val));
+                tests.push(Expr::Prim(Prim::StringEq, vec![val, Expr::Str(s)]));
+            }
+            Pattern::Vec(elems) => {
+                tests.push(is(Op1::IsVec, &val));
+                tests.push(Expr::If(
+                    Box::new(eq(val.clone(), Expr::Nil)),
+                    Box::new(Expr::Boolean(false)),
+                    Box::new(Expr::Boolean(true)),
+                ));
+                tests.push(eq(
+                    Expr::VecLen(Box::new(val.clone())),
+                    Expr::Number(elems.len() as i64),
+                ));
+                for (i, elem) in elems.into_iter().enumerate() {
+                    let idx = Box::new(Expr::Number(i as i64));
+                    elem.lower(Expr::VecGet(Box::new(val.clone()), idx), tests, bindings);
+                }
+            }
+            Pattern::Variant(name, fields) => {
+                tests.push(Expr::StructIs(name, Box::new(val.clone())));
+                for (i, field) in fields.into_iter().enumerate() {
+                    field.lower(
+                        Expr::StructGet(name, i, Box::new(val.clone())),
+                        tests,
+                        bindings,
+                    );
+                }
+            }
+        }
+    }
+
+    /// The constructor of the values matched by the pattern and its sub-patterns, or `None` if it
+    /// matches anything.
+    fn ctor(&self) -> Option<(Ctor, &[Pattern])> {
+        match self {
+            Pattern::Wildcard | Pattern::Var(_) => None,
+            Pattern::Number(n) => Some((Ctor::Number(*n), &[])),
+            Pattern::Boolean(b) => Some((Ctor::Boolean(*b), &[])),
+            Pattern::Nil => Some((Ctor::Nil, &[])),
+            Pattern::Str(s) => Some((Ctor::Str(s.clone()), &[])),
+            Pattern::Vec(elems) => Some((Ctor::Vec(elems.len()), elems)),
+            Pattern::Variant(name, fields) => Some((Ctor::Variant(*name), fields)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Ctor {
+    Number(i64),
+    Boolean(bool),
+    Nil,
+    Str(String),
+    Vec(usize),
+    Variant(Symbol),
+}
+
+impl Ctor {
+    fn build(self, args: Vec<Pattern>) -> Pattern {
+        match self {
+            Ctor::Number(n) => Pattern::Number(n),
+            Ctor::Boolean(b) => Pattern::Boolean(b),
+            Ctor::Nil => Pattern::Nil,
+            Ctor::Str(s) => Pattern::Str(s),
+            Ctor::Vec(_) => Pattern::Vec(args),
+            Ctor::Variant(name) => Pattern::Variant(name, args),
+        }
+    }
+}
+
+/// Returns an example of a value that isn't matched by any of the patterns, if there's one.
+pub fn uncovered(patterns: &[Pattern], sigs: &Signatures) -> Option<Pattern> {
+    let rows: Vec<_> = patterns.iter().map(|p| vec![p.clone()]).collect();
+    uncovered_rows(&rows, 1, sigs).map(|mut values| values.remove(0))
+}
+
+/// Finds a sequence of `width` values that isn't matched by any row of patterns. This is the
+/// usefulness check of Maranget's "Warnings for pattern matching", returning a witness.
+fn uncovered_rows(rows: &[Vec<Pattern>], width: usize, sigs: &Signatures) -> Option<Vec<Pattern>> {
+    if width == 0 {
+        return rows.is_empty().then(Vec::new);
+    }
+    let mut heads: Vec<Ctor> = vec![];
+    for row in rows {
+        if let Some((ctor, _)) = row[0].ctor() {
+            if !heads.contains(&ctor) {
+                heads.push(ctor);
+            }
+        }
+    }
+    match complete_signature(&heads, sigs) {
+        // Every constructor appears, so the value must be missing below one of them
+        Some(all) => all.into_iter().find_map(|(ctor, arity)| {
+            let rows = specialize(rows, &ctor, arity);
+            let mut args = uncovered_rows(&rows, arity + width - 1, sigs)?;
+            let rest = args.split_off(arity);
+            Some(iter::once(ctor.build(args)).chain(rest).collect())
+        }),
+        // Some constructor is missing, so only rows starting with a wildcard can match it
+        None => {
+            let rows: Vec<_> = rows
+                .iter()
+                .filter(|row| row[0].ctor().is_none())
+                .map(|row| row[1..].to_vec())
+                .collect();
+            let rest = uncovered_rows(&rows, width - 1, sigs)?;
+            Some(iter::once(missing_ctor(&heads, sigs)).chain(rest).collect())
+        }
+    }
+}
+
+/// All the constructors of the type of `heads` if each of them appears in `heads`.
+fn complete_signature(heads: &[Ctor], sigs: &Signatures) -> Option<Vec<(Ctor, usize)>> {
+    let all = match heads.first()? {
+        Ctor::Boolean(_) => vec![(Ctor::Boolean(true), 0), (Ctor::Boolean(false), 0)],
+        Ctor::Variant(name) => sigs[name]
+            .iter()
+            .map(|(variant, arity)| (Ctor::Variant(*variant), *arity))
+            .collect(),
+        // Numbers, strings and vectors can't all be listed
+        _ => return None,
+    };
+    all.iter()
+        .all(|(ctor, _)| heads.contains(ctor))
+        .then_some(all)
+}
+
+/// A pattern for values whose constructor isn't in `heads`.
+fn missing_ctor(heads: &[Ctor], sigs: &Signatures) -> Pattern {
+    match heads.first() {
+        Some(Ctor::Boolean(b)) => Pattern::Boolean(!b),
+        Some(Ctor::Variant(name)) => {
+            let (variant, arity) = sigs[name]
+                .iter()
+                .find(|(variant, _)| !heads.contains(&Ctor::Variant(*variant)))
+                .unwrap();
+            Pattern::Variant(*variant, vec![Pattern::Wildcard; *arity])
+        }
+        _ => Pattern::Wildcard,
+    }
+}
+
+/// Keeps the rows that may match a value built with `ctor`, replacing their first pattern with
+/// the patterns for the `arity` arguments of the constructor.
+fn specialize(rows: &[Vec<Pattern>], ctor: &Ctor, arity: usize) -> Vec<Vec<Pattern>> {
+    rows.iter()
+        .filter_map(|row| {
+            let args = match row[0].ctor() {
+                Some((head, args)) if head == *ctor => args.to_vec(),
+                Some(_) => return None,
+                None => vec![Pattern::Wildcard; arity],
+            };
+            Some(args.into_iter().chain(row[1..].iter().cloned()).collect())
+        })
+        .collect()
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |f: &mut fmt::Formatter<'_>, head: &str, args: &[Pattern]| {
+            write!(f, "({head}")?;
+            for arg in args {
+                write!(f, " {arg}")?;
+            }
+            write!(f, ")")
+        };
+        match self {
+            Pattern::Wildcard => write!(f, "_"),
+            Pattern::Var(x) => write!(f, "{x}"),
+            Pattern::Number(n) => write!(f, "{n}"),
+            Pattern::Boolean(b) => write!(f, "{b}"),
+            Pattern::Nil => write!(f, "nil"),
+            Pattern::Str(s) => write!(f, "{s:?}"),
+            Pattern::Vec(elems) => list(f, "vec", elems),
+            Pattern::Variant(name, fields) => list(f, &name.to_string(), fields),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list_sigs() -> Signatures {
+        let list = vec![(Symbol::new("Nil"), 0), (Symbol::new("Cons"), 2)];
+        HashMap::from([
+            (Symbol::new("Nil"), list.clone()),
+            (Symbol::new("Cons"), list),
+        ])
+    }
+
+    fn nil() -> Pattern {
+        Pattern::Variant(Symbol::new("Nil"), vec![])
+    }
+
+    fn cons(head: Pattern, tail: Pattern) -> Pattern {
+        Pattern::Variant(Symbol::new("Cons"), vec![head, tail])
+    }
+
+    fn var(x: &str) -> Pattern {
+        Pattern::Var(Symbol::new(x))
+    }
+
+    #[test]
+    fn all_variants_are_exhaustive() {
+        let patterns = [nil(), cons(var("x"), var("xs"))];
+        assert!(uncovered(&patterns, &list_sigs()).is_none());
+    }
+
+    #[test]
+    fn reports_missing_variant() {
+        let patterns = [cons(var("x"), var("xs"))];
+        let missing = uncovered(&patterns, &list_sigs()).unwrap();
+        assert_eq!(missing.to_string(), "(Nil)");
+    }
+
+    #[test]
+    fn reports_missing_nested_variant() {
+        let patterns = [nil(), cons(var("x"), nil())];
+        let missing = uncovered(&patterns, &list_sigs()).unwrap();
+        assert_eq!(missing.to_string(), "(Cons _ (Cons _ _))");
+    }
+
+    #[test]
+    fn literals_need_a_catch_all() {
+        let patterns = [Pattern::Number(0), Pattern::Boolean(true)];
+        assert_eq!(uncovered(&patterns, &list_sigs()).unwrap().to_string(), "_");
+        let patterns = [Pattern::Number(0), Pattern::Wildcard];
+        assert!(uncovered(&patterns, &list_sigs()).is_none());
+    }
+
+    #[test]
+    fn booleans_are_finite() {
+        let patterns = [
+            nil(),
+            cons(Pattern::Boolean(true), Pattern::Wildcard),
+            cons(Pattern::Boolean(false), var("xs")),
+        ];
+        assert!(uncovered(&patterns, &list_sigs()).is_none());
+        let patterns = [nil(), cons(Pattern::Boolean(true), Pattern::Wildcard)];
+        let missing = uncovered(&patterns, &list_sigs()).unwrap();
+        assert_eq!(missing.to_string(), "(Cons false _)");
+    }
+}
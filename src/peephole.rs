@@ -0,0 +1,333 @@
+use crate::asm::{Arg32, Arg64, BinArgs, Instr, Loc, MemRef, MovArgs, Offset, Reg, Reg32};
+
+/// A rewrite rule. Given the instructions starting at some position it returns the number of
+/// instructions to replace and their replacement, or `None` if the rule doesn't apply.
+type Rule = fn(&[Instr]) -> Option<(usize, Vec<Instr>)>;
+
+const RULES: &[Rule] = &[self_move, store_load, dead_store, jump_to_next];
+
+/// Applies the peephole rules until none of them fires.
+pub fn optimize(mut instrs: Vec<Instr>) -> Vec<Instr> {
+    loop {
+        let (next, changed) = pass(&instrs);
+        if !changed {
+            return next;
+        }
+        instrs = next;
+    }
+}
+
+fn pass(instrs: &[Instr]) -> (Vec<Instr>, bool) {
+    let mut out = Vec::with_capacity(instrs.len());
+    let mut changed = false;
+    let mut i = 0;
+    'outer: while i < instrs.len() {
+        for rule in RULES {
+            if let Some((n, replacement)) = rule(&instrs[i..]) {
+                out.extend(replacement);
+                i += n;
+                changed = true;
+                continue 'outer;
+            }
+        }
+        out.push(instrs[i].clone());
+        i += 1;
+    }
+    (out, changed)
+}
+
+/// `mov reg, reg` does nothing.
+fn self_move(instrs: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    match instrs {
+        [Instr::Mov(MovArgs::ToReg(dst, Arg64::Reg(src))), ..] if dst == src => Some((1, vec![])),
+        _ => None,
+    }
+}
+
+/// A load from a slot right after storing to it can reuse the stored value.
+fn store_load(instrs: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    let [store @ Instr::Mov(MovArgs::ToMem(m1, val)), Instr::Mov(MovArgs::ToReg(reg, Arg64::Mem(m2))), ..] =
+        instrs
+    else {
+        return None;
+    };
+    if m1 != m2 {
+        return None;
+    }
+    let mut replacement = vec![store.clone()];
+    match val {
+        Reg32::Reg(src) if src == reg => {}
+        Reg32::Reg(src) => replacement.push(Instr::Mov(MovArgs::ToReg(*reg, Arg64::Reg(*src)))),
+        Reg32::Imm(n) => replacement.push(Instr::Mov(MovArgs::ToReg(*reg, Arg64::Imm(*n as i64)))),
+    }
+    Some((2, replacement))
+}
+
+/// A store to a stack slot that is overwritten before being read is useless. The search stops at
+/// any instruction that may transfer control (and thus let the garbage collector observe the
+/// slot) or change the frame pointer.
+fn dead_store(instrs: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    let [Instr::Mov(MovArgs::ToMem(slot, _)), rest @ ..] = instrs else {
+        return None;
+    };
+    if !is_stack_slot(slot) {
+        return None;
+    }
+    for instr in rest {
+        match instr {
+            Instr::Mov(MovArgs::ToMem(m, _)) if m == slot => return Some((1, vec![])),
+            Instr::Comment(_) => {}
+            _ if is_barrier(instr) || mentions(instr, slot) => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// A jump to a label that immediately follows it does nothing.
+fn jump_to_next(instrs: &[Instr]) -> Option<(usize, Vec<Instr>)> {
+    let [jump, rest @ ..] = instrs else {
+        return None;
+    };
+    let target = jump_target(jump)?;
+    for instr in rest {
+        match instr {
+            Instr::Label(lbl) if lbl == target => return Some((1, vec![])),
+            Instr::Label(_) | Instr::Comment(_) => {}
+            _ => return None,
+        }
+    }
+    None
+}
+
+fn jump_target(instr: &Instr) -> Option<&str> {
+    match instr {
+        Instr::Jmp(lbl)
+        | Instr::Je(lbl)
+        | Instr::Jne(lbl)
+        | Instr::Jl(lbl)
+        | Instr::Jle(lbl)
+        | Instr::Jg(lbl)
+        | Instr::Jge(lbl)
+        | Instr::Js(lbl)
+        | Instr::Jns(lbl)
+        | Instr::Jz(lbl)
+        | Instr::Jnz(lbl)
+        | Instr::Jo(lbl)
+        | Instr::Jno(lbl) => Some(lbl),
+        _ => None,
+    }
+}
+
+fn is_stack_slot(m: &MemRef) -> bool {
+    m.reg == Reg::Rbp && matches!(m.offset, Offset::Constant(_))
+}
+
+/// Instructions after which we can't reason locally about the contents of a stack slot.
+fn is_barrier(instr: &Instr) -> bool {
+    match instr {
+        Instr::Label(_) | Instr::Call(_) | Instr::Ret | Instr::Rep(_) => true,
+        Instr::Mov(MovArgs::ToReg(reg, _))
+        | Instr::Lea(reg, _)
+        | Instr::Add(BinArgs::ToReg(reg, _))
+        | Instr::Sub(BinArgs::ToReg(reg, _))
+            if *reg == Reg::Rbp =>
+        {
+            true
+        }
+        Instr::Pop(Loc::Reg(Reg::Rbp)) => true,
+        _ => jump_target(instr).is_some(),
+    }
+}
+
+/// Whether `instr` reads or writes the memory at `m` (or may do so through an aliasing address).
+fn mentions(instr: &Instr, m: &MemRef) -> bool {
+    let aliases = |other: &MemRef| other == m || (other.reg == Reg::Rbp && !is_stack_slot(other));
+    let arg32 = |arg: &Arg32| matches!(arg, Arg32::Mem(other) if aliases(other));
+    let bin = |args: &BinArgs| match args {
+        BinArgs::ToReg(_, arg) => arg32(arg),
+        BinArgs::ToMem(other, _) => aliases(other),
+    };
+    match instr {
+        Instr::Mov(MovArgs::ToReg(_, Arg64::Mem(other))) | Instr::Lea(_, other) => aliases(other),
+        Instr::Mov(MovArgs::ToMem(other, _)) => aliases(other),
+        Instr::Mov(MovArgs::ToReg(..)) => false,
+        Instr::CMov(_) => true,
+        Instr::Add(args)
+        | Instr::Sub(args)
+        | Instr::IMul(args)
+        | Instr::And(args)
+        | Instr::Or(args)
+        | Instr::Xor(args)
+        | Instr::Shr(args)
+        | Instr::Sar(args)
+        | Instr::Sal(args)
+        | Instr::Shl(args)
+        | Instr::Cmp(args)
+        | Instr::Test(args) => bin(args),
+        Instr::Push(arg) => arg32(arg),
+        Instr::Not(Loc::Mem(other))
+        | Instr::Pop(Loc::Mem(other))
+        | Instr::MovsdLoad(_, other)
+        | Instr::MovsdStore(other, _) => aliases(other),
+        Instr::Not(Loc::Reg(_))
+        | Instr::Pop(Loc::Reg(_))
+        | Instr::IDiv(_)
+        | Instr::Cqo
+        | Instr::LeaRel(..)
+        | Instr::Cvtsi2sd(..)
+        | Instr::Sse(..)
+        | Instr::Ucomisd(..) => false,
+        Instr::Label(_)
+        | Instr::Call(_)
+        | Instr::Ret
+        | Instr::Rep(_)
+        | Instr::Comment(_)
+        | Instr::Jmp(_)
+        | Instr::Je(_)
+        | Instr::Jne(_)
+        | Instr::Jl(_)
+        | Instr::Jle(_)
+        | Instr::Jg(_)
+        | Instr::Jge(_)
+        | Instr::Js(_)
+        | Instr::Jns(_)
+        | Instr::Jz(_)
+        | Instr::Jnz(_)
+        | Instr::Jo(_)
+        | Instr::Jno(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Reg::*;
+    use crate::mref;
+
+    fn slot(i: i32) -> MemRef {
+        mref![Rbp - %(8 * i)]
+    }
+
+    fn store(i: i32, val: Reg32) -> Instr {
+        Instr::Mov(MovArgs::ToMem(slot(i), val))
+    }
+
+    fn load(reg: Reg, i: i32) -> Instr {
+        Instr::Mov(MovArgs::ToReg(reg, Arg64::Mem(slot(i))))
+    }
+
+    fn mov(dst: Reg, src: Reg) -> Instr {
+        Instr::Mov(MovArgs::ToReg(dst, Arg64::Reg(src)))
+    }
+
+    #[test]
+    fn removes_self_moves() {
+        assert_eq!(
+            optimize(vec![mov(Rax, Rax), mov(Rcx, Rax)]),
+            vec![mov(Rcx, Rax)]
+        );
+    }
+
+    #[test]
+    fn forwards_stored_register() {
+        let code = vec![store(1, Reg32::Reg(Rax)), load(Rcx, 1)];
+        assert_eq!(
+            optimize(code),
+            vec![store(1, Reg32::Reg(Rax)), mov(Rcx, Rax)]
+        );
+    }
+
+    #[test]
+    fn drops_reload_into_same_register() {
+        let code = vec![store(1, Reg32::Reg(Rax)), load(Rax, 1)];
+        assert_eq!(optimize(code), vec![store(1, Reg32::Reg(Rax))]);
+    }
+
+    #[test]
+    fn forwards_stored_immediate() {
+        let code = vec![store(1, Reg32::Imm(-3)), load(Rax, 1)];
+        let expected = vec![
+            store(1, Reg32::Imm(-3)),
+            Instr::Mov(MovArgs::ToReg(Rax, Arg64::Imm(-3))),
+        ];
+        assert_eq!(optimize(code), expected);
+    }
+
+    #[test]
+    fn keeps_load_from_other_slot() {
+        let code = vec![store(1, Reg32::Reg(Rax)), load(Rcx, 2)];
+        assert_eq!(optimize(code.clone()), code);
+    }
+
+    #[test]
+    fn removes_overwritten_store() {
+        let code = vec![
+            store(1, Reg32::Imm(1)),
+            mov(Rcx, Rax),
+            store(2, Reg32::Imm(1)),
+            store(1, Reg32::Reg(Rcx)),
+        ];
+        let expected = vec![
+            mov(Rcx, Rax),
+            store(2, Reg32::Imm(1)),
+            store(1, Reg32::Reg(Rcx)),
+        ];
+        assert_eq!(optimize(code), expected);
+    }
+
+    #[test]
+    fn keeps_store_read_before_overwrite() {
+        let code = vec![
+            store(1, Reg32::Imm(1)),
+            Instr::Add(BinArgs::ToReg(Rax, Arg32::Mem(slot(1)))),
+            store(1, Reg32::Reg(Rax)),
+        ];
+        assert_eq!(optimize(code.clone()), code);
+    }
+
+    #[test]
+    fn keeps_store_across_calls_and_labels() {
+        for barrier in [
+            Instr::Call("snek_try_gc".to_string()),
+            Instr::Label("l".to_string()),
+            Instr::Jmp("l".to_string()),
+        ] {
+            let code = vec![store(1, Reg32::Imm(1)), barrier, store(1, Reg32::Reg(Rax))];
+            assert_eq!(optimize(code.clone()), code);
+        }
+    }
+
+    #[test]
+    fn keeps_store_to_non_stack_memory() {
+        let code = vec![
+            Instr::Mov(MovArgs::ToMem(mref![R15 + 8], Reg32::Imm(1))),
+            Instr::Mov(MovArgs::ToMem(mref![R15 + 8], Reg32::Imm(2))),
+        ];
+        assert_eq!(optimize(code.clone()), code);
+    }
+
+    #[test]
+    fn removes_jump_to_next_label() {
+        let code = vec![
+            Instr::Jmp("end".to_string()),
+            Instr::Label("other".to_string()),
+            Instr::Label("end".to_string()),
+        ];
+        let expected = vec![
+            Instr::Label("other".to_string()),
+            Instr::Label("end".to_string()),
+        ];
+        assert_eq!(optimize(code), expected);
+    }
+
+    #[test]
+    fn keeps_jump_over_code() {
+        let code = vec![
+            Instr::Je("end".to_string()),
+            mov(Rax, Rcx),
+            Instr::Label("end".to_string()),
+        ];
+        assert_eq!(optimize(code.clone()), code);
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    parser,
+    syntax::{Expr, FunDecl, Prog, Symbol},
+};
+
+const SOURCE: &str = include_str!("prelude.snek");
+
+/// Adds the prelude functions called by a module, directly or through other prelude functions, to
+/// its functions. Functions defined or imported by the module take precedence over the prelude.
+///
+/// Snek functions aren't values, so prelude functions calling one of their parameters are
+/// specialized for the functions they are passed: `(map double xs)` calls `prelude/map.double`,
+/// a copy of `map` without the parameter `f` where `(f x)` is `(double x)`.
+pub fn link(prg: Prog) -> Prog {
+    let (prelude, _) = parser::parse(&parser::read(SOURCE), "prelude", &HashMap::new());
+    let mut linker = Linker {
+        prelude: prelude
+            .funs
+            .iter()
+            .map(|fun| (fun.name.unqualified(), fun))
+            .collect(),
+        defined: prg
+            .funs
+            .iter()
+            .map(|fun| fun.name)
+            .chain(prg.imported_funs.iter().map(|&(fun, _)| fun))
+            .collect(),
+        local: prg
+            .funs
+            .iter()
+            .map(|fun| (fun.name.unqualified(), fun.name))
+            .collect(),
+        linked: HashSet::new(),
+        pending: vec![],
+    };
+    let mut funs: Vec<_> = prg
+        .funs
+        .iter()
+        .map(|fun| FunDecl {
+            body: linker.expr(&fun.body),
+            ..fun.clone()
+        })
+        .collect();
+    let main = prg.main.as_ref().map(|main| linker.expr(main));
+    while let Some(fun) = linker.pending.pop() {
+        funs.push(FunDecl {
+            body: linker.expr(&fun.body),
+            ..fun
+        });
+    }
+    Prog { funs, main, ..prg }
+}
+
+/// Whether `fun` was linked from the prelude. Those functions are private to the module.
+pub fn is_prelude(fun: Symbol) -> bool {
+    fun.to_string().starts_with("prelude/")
+}
+
+struct Linker<'a> {
+    /// Prelude functions by unqualified name
+    prelude: HashMap<&'static str, &'a FunDecl>,
+    /// Functions of the module and imported functions, by canonical name
+    defined: HashSet<Symbol>,
+    /// Functions of the module by unqualified name, to find functions passed as arguments
+    local: HashMap<&'static str, Symbol>,
+    /// Prelude functions and specializations added to the module so far
+    linked: HashSet<Symbol>,
+    /// Linked functions whose bodies haven't been linked yet
+    pending: Vec<FunDecl>,
+}
+
+impl<'a> Linker<'a> {
+    fn expr(&mut self, e: &Expr) -> Expr {
+        match e {
+            Expr::Call(fun, args) => {
+                let args = args.iter().map(|arg| self.expr(arg)).collect();
+                match self.prelude_fun(*fun) {
+                    Some(decl) => self.call(decl, args),
+                    None => Expr::Call(*fun, args),
+                }
+            }
+            _ => e.map_children(|e| self.expr(e)),
+        }
+    }
+
+    /// The prelude function called by `(fun ..)`, unless the module defines it.
+    fn prelude_fun(&self, fun: Symbol) -> Option<&'a FunDecl> {
+        if self.defined.contains(&fun) {
+            return None;
+        }
+        let name = fun.to_string();
+        let name = name.strip_prefix("prelude/").unwrap_or(&name);
+        self.prelude.get(name).copied()
+    }
+
+    fn call(&mut self, decl: &'a FunDecl, args: Vec<Expr>) -> Expr {
+        let name = decl.name.unqualified();
+        if args.len() != decl.params.len() {
+            panic!(
+                "Invalid syntax: `{name}` takes {} arguments but {} were supplied",
+                decl.params.len(),
+                args.len()
+            )
+        }
+        let called = called_params(decl);
+        let mut subst = vec![];
+        let mut rest = vec![];
+        for (i, (param, arg)) in decl.params.iter().zip(args).enumerate() {
+            if !called.contains(param) {
+                rest.push(arg);
+                continue;
+            }
+            let fun = match arg {
+                Expr::Var(fun) => self.function(fun),
+                _ => None,
+            };
+            let Some(fun) = fun else {
+                panic!(
+                    "Invalid syntax: argument {} of `{name}` must be the name of a function",
+                    i + 1
+                )
+            };
+            subst.push((*param, fun));
+        }
+        Expr::Call(self.instance(decl, &subst), rest)
+    }
+
+    /// The function named `fun` when passed to a prelude function.
+    fn function(&mut self, fun: Symbol) -> Option<Symbol> {
+        if self.defined.contains(&fun) {
+            return Some(fun);
+        }
+        if let Some(&local) = self.local.get(fun.unqualified()) {
+            return Some(local);
+        }
+        let decl = self.prelude_fun(fun)?;
+        Some(self.instance(decl, &[]))
+    }
+
+    /// Links `decl` with the parameters in `subst` replaced by functions, and returns its name.
+    fn instance(&mut self, decl: &FunDecl, subst: &[(Symbol, Symbol)]) -> Symbol {
+        let mut name = decl.name.to_string();
+        for (_, fun) in subst {
+            name += &format!(".{}", fun.replace("/", "."));
+        }
+        let name = Symbol::new(name);
+        if self.linked.insert(name) {
+            let subst: HashMap<_, _> = subst.iter().copied().collect();
+            let (params, param_tys) = decl
+                .params
+                .iter()
+                .zip(&decl.param_tys)
+                .filter(|(param, _)| !subst.contains_key(*param))
+                .map(|(param, ty)| (*param, ty.clone()))
+                .unzip();
+            self.pending.push(FunDecl {
+                name,
+                params,
+                param_tys,
+                ret_ty: decl.ret_ty.clone(),
+                body: substitute(&decl.body, &subst),
+            });
+        }
+        name
+    }
+}
+
+/// The parameters of `decl` that its body calls as functions.
+fn called_params(decl: &FunDecl) -> HashSet<Symbol> {
+    fn go(e: &Expr, params: &[Symbol], out: &mut HashSet<Symbol>) {
+        if let Expr::Call(fun, _) = e {
+            if params.contains(fun) {
+                out.insert(*fun);
+            }
+        }
+        e.for_each_child(|e| go(e, params, out));
+    }
+    let mut out = HashSet::new();
+    go(&decl.body, &decl.params, &mut out);
+    out
+}
+
+fn substitute(e: &Expr, subst: &HashMap<Symbol, Symbol>) -> Expr {
+    match e {
+        Expr::Var(x) if subst.contains_key(x) => Expr::Var(subst[x]),
+        Expr::Call(fun, args) if subst.contains_key(fun) => Expr::Call(
+            subst[fun],
+            args.iter().map(|arg| substitute(arg, subst)).collect(),
+        ),
+        _ => e.map_children(|e| substitute(e, subst)),
+    }
+}
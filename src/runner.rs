@@ -0,0 +1,111 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+use crate::syntax::{Expr, Op2};
+
+/// The runtime the tests are linked with.
+const RUNTIME: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/runtime/start.rs");
+
+/// The main expression of a program compiled with `--test`: it evaluates the body of the test whose
+/// index is the input, and returns nil.
+pub fn test_main(tests: &[(String, Expr)]) -> Expr {
+    tests
+        .iter()
+        .enumerate()
+        .rev()
+        .fold(Expr::Nil, |rest, (i, (_, body))| {
+            let selected = Expr::BinOp(
+                Op2::Equal,
+                Box::new(Expr::Input),
+                Box::new(Expr::Number(i as i64)),
+            );
+            Expr::If(
+                Box::new(selected),
+                Box::new(Expr::Block(vec![body.clone(), Expr::Nil])),
+                Box::new(rest),
+            )
+        })
+}
+
+/// Builds the program compiled with `--test` to `asm`, along with the modules it imports and the
+/// files passed to `--link`, and runs each of its `tests` in a separate process. A test passes if
+/// it finishes without error. Returns whether all tests passed.
+pub fn run(asm: &str, modules: &[PathBuf], links: &[String], tests: &[String]) -> io::Result<bool> {
+    let dir = env::temp_dir().join(format!("snek-test-{}", process::id()));
+    fs::create_dir_all(&dir)?;
+    let exe = build(&dir, asm, modules, links)?;
+    let mut failed = 0;
+    for (i, name) in tests.iter().enumerate() {
+        let output = Command::new(&exe).arg(i.to_string()).output()?;
+        if output.status.success() {
+            println!("test {name} ... ok");
+        } else {
+            failed += 1;
+            println!("test {name} ... FAILED");
+            for line in String::from_utf8_lossy(&output.stderr).lines() {
+                println!("    {line}");
+            }
+        }
+    }
+    println!(
+        "\ntest result: {} passed; {failed} failed",
+        tests.len() - failed
+    );
+    fs::remove_dir_all(&dir)?;
+    Ok(failed == 0)
+}
+
+/// Assembles and links the program the way the `Makefile` does, returning the executable.
+fn build(dir: &Path, asm: &str, modules: &[PathBuf], links: &[String]) -> io::Result<PathBuf> {
+    let format = if cfg!(target_os = "macos") {
+        "macho64"
+    } else {
+        "elf64"
+    };
+    let main = dir.join("tests.s");
+    fs::write(&main, asm)?;
+    let mut objects = vec![];
+    for (i, path) in [&main].into_iter().chain(modules).enumerate() {
+        let object = dir.join(format!("{i}.o"));
+        tool(
+            Command::new("nasm")
+                .args(["-f", format])
+                .arg(path)
+                .arg("-o")
+                .arg(&object),
+        )?;
+        objects.push(object);
+    }
+    tool(
+        Command::new("ar")
+            .arg("rcs")
+            .arg(dir.join("libour_code.a"))
+            .args(&objects),
+    )?;
+    let exe = dir.join("tests.run");
+    tool(
+        Command::new("rustc")
+            .arg("-L")
+            .arg(dir)
+            .arg(RUNTIME)
+            .args(links.iter().map(|file| format!("-Clink-arg={file}")))
+            .arg("-o")
+            .arg(&exe),
+    )?;
+    Ok(exe)
+}
+
+fn tool(cmd: &mut Command) -> io::Result<()> {
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} failed:\n{}",
+            cmd.get_program().to_string_lossy(),
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,225 @@
+use std::fmt;
+
+/// An atom read from the source. String literals are kept apart from symbols so `"foo"` and `foo`
+/// can be told apart by the parser.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Atom {
+    S(String),
+    I(i64),
+    /// An integer literal too large for `I`, as it was written
+    Big(String),
+    F(f64),
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sexp {
+    Atom(Atom),
+    /// A list and the position of its opening parenthesis
+    List(Vec<Sexp>, Pos),
+}
+
+/// A position in the source, lines and columns start at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pos {
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug)]
+pub struct Error {
+    pub message: &'static str,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// Reads a single s-expression from `s`.
+pub fn parse(s: &str) -> Result<Sexp, Error> {
+    let chars: Vec<char> = s.chars().collect();
+    let line_starts = std::iter::once(0)
+        .chain(
+            chars
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| **c == '\n')
+                .map(|(i, _)| i + 1),
+        )
+        .collect();
+    let mut reader = Reader {
+        chars,
+        pos: 0,
+        line_starts,
+    };
+    let sexp = reader.sexp()?;
+    reader.skip_whitespace();
+    if reader.pos < reader.chars.len() {
+        return Err(reader.error("unexpected data after s-expression"));
+    }
+    Ok(sexp)
+}
+
+struct Reader {
+    chars: Vec<char>,
+    pos: usize,
+    /// The index of the first character of each line
+    line_starts: Vec<usize>,
+}
+
+impl Reader {
+    fn sexp(&mut self) -> Result<Sexp, Error> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => Err(self.error("unexpected eof")),
+            Some('(') => {
+                let start = self.position();
+                self.pos += 1;
+                let mut es = vec![];
+                loop {
+                    self.skip_whitespace();
+                    match self.peek() {
+                        None => return Err(self.error("unexpected eof")),
+                        Some(')') => {
+                            self.pos += 1;
+                            return Ok(Sexp::List(es, start));
+                        }
+                        Some(_) => es.push(self.sexp()?),
+                    }
+                }
+            }
+            Some(')') => Err(self.error("unexpected `)`")),
+            Some('"') => self.string(),
+            Some(_) => Ok(Sexp::Atom(self.atom())),
+        }
+    }
+
+    fn string(&mut self) -> Result<Sexp, Error> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                None => return Err(self.error("unterminated string literal")),
+                Some('"') => return Ok(Sexp::Atom(Atom::Str(s))),
+                Some('\\') => match self.next() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some(c @ ('"' | '\\')) => s.push(c),
+                    _ => return Err(self.error("invalid escape sequence")),
+                },
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn atom(&mut self) -> Atom {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || matches!(c, '(' | ')' | '"' | ';') {
+                break;
+            }
+            self.pos += 1;
+        }
+        let token: String = self.chars[start..self.pos].iter().collect();
+        if let Ok(n) = token.parse() {
+            Atom::I(n)
+        } else if is_integer(&token) {
+            Atom::Big(token)
+        } else if looks_like_float(&token) {
+            token.parse().map(Atom::F).unwrap_or(Atom::S(token))
+        } else {
+            Atom::S(token)
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == ';' {
+                while !matches!(self.next(), None | Some('\n')) {}
+            } else if c.is_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn error(&self, message: &'static str) -> Error {
+        let Pos { line, column } = self.position();
+        Error {
+            message,
+            line,
+            column: column - 1,
+        }
+    }
+
+    fn position(&self) -> Pos {
+        let pos = self.pos.min(self.chars.len());
+        let line = self.line_starts.partition_point(|start| *start <= pos);
+        let column = pos - self.line_starts[line - 1] + 1;
+        Pos { line, column }
+    }
+}
+
+/// Whether `token` is an optionally signed sequence of decimal digits.
+fn is_integer(token: &str) -> bool {
+    let digits = token.strip_prefix(['-', '+']).unwrap_or(token);
+    !digits.is_empty() && digits.bytes().all(|c| c.is_ascii_digit())
+}
+
+/// Floats must start with a digit (after an optional sign) so that names like `inf` or `nan`
+/// remain symbols.
+fn looks_like_float(token: &str) -> bool {
+    let digits = token.trim_start_matches(['-', '+']);
+    digits.starts_with(|c: char| c.is_ascii_digit())
+}
+
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Atom::S(s) => write!(f, "{s}"),
+            Atom::I(n) => write!(f, "{n}"),
+            Atom::Big(n) => write!(f, "{n}"),
+            Atom::F(x) => write!(f, "{x:?}"),
+            Atom::Str(s) => write!(f, "{s:?}"),
+        }
+    }
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+impl fmt::Display for Sexp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sexp::Atom(a) => write!(f, "{a}"),
+            Sexp::List(es, _) => {
+                write!(f, "(")?;
+                for (i, e) in es.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{e}")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
@@ -1,32 +1,95 @@
 use std::fmt;
 
+use crate::sexp::Pos;
+
 #[derive(Copy, Clone, Hash, PartialEq, Eq)]
 pub struct Symbol(&'static str);
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct Prog {
+    pub structs: Vec<StructDecl>,
+    /// Structs defined by imported modules
+    pub imported_structs: Vec<StructDecl>,
     pub funs: Vec<FunDecl>,
-    pub main: Expr,
+    /// Functions defined by imported modules, and their arity
+    pub imported_funs: Vec<(Symbol, usize)>,
+    /// Libraries don't have a main expression
+    pub main: Option<Expr>,
+    /// `(test "name" e1 .. en)` declarations, only compiled by `--test`
+    pub tests: Vec<(String, Expr)>,
+    /// C functions declared by `(extern name arity)`
+    pub externs: Vec<(Symbol, usize)>,
+    /// Functions exported to C by `(export f1 .. fn)`, see `export`
+    pub exports: Vec<Symbol>,
+}
+
+/// What a library module provides to the modules importing it. Names are qualified by the name of
+/// the module, e.g. `lists/append`.
+#[derive(Debug, Clone)]
+pub struct Interface {
+    pub name: String,
+    pub funs: Vec<(Symbol, usize)>,
+    pub structs: Vec<StructDecl>,
+    /// The variants of each enum, by unqualified enum name
+    pub enums: Vec<(Symbol, Vec<Symbol>)>,
+}
+
+/// `(struct Name (field1 .. fieldn))`
+#[derive(Debug, Clone)]
+pub struct StructDecl {
+    pub name: Symbol,
+    pub fields: Vec<Symbol>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FunDecl {
     pub name: Symbol,
     pub params: Vec<Symbol>,
+    /// Declared types of the parameters, `any` when they aren't annotated
+    pub param_tys: Vec<Type>,
+    /// Declared return type, inferred by the type checker when it's missing
+    pub ret_ty: Option<Type>,
     pub body: Expr,
 }
 
-#[derive(Debug)]
+/// Type annotations, checked by the optional type checker.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    /// Any value, checked at runtime
+    Any,
+    /// An integer
+    Num,
+    Float,
+    Bool,
+    Nil,
+    Str,
+    /// A hash map, its keys and values aren't checked
+    Map,
+    /// `(vec T)`, a vector of `T`s or nil
+    Vec(Box<Type>),
+    /// The type of expressions that never produce a value, it can't be written in annotations
+    Never,
+}
+
+#[derive(Debug, Clone)]
 pub enum Expr {
     Number(i64),
+    /// An integer literal too large for a tagged number, as it was written
+    BigNum(String),
+    Float(f64),
     Boolean(bool),
+    Str(String),
     Var(Symbol),
     Let(Vec<(Symbol, Expr)>, Box<Expr>),
     UnOp(Op1, Box<Expr>),
     BinOp(Op2, Box<Expr>, Box<Expr>),
     If(Box<Expr>, Box<Expr>, Box<Expr>),
-    Loop(Box<Expr>),
-    Break(Box<Expr>),
+    /// A loop and its label, if some `break` inside a nested loop targets it
+    Loop(Option<Symbol>, Box<Expr>),
+    /// Leaves the innermost loop or the loop with the given label
+    Break(Option<Symbol>, Box<Expr>),
+    /// Starts the next iteration of the innermost loop or the loop with the given label
+    Continue(Option<Symbol>),
     Set(Symbol, Box<Expr>),
     MakeVec(Box<Expr>, Box<Expr>),
     Vec(Vec<Expr>),
@@ -35,6 +98,31 @@ pub enum Expr {
     VecLen(Box<Expr>),
     Block(Vec<Expr>),
     Call(Symbol, Vec<Expr>),
+    /// A call of a C function declared by `extern`, which takes and returns 64-bit integers
+    CallExtern(Symbol, Vec<Expr>),
+    Prim(Prim, Vec<Expr>),
+    /// `(Name e1 .. en)` builds an instance of the struct `Name`
+    StructNew(Symbol, Vec<Expr>),
+    /// `(Name-field e)` reads the field with the given index
+    StructGet(Symbol, usize, Box<Expr>),
+    /// `(set-Name-field! e1 e2)` writes the field with the given index
+    StructSet(Symbol, usize, Box<Expr>, Box<Expr>),
+    /// `(Name? e)`
+    StructIs(Symbol, Box<Expr>),
+    /// Reports that no clause of a `match` accepts the value
+    NoMatch(Box<Expr>),
+    /// Reports that the condition of an `assert`, as it was written, doesn't hold, with the
+    /// message of the `assert`
+    AssertFailed(String, Box<Expr>),
+    /// Leaves the innermost `try` with the value, or stops the program if there is none
+    Raise(Box<Expr>),
+    /// `(try e1 (catch x e2))` evaluates `e2` with `x` bound to the value raised by `e1`, or to
+    /// the kind of runtime error it caused, if it doesn't finish normally
+    Try(Box<Expr>, Symbol, Box<Expr>),
+    /// `(let ((x : T e)) ..)` checks `e` has type `T`
+    Annot(Type, Box<Expr>),
+    /// An expression and its position in the source, for error messages
+    At(Pos, Box<Expr>),
     Input,
     Nil,
     PrintStack,
@@ -49,6 +137,8 @@ pub enum Op1 {
     IsNum,
     IsBool,
     IsVec,
+    IsString,
+    IsFloat,
     Print,
 }
 
@@ -58,6 +148,10 @@ pub enum Op2 {
     Minus,
     Times,
     Divide,
+    /// Remainder of the truncating division, with the sign of the dividend
+    Remainder,
+    /// Remainder of the flooring division, with the sign of the divisor
+    Modulo,
     Equal,
     Greater,
     GreaterEqual,
@@ -65,6 +159,314 @@ pub enum Op2 {
     LessEqual,
 }
 
+/// Builtins implemented in the runtime. See `Session::call_prim` for the calling convention.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Prim {
+    StringLength,
+    StringAppend,
+    Substring,
+    StringRef,
+    StringEq,
+    StringLess,
+    NumberToString,
+    StringToNumber,
+    Equal,
+    ToFloat,
+    Floor,
+    Ceiling,
+    Round,
+    Truncate,
+    MakeMap,
+    MapGet,
+    MapSet,
+    MapDelete,
+    MapHas,
+    MapCount,
+    MapKeys,
+    /// The arguments the program was run with, as strings
+    CommandLine,
+    ReadLine,
+    ReadInt,
+    /// `print` without the newline
+    Display,
+    /// `print` on stderr
+    EPrint,
+    ReadFile,
+    WriteFile,
+}
+
+impl Prim {
+    pub const ALL: &'static [Prim] = &[
+        Prim::StringLength,
+        Prim::StringAppend,
+        Prim::Substring,
+        Prim::StringRef,
+        Prim::StringEq,
+        Prim::StringLess,
+        Prim::NumberToString,
+        Prim::StringToNumber,
+        Prim::Equal,
+        Prim::ToFloat,
+        Prim::Floor,
+        Prim::Ceiling,
+        Prim::Round,
+        Prim::Truncate,
+        Prim::MakeMap,
+        Prim::MapGet,
+        Prim::MapSet,
+        Prim::MapDelete,
+        Prim::MapHas,
+        Prim::MapCount,
+        Prim::MapKeys,
+        Prim::CommandLine,
+        Prim::ReadLine,
+        Prim::ReadInt,
+        Prim::Display,
+        Prim::EPrint,
+        Prim::ReadFile,
+        Prim::WriteFile,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Prim> {
+        Prim::ALL.iter().copied().find(|prim| prim.name() == name)
+    }
+
+    /// Name of the primitive in snek source code.
+    pub fn name(self) -> &'static str {
+        match self {
+            Prim::StringLength => "string-length",
+            Prim::StringAppend => "string-append",
+            Prim::Substring => "substring",
+            Prim::StringRef => "string-ref",
+            Prim::StringEq => "string=?",
+            Prim::StringLess => "string<?",
+            Prim::NumberToString => "number->string",
+            Prim::StringToNumber => "string->number",
+            Prim::Equal => "equal?",
+            Prim::ToFloat => "float",
+            Prim::Floor => "floor",
+            Prim::Ceiling => "ceiling",
+            Prim::Round => "round",
+            Prim::Truncate => "truncate",
+            Prim::MakeMap => "make-map",
+            Prim::MapGet => "map-get",
+            Prim::MapSet => "map-set!",
+            Prim::MapDelete => "map-delete!",
+            Prim::MapHas => "map-has?",
+            Prim::MapCount => "map-count",
+            Prim::MapKeys => "map-keys",
+            Prim::CommandLine => "command-line",
+            Prim::ReadLine => "read-line",
+            Prim::ReadInt => "read-int",
+            Prim::Display => "display",
+            Prim::EPrint => "eprint",
+            Prim::ReadFile => "read-file",
+            Prim::WriteFile => "write-file",
+        }
+    }
+
+    /// Name of the function implementing the primitive in the runtime.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Prim::StringLength => "snek_string_length",
+            Prim::StringAppend => "snek_string_append",
+            Prim::Substring => "snek_substring",
+            Prim::StringRef => "snek_string_ref",
+            Prim::StringEq => "snek_string_eq",
+            Prim::StringLess => "snek_string_less",
+            Prim::NumberToString => "snek_number_to_string",
+            Prim::StringToNumber => "snek_string_to_number",
+            Prim::Equal => "snek_equal",
+            Prim::ToFloat => "snek_to_float",
+            Prim::Floor => "snek_floor",
+            Prim::Ceiling => "snek_ceiling",
+            Prim::Round => "snek_round",
+            Prim::Truncate => "snek_truncate",
+            Prim::MakeMap => "snek_make_map",
+            Prim::MapGet => "snek_map_get",
+            Prim::MapSet => "snek_map_set",
+            Prim::MapDelete => "snek_map_delete",
+            Prim::MapHas => "snek_map_has",
+            Prim::MapCount => "snek_map_count",
+            Prim::MapKeys => "snek_map_keys",
+            Prim::CommandLine => "snek_command_line",
+            Prim::ReadLine => "snek_read_line",
+            Prim::ReadInt => "snek_read_int",
+            Prim::Display => "snek_display",
+            Prim::EPrint => "snek_eprint",
+            Prim::ReadFile => "snek_read_file",
+            Prim::WriteFile => "snek_write_file",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Prim::MakeMap | Prim::CommandLine | Prim::ReadLine | Prim::ReadInt => 0,
+            Prim::StringLength
+            | Prim::NumberToString
+            | Prim::StringToNumber
+            | Prim::ToFloat
+            | Prim::Floor
+            | Prim::Ceiling
+            | Prim::Round
+            | Prim::Truncate
+            | Prim::MapCount
+            | Prim::MapKeys
+            | Prim::Display
+            | Prim::EPrint
+            | Prim::ReadFile => 1,
+            Prim::StringAppend
+            | Prim::StringRef
+            | Prim::StringEq
+            | Prim::StringLess
+            | Prim::Equal
+            | Prim::MapDelete
+            | Prim::MapHas
+            | Prim::WriteFile => 2,
+            Prim::Substring | Prim::MapGet | Prim::MapSet => 3,
+        }
+    }
+}
+
+impl Prog {
+    /// Removes type annotations and source positions, which are only used by the type checker.
+    pub fn erase_annotations(self) -> Prog {
+        Prog {
+            funs: self
+                .funs
+                .into_iter()
+                .map(|fun| FunDecl {
+                    body: fun.body.erase_annotations(),
+                    ..fun
+                })
+                .collect(),
+            main: self.main.as_ref().map(Expr::erase_annotations),
+            ..self
+        }
+    }
+}
+
+impl Expr {
+    fn erase_annotations(&self) -> Expr {
+        match self {
+            Expr::Annot(_, e) | Expr::At(_, e) => e.erase_annotations(),
+            _ => self.map_children(Expr::erase_annotations),
+        }
+    }
+
+    /// Rebuilds the expression applying `f` to each of its immediate subexpressions.
+    pub fn map_children(&self, mut f: impl FnMut(&Expr) -> Expr) -> Expr {
+        let mut f = |e: &Expr| Box::new(f(e));
+        match self {
+            Expr::Number(_)
+            | Expr::BigNum(_)
+            | Expr::Float(_)
+            | Expr::Boolean(_)
+            | Expr::Str(_)
+            | Expr::Var(_)
+            | Expr::Input
+            | Expr::Nil
+            | Expr::PrintStack
+            | Expr::PrintHeap
+            | Expr::Gc
+            | Expr::Continue(_) => self.clone(),
+            Expr::Let(bindings, body) => Expr::Let(
+                bindings.iter().map(|(x, rhs)| (*x, *f(rhs))).collect(),
+                f(body),
+            ),
+            Expr::UnOp(op, e) => Expr::UnOp(*op, f(e)),
+            Expr::BinOp(op, e1, e2) => Expr::BinOp(*op, f(e1), f(e2)),
+            Expr::If(e1, e2, e3) => Expr::If(f(e1), f(e2), f(e3)),
+            Expr::Loop(label, e) => Expr::Loop(*label, f(e)),
+            Expr::Break(label, e) => Expr::Break(*label, f(e)),
+            Expr::Set(x, e) => Expr::Set(*x, f(e)),
+            Expr::MakeVec(size, elem) => Expr::MakeVec(f(size), f(elem)),
+            Expr::Vec(es) => Expr::Vec(es.iter().map(|e| *f(e)).collect()),
+            Expr::VecSet(vec, idx, elem) => Expr::VecSet(f(vec), f(idx), f(elem)),
+            Expr::VecGet(vec, idx) => Expr::VecGet(f(vec), f(idx)),
+            Expr::VecLen(vec) => Expr::VecLen(f(vec)),
+            Expr::Block(es) => Expr::Block(es.iter().map(|e| *f(e)).collect()),
+            Expr::Call(fun, args) => Expr::Call(*fun, args.iter().map(|e| *f(e)).collect()),
+            Expr::CallExtern(fun, args) => {
+                Expr::CallExtern(*fun, args.iter().map(|e| *f(e)).collect())
+            }
+            Expr::Prim(prim, args) => Expr::Prim(*prim, args.iter().map(|e| *f(e)).collect()),
+            Expr::StructNew(name, args) => {
+                Expr::StructNew(*name, args.iter().map(|e| *f(e)).collect())
+            }
+            Expr::StructGet(name, field, e) => Expr::StructGet(*name, *field, f(e)),
+            Expr::StructSet(name, field, e1, e2) => Expr::StructSet(*name, *field, f(e1), f(e2)),
+            Expr::StructIs(name, e) => Expr::StructIs(*name, f(e)),
+            Expr::NoMatch(e) => Expr::NoMatch(f(e)),
+            Expr::AssertFailed(cond, e) => Expr::AssertFailed(cond.clone(), f(e)),
+            Expr::Raise(e) => Expr::Raise(f(e)),
+            Expr::Try(body, x, handler) => Expr::Try(f(body), *x, f(handler)),
+            Expr::Annot(ty, e) => Expr::Annot(ty.clone(), f(e)),
+            Expr::At(pos, e) => Expr::At(*pos, f(e)),
+        }
+    }
+
+    /// Calls `f` on each of the immediate subexpressions, in evaluation order.
+    pub fn for_each_child(&self, mut f: impl FnMut(&Expr)) {
+        match self {
+            Expr::Number(_)
+            | Expr::BigNum(_)
+            | Expr::Float(_)
+            | Expr::Boolean(_)
+            | Expr::Str(_)
+            | Expr::Var(_)
+            | Expr::Input
+            | Expr::Nil
+            | Expr::PrintStack
+            | Expr::PrintHeap
+            | Expr::Gc
+            | Expr::Continue(_) => {}
+            Expr::Let(bindings, body) => {
+                bindings.iter().for_each(|(_, rhs)| f(rhs));
+                f(body)
+            }
+            Expr::UnOp(_, e)
+            | Expr::Loop(_, e)
+            | Expr::Break(_, e)
+            | Expr::Set(_, e)
+            | Expr::VecLen(e)
+            | Expr::StructGet(_, _, e)
+            | Expr::StructIs(_, e)
+            | Expr::NoMatch(e)
+            | Expr::AssertFailed(_, e)
+            | Expr::Raise(e)
+            | Expr::Annot(_, e)
+            | Expr::At(_, e) => f(e),
+            Expr::BinOp(_, e1, e2)
+            | Expr::Try(e1, _, e2)
+            | Expr::MakeVec(e1, e2)
+            | Expr::VecGet(e1, e2)
+            | Expr::StructSet(_, _, e1, e2) => {
+                f(e1);
+                f(e2)
+            }
+            Expr::If(e1, e2, e3) | Expr::VecSet(e1, e2, e3) => {
+                f(e1);
+                f(e2);
+                f(e3)
+            }
+            Expr::Vec(es)
+            | Expr::Block(es)
+            | Expr::Call(_, es)
+            | Expr::CallExtern(_, es)
+            | Expr::Prim(_, es)
+            | Expr::StructNew(_, es) => es.iter().for_each(f),
+        }
+    }
+
+    /// Whether the expression may assign the variable `x`.
+    pub fn assigns(&self, x: Symbol) -> bool {
+        let mut found = matches!(self, Expr::Set(y, _) if *y == x);
+        self.for_each_child(|e| found |= e.assigns(x));
+        found
+    }
+}
+
 impl Symbol {
     pub fn new(s: impl ToString) -> Symbol {
         Symbol(Box::leak(s.to_string().into_boxed_str()))
@@ -73,6 +475,11 @@ impl Symbol {
     pub fn replace(&self, from: &str, to: &str) -> String {
         self.0.replace(from, to)
     }
+
+    /// The name without the qualifier of the module defining it.
+    pub fn unqualified(&self) -> &'static str {
+        self.0.rsplit('/').next().unwrap()
+    }
 }
 
 impl fmt::Display for Symbol {
@@ -86,3 +493,19 @@ impl fmt::Debug for Symbol {
         write!(f, "{:?}", self.0)
     }
 }
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Any => write!(f, "any"),
+            Type::Num => write!(f, "num"),
+            Type::Float => write!(f, "float"),
+            Type::Bool => write!(f, "bool"),
+            Type::Nil => write!(f, "nil"),
+            Type::Str => write!(f, "str"),
+            Type::Map => write!(f, "map"),
+            Type::Vec(elem) => write!(f, "(vec {elem})"),
+            Type::Never => write!(f, "never"),
+        }
+    }
+}
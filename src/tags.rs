@@ -0,0 +1,752 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::syntax::{Expr, Op1, Op2, Prim, Prog, Symbol};
+
+/// Smallest and largest integers that can be represented as a tagged number.
+const MIN_NUM: i64 = -(1 << 62);
+const MAX_NUM: i64 = (1 << 62) - 1;
+
+/// Number of iterations after which loops and function summaries start widening integer ranges.
+const WIDEN_AFTER: u32 = 3;
+
+/// Static approximation of a runtime value: the set of tags it may have and, if it may be a number,
+/// the range it's in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ty {
+    tags: u8,
+    lo: i64,
+    hi: i64,
+}
+
+impl Ty {
+    const NUM: u8 = 0b0001;
+    const BOOL: u8 = 0b0010;
+    /// A vector, excluding nil
+    const VEC: u8 = 0b0100;
+    const NIL: u8 = 0b1000;
+    const STR: u8 = 0b1_0000;
+    /// An instance of a struct or a hash map
+    const OBJ: u8 = 0b10_0000;
+    /// A number too large for a tagged number
+    const BIG: u8 = 0b100_0000;
+    const FLOAT: u8 = 0b1000_0000;
+    /// The values arithmetic accepts
+    const NUMBERS: u8 = Ty::NUM | Ty::BIG | Ty::FLOAT;
+    /// The values the input of the program may be
+    const INPUT: u8 = Ty::NUM | Ty::BIG | Ty::BOOL | Ty::VEC | Ty::NIL;
+
+    pub const TOP: Ty =
+        Ty::of(Ty::NUM | Ty::BOOL | Ty::VEC | Ty::NIL | Ty::STR | Ty::OBJ | Ty::BIG | Ty::FLOAT);
+    /// The type of expressions that never produce a value (e.g., `break`)
+    const BOTTOM: Ty = Ty::of(0);
+
+    const fn of(tags: u8) -> Ty {
+        if tags & Ty::NUM != 0 {
+            Ty {
+                tags,
+                lo: MIN_NUM,
+                hi: MAX_NUM,
+            }
+        } else {
+            Ty {
+                tags,
+                lo: MAX_NUM,
+                hi: MIN_NUM,
+            }
+        }
+    }
+
+    fn num(lo: i64, hi: i64) -> Ty {
+        Ty::of(0).with_range(lo, hi)
+    }
+
+    /// Replaces the numeric part of the type with the range `[lo, hi]`.
+    fn with_range(self, lo: i64, hi: i64) -> Ty {
+        if lo > hi {
+            Ty::of(self.tags & !Ty::NUM)
+        } else {
+            Ty {
+                tags: self.tags | Ty::NUM,
+                lo,
+                hi,
+            }
+        }
+    }
+
+    /// The value is a tagged number.
+    pub fn is_num(&self) -> bool {
+        self.tags & !Ty::NUM == 0
+    }
+
+    /// The value is a boolean.
+    pub fn is_bool(&self) -> bool {
+        self.tags & !Ty::BOOL == 0
+    }
+
+    /// The value may be a bignum.
+    pub fn may_be_big(&self) -> bool {
+        self.tags & Ty::BIG != 0
+    }
+
+    /// The value may be a float.
+    pub fn may_be_float(&self) -> bool {
+        self.tags & Ty::FLOAT != 0
+    }
+
+    /// The value is a vector or nil.
+    pub fn is_vec_or_nil(&self) -> bool {
+        self.tags & !(Ty::VEC | Ty::NIL) == 0
+    }
+
+    /// The value is a vector other than nil.
+    pub fn is_vec(&self) -> bool {
+        self.tags & !Ty::VEC == 0
+    }
+
+    /// The value may be the number zero.
+    pub fn may_be_zero(&self) -> bool {
+        self.tags & Ty::NUM != 0 && self.lo <= 0 && 0 <= self.hi
+    }
+
+    /// Values of both types can be compared with `=` without a runtime error.
+    pub fn comparable(&self, other: &Ty) -> bool {
+        [Ty::NUMBERS, Ty::BOOL, Ty::VEC | Ty::NIL | Ty::STR | Ty::OBJ]
+            .iter()
+            .any(|class| (self.tags | other.tags) & !class == 0)
+    }
+
+    fn join(self, other: Ty) -> Ty {
+        Ty {
+            tags: self.tags | other.tags,
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+        }
+    }
+
+    /// Like `join` but jumps straight to the largest range if `next` grows in any direction,
+    /// which guarantees that fixpoint iterations terminate.
+    fn widen(self, next: Ty) -> Ty {
+        let next = self.join(next);
+        if self.tags & Ty::NUM == 0 {
+            return next;
+        }
+        Ty {
+            lo: if next.lo < self.lo { MIN_NUM } else { next.lo },
+            hi: if next.hi > self.hi { MAX_NUM } else { next.hi },
+            ..next
+        }
+    }
+
+    fn keep(self, tags: u8) -> Ty {
+        Ty {
+            tags: self.tags & tags,
+            ..self
+        }
+        .normalize()
+    }
+
+    fn remove(self, tags: u8) -> Ty {
+        self.keep(!tags)
+    }
+
+    fn normalize(self) -> Ty {
+        if self.tags & Ty::NUM == 0 {
+            Ty::of(self.tags)
+        } else {
+            self
+        }
+    }
+}
+
+fn prim_ty(prim: Prim) -> Ty {
+    match prim {
+        Prim::StringLength => Ty::num(0, MAX_NUM),
+        Prim::StringAppend | Prim::Substring | Prim::StringRef | Prim::NumberToString => {
+            Ty::of(Ty::STR)
+        }
+        Prim::StringEq | Prim::StringLess | Prim::Equal => Ty::of(Ty::BOOL),
+        Prim::StringToNumber => Ty::of(Ty::NUMBERS | Ty::BOOL),
+        Prim::ToFloat => Ty::of(Ty::FLOAT),
+        Prim::Floor | Prim::Ceiling | Prim::Round | Prim::Truncate => Ty::of(Ty::NUM | Ty::BIG),
+        Prim::MakeMap | Prim::MapSet | Prim::MapDelete => Ty::of(Ty::OBJ),
+        Prim::MapGet => Ty::TOP,
+        Prim::MapHas => Ty::of(Ty::BOOL),
+        Prim::MapCount => Ty::num(0, MAX_NUM),
+        Prim::MapKeys | Prim::CommandLine => Ty::of(Ty::VEC),
+        Prim::ReadLine => Ty::of(Ty::STR | Ty::NIL),
+        Prim::ReadInt => Ty::of(Ty::NUM | Ty::BIG | Ty::NIL),
+        Prim::Display | Prim::EPrint => Ty::TOP,
+        Prim::ReadFile => Ty::of(Ty::STR),
+        Prim::WriteFile => Ty::of(Ty::NIL),
+    }
+}
+
+/// Whether the arithmetic in `op` may overflow given the types of its operands. Operands that
+/// can't be numbers are ignored because the operation would fail its tag check before.
+pub fn bin_op_overflows(op: Op2, t1: Ty, t2: Ty) -> bool {
+    bin_op_range(op, t1, t2).1
+}
+
+/// Whether `add1`/`sub1` may overflow on a value of type `ty`.
+pub fn un_op_overflows(op: Op1, ty: Ty) -> bool {
+    un_op_range(op, ty).1
+}
+
+/// The type of the result of `op` and whether the arithmetic on tagged numbers may overflow. A
+/// bignum operand may give any integer, and a float operand gives a float.
+fn bin_op_range(op: Op2, t1: Ty, t2: Ty) -> (Ty, bool) {
+    if t1.tags & Ty::NUMBERS == 0 || t2.tags & Ty::NUMBERS == 0 {
+        return (Ty::BOTTOM, false);
+    }
+    let cmp = matches!(
+        op,
+        Op2::Equal | Op2::Greater | Op2::GreaterEqual | Op2::Less | Op2::LessEqual
+    );
+    if cmp {
+        return (Ty::of(Ty::BOOL), false);
+    }
+    let (mut ty, overflow) = if t1.tags & Ty::NUM != 0 && t2.tags & Ty::NUM != 0 {
+        tagged_bin_op_range(op, t1, t2)
+    } else {
+        (Ty::BOTTOM, false)
+    };
+    if t1.may_be_big() || t2.may_be_big() {
+        ty = ty.join(Ty::of(Ty::NUM | Ty::BIG));
+    }
+    if t1.may_be_float() || t2.may_be_float() {
+        ty = ty.join(Ty::of(Ty::FLOAT));
+    }
+    (ty, overflow)
+}
+
+fn tagged_bin_op_range(op: Op2, t1: Ty, t2: Ty) -> (Ty, bool) {
+    let (lo1, hi1, lo2, hi2) = (t1.lo as i128, t1.hi as i128, t2.lo as i128, t2.hi as i128);
+    let (lo, hi) = match op {
+        Op2::Plus => (lo1 + lo2, hi1 + hi2),
+        Op2::Minus => (lo1 - hi2, hi1 - lo2),
+        Op2::Times => {
+            let corners = [lo1 * lo2, lo1 * hi2, hi1 * lo2, hi1 * hi2];
+            (
+                *corners.iter().min().unwrap(),
+                *corners.iter().max().unwrap(),
+            )
+        }
+        Op2::Divide => {
+            // The quotient is never larger in magnitude than the dividend, except for
+            // MIN_NUM / -1 which doesn't fit.
+            let max = lo1.abs().max(hi1.abs());
+            let overflow = lo1 == MIN_NUM as i128 && lo2 <= -1 && -1 <= hi2;
+            let max = max.min(MAX_NUM as i128) as i64;
+            let ty = Ty::num(-max, max);
+            return (
+                if overflow {
+                    ty.join(Ty::of(Ty::BIG))
+                } else {
+                    ty
+                },
+                overflow,
+            );
+        }
+        Op2::Remainder | Op2::Modulo => {
+            // The result is smaller in magnitude than the divisor and has the sign of the dividend
+            // (remainder) or the divisor (modulo)
+            let max = lo2.abs().max(hi2.abs()) - 1;
+            let (max, lo, hi) = match op {
+                Op2::Remainder => (max.min(lo1.abs().max(hi1.abs())), lo1, hi1),
+                _ => (max, lo2, hi2),
+            };
+            let lo = if lo >= 0 { 0 } else { -max };
+            let hi = if hi <= 0 { 0 } else { max };
+            (lo, hi)
+        }
+        Op2::Equal | Op2::Greater | Op2::GreaterEqual | Op2::Less | Op2::LessEqual => {
+            unreachable!()
+        }
+    };
+    clamp(lo, hi)
+}
+
+fn un_op_range(op: Op1, ty: Ty) -> (Ty, bool) {
+    let step = match op {
+        Op1::Add1 => 1,
+        Op1::Sub1 => -1,
+        _ => 0,
+    };
+    match op {
+        Op1::Add1 | Op1::Sub1 => {
+            let (mut result, overflow) = if ty.tags & Ty::NUM != 0 {
+                clamp(ty.lo as i128 + step, ty.hi as i128 + step)
+            } else {
+                (Ty::BOTTOM, false)
+            };
+            if ty.may_be_big() {
+                result = result.join(Ty::of(Ty::NUM | Ty::BIG));
+            }
+            if ty.may_be_float() {
+                result = result.join(Ty::of(Ty::FLOAT));
+            }
+            (result, overflow)
+        }
+        Op1::IsNum | Op1::IsBool | Op1::IsVec | Op1::IsString | Op1::IsFloat => {
+            (Ty::of(Ty::BOOL), false)
+        }
+        Op1::Print => (ty, false),
+    }
+}
+
+fn clamp(lo: i128, hi: i128) -> (Ty, bool) {
+    let overflow = lo < MIN_NUM as i128 || hi > MAX_NUM as i128;
+    let lo = lo.clamp(MIN_NUM as i128, MAX_NUM as i128) as i64;
+    let hi = hi.clamp(MIN_NUM as i128, MAX_NUM as i128) as i64;
+    let ty = Ty::num(lo, hi);
+    (
+        if overflow {
+            ty.join(Ty::of(Ty::BIG))
+        } else {
+            ty
+        },
+        overflow,
+    )
+}
+
+/// The type of every expression in a program, computed by [`Facts::analyze`].
+#[derive(Default)]
+pub struct Facts {
+    tys: HashMap<*const Expr, Ty>,
+}
+
+impl Facts {
+    /// Runs a flow-sensitive analysis over the program approximating the value of each expression.
+    ///
+    /// Function parameters are assumed to be of any type. Function results are approximated by
+    /// iterating over all function bodies until their summaries stop changing. Without `bignums`,
+    /// no value is ever a bignum.
+    pub fn analyze(prg: &Prog, bignums: bool) -> Facts {
+        let mut analyzer = Analyzer {
+            rets: prg.funs.iter().map(|fun| (fun.name, Ty::BOTTOM)).collect(),
+            loops: vec![],
+            record: None,
+            bignums,
+        };
+        let mut iter = 0;
+        loop {
+            let mut changed = false;
+            for fun in &prg.funs {
+                let ty = analyzer.fun_body(fun.params.as_slice(), &fun.body);
+                let prev = analyzer.rets[&fun.name];
+                let next = if iter >= WIDEN_AFTER {
+                    prev.widen(ty)
+                } else {
+                    prev.join(ty)
+                };
+                if next != prev {
+                    analyzer.rets.insert(fun.name, next);
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+            iter += 1;
+        }
+
+        analyzer.record = Some(HashMap::new());
+        for fun in &prg.funs {
+            analyzer.fun_body(&fun.params, &fun.body);
+        }
+        if let Some(main) = &prg.main {
+            analyzer.expr(&im::HashMap::new(), &mut Store::new(), main);
+        }
+        Facts {
+            tys: analyzer.record.unwrap(),
+        }
+    }
+
+    pub fn ty(&self, e: &Expr) -> Ty {
+        self.tys
+            .get(&(e as *const Expr))
+            .copied()
+            .unwrap_or(Ty::TOP)
+    }
+}
+
+/// Identifies a variable binding: the address of the node introducing it and its position.
+type VarId = (usize, usize);
+
+/// Lexical scope mapping names to bindings.
+type Scope = im::HashMap<Symbol, VarId>;
+
+/// Type of each binding at a program point.
+type Store = im::HashMap<VarId, Ty>;
+
+/// What is known about an enclosing loop while analyzing its body.
+struct LoopState {
+    label: Option<Symbol>,
+    /// The join of the stores and types of the values at each `break` seen so far
+    exit: Option<(Store, Ty)>,
+    /// The join of the stores at each `continue` seen so far
+    restart: Option<Store>,
+}
+
+struct Analyzer {
+    rets: HashMap<Symbol, Ty>,
+    loops: Vec<LoopState>,
+    /// Types observed for each expression, only collected once function summaries are stable
+    record: Option<HashMap<*const Expr, Ty>>,
+    bignums: bool,
+}
+
+impl Analyzer {
+    fn target_loop(&mut self, label: Option<Symbol>) -> Option<&mut LoopState> {
+        match label {
+            Some(label) => self.loops.iter_mut().rfind(|lp| lp.label == Some(label)),
+            None => self.loops.last_mut(),
+        }
+    }
+
+    fn fun_body(&mut self, params: &[Symbol], body: &Expr) -> Ty {
+        let mut scope = Scope::new();
+        let mut store = Store::new();
+        for (i, param) in params.iter().enumerate() {
+            let id = (params.as_ptr() as usize, i);
+            scope.insert(*param, id);
+            store.insert(id, Ty::TOP);
+        }
+        self.expr(&scope, &mut store, body)
+    }
+
+    fn expr(&mut self, scope: &Scope, store: &mut Store, e: &Expr) -> Ty {
+        let ty = match e {
+            Expr::Number(n) => Ty::num(*n, *n),
+            Expr::BigNum(_) => Ty::of(Ty::BIG),
+            Expr::Float(_) => Ty::of(Ty::FLOAT),
+            Expr::Boolean(_) => Ty::of(Ty::BOOL),
+            Expr::Str(_) => Ty::of(Ty::STR),
+            Expr::Nil => Ty::of(Ty::NIL),
+            Expr::Input => Ty::of(Ty::INPUT),
+            Expr::Var(x) => match scope.get(x) {
+                Some(id) => store[id],
+                None => Ty::TOP,
+            },
+            Expr::Let(bindings, body) => {
+                let mut scope = scope.clone();
+                for (i, (x, rhs)) in bindings.iter().enumerate() {
+                    let ty = self.expr(&scope, store, rhs);
+                    let id = (e as *const Expr as usize, i);
+                    scope.insert(*x, id);
+                    store.insert(id, ty);
+                }
+                self.expr(&scope, store, body)
+            }
+            Expr::UnOp(op, e) => {
+                let ty = self.expr(scope, store, e);
+                un_op_range(*op, ty).0
+            }
+            Expr::BinOp(op, e1, e2) => {
+                let t1 = self.expr(scope, store, e1);
+                let t2 = self.expr(scope, store, e2);
+                bin_op_range(*op, t1, t2).0
+            }
+            Expr::If(e1, e2, e3) => {
+                let (mut then_store, mut else_store) = self.cond(scope, store, e1);
+                let t2 = self.expr(scope, &mut then_store, e2);
+                let t3 = self.expr(scope, &mut else_store, e3);
+                *store = join_stores(&then_store, &else_store);
+                t2.join(t3)
+            }
+            Expr::Loop(label, body) => {
+                let mut head = store.clone();
+                let mut iter = 0;
+                loop {
+                    self.loops.push(LoopState {
+                        label: *label,
+                        exit: None,
+                        restart: None,
+                    });
+                    let mut body_store = head.clone();
+                    self.expr(scope, &mut body_store, body);
+                    let LoopState { exit, restart, .. } = self.loops.pop().unwrap();
+                    if let Some(restart) = restart {
+                        body_store = join_stores(&body_store, &restart);
+                    }
+                    let next = if iter >= WIDEN_AFTER {
+                        widen_stores(&head, &body_store)
+                    } else {
+                        join_stores(&head, &body_store)
+                    };
+                    if next == head {
+                        // A loop without `break` never finishes
+                        let (exit_store, ty) = exit.unwrap_or((head, Ty::BOTTOM));
+                        *store = exit_store;
+                        break ty;
+                    }
+                    head = next;
+                    iter += 1;
+                }
+            }
+            Expr::Break(label, e) => {
+                let ty = self.expr(scope, store, e);
+                if let Some(target) = self.target_loop(*label) {
+                    target.exit = Some(match target.exit.take() {
+                        Some((prev_store, prev_ty)) => {
+                            (join_stores(&prev_store, store), prev_ty.join(ty))
+                        }
+                        None => (store.clone(), ty),
+                    });
+                }
+                Ty::BOTTOM
+            }
+            Expr::Continue(label) => {
+                if let Some(target) = self.target_loop(*label) {
+                    target.restart = Some(match target.restart.take() {
+                        Some(prev) => join_stores(&prev, store),
+                        None => store.clone(),
+                    });
+                }
+                Ty::BOTTOM
+            }
+            Expr::Set(x, e) => {
+                let ty = self.expr(scope, store, e);
+                if let Some(id) = scope.get(x) {
+                    store.insert(*id, ty);
+                }
+                ty
+            }
+            Expr::Block(es) => es
+                .iter()
+                .fold(Ty::BOTTOM, |_, e| self.expr(scope, store, e)),
+            Expr::Call(fun, args) => {
+                for arg in args {
+                    self.expr(scope, store, arg);
+                }
+                self.rets.get(fun).copied().unwrap_or(Ty::TOP)
+            }
+            Expr::Prim(prim, args) => {
+                for arg in args {
+                    self.expr(scope, store, arg);
+                }
+                prim_ty(*prim)
+            }
+            Expr::StructNew(_, args) => {
+                for arg in args {
+                    self.expr(scope, store, arg);
+                }
+                Ty::of(Ty::OBJ)
+            }
+            Expr::CallExtern(_, args) => {
+                for arg in args {
+                    self.expr(scope, store, arg);
+                }
+                Ty::num(MIN_NUM, MAX_NUM)
+            }
+            Expr::StructGet(_, _, e) => {
+                self.expr(scope, store, e);
+                Ty::TOP
+            }
+            Expr::StructSet(_, _, e1, e2) => {
+                self.expr(scope, store, e1);
+                self.expr(scope, store, e2);
+                Ty::of(Ty::OBJ)
+            }
+            Expr::Annot(_, e) | Expr::At(_, e) => self.expr(scope, store, e),
+            Expr::NoMatch(e) | Expr::AssertFailed(_, e) | Expr::Raise(e) => {
+                self.expr(scope, store, e);
+                Ty::BOTTOM
+            }
+            Expr::Try(body, x, handler) => {
+                // The handler may run after any part of the body, so the variables the body
+                // assigns may have any value
+                let mut assigned = HashSet::new();
+                assigned_vars(body, &mut assigned);
+                let mut handler_store = store.clone();
+                for x in &assigned {
+                    if let Some(id) = scope.get(x) {
+                        handler_store.insert(*id, Ty::TOP);
+                    }
+                }
+                let t1 = self.expr(scope, store, body);
+                let id = (e as *const Expr as usize, 0);
+                handler_store.insert(id, Ty::TOP);
+                let t2 = self.expr(&scope.update(*x, id), &mut handler_store, handler);
+                *store = join_stores(store, &handler_store);
+                t1.join(t2)
+            }
+            Expr::StructIs(_, e) => {
+                self.expr(scope, store, e);
+                Ty::of(Ty::BOOL)
+            }
+            Expr::MakeVec(size, elem) => {
+                self.expr(scope, store, size);
+                self.expr(scope, store, elem);
+                Ty::of(Ty::VEC)
+            }
+            Expr::Vec(es) => {
+                for e in es {
+                    self.expr(scope, store, e);
+                }
+                Ty::of(Ty::VEC)
+            }
+            Expr::VecSet(vec, idx, elem) => {
+                self.expr(scope, store, vec);
+                self.expr(scope, store, idx);
+                self.expr(scope, store, elem);
+                Ty::of(Ty::VEC)
+            }
+            Expr::VecGet(vec, idx) => {
+                self.expr(scope, store, vec);
+                self.expr(scope, store, idx);
+                Ty::TOP
+            }
+            Expr::VecLen(vec) => {
+                self.expr(scope, store, vec);
+                Ty::num(0, MAX_NUM)
+            }
+            Expr::Gc | Expr::PrintStack | Expr::PrintHeap => Ty::num(0, 0),
+        };
+        let ty = if self.bignums { ty } else { ty.remove(Ty::BIG) };
+        if let Some(record) = &mut self.record {
+            record
+                .entry(e as *const Expr)
+                .and_modify(|prev| *prev = prev.join(ty))
+                .or_insert(ty);
+        }
+        ty
+    }
+
+    /// Analyzes the condition of an `if` returning the stores for the `then` and `else` branches,
+    /// narrowing the type of variables tested in the condition.
+    fn cond(&mut self, scope: &Scope, store: &mut Store, e: &Expr) -> (Store, Store) {
+        self.expr(scope, store, e);
+        let (mut then_store, mut else_store) = (store.clone(), store.clone());
+        let var = |e: &Expr| match e {
+            Expr::Var(x) => scope.get(x).copied(),
+            _ => None,
+        };
+        match e {
+            Expr::UnOp(
+                op @ (Op1::IsNum | Op1::IsBool | Op1::IsVec | Op1::IsString | Op1::IsFloat),
+                x,
+            ) => {
+                if let Some(id) = var(x) {
+                    let tags = match op {
+                        Op1::IsNum => Ty::NUM | Ty::BIG,
+                        Op1::IsBool => Ty::BOOL,
+                        Op1::IsString => Ty::STR,
+                        Op1::IsFloat => Ty::FLOAT,
+                        _ => Ty::VEC | Ty::NIL,
+                    };
+                    then_store.insert(id, store[&id].keep(tags));
+                    else_store.insert(id, store[&id].remove(tags));
+                }
+            }
+            Expr::BinOp(Op2::Equal, e1, e2) => {
+                for (x, other) in [(e1, e2), (e2, e1)] {
+                    if let (Some(id), Expr::Nil) = (var(x), &**other) {
+                        then_store.insert(id, store[&id].keep(Ty::NIL));
+                        else_store.insert(id, store[&id].remove(Ty::NIL));
+                    }
+                }
+            }
+            Expr::BinOp(
+                op @ (Op2::Less | Op2::LessEqual | Op2::Greater | Op2::GreaterEqual),
+                e1,
+                e2,
+            ) if is_simple(e1) && is_simple(e2) => {
+                let ty = |e: &Expr| match var(e) {
+                    Some(id) => store[&id],
+                    None => simple_ty(e),
+                };
+                let (t1, t2) = (ty(e1), ty(e2));
+                if let Some(id) = var(e1) {
+                    let (then_ty, else_ty) = narrow_cmp(*op, t1, t2);
+                    then_store.insert(id, then_ty);
+                    else_store.insert(id, else_ty);
+                }
+                if let Some(id) = var(e2) {
+                    let (then_ty, else_ty) = narrow_cmp(flip(*op), t2, t1);
+                    then_store.insert(id, then_ty);
+                    else_store.insert(id, else_ty);
+                }
+            }
+            _ => {}
+        }
+        (then_store, else_store)
+    }
+}
+
+/// Expressions that can be evaluated without side effects.
+fn is_simple(e: &Expr) -> bool {
+    matches!(e, Expr::Var(_) | Expr::Number(_) | Expr::Input)
+}
+
+/// Type of a simple expression other than a variable.
+fn simple_ty(e: &Expr) -> Ty {
+    match e {
+        Expr::Number(n) => Ty::num(*n, *n),
+        Expr::Input => Ty::of(Ty::INPUT),
+        _ => Ty::TOP,
+    }
+}
+
+/// Narrows `t1` knowing `t1 op t2` is true (first component) or false (second component). Both
+/// operands of an ordering comparison must be numbers, otherwise the comparison fails. Only the
+/// range of tagged numbers is narrowed, and only against tagged numbers.
+fn narrow_cmp(op: Op2, t1: Ty, t2: Ty) -> (Ty, Ty) {
+    let t1 = t1.keep(Ty::NUMBERS);
+    if t1.tags & Ty::NUM == 0 || t2.tags & Ty::NUM == 0 || t2.may_be_big() || t2.may_be_float() {
+        return (t1, t1);
+    }
+    let (lo, hi) = (t1.lo, t1.hi);
+    match op {
+        Op2::Less => (
+            t1.with_range(lo, hi.min(t2.hi.saturating_sub(1))),
+            t1.with_range(lo.max(t2.lo), hi),
+        ),
+        Op2::LessEqual => (
+            t1.with_range(lo, hi.min(t2.hi)),
+            t1.with_range(lo.max(t2.lo.saturating_add(1)), hi),
+        ),
+        Op2::Greater => (
+            t1.with_range(lo.max(t2.lo.saturating_add(1)), hi),
+            t1.with_range(lo, hi.min(t2.hi)),
+        ),
+        Op2::GreaterEqual => (
+            t1.with_range(lo.max(t2.lo), hi),
+            t1.with_range(lo, hi.min(t2.hi.saturating_sub(1))),
+        ),
+        Op2::Equal
+        | Op2::Plus
+        | Op2::Minus
+        | Op2::Times
+        | Op2::Divide
+        | Op2::Remainder
+        | Op2::Modulo => (t1, t1),
+    }
+}
+
+/// The comparison obtained by swapping the operands.
+fn flip(op: Op2) -> Op2 {
+    match op {
+        Op2::Less => Op2::Greater,
+        Op2::LessEqual => Op2::GreaterEqual,
+        Op2::Greater => Op2::Less,
+        Op2::GreaterEqual => Op2::LessEqual,
+        _ => op,
+    }
+}
+
+/// Adds the variables `e` assigns to `out`.
+fn assigned_vars(e: &Expr, out: &mut HashSet<Symbol>) {
+    if let Expr::Set(x, _) = e {
+        out.insert(*x);
+    }
+    e.for_each_child(|e| assigned_vars(e, out));
+}
+
+fn join_stores(s1: &Store, s2: &Store) -> Store {
+    s1.clone().union_with(s2.clone(), |t1, t2| t1.join(t2))
+}
+
+fn widen_stores(prev: &Store, next: &Store) -> Store {
+    prev.clone().union_with(next.clone(), |t1, t2| t1.widen(t2))
+}
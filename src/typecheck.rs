@@ -0,0 +1,471 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::{
+    sexp::Pos,
+    syntax::{Expr, FunDecl, Op1, Op2, Prim, Prog, Symbol, Type},
+};
+
+/// Number of rounds after which return types that keep changing are widened to `any`.
+const WIDEN_AFTER: u32 = 3;
+
+/// Checks the program against its type annotations and reports every mismatch.
+///
+/// The checking is gradual: `any` (the type of unannotated parameters) is consistent with every
+/// type, and those values are still checked at runtime. The types of `let` bindings and the return
+/// types of functions are inferred when they aren't annotated, except for variables assigned with
+/// `set!`, which may hold values of different types and are given type `any`.
+pub fn check(prg: &Prog) {
+    let mut checker = Checker::new(prg);
+    checker.infer_returns(&prg.funs);
+    for fun in &prg.funs {
+        checker.check_fun(fun);
+    }
+    if let Some(main) = &prg.main {
+        checker.pos = None;
+        checker.expr(&Env::new(), main);
+    }
+    if !checker.errors.is_empty() {
+        panic!("{}", checker.errors.join("\n"))
+    }
+}
+
+type Env = im::HashMap<Symbol, Type>;
+
+struct Checker {
+    /// Parameter and return types of each function
+    sigs: HashMap<Symbol, (Vec<Type>, Type)>,
+    /// Fields of each struct, to name accessors in errors
+    fields: HashMap<Symbol, Vec<Symbol>>,
+    /// The types of the `break`s seen so far in each enclosing loop
+    loops: Vec<(Option<Symbol>, Type)>,
+    /// Position of the innermost compound expression being checked
+    pos: Option<Pos>,
+    errors: Vec<String>,
+}
+
+impl Checker {
+    fn new(prg: &Prog) -> Checker {
+        let sigs = prg
+            .funs
+            .iter()
+            .map(|fun| {
+                let ret = fun.ret_ty.clone().unwrap_or(Type::Never);
+                (fun.name, (fun.param_tys.clone(), ret))
+            })
+            .collect();
+        let fields = prg
+            .structs
+            .iter()
+            .chain(&prg.imported_structs)
+            .map(|decl| (decl.name, decl.fields.clone()))
+            .collect();
+        Checker {
+            sigs,
+            fields,
+            loops: vec![],
+            pos: None,
+            errors: vec![],
+        }
+    }
+
+    /// Infers the return types of functions without annotations, starting from `never` and
+    /// joining the type of their bodies until nothing changes. Errors are only reported afterwards.
+    fn infer_returns(&mut self, funs: &[FunDecl]) {
+        for iter in 0.. {
+            let mut changed = false;
+            for fun in funs.iter().filter(|fun| fun.ret_ty.is_none()) {
+                let ty = self.body(fun);
+                let prev = &self.sigs[&fun.name].1;
+                let mut next = join(prev, &ty);
+                if next != *prev {
+                    if iter >= WIDEN_AFTER {
+                        next = Type::Any;
+                    }
+                    self.sigs.get_mut(&fun.name).unwrap().1 = next;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        self.errors.clear();
+    }
+
+    fn check_fun(&mut self, fun: &FunDecl) {
+        let ty = self.body(fun);
+        if let Some(ret) = &fun.ret_ty {
+            self.pos = match &fun.body {
+                Expr::At(pos, _) => Some(*pos),
+                _ => None,
+            };
+            if !consistent(&ty, ret) {
+                self.error(format!("`{}` should return {ret}, found {ty}", fun.name));
+            }
+        }
+    }
+
+    fn body(&mut self, fun: &FunDecl) -> Type {
+        let env = fun
+            .params
+            .iter()
+            .copied()
+            .zip(fun.param_tys.iter().cloned())
+            .collect();
+        self.pos = None;
+        self.expr(&env, &fun.body)
+    }
+
+    fn expr(&mut self, env: &Env, e: &Expr) -> Type {
+        match e {
+            Expr::Number(_) | Expr::BigNum(_) => Type::Num,
+            Expr::Float(_) => Type::Float,
+            Expr::Boolean(_) => Type::Bool,
+            Expr::Str(_) => Type::Str,
+            Expr::Nil => Type::Nil,
+            Expr::Input | Expr::PrintStack | Expr::PrintHeap => Type::Any,
+            Expr::Gc => Type::Num,
+            // Unbound variables are reported by the compiler
+            Expr::Var(x) => env.get(x).cloned().unwrap_or(Type::Any),
+            Expr::Let(bindings, body) => {
+                let mut env = env.clone();
+                for (x, rhs) in bindings {
+                    let mut ty = self.expr(&env, rhs);
+                    if !matches!(rhs, Expr::Annot(..)) && body.assigns(*x) {
+                        ty = Type::Any;
+                    }
+                    env.insert(*x, ty);
+                }
+                self.expr(&env, body)
+            }
+            Expr::UnOp(op, e) => {
+                let ty = self.expr(env, e);
+                match op {
+                    Op1::Add1 | Op1::Sub1 => {
+                        self.expect_number(&ty, format!("`{}`", op1_name(*op)));
+                        arith_ty(&ty, &Type::Num)
+                    }
+                    Op1::IsNum | Op1::IsBool | Op1::IsVec | Op1::IsString | Op1::IsFloat => {
+                        Type::Bool
+                    }
+                    Op1::Print => ty,
+                }
+            }
+            Expr::BinOp(op, e1, e2) => {
+                let t1 = self.expr(env, e1);
+                let t2 = self.expr(env, e2);
+                match op {
+                    Op2::Equal => {
+                        if !(consistent(&t1, &t2) || is_number(&t1) && is_number(&t2)) {
+                            self.error(format!("`=` can't compare {t1} and {t2}"));
+                        }
+                        Type::Bool
+                    }
+                    _ => {
+                        let what = format!("`{}`", op2_name(*op));
+                        self.expect_number(&t1, &what);
+                        self.expect_number(&t2, &what);
+                        match op {
+                            Op2::Greater | Op2::GreaterEqual | Op2::Less | Op2::LessEqual => {
+                                Type::Bool
+                            }
+                            _ => arith_ty(&t1, &t2),
+                        }
+                    }
+                }
+            }
+            Expr::If(cond, thn, els) => {
+                self.expr(env, cond);
+                let t1 = self.expr(env, thn);
+                let t2 = self.expr(env, els);
+                join(&t1, &t2)
+            }
+            Expr::Loop(label, body) => {
+                self.loops.push((*label, Type::Never));
+                self.expr(env, body);
+                self.loops.pop().unwrap().1
+            }
+            Expr::Break(label, e) => {
+                let ty = self.expr(env, e);
+                let target = match label {
+                    Some(label) => self.loops.iter_mut().rfind(|(l, _)| *l == Some(*label)),
+                    None => self.loops.last_mut(),
+                };
+                if let Some((_, exit)) = target {
+                    *exit = join(exit, &ty);
+                }
+                Type::Never
+            }
+            Expr::Continue(_) => Type::Never,
+            Expr::Set(x, e) => {
+                let ty = self.expr(env, e);
+                if let Some(var_ty) = env.get(x) {
+                    if !consistent(&ty, var_ty) {
+                        self.error(format!("can't assign {ty} to `{x}` of type {var_ty}"));
+                    }
+                }
+                ty
+            }
+            Expr::Block(es) => es.iter().fold(Type::Never, |_, e| self.expr(env, e)),
+            Expr::MakeVec(size, elem) => {
+                let size = self.expr(env, size);
+                self.expect(&size, &Type::Num, "`make-vec`");
+                Type::Vec(Box::new(self.expr(env, elem)))
+            }
+            Expr::Vec(es) => {
+                let elem = es
+                    .iter()
+                    .fold(Type::Never, |ty, e| join(&ty, &self.expr(env, e)));
+                Type::Vec(Box::new(elem))
+            }
+            Expr::VecSet(vec, idx, val) => {
+                let vec = self.expr(env, vec);
+                let elem = self.vec_elem(&vec, "vec-set!");
+                let idx = self.expr(env, idx);
+                self.expect(&idx, &Type::Num, "`vec-set!`");
+                let val = self.expr(env, val);
+                self.expect(&val, &elem, "`vec-set!`");
+                vec
+            }
+            Expr::VecGet(vec, idx) => {
+                let vec = self.expr(env, vec);
+                let elem = self.vec_elem(&vec, "vec-get");
+                let idx = self.expr(env, idx);
+                self.expect(&idx, &Type::Num, "`vec-get`");
+                elem
+            }
+            Expr::VecLen(vec) => {
+                let vec = self.expr(env, vec);
+                self.vec_elem(&vec, "vec-len");
+                Type::Num
+            }
+            Expr::Call(fun, args) => {
+                let tys: Vec<_> = args.iter().map(|arg| self.expr(env, arg)).collect();
+                // Undefined functions and arity mismatches are reported by the compiler
+                let Some((params, ret)) = self.sigs.get(fun).cloned() else {
+                    return Type::Any;
+                };
+                if params.len() == tys.len() {
+                    for (i, (ty, param)) in tys.iter().zip(&params).enumerate() {
+                        self.expect(ty, param, format!("argument {} of `{fun}`", i + 1));
+                    }
+                }
+                ret
+            }
+            Expr::Prim(prim, args) => {
+                let (params, ret) = prim_sig(*prim);
+                for (i, (arg, param)) in args.iter().zip(&params).enumerate() {
+                    let ty = self.expr(env, arg);
+                    let what = format!("argument {} of `{}`", i + 1, prim.name());
+                    if accepts_floats(*prim) {
+                        self.expect_number(&ty, what);
+                    } else {
+                        self.expect(&ty, param, what);
+                    }
+                }
+                ret
+            }
+            Expr::StructNew(_, args) => {
+                for arg in args {
+                    self.expr(env, arg);
+                }
+                Type::Any
+            }
+            // The arguments are numbers or booleans, checked at runtime
+            Expr::CallExtern(_, args) => {
+                for arg in args {
+                    self.expr(env, arg);
+                }
+                Type::Num
+            }
+            Expr::StructGet(name, field, e) => {
+                let ty = self.expr(env, e);
+                let accessor = format!("{name}-{}", self.fields[name][*field]);
+                self.expect_struct(&ty, *name, &accessor);
+                Type::Any
+            }
+            Expr::StructSet(name, field, e, val) => {
+                let ty = self.expr(env, e);
+                self.expr(env, val);
+                let setter = format!("set-{name}-{}!", self.fields[name][*field]);
+                self.expect_struct(&ty, *name, &setter);
+                Type::Any
+            }
+            Expr::StructIs(_, e) => {
+                self.expr(env, e);
+                Type::Bool
+            }
+            Expr::NoMatch(e) | Expr::AssertFailed(_, e) | Expr::Raise(e) => {
+                self.expr(env, e);
+                Type::Never
+            }
+            Expr::Try(body, x, handler) => {
+                let t1 = self.expr(env, body);
+                let t2 = self.expr(&env.update(*x, Type::Any), handler);
+                join(&t1, &t2)
+            }
+            Expr::Annot(ty, e) => {
+                let found = self.expr(env, e);
+                if !consistent(&found, ty) {
+                    self.error(format!("expected {ty}, found {found}"));
+                }
+                ty.clone()
+            }
+            Expr::At(pos, e) => {
+                let outer = self.pos.replace(*pos);
+                let ty = self.expr(env, e);
+                self.pos = outer;
+                ty
+            }
+        }
+    }
+
+    /// The type of the elements of a value of type `ty` used as a vector by `what`.
+    fn vec_elem(&mut self, ty: &Type, what: &str) -> Type {
+        match ty {
+            Type::Vec(elem) => (**elem).clone(),
+            Type::Never => Type::Never,
+            Type::Any | Type::Nil => Type::Any,
+            _ => {
+                self.error(format!("`{what}` expects a vector, found {ty}"));
+                Type::Any
+            }
+        }
+    }
+
+    /// Structs aren't part of the type language, so only values of type `any` may be structs.
+    fn expect_struct(&mut self, ty: &Type, name: Symbol, what: &str) {
+        if !matches!(ty, Type::Any | Type::Never) {
+            self.error(format!("`{what}` expects a {name}, found {ty}"));
+        }
+    }
+
+    fn expect(&mut self, found: &Type, expected: &Type, what: impl Display) {
+        if !consistent(found, expected) {
+            self.error(format!("{what} expects {expected}, found {found}"));
+        }
+    }
+
+    /// Checks `found` may be an integer or a float.
+    fn expect_number(&mut self, found: &Type, what: impl Display) {
+        if !is_number(found) {
+            self.error(format!("{what} expects a number, found {found}"));
+        }
+    }
+
+    fn error(&mut self, msg: String) {
+        let msg = match self.pos {
+            Some(pos) => format!("Type error at {pos}: {msg}"),
+            None => format!("Type error: {msg}"),
+        };
+        self.errors.push(msg);
+    }
+}
+
+/// Whether a value of type `t1` may be used where a `t2` is expected. Nil is the empty vector, so
+/// it's consistent with every vector type.
+fn consistent(t1: &Type, t2: &Type) -> bool {
+    match (t1, t2) {
+        (Type::Any | Type::Never, _) | (_, Type::Any | Type::Never) => true,
+        (Type::Nil, Type::Vec(_)) | (Type::Vec(_), Type::Nil) => true,
+        (Type::Vec(e1), Type::Vec(e2)) => consistent(e1, e2),
+        _ => t1 == t2,
+    }
+}
+
+/// The most precise type of values that may have type `t1` or `t2`.
+fn join(t1: &Type, t2: &Type) -> Type {
+    match (t1, t2) {
+        (Type::Never, t) | (t, Type::Never) => t.clone(),
+        (Type::Nil, t @ Type::Vec(_)) | (t @ Type::Vec(_), Type::Nil) => t.clone(),
+        (Type::Vec(e1), Type::Vec(e2)) => Type::Vec(Box::new(join(e1, e2))),
+        _ if t1 == t2 => t1.clone(),
+        _ => Type::Any,
+    }
+}
+
+/// Whether values of type `ty` may be integers or floats.
+fn is_number(ty: &Type) -> bool {
+    matches!(ty, Type::Num | Type::Float | Type::Any | Type::Never)
+}
+
+/// The type of the result of arithmetic on numbers of types `t1` and `t2`: a float if either is a
+/// float, and an integer if both are integers.
+fn arith_ty(t1: &Type, t2: &Type) -> Type {
+    match (t1, t2) {
+        (Type::Float, _) | (_, Type::Float) => Type::Float,
+        (Type::Any, _) | (_, Type::Any) => Type::Any,
+        _ => Type::Num,
+    }
+}
+
+/// Primitives whose `num` parameters accept floats too.
+fn accepts_floats(prim: Prim) -> bool {
+    matches!(
+        prim,
+        Prim::NumberToString
+            | Prim::ToFloat
+            | Prim::Floor
+            | Prim::Ceiling
+            | Prim::Round
+            | Prim::Truncate
+    )
+}
+
+fn prim_sig(prim: Prim) -> (Vec<Type>, Type) {
+    match prim {
+        Prim::StringLength => (vec![Type::Str], Type::Num),
+        Prim::StringAppend => (vec![Type::Str, Type::Str], Type::Str),
+        Prim::Substring => (vec![Type::Str, Type::Num, Type::Num], Type::Str),
+        Prim::StringRef => (vec![Type::Str, Type::Num], Type::Str),
+        Prim::StringEq | Prim::StringLess => (vec![Type::Str, Type::Str], Type::Bool),
+        Prim::NumberToString => (vec![Type::Num], Type::Str),
+        // `false` when the string isn't a number
+        Prim::StringToNumber => (vec![Type::Str], Type::Any),
+        Prim::Equal => (vec![Type::Any, Type::Any], Type::Bool),
+        Prim::ToFloat => (vec![Type::Num], Type::Float),
+        Prim::Floor | Prim::Ceiling | Prim::Round | Prim::Truncate => (vec![Type::Num], Type::Num),
+        Prim::MakeMap => (vec![], Type::Map),
+        Prim::MapGet => (vec![Type::Map, Type::Any, Type::Any], Type::Any),
+        Prim::MapSet => (vec![Type::Map, Type::Any, Type::Any], Type::Map),
+        Prim::MapDelete => (vec![Type::Map, Type::Any], Type::Map),
+        Prim::MapHas => (vec![Type::Map, Type::Any], Type::Bool),
+        Prim::MapCount => (vec![Type::Map], Type::Num),
+        Prim::MapKeys => (vec![Type::Map], Type::Vec(Box::new(Type::Any))),
+        Prim::CommandLine => (vec![], Type::Vec(Box::new(Type::Str))),
+        // nil at the end of the input
+        Prim::ReadLine | Prim::ReadInt => (vec![], Type::Any),
+        Prim::Display | Prim::EPrint => (vec![Type::Any], Type::Any),
+        Prim::ReadFile => (vec![Type::Str], Type::Str),
+        Prim::WriteFile => (vec![Type::Str, Type::Str], Type::Nil),
+    }
+}
+
+fn op1_name(op: Op1) -> &'static str {
+    match op {
+        Op1::Add1 => "add1",
+        Op1::Sub1 => "sub1",
+        Op1::IsNum => "isnum",
+        Op1::IsBool => "isbool",
+        Op1::IsVec => "isvec",
+        Op1::IsString => "isstring",
+        Op1::IsFloat => "isfloat",
+        Op1::Print => "print",
+    }
+}
+
+fn op2_name(op: Op2) -> &'static str {
+    match op {
+        Op2::Plus => "+",
+        Op2::Minus => "-",
+        Op2::Times => "*",
+        Op2::Divide => "/",
+        Op2::Remainder => "remainder",
+        Op2::Modulo => "modulo",
+        Op2::Equal => "=",
+        Op2::Greater => ">",
+        Op2::GreaterEqual => ">=",
+        Op2::Less => "<",
+        Op2::LessEqual => "<=",
+    }
+}
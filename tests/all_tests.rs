@@ -79,7 +79,272 @@ success_tests! {
         file: "simple_garbage.snek",
         expected: "0",
     },
-
+    {
+        name: inline_wrappers,
+        file: "inline_wrappers.snek",
+        expected: "-9\n4\n2\n1\n22",
+    },
+    {
+        name: inline_wrappers_o0,
+        file: "inline_wrappers.snek",
+        flags: ["-O0"],
+        expected: "-9\n4\n2\n1\n22",
+    },
+    {
+        name: inline_recursive,
+        file: "inline_recursive.snek",
+        input: "5",
+        expected: "[120, false]",
+    },
+    {
+        name: tag_checks,
+        file: "tag_checks.snek",
+        expected: "[2, 0, 3]\n45\n[6, 2, 3]",
+    },
+    {
+        name: tag_checks_o0,
+        file: "tag_checks.snek",
+        flags: ["-O0"],
+        expected: "[2, 0, 3]\n45\n[6, 2, 3]",
+    },
+    {
+        name: strings,
+        file: "strings.snek",
+        expected: "hello, snek!\n5\nflame\nb\n[true, false, true, false]\n42 is the answer\n-16\nfalse\n[true, false, false, true, false]\n[\"a \\\"quoted\\\" string\", 1, nil]\ntrue\na, b, c",
+    },
+    {
+        name: string_gc,
+        file: "string_gc.snek",
+        heap_size: 30,
+        expected: "[\"start!\", \"012345678901234567890123456789\"]",
+    },
+    {
+        name: cond_forms,
+        file: "cond_forms.snek",
+        expected: "[true, false, false]\n12\n[\"negative\", \"zero\", \"small\", \"large\"]\n[true, 2, false, false, 3, 4]\nnil\nevaluated once\n[true, false, false]\nnil",
+    },
+    {
+        name: division,
+        file: "division.snek",
+        input: "0",
+        expected: "[3, -3, -3, 3]\n[1, -1, 1, -1]\n[1, 1, -1, -1, 0]\n[false, 1]",
+    },
+    {
+        name: equal,
+        file: "equal.snek",
+        expected: "[false, true, true]\n[true, false, true, true, false]\n[true, false, false]\n[false, false]\n[true, false]",
+    },
+    {
+        name: bst_struct,
+        file: "bst_struct.snek",
+        heap_size: 40,
+        expected: "Node{val: 5, left: Node{val: 2, left: nil, right: nil}, right: Node{val: 8, left: nil, right: nil}}\n[true, true, false]\n[true, false, false]\ntrue\n8",
+    },
+    {
+        name: typed,
+        file: "typed.snek",
+        flags: ["--typecheck"],
+        input: "4",
+        expected: "6\ntrue\n5\nhello, snek\n5",
+    },
+    {
+        name: linked_list_match,
+        file: "linked_list_match.snek",
+        expected: "Cons{head: 1, tail: Cons{head: 2, tail: Cons{head: 3, tail: Cons{head: 4, tail: Cons{head: 5, tail: Empty}}}}}\n30\n3\n[\"zero\", \"yes\", \"a string\", \"one and 2\"]\n[\"three\", \"singleton\", \"singleton\"]\n[\"nested\", \"something else\", \"something else\"]\nEmpty",
+    },
+    {
+        name: modules,
+        file: "modules.snek",
+        expected: "Node{val: 5, left: Node{val: 2, left: nil, right: nil}, right: Node{val: 8, left: nil, right: nil}}\nCons{head: 2, tail: Cons{head: 5, tail: Cons{head: 8, tail: Empty}}}\n[true, false, 5]\n15",
+    },
+    {
+        name: prelude,
+        file: "prelude.snek",
+        expected: "[1, [2, [3, [4, [5, nil]]]]]\n[5, 3, 0]\n[1, [2, [3, [4, [5, [5, [4, [3, [2, [1, nil]]]]]]]]]]\n[2, [4, [6, [8, [10, nil]]]]]\n[3, [4, nil]]\n[1, [3, [5, nil]]]\n[15, 18]\n[1, [1, [2, [3, [4, [5, nil]]]]]]\n[[1, [2, nil]], nil, [1, [2, [3, [4, [5, nil]]]]], nil]\n[[4, [5, nil]], [1, [2, [3, [4, [5, nil]]]]], nil, nil]\n[7, 7, -2, 3]\n[[1, 2, 3, 4, 5], [2, 3], [true, true, true]]\ntrue",
+    },
+    {
+        name: prelude_override,
+        file: "prelude_override.snek",
+        expected: "[[3], -2]",
+    },
+    {
+        name: macros,
+        file: "macros.snek",
+        expected: "[2, 1]\n[false, 7, false]\n[1, 2, 20]\n1024\n1200\n12",
+    },
+    {
+        name: loops,
+        file: "loops.snek",
+        expected: "5\n25\n[2, 3]\nnil\n18\n[2, [4, [6, nil]]]\n[1, [2, [3, nil]]]\n10\n7\n4\n1\n10",
+    },
+    {
+        name: local_funs,
+        file: "local_funs.snek",
+        expected: "12\n3\n[false, true]\n[false, true]\n11\n21\n31\n1\n22",
+    },
+    {
+        name: bignums,
+        file: "bignums.snek",
+        expected: "265252859812191058636308480000000\n870\n0\n4611686018427387904\n-4611686018427387905\n123456789012345678901234567890\n-9999999999999999999800000000000000000001\ntrue\ntrue\nfalse\ntrue\n-886884\n113123\n15511210043330985984000000\ntrue\n4611686018427387904",
+    },
+    {
+        name: bignums_unoptimized,
+        file: "bignums.snek",
+        flags: ["-O0"],
+        expected: "265252859812191058636308480000000\n870\n0\n4611686018427387904\n-4611686018427387905\n123456789012345678901234567890\n-9999999999999999999800000000000000000001\ntrue\ntrue\nfalse\ntrue\n-886884\n113123\n15511210043330985984000000\ntrue\n4611686018427387904",
+    },
+    {
+        name: bignum_gc,
+        file: "bignum_gc.snek",
+        heap_size: 60,
+        expected: "[815915283247897734345611269596115894272000000096, 815915283247897734345611269596115894272000000097, 815915283247897734345611269596115894272000000098, 815915283247897734345611269596115894272000000099]\n99",
+    },
+    {
+        name: overflow_promotes_add1,
+        file: "tag_check_overflow.snek",
+        input: "true",
+        expected: "4611686018427387904",
+    },
+    {
+        name: overflow_promotes_sub1,
+        file: "tag_check_overflow.snek",
+        input: "false",
+        expected: "-4611686018427387905",
+    },
+    {
+        name: divide_overflow_promotes,
+        file: "divide_overflow.snek",
+        input: "-1",
+        expected: "4611686018427387904",
+    },
+    {
+        name: floats,
+        file: "floats.snek",
+        expected: "1.5\n-0.1\n0.30000000000000004\n2.5\n0.5\n0.25\n3\n1.8333333333333333\n2.5\ntrue\ntrue\nfalse\ntrue\ntrue\ntrue\nfalse\n3.0\n-2\n2\n2\n4\n-2\n100000000000000000000\n1.5\n0.5\n1e300\n6.02e23\ntrue\n0.3333333333333333",
+    },
+    {
+        name: floats_unoptimized,
+        file: "floats.snek",
+        flags: ["-O0"],
+        expected: "1.5\n-0.1\n0.30000000000000004\n2.5\n0.5\n0.25\n3\n1.8333333333333333\n2.5\ntrue\ntrue\nfalse\ntrue\ntrue\ntrue\nfalse\n3.0\n-2\n2\n2\n4\n-2\n100000000000000000000\n1.5\n0.5\n1e300\n6.02e23\ntrue\n0.3333333333333333",
+    },
+    {
+        name: floats_without_bignums,
+        file: "floats.snek",
+        flags: ["--no-bignums"],
+        expected: "1.5\n-0.1\n0.30000000000000004\n2.5\n0.5\n0.25\n3\n1.8333333333333333\n2.5\ntrue\ntrue\nfalse\ntrue\ntrue\ntrue\nfalse\n3.0\n-2\n2\n2\n4\n-2\n100000000000000000000\n1.5\n0.5\n1e300\n6.02e23\ntrue\n0.3333333333333333",
+    },
+    {
+        name: float_gc,
+        file: "float_gc.snek",
+        heap_size: 12,
+        expected: "[99.0, 99.5, 98.5]\n9950",
+    },
+    {
+        name: maps,
+        file: "maps.snek",
+        expected: "3\n2\nfalse\n3\n100\n9801\n66\n215589\nfloat\nbig\nbool\nvec\nnil\n#{\"k\": [1]}\n3\n2",
+    },
+    {
+        name: maps_unoptimized,
+        file: "maps.snek",
+        flags: ["-O0"],
+        expected: "3\n2\nfalse\n3\n100\n9801\n66\n215589\nfloat\nbig\nbool\nvec\nnil\n#{\"k\": [1]}\n3\n2",
+    },
+    {
+        name: map_gc,
+        file: "map_gc.snek",
+        heap_size: 200,
+        expected: "20\nfresh vector",
+    },
+    {
+        name: exceptions,
+        file: "exceptions.snek",
+        expected: "43\n3\nindex out of bounds\ninvalid argument\ndivision by zero\n3\ninvalid argument\nwrong struct\nno match\n[\"bottom\", 0]\nouter: rethrown\n5\n20\n103\n[1, 2, 3]",
+    },
+    {
+        name: exceptions_unoptimized,
+        file: "exceptions.snek",
+        flags: ["-O0"],
+        expected: "43\n3\nindex out of bounds\ninvalid argument\ndivision by zero\n3\ninvalid argument\nwrong struct\nno match\n[\"bottom\", 0]\nouter: rethrown\n5\n20\n103\n[1, 2, 3]",
+    },
+    {
+        name: exception_gc,
+        file: "exception_gc.snek",
+        heap_size: 10,
+        expected: "100",
+    },
+    {
+        name: exception_overflow,
+        file: "exception_overflow.snek",
+        flags: ["--no-bignums"],
+        input: "4611686018427387903",
+        expected: "[\"overflow\", \"overflow\"]",
+    },
+    {
+        name: snek_test_selected,
+        file: "snek_tests.snek",
+        flags: ["--test"],
+        input: "0",
+        expected: "checking double\nnil",
+    },
+    {
+        name: structured_input,
+        file: "inputs.snek",
+        input: "[1, [2, 3], nil, 100000000000000000000]",
+        heap_size: 30,
+        expected: "[1, [2, 3], nil, 100000000000000000000]\n[2, 3]\n[100000000000000000006, 2]\n[\"replaced\", [[...], [2, 3], nil, 100000000000000000000]]\n[\"[1, [2, 3], nil, 100000000000000000000]\", \"30\"]\n2",
+    },
+    {
+        name: structured_input_unoptimized,
+        file: "inputs.snek",
+        flags: ["-O0"],
+        input: "[ 7 ,[], [-8]]",
+        expected: "[7, [], [-8]]\n[]\n[-1, 2]\n[\"replaced\", [[...], [], [-8]]]\n[\"[ 7 ,[], [-8]]\"]\n1",
+    },
+    {
+        name: io,
+        file: "io.snek",
+        stdin: "numbers\n1\n 2 \n100000000000000000000\n",
+        expected: "header: numbers\ntotal 100000000000000000003\nnil\nio error\nnil\n100000000000000000003",
+    },
+    {
+        name: ffi,
+        file: "ffi.snek",
+        flags: ["--link", "tests/ffi_lib.o"],
+        expected: "6\n654321\n42\n[1, 0, -4611686018427387904]\n5050\n1\n7\ninvalid argument\ninvalid argument\noverflow",
+    },
+    {
+        name: exceptions_c,
+        file: "exceptions.snek",
+        flags: ["--backend", "c"],
+        expected: "43\n3\nindex out of bounds\ninvalid argument\ndivision by zero\n3\ninvalid argument\nwrong struct\nno match\n[\"bottom\", 0]\nouter: rethrown\n5\n20\n103\n[1, 2, 3]",
+    },
+    {
+        name: bignums_c,
+        file: "bignums.snek",
+        flags: ["--backend", "c"],
+        expected: "265252859812191058636308480000000\n870\n0\n4611686018427387904\n-4611686018427387905\n123456789012345678901234567890\n-9999999999999999999800000000000000000001\ntrue\ntrue\nfalse\ntrue\n-886884\n113123\n15511210043330985984000000\ntrue\n4611686018427387904",
+    },
+    {
+        name: floats_c,
+        file: "floats.snek",
+        flags: ["--backend", "c"],
+        expected: "1.5\n-0.1\n0.30000000000000004\n2.5\n0.5\n0.25\n3\n1.8333333333333333\n2.5\ntrue\ntrue\nfalse\ntrue\ntrue\ntrue\nfalse\n3.0\n-2\n2\n2\n4\n-2\n100000000000000000000\n1.5\n0.5\n1e300\n6.02e23\ntrue\n0.3333333333333333",
+    },
+    {
+        name: modules_c,
+        file: "modules.snek",
+        flags: ["--backend", "c"],
+        expected: "Node{val: 5, left: Node{val: 2, left: nil, right: nil}, right: Node{val: 8, left: nil, right: nil}}\nCons{head: 2, tail: Cons{head: 5, tail: Cons{head: 8, tail: Empty}}}\n[true, false, 5]\n15",
+    },
+    {
+        name: bst_struct_c,
+        file: "bst_struct.snek",
+        flags: ["--backend", "c"],
+        heap_size: 40,
+        expected: "Node{val: 5, left: Node{val: 2, left: nil, right: nil}, right: Node{val: 8, left: nil, right: nil}}\n[true, true, false]\n[true, false, false]\ntrue\n8",
+    },
 }
 
 runtime_error_tests! {
@@ -110,7 +375,335 @@ runtime_error_tests! {
         heap_size: 50,
         expected: "out of memory",
     },
+    {
+        name: tag_check_set,
+        file: "tag_check_set.snek",
+        expected: "invalid argument",
+    },
+    {
+        name: tag_check_overflow_add1,
+        file: "tag_check_overflow.snek",
+        flags: ["--no-bignums"],
+        input: "true",
+        expected: "overflow",
+    },
+    {
+        name: tag_check_overflow_sub1,
+        file: "tag_check_overflow.snek",
+        flags: ["--no-bignums"],
+        input: "false",
+        expected: "overflow",
+    },
+    {
+        name: tag_check_fun,
+        file: "tag_check_fun.snek",
+        input: "5",
+        expected: "invalid argument",
+    },
+    {
+        name: struct_wrong_struct,
+        file: "struct_wrong_type.snek",
+        input: "true",
+        expected: "expected struct Point, got Pair{x: 1, y: 2}",
+    },
+    {
+        name: struct_wrong_type,
+        file: "struct_wrong_type.snek",
+        expected: "expected struct Point, got [1, 2]",
+    },
+    {
+        name: typed_mismatch_unchecked,
+        file: "typed_mismatch.snek",
+        expected: "invalid argument",
+    },
+    {
+        name: match_no_match,
+        file: "match_no_match.snek",
+        input: "-2",
+        expected: "no match for Rect{w: -2, h: 3}",
+    },
+    {
+        name: string_oob,
+        file: "string_oob.snek",
+        input: "5",
+        expected: "index out of bounds",
+    },
+    {
+        name: string_bad_arg,
+        file: "string_bad_arg.snek",
+        input: "3",
+        expected: "invalid argument",
+    },
+    {
+        name: divide_by_zero,
+        file: "divide_by_zero.snek",
+        input: "0",
+        expected: "division by zero",
+    },
+    {
+        name: modulo_by_zero,
+        file: "modulo_by_zero.snek",
+        input: "1",
+        expected: "division by zero",
+    },
+    {
+        name: divide_overflow,
+        file: "divide_overflow.snek",
+        flags: ["--no-bignums"],
+        input: "-1",
+        expected: "overflow",
+    },
+    {
+        name: float_round_inf,
+        file: "float_round_inf.snek",
+        input: "1",
+        expected: "invalid argument",
+    },
+    {
+        name: map_wrong_type,
+        file: "map_wrong_type.snek",
+        input: "5",
+        expected: "invalid argument",
+    },
+    {
+        name: exception_uncaught,
+        file: "exception_uncaught.snek",
+        input: "5",
+        expected: "uncaught exception: [\"oops\", 5]",
+    },
+    {
+        name: divide_overflow_c,
+        file: "divide_overflow.snek",
+        flags: ["--no-bignums", "--backend", "c"],
+        input: "-1",
+        expected: "overflow",
+    },
+    {
+        name: snek_test_assert,
+        file: "snek_tests.snek",
+        flags: ["--test"],
+        input: "1",
+        expected: "assertion failed: (= (double 2) 6): expected 4",
+    },
+    {
+        name: invalid_input,
+        file: "inputs.snek",
+        input: "[1, 2",
+        expected: "invalid input: [1, 2",
+    },
+    {
+        name: io_missing_file,
+        file: "io_missing_file.snek",
+        stdin: "12",
+        expected: "io error: tests/missing/file: No such file or directory",
+    },
+    {
+        name: io_bad_int,
+        file: "io.snek",
+        stdin: "numbers\n1\nx\n",
+        expected: "invalid argument",
+    },
+    {
+        name: ffi_invalid,
+        file: "ffi_invalid.snek",
+        input: "1",
+        expected: "invalid argument",
+    },
+}
+
+static_error_tests! {
+    {
+        name: continue_outside_loop,
+        file: "continue_outside_loop.snek",
+        expected: "continue outside loop",
+    },
+    {
+        name: loop_unknown_label,
+        file: "loop_unknown_label.snek",
+        expected: "unknown loop label 'outer",
+    },
+    {
+        name: local_fun_assign,
+        file: "local_fun_assign.snek",
+        expected: "local function bump can't assign variable count of the enclosing function",
+    },
+    {
+        name: string_prim_arity,
+        file: "string_prim_arity.snek",
+        expected: "`string-length` takes 1 arguments but 2 were supplied",
+    },
+    {
+        name: struct_arity,
+        file: "struct_arity.snek",
+        expected: "`Point` takes 2 arguments but 1 were supplied",
+    },
+    {
+        name: typed_mismatch_return,
+        file: "typed_mismatch.snek",
+        flags: ["--typecheck"],
+        expected: "Type error at 5:3: `negate` should return num, found bool",
+    },
+    {
+        name: typed_mismatch_argument,
+        file: "typed_mismatch.snek",
+        flags: ["--typecheck"],
+        expected: "Type error at 10:12: argument 1 of `double` expects num, found bool",
+    },
+    {
+        name: match_pattern_arity,
+        file: "match_pattern_arity.snek",
+        expected: "pattern `Rect` takes 2 fields but 1 were supplied",
+    },
+    {
+        name: match_duplicate_var,
+        file: "match_duplicate_var.snek",
+        expected: "variable x is bound twice in pattern",
+    },
+    {
+        name: import_cycle,
+        file: "import_cycle.snek",
+        expected: "import cycle cycle_a.snek -> cycle_b.snek -> cycle_a.snek",
+    },
+    {
+        name: import_unknown,
+        file: "import_unknown.snek",
+        expected: "module lists doesn't define length",
+    },
+    {
+        name: prelude_disabled,
+        file: "prelude_disabled.snek",
+        flags: ["--no-prelude"],
+        expected: "function length not defined",
+    },
+    {
+        name: prelude_not_a_function,
+        file: "prelude_not_a_function.snek",
+        expected: "argument 1 of `map` must be the name of a function",
+    },
+    {
+        name: macro_no_rule,
+        file: "macro_no_rule.snek",
+        expected: "no rule of macro `swap!` matches (swap! x) at 7:5",
+    },
+    {
+        name: macro_forever,
+        file: "macro_forever.snek",
+        expected: "expansion of macro `forever` at 5:1 doesn't terminate",
+    },
+    {
+        name: string_unterminated,
+        file: "string_unterminated.snek",
+        expected: "unterminated string literal",
+    },
+    {
+        name: cond_else_not_last,
+        file: "cond_else_not_last.snek",
+        expected: "else must be the last cond clause",
+    },
+    {
+        name: keyword_or,
+        file: "keyword_or.snek",
+        expected: "cannot use keyword `or` as identifier",
+    },
+    {
+        name: big_literal_without_bignums,
+        file: "bignums.snek",
+        flags: ["--no-bignums"],
+        expected: "integer literal overflow",
+    },
+    {
+        name: map_wrong_type_typecheck,
+        file: "map_wrong_type.snek",
+        flags: ["--typecheck"],
+        expected: "Type error at 1:2: argument 1 of `map-get` expects map, found (vec any)",
+    },
+    {
+        name: ffi_arity,
+        file: "ffi_arity.snek",
+        expected: "extern labs takes 1 arguments but 2 were supplied",
+    },
+    {
+        name: export_str,
+        file: "export_str.snek",
+        flags: ["--lib"],
+        expected: "exported function greet uses type str, which C can't represent",
+    },
+    {
+        name: export_c,
+        file: "export.snek",
+        flags: ["--lib", "--backend", "c"],
+        expected: "`--lib` requires the x86-64 backend",
+    },
+}
+
+#[test]
+fn snek_test_command() {
+    let compiler: std::path::PathBuf = ["target", "debug", env!("CARGO_PKG_NAME")].iter().collect();
+    let output = std::process::Command::new(compiler)
+        .args(["test", "tests/snek_tests.snek"])
+        .output()
+        .expect("could not run the compiler");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!output.status.success());
+    assert!(stdout.contains("test double ... ok"), "{stdout}");
+    assert!(
+        stdout.contains("test vec-get out of bounds ... FAILED\n    index out of bounds"),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("test result: 2 passed; 2 failed"),
+        "{stdout}"
+    );
+}
 
+#[test]
+fn tag_checks_stats() {
+    let compiler: std::path::PathBuf = ["target", "debug", env!("CARGO_PKG_NAME")].iter().collect();
+    for (opt, expected) in [
+        ("-O0", "eliminated 0 of 25 runtime checks"),
+        ("-O1", "eliminated 33 of 50 runtime checks"),
+    ] {
+        let output = std::process::Command::new(&compiler)
+            .args([opt, "--stats", "tests/tag_checks.snek"])
+            .arg(format!("tests/tag_checks_stats{opt}.s"))
+            .output()
+            .expect("could not run the compiler");
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(output.status.success(), "{stderr}");
+        assert_eq!(stderr.trim(), expected);
+    }
 }
 
-static_error_tests! {}
+#[test]
+fn snek_export_library() {
+    let compiler: std::path::PathBuf = ["target", "debug", env!("CARGO_PKG_NAME")].iter().collect();
+    let output = std::process::Command::new(compiler)
+        .args(["--lib", "tests/export.snek", "tests/export.s"])
+        .output()
+        .expect("could not run the compiler");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let output = std::process::Command::new("make")
+        .arg("tests/export.embed")
+        .output()
+        .expect("could not run make");
+    assert!(output.status.success(), "linking failed");
+
+    let output = std::process::Command::new("tests/export.embed")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "6\n654321\n1 0\n1 2\n111\n28500\n3 -1\n"
+    );
+    let output = std::process::Command::new("tests/export.embed")
+        .arg("overflow")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "overflow\n");
+}
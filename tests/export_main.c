@@ -0,0 +1,24 @@
+#include <stdio.h>
+
+#include "export.h"
+
+int main(int argc, char **argv) {
+    snek_init(1000);
+    printf("%lld\n", (long long)add3(1, 2, 3));
+    printf("%lld\n", (long long)weighted(1, 10, 100, 1000, 10000, 100000));
+    printf("%d %d\n", is_even(10), is_even(7));
+    printf("%lld %lld\n", (long long)pick(true, 1, 2), (long long)pick(false, 1, 2));
+    printf("%lld\n", (long long)collatz_steps(27));
+    /* Each call allocates a vector, more than the heap holds in total */
+    long long total = 0;
+    for (int i = 0; i < 100; i++) {
+        total += sum_squares(10);
+    }
+    printf("%lld\n", total);
+    printf("%lld %lld\n", (long long)safe_div(7, 2), (long long)safe_div(7, 0));
+    if (argc > 1) {
+        /* Too large for a snek number */
+        add3(INT64_MAX, 0, 0);
+    }
+    return 0;
+}
@@ -0,0 +1,14 @@
+#include <stdint.h>
+
+int64_t add3(int64_t a, int64_t b, int64_t c) { return a + b + c; }
+
+int64_t weighted6(int64_t a, int64_t b, int64_t c, int64_t d, int64_t e, int64_t f) {
+    return a + 2 * b + 3 * c + 4 * d + 5 * e + 6 * f;
+}
+
+int64_t identity(int64_t x) { return x; }
+
+/* 1 if the caller aligned the stack to 16 bytes before the call */
+int64_t stack_aligned(void) {
+    return (uintptr_t)__builtin_frame_address(0) % 16 == 0;
+}
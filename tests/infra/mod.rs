@@ -1,6 +1,7 @@
 use std::{
+    io::Write,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
 };
 
 #[derive(Debug)]
@@ -36,7 +37,9 @@ macro_rules! tests {
             {
                 name: $name:ident,
                 file: $file:literal,
+                $(flags: [$($flag:literal),* $(,)?],)?
                 $(input: $input:literal,)?
+                $(stdin: $stdin:literal,)?
                 $(heap_size: $heap_size:literal,)?
                 expected: $expected:literal $(,)?
                 $(" $(tt:$tt)* ")?
@@ -51,20 +54,27 @@ macro_rules! tests {
                 let mut input = None;
                 $(input = Some($input);)?
                 #[allow(unused_assignments, unused_mut)]
+                let mut stdin = "";
+                $(stdin = $stdin;)?
+                #[allow(unused_assignments, unused_mut)]
                 let mut heap_size = None;
                 $(heap_size = Some($heap_size);)?
+                let flags: &[&str] = &[$($($flag),*)?];
                 let kind = $crate::infra::TestKind::$kind;
-                $crate::infra::run_test(stringify!($name), $subdir, $file, input, heap_size, $expected, kind);
+                $crate::infra::run_test(stringify!($name), $subdir, $file, flags, input, stdin, heap_size, $expected, kind);
             }
         )*
     };
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn run_test(
     name: &str,
     subdir: Option<&str>,
     file: &str,
+    flags: &[&str],
     input: Option<&str>,
+    stdin: &str,
     heap_size: Option<usize>,
     expected: &str,
     kind: TestKind,
@@ -77,23 +87,30 @@ pub(crate) fn run_test(
     path.push(file);
 
     match kind {
-        TestKind::Success => run_success_test(name, &path, expected, input, heap_size),
-        TestKind::RuntimeError => run_runtime_error_test(name, &path, expected, input, heap_size),
-        TestKind::StaticError => run_static_error_test(name, &path, expected),
+        TestKind::Success => {
+            run_success_test(name, &path, flags, expected, input, stdin, heap_size)
+        }
+        TestKind::RuntimeError => {
+            run_runtime_error_test(name, &path, flags, expected, input, stdin, heap_size)
+        }
+        TestKind::StaticError => run_static_error_test(name, &path, flags, expected),
     }
 }
 
 fn run_success_test(
     name: &str,
     file: &Path,
+    flags: &[&str],
     expected: &str,
     input: Option<&str>,
+    stdin: &str,
     heap_size: Option<usize>,
 ) {
-    if let Err(err) = compile(name, file) {
-        panic!("expected a successful compilation, but got an error: `{err}`");
-    }
-    match run(name, input, heap_size) {
+    let exe = match compile(name, file, flags) {
+        Ok(exe) => exe,
+        Err(err) => panic!("expected a successful compilation, but got an error: `{err}`"),
+    };
+    match run(&exe, input, stdin, heap_size) {
         Err(err) => {
             panic!("expected a successful execution, but got an error: `{err}`");
         }
@@ -106,14 +123,17 @@ fn run_success_test(
 fn run_runtime_error_test(
     name: &str,
     file: &Path,
+    flags: &[&str],
     expected: &str,
     input: Option<&str>,
+    stdin: &str,
     heap_size: Option<usize>,
 ) {
-    if let Err(err) = compile(name, file) {
-        panic!("expected a successful compilation, but got an error: `{err}`");
-    }
-    match run(name, input, heap_size) {
+    let exe = match compile(name, file, flags) {
+        Ok(exe) => exe,
+        Err(err) => panic!("expected a successful compilation, but got an error: `{err}`"),
+    };
+    match run(&exe, input, stdin, heap_size) {
         Ok(out) => {
             panic!("expected a runtime error, but program executed succesfully - expected error: `{expected}`, output: `{out}`");
         }
@@ -121,9 +141,9 @@ fn run_runtime_error_test(
     }
 }
 
-fn run_static_error_test(name: &str, file: &Path, expected: &str) {
-    match compile(name, file) {
-        Ok(()) => {
+fn run_static_error_test(name: &str, file: &Path, flags: &[&str], expected: &str) {
+    match compile(name, file, flags) {
+        Ok(_) => {
             panic!(
                 "expected a static error, but compilation succeeded - expected error: `{expected}`"
             )
@@ -132,37 +152,70 @@ fn run_static_error_test(name: &str, file: &Path, expected: &str) {
     }
 }
 
-fn compile(name: &str, file: &Path) -> Result<(), String> {
+/// Compiles a test with the backend its flags select, or else the one named by the `SNEK_BACKEND`
+/// environment variable (`x86-64` by default, and always for libraries compiled with `--lib`), and
+/// returns the path of the executable.
+fn compile(name: &str, file: &Path, flags: &[&str]) -> Result<PathBuf, String> {
+    let mut flags: Vec<String> = flags.iter().map(|flag| flag.to_string()).collect();
+    if !flags
+        .iter()
+        .any(|flag| flag == "--backend" || flag == "--lib")
+    {
+        if let Ok(backend) = std::env::var("SNEK_BACKEND") {
+            flags.extend(["--backend".to_string(), backend]);
+        }
+    }
+    let c_backend = flags.windows(2).any(|w| w[0] == "--backend" && w[1] == "c");
+    let (out, exe) = if c_backend {
+        (Ext::C, Ext::CRun)
+    } else {
+        (Ext::Asm, Ext::Run)
+    };
+
     // Run the compiler
     let compiler: PathBuf = ["target", "debug", env!("CARGO_PKG_NAME")].iter().collect();
     let output = Command::new(&compiler)
+        .args(&flags)
         .arg(file)
-        .arg(&mk_path(name, Ext::Asm))
+        .arg(mk_path(name, out))
         .output()
         .expect("could not run the compiler");
     if !output.status.success() {
         return Err(String::from_utf8(output.stderr).unwrap());
     }
 
-    // Assemble and link
+    // Assemble (or compile the C) and link
     let output = Command::new("make")
-        .arg(&mk_path(name, Ext::Run))
+        .arg(mk_path(name, exe))
         .output()
         .expect("could not run make");
     assert!(output.status.success(), "linking failed");
 
-    Ok(())
+    Ok(mk_path(name, exe))
 }
 
-fn run(name: &str, input: Option<&str>, heap_size: Option<usize>) -> Result<String, String> {
-    let mut cmd = Command::new(&mk_path(name, Ext::Run));
+fn run(
+    exe: &Path,
+    input: Option<&str>,
+    stdin: &str,
+    heap_size: Option<usize>,
+) -> Result<String, String> {
+    let mut cmd = Command::new(exe);
     if let Some(input) = input {
         cmd.arg(input);
     }
     if let Some(heap_size) = heap_size {
         cmd.arg(heap_size.to_string());
     }
-    let output = cmd.output().unwrap();
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The program may exit without reading all of stdin
+    let _ = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+    let output = child.wait_with_output().unwrap();
     if output.status.success() {
         Ok(String::from_utf8(output.stdout).unwrap().trim().to_string())
     } else {
@@ -201,6 +254,9 @@ fn mk_path(name: &str, ext: Ext) -> PathBuf {
 enum Ext {
     Asm,
     Run,
+    /// C written by the C backend
+    C,
+    CRun,
 }
 
 impl std::fmt::Display for Ext {
@@ -208,6 +264,8 @@ impl std::fmt::Display for Ext {
         match self {
             Ext::Asm => write!(f, "s"),
             Ext::Run => write!(f, "run"),
+            Ext::C => write!(f, "gen.c"),
+            Ext::CRun => write!(f, "gen.run"),
         }
     }
 }